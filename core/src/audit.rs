@@ -0,0 +1,200 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::queue::read_list;
+use crate::{
+    append_state_file, current_crate_name, encode_list_item, setting_enabled, stable_hash,
+    state_file_path, StateResult, RESERVED_KEY_PREFIX,
+};
+
+/// The internal key under which the reads recorded via [`proc_record_reads`] are kept.
+const READ_AUDIT_KEY: &str = "__macro_state/read_audit";
+
+/// Whether the reads made by the current process are being recorded.
+static RECORD_READS: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// The reads already recorded by the current process, so that a macro reading the same
+    /// key over and over only records it once.
+    static ref RECORDED_READS: Mutex<HashSet<(String, Option<u64>)>> = Mutex::new(HashSet::new());
+}
+
+/// A single state read recorded via [`proc_record_reads`], as returned by
+/// [`proc_read_audit`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StateRead {
+    /// The key that was read.
+    pub key: String,
+    /// The 64-bit FNV-1a hash of the value that was read, or [`None`] if the key had no value.
+    pub value_hash: Option<u64>,
+    /// The name of the crate whose compilation made the read.
+    pub crate_name: String,
+}
+
+impl StateRead {
+    /// Parses an entry of the read audit, as recorded by [`note_read`].
+    fn parse(entry: &str) -> Option<StateRead> {
+        let mut fields = entry.split('\u{1f}');
+        let crate_name = fields.next()?.to_string();
+        let key = fields.next()?.to_string();
+        let value_hash = match fields.next()? {
+            "-" => None,
+            hash => Some(u64::from_str_radix(hash, 16).ok()?),
+        };
+        Some(StateRead {
+            key,
+            value_hash,
+            crate_name,
+        })
+    }
+}
+
+/// Starts (if `enabled` is `true`) or stops recording every state read made by the current
+/// process, along with a hash of the value each read returned, so that the build can later be
+/// audited via [`proc_read_audit`] or [`proc_export_read_audit`]. Reads that find no value are
+/// recorded too, since generated code can just as well depend on the absence of a key.
+///
+/// Recording applies to [`proc_read_state`](crate::proc_read_state),
+/// [`proc_read_state_vec`](crate::proc_read_state_vec),
+/// [`proc_has_state`](crate::proc_has_state), and every other function that reads the value
+/// of a key. Since every crate is compiled by its own process, this is best called at the start
+/// of each proc macro. Alternatively, enabling the `record_reads` setting (or the
+/// `MACRO_STATE_RECORD_READS` environment variable) records every read of the build, including
+/// those made by macros such as [`read_state!`](crate::read_state!) and
+/// [`has_state!`](crate::has_state!). Reads of the keys `macro_state` keeps internally are never
+/// recorded.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("audited input", "checked in").unwrap();
+/// proc_record_reads(true);
+/// proc_read_state("audited input").unwrap();
+/// proc_record_reads(false);
+/// assert!(proc_read_audit()
+///     .iter()
+///     .any(|read| read.key == "audited input" && read.value_hash.is_some()));
+/// ```
+pub fn proc_record_reads(enabled: bool) {
+    RECORD_READS.store(enabled, Ordering::SeqCst);
+}
+
+/// If reads are being recorded (see [`proc_record_reads`]), records that the current crate
+/// read `value` (or found no value) for `key`.
+pub(crate) fn note_read(key: &str, value: Option<&str>) {
+    if !(RECORD_READS.load(Ordering::SeqCst) || setting_enabled("record_reads"))
+        || key.starts_with(RESERVED_KEY_PREFIX)
+    {
+        return;
+    }
+    let value_hash = value.map(stable_hash);
+    if !RECORDED_READS
+        .lock()
+        .unwrap()
+        .insert((key.to_string(), value_hash))
+    {
+        return;
+    }
+    let hash = match value_hash {
+        Some(hash) => format!("{:016x}", hash),
+        None => String::from("-"),
+    };
+    let entry = format!("{}\u{1f}{}\u{1f}{}", current_crate_name(), key, hash);
+    let _ = append_state_file(
+        &state_file_path(READ_AUDIT_KEY),
+        &encode_list_item(&entry),
+        None,
+    );
+}
+
+/// Returns every read recorded via [`proc_record_reads`] during the current build, by any
+/// crate, sorted by key. A key read with several different values (for example, once before
+/// and once after it was written) is listed once per value, which is usually a sign that the
+/// generated code depends on the order in which macros expand.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_record_reads(true);
+/// proc_has_state("audited missing key");
+/// proc_record_reads(false);
+/// let audit = proc_read_audit();
+/// let read = audit.iter().find(|read| read.key == "audited missing key").unwrap();
+/// assert_eq!(read.value_hash, None);
+/// ```
+pub fn proc_read_audit() -> Vec<StateRead> {
+    let mut reads: Vec<StateRead> = read_list(READ_AUDIT_KEY)
+        .iter()
+        .filter_map(|entry| StateRead::parse(entry))
+        .collect();
+    reads.sort();
+    reads.dedup();
+    reads
+}
+
+/// Formats the specified reads as the contents of a read audit file, with one tab-separated
+/// `key`, `value hash`, and `crate` line per read.
+fn format_read_audit(reads: &[StateRead]) -> String {
+    let mut audit = String::from("# macro_state read audit: key, FNV-1a hash of value, crate\n");
+    for read in reads {
+        let hash = match read.value_hash {
+            Some(hash) => format!("{:016x}", hash),
+            None => String::from("missing"),
+        };
+        audit.push_str(&format!("{}\t{}\t{}\n", read.key, hash, read.crate_name));
+    }
+    audit
+}
+
+/// Writes every read returned by [`proc_read_audit`] to the file at `path`, one tab-separated
+/// `key`, `value hash`, and `crate` line per read (with `missing` in place of the hash of keys
+/// that had no value), so that reproducible-build auditors can compare the state a build
+/// depended on against its checked-in inputs, or diff the audits of two builds. Since the reads
+/// are sorted, building the same inputs twice produces identical files.
+///
+/// If any IO error occurs while writing the file, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("exported audit input", "value").unwrap();
+/// proc_record_reads(true);
+/// proc_read_state("exported audit input").unwrap();
+/// proc_record_reads(false);
+/// let path = std::path::Path::new(STATE_DIR).join("read_audit.tsv");
+/// proc_export_read_audit(&path).unwrap();
+/// let audit = std::fs::read_to_string(path).unwrap();
+/// assert!(audit.contains("exported audit input\t"));
+/// ```
+pub fn proc_export_read_audit(path: impl AsRef<Path>) -> StateResult<()> {
+    fs::write(path, format_read_audit(&proc_read_audit()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_read_audit() {
+        let reads = vec![
+            StateRead::parse("my_macros\u{1f}models\u{1f}00000000000000ff").unwrap(),
+            StateRead::parse("my_app\u{1f}routes\u{1f}-").unwrap(),
+        ];
+        assert_eq!(reads[0].value_hash, Some(0xff));
+        assert_eq!(reads[1].value_hash, None);
+        assert_eq!(StateRead::parse("my_app\u{1f}routes"), None);
+        assert_eq!(
+            format_read_audit(&reads),
+            "# macro_state read audit: key, FNV-1a hash of value, crate\n\
+            models\t00000000000000ff\tmy_macros\n\
+            routes\tmissing\tmy_app\n"
+        );
+    }
+}
@@ -0,0 +1,567 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::eviction::active_suffixes;
+use crate::{
+    flatten_records, key_filename, lock_state_dir, proc_state_generation, read_state_contents,
+    setting, stable_hash, state_dir, state_file_path, StateResult, STATE_FORMAT_VERSION,
+};
+
+lazy_static! {
+    static ref STATE_BACKEND: Mutex<Option<Arc<dyn StateBackend>>> =
+        Mutex::new(ObjectStoreBackend::from_env().map(|backend| Arc::new(backend) as _));
+}
+
+/// A remote store that state can be fetched from when a key has no local value, and that
+/// written state is stored to, so that state produced by one build can be reused by others.
+///
+/// The backend used by `macro_state` is an [`ObjectStoreBackend`] if the `MACRO_STATE_REMOTE`
+/// environment variable is set, and can be replaced by any other implementation via
+/// [`proc_set_state_backend`].
+pub trait StateBackend: Send + Sync {
+    /// Returns the value stored remotely for `key`, or [`None`] if there is none.
+    fn fetch(&self, key: &str) -> StateResult<Option<String>>;
+
+    /// Stores `value` remotely as the value of `key`.
+    fn store(&self, key: &str, value: &str) -> StateResult<()>;
+
+    /// Stores each of the `values` (pairs of keys and values) remotely as a single batch.
+    /// Stores them one by one via [`StateBackend::store`] by default.
+    fn store_all(&self, values: &[(String, String)]) -> StateResult<()> {
+        for (key, value) in values {
+            self.store(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Uploads any values whose upload has been deferred by [`StateBackend::store`]. Does
+    /// nothing by default.
+    fn flush(&self) -> StateResult<()> {
+        Ok(())
+    }
+}
+
+/// Installs `backend` as the [`StateBackend`] of the current process, replacing the backend
+/// configured via environment variables (if any). Should only be used within proc macros.
+///
+/// From then on, reads that find no local value for a key (via
+/// [`proc_read_state`](crate::proc_read_state) and friends) fall back to [`StateBackend::fetch`].
+/// Fetched values are never stored locally, so appending to a key whose value was fetched
+/// starts a fresh local list rather than extending the remote one. Every time a key is written
+/// via [`proc_write_state`](crate::proc_write_state) or appended to via
+/// [`proc_append_state`](crate::proc_append_state), its new value is passed to
+/// [`StateBackend::store`] before the write returns, and an error returned by the backend is
+/// returned by the write. Backends that would rather upload in batches can defer the upload
+/// until [`StateBackend::flush`] is called by [`proc_flush_state_backend`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// struct Prebuilt;
+///
+/// impl StateBackend for Prebuilt {
+///     fn fetch(&self, key: &str) -> StateResult<Option<String>> {
+///         Ok((key == "prebuilt models").then(|| String::from("User")))
+///     }
+///
+///     fn store(&self, _key: &str, _value: &str) -> StateResult<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// proc_set_state_backend(Prebuilt);
+/// assert_eq!(proc_read_state("prebuilt models").unwrap(), "User");
+/// proc_clear_state_backend();
+/// ```
+pub fn proc_set_state_backend<B: StateBackend + 'static>(backend: B) {
+    *STATE_BACKEND.lock().unwrap() = Some(Arc::new(backend));
+}
+
+/// Removes the [`StateBackend`] of the current process, if any, so that state is only ever
+/// read from and written to the local state directory.
+pub fn proc_clear_state_backend() {
+    *STATE_BACKEND.lock().unwrap() = None;
+}
+
+/// Uploads any values whose upload has been deferred by the [`StateBackend`] of the current
+/// process, such as those written to an [`ObjectStoreBackend`] with [`WritePolicy::Back`].
+/// Should only be used within proc macros, typically once all state has been written (for
+/// example from the last macro expanded by the build).
+pub fn proc_flush_state_backend() -> StateResult<()> {
+    match state_backend() {
+        Some(backend) => backend.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Returns the [`StateBackend`] of the current process, if any.
+fn state_backend() -> Option<Arc<dyn StateBackend>> {
+    STATE_BACKEND.lock().unwrap().clone()
+}
+
+/// Fetches the value of `key` from the [`StateBackend`] of the current process (if any). The
+/// value is only ever overlaid on the local state, never written to it.
+pub(crate) fn fetch_remote_state(key: &str) -> StateResult<Option<String>> {
+    match state_backend() {
+        Some(backend) => backend.fetch(key),
+        None => Ok(None),
+    }
+}
+
+/// Passes the current value of `key`, which has just been written to or appended to, to the
+/// [`StateBackend`] of the current process (if any). A key removed in the meantime is skipped.
+pub(crate) fn store_remote_state(key: &str) -> StateResult<()> {
+    let Some(backend) = state_backend() else {
+        return Ok(());
+    };
+    match read_state_contents(&state_file_path(key)) {
+        Ok(value) => backend.store(key, &flatten_records(value)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// When values written to an [`ObjectStoreBackend`] are uploaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Values are never uploaded, so the build only consumes remote state.
+    None,
+    /// Every value is uploaded as soon as it is written, before the write returns.
+    Through,
+    /// Values are kept locally and uploaded all at once by [`proc_flush_state_backend`], so
+    /// writes never wait on the network.
+    Back,
+}
+
+/// A [`StateBackend`] storing state in an Amazon S3 (`s3://bucket/prefix`) or Google Cloud
+/// Storage (`gs://bucket/prefix`) location, with one object per key. Transfers are made via the
+/// `aws` and `gcloud` command line tools respectively, using whatever credentials they are
+/// configured with.
+///
+/// Reads that find no value in the current generation are served from a local persistent
+/// mirror of the remote location, which outlives builds. The first remote fetch made by a build
+/// synchronizes the mirror with the location (once for every crate of the build), transferring
+/// only the objects that changed since the mirror was last synchronized, so that a cold CI
+/// build can be primed with the state of a warm one at the cost of a single transfer, while
+/// hot local iteration only downloads what changed. Values are never served from a mirror that
+/// has not been synchronized by the current build. Since remote state outlives builds, the
+/// location should identify the inputs the state depends on, for example by including a hash
+/// of `Cargo.lock` in the prefix.
+///
+/// Written values are stored in a local directory of the current build and uploaded from there
+/// according to the [`WritePolicy`] of the backend, always in a single transfer per batch.
+///
+/// The backend is configured via environment variables by setting `MACRO_STATE_REMOTE` to the
+/// location, and `MACRO_STATE_REMOTE_WRITE` to `through` or `back` to select a [`WritePolicy`]
+/// other than [`WritePolicy::None`].
+pub struct ObjectStoreBackend {
+    url: String,
+    policy: WritePolicy,
+    downloaded: OnceLock<bool>,
+    /// Runs this program in place of the command line tool found in `PATH`, for testing.
+    program: Option<PathBuf>,
+}
+
+impl ObjectStoreBackend {
+    /// Creates a backend for the specified `s3://` or `gs://` location, uploading written
+    /// values according to `policy`.
+    ///
+    /// Returns an [`Err`] of kind [`ErrorKind::InvalidInput`] if `url` is neither an `s3://` nor
+    /// a `gs://` location.
+    pub fn new(url: &str, policy: WritePolicy) -> StateResult<Self> {
+        if !url.starts_with("s3://") && !url.starts_with("gs://") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "unsupported remote state location \"{}\" (expected s3:// or gs://)",
+                    url
+                ),
+            )
+            .into());
+        }
+        Ok(ObjectStoreBackend {
+            url: url.trim_end_matches('/').to_string(),
+            policy,
+            downloaded: OnceLock::new(),
+            program: None,
+        })
+    }
+
+    /// Creates a backend as configured by the `remote` and `remote_write` settings (set via
+    /// `macro_state.toml` or the `MACRO_STATE_REMOTE` and `MACRO_STATE_REMOTE_WRITE`
+    /// environment variables), or returns [`None`] if no (valid) location is configured.
+    pub fn from_env() -> Option<Self> {
+        let url = setting("remote")?;
+        let policy = match setting("remote_write").as_deref() {
+            Some("through") => WritePolicy::Through,
+            Some("back") => WritePolicy::Back,
+            _ => WritePolicy::None,
+        };
+        match ObjectStoreBackend::new(&url, policy) {
+            Ok(backend) => Some(backend),
+            Err(e) => {
+                eprintln!("warning: macro_state: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Returns the location of this backend.
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// Returns the [`WritePolicy`] of this backend.
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// Returns the local directory of this backend, holding the mirror of the remote location
+    /// (see [`ObjectStoreBackend::mirror_dir`]) along with the values written by each build.
+    fn cache_dir(&self) -> PathBuf {
+        let mut dir = state_dir().to_path_buf();
+        dir.push(format!("v{}", STATE_FORMAT_VERSION));
+        dir.push("remote");
+        dir.push(format!("{:016x}", stable_hash(&self.url)));
+        dir
+    }
+
+    /// Returns the local persistent mirror of the remote location.
+    fn mirror_dir(&self) -> PathBuf {
+        self.cache_dir().join("objects")
+    }
+
+    /// Returns the directory holding the values written by the current build, from which they
+    /// are uploaded.
+    fn upload_dir(&self) -> PathBuf {
+        self.cache_dir()
+            .join(format!("upload_{}", proc_state_generation()))
+    }
+
+    /// Returns the command line tool used to access the location of this backend, along with
+    /// the sub-command all transfers go through.
+    fn tool(&self) -> (&'static str, &'static str) {
+        match self.url.starts_with("s3://") {
+            true => ("aws", "s3"),
+            false => ("gcloud", "storage"),
+        }
+    }
+
+    /// Returns a command running the command line tool for the location of this backend.
+    fn command(&self) -> Command {
+        let (program, subcommand) = self.tool();
+        let mut command = match &self.program {
+            Some(path) => Command::new(path),
+            None => Command::new(program),
+        };
+        command.arg(subcommand);
+        command
+    }
+
+    /// Runs the command line tool of this backend with the specified arguments. Failing to run
+    /// the tool at all (most commonly because it is not installed) is reported as such, keeping
+    /// the kind of the underlying IO error.
+    fn run(&self, args: &[&str]) -> StateResult<()> {
+        let output = self.command().args(args).output().map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "cannot run `{}`, which is required to access remote state at {}: {}",
+                    self.tool().0,
+                    self.url,
+                    e
+                ),
+            )
+        })?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(Error::other(format!(
+                "`{:?}` failed: {}",
+                self.command().args(args),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+            .into()),
+        }
+    }
+
+    /// Synchronizes the directory or location `from` into the directory or location `to`,
+    /// removing whatever is only found in `to` if `delete` is `true`.
+    fn sync(&self, from: &str, to: &str, delete: bool) -> StateResult<()> {
+        match (self.url.starts_with("s3://"), delete) {
+            (true, true) => self.run(&["sync", "--delete", from, to]),
+            (true, false) => self.run(&["sync", from, to]),
+            (false, true) => self.run(&[
+                "rsync",
+                "-r",
+                "--delete-unmatched-destination-objects",
+                from,
+                to,
+            ]),
+            (false, false) => self.run(&["rsync", "-r", from, to]),
+        }
+    }
+
+    /// Synchronizes the persistent mirror with the location of this backend, unless another
+    /// process of the current build has already done so, removing the leftovers of builds that
+    /// are no longer running.
+    fn download(&self) -> StateResult<()> {
+        let _lock = lock_state_dir()?;
+        let dir = self.cache_dir();
+        let synced = dir.join(format!("synced_{}", proc_state_generation()));
+        if synced.exists() {
+            return Ok(());
+        }
+        let mirror = self.mirror_dir();
+        fs::create_dir_all(&mirror)?;
+        self.sync(&self.url, &mirror.to_string_lossy(), true)?;
+        fs::write(&synced, "")?;
+        Ok(remove_stale_remote_files(&dir)?)
+    }
+
+    /// Uploads the values written by the current build.
+    fn upload(&self) -> StateResult<()> {
+        let _lock = lock_state_dir()?;
+        let dir = self.upload_dir();
+        match dir.exists() {
+            true => self.sync(&dir.to_string_lossy(), &self.url, false),
+            false => Ok(()),
+        }
+    }
+
+    /// Returns the value mirrored for `key`, if any.
+    fn cached(&self, key: &str) -> StateResult<Option<String>> {
+        match fs::read_to_string(self.mirror_dir().join(key_filename(key))) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl StateBackend for ObjectStoreBackend {
+    fn fetch(&self, key: &str) -> StateResult<Option<String>> {
+        let downloaded = *self.downloaded.get_or_init(|| match self.download() {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!(
+                    "warning: macro_state: cannot fetch remote state from {}: {}",
+                    self.url, e
+                );
+                false
+            }
+        });
+        match downloaded {
+            true => self.cached(key),
+            false => Ok(None),
+        }
+    }
+
+    fn store(&self, key: &str, value: &str) -> StateResult<()> {
+        self.store_all(&[(key.to_string(), value.to_string())])
+    }
+
+    fn store_all(&self, values: &[(String, String)]) -> StateResult<()> {
+        if self.policy == WritePolicy::None {
+            return Ok(());
+        }
+        let dir = self.upload_dir();
+        fs::create_dir_all(&dir)?;
+        for (key, value) in values {
+            fs::write(dir.join(key_filename(key)), value)?;
+        }
+        match self.policy {
+            WritePolicy::Through => self.upload(),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> StateResult<()> {
+        match self.policy {
+            WritePolicy::Back => self.upload(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Removes the sync markers and upload directories that the local directory `dir` of an
+/// [`ObjectStoreBackend`] holds for builds that are no longer running. Values a build never
+/// uploaded (see [`WritePolicy::Back`]) are discarded along with it.
+fn remove_stale_remote_files(dir: &Path) -> std::io::Result<()> {
+    let active = active_suffixes();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let owned = name.starts_with("synced_") || name.starts_with("upload_");
+        if !owned || active.iter().any(|suffix| name.ends_with(suffix.as_str())) {
+            continue;
+        }
+        let removed = match entry.file_type()?.is_dir() {
+            true => fs::remove_dir_all(entry.path()),
+            false => fs::remove_file(entry.path()),
+        };
+        match removed {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl std::fmt::Debug for ObjectStoreBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObjectStoreBackend")
+            .field("url", &self.url)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::collections::HashMap;
+
+    /// Serializes the tests installing a backend for the whole process.
+    static BACKEND_LOCK: Mutex<()> = Mutex::new(());
+
+    #[derive(Default)]
+    struct MemoryBackend(Mutex<HashMap<String, String>>);
+
+    impl StateBackend for Arc<MemoryBackend> {
+        fn fetch(&self, key: &str) -> StateResult<Option<String>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        fn store(&self, key: &str, value: &str) -> StateResult<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_state_backend() {
+        let _lock = BACKEND_LOCK.lock().unwrap();
+        assert!(ObjectStoreBackend::new("https://example.com", WritePolicy::None).is_err());
+        let backend = ObjectStoreBackend::new("s3://bucket/prefix/", WritePolicy::Back).unwrap();
+        assert_eq!(backend.url(), "s3://bucket/prefix");
+        backend.store("backend tiered", "written").unwrap();
+        let uploaded = backend.upload_dir().join(key_filename("backend tiered"));
+        assert_eq!(fs::read_to_string(uploaded).unwrap(), "written");
+        let mirrored = backend.mirror_dir().join(key_filename("backend tiered"));
+        fs::create_dir_all(backend.mirror_dir()).unwrap();
+        fs::write(mirrored, "from an earlier build").unwrap();
+        assert_eq!(
+            backend.cached("backend tiered").unwrap().unwrap(),
+            "from an earlier build"
+        );
+        // the mirror is never served before the current build has synchronized it
+        assert_eq!(backend.fetch("backend tiered").unwrap(), None);
+        let stale = backend.cache_dir().join("upload_1");
+        fs::create_dir_all(&stale).unwrap();
+        remove_stale_remote_files(&backend.cache_dir()).unwrap();
+        assert!(!stale.exists());
+        assert!(backend.upload_dir().exists());
+
+        let remote = Arc::new(MemoryBackend::default());
+        remote.store("backend registry", "User\nPost\n").unwrap();
+        proc_set_state_backend(remote.clone());
+        assert_eq!(
+            proc_read_state_vec("backend registry"),
+            vec!["User", "Post"]
+        );
+        assert!(!proc_has_state("backend registry"));
+        proc_append_state("backend registry", "Comment").unwrap();
+        assert_eq!(proc_read_state_vec("backend registry"), vec!["Comment"]);
+        // values are passed to the backend as they are written, not when the process exits
+        proc_write_state("backend written", "value").unwrap();
+        assert_eq!(remote.0.lock().unwrap()["backend written"], "value");
+        proc_flush_state_backend().unwrap();
+        proc_clear_state_backend();
+        flush_remote_state!();
+        let stored = remote.0.lock().unwrap().clone();
+        assert_eq!(stored["backend registry"], "Comment\n");
+        assert_eq!(stored["backend written"], "value");
+        assert!(proc_read_state("backend missing").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_through() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let _lock = BACKEND_LOCK.lock().unwrap();
+        let root = std::env::temp_dir().join(format!("macro_state_s3_{}", std::process::id()));
+        let bucket = root.join("bucket");
+        // stands in for `aws s3 sync <from> <to>`, with `s3://bucket` mapped to a directory
+        let tool = root.join("aws");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(
+            &tool,
+            format!(
+                "#!/bin/sh\nshift 2\n[ \"$1\" = --delete ] && shift\n\
+                 to=$(echo \"$2\" | sed 's|^s3://bucket|{}|')\n\
+                 mkdir -p \"$to\" && cp -R \"$1\"/. \"$to\"\n",
+                bucket.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut backend = ObjectStoreBackend::new("s3://bucket", WritePolicy::Through).unwrap();
+        backend.program = Some(tool.clone());
+        proc_set_state_backend(backend);
+        proc_write_state("backend through", "uploaded").unwrap();
+        // the object exists as soon as the write returns
+        let object = bucket.join(key_filename("backend through"));
+        assert_eq!(fs::read_to_string(&object).unwrap(), "uploaded");
+        proc_clear_state_backend();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    struct RejectingBackend;
+
+    impl StateBackend for RejectingBackend {
+        fn fetch(&self, _key: &str) -> StateResult<Option<String>> {
+            Ok(None)
+        }
+
+        fn store(&self, key: &str, _value: &str) -> StateResult<()> {
+            match key {
+                "backend rejected" => Err(Error::other("upload failed").into()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_store() {
+        let _lock = BACKEND_LOCK.lock().unwrap();
+        proc_set_state_backend(RejectingBackend);
+        let result = proc_write_state("backend rejected", "value");
+        proc_clear_state_backend();
+        assert_eq!(result.unwrap_err().to_string(), "upload failed");
+    }
+
+    #[test]
+    fn test_missing_remote_tool() {
+        let mut backend = ObjectStoreBackend::new("s3://bucket", WritePolicy::Through).unwrap();
+        backend.program = Some(PathBuf::from("/nonexistent/aws"));
+        let e = match backend.store("backend missing tool", "value") {
+            Err(MacroStateError::Io(e)) => e,
+            other => panic!("expected an IO error, got {:?}", other),
+        };
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert!(e.to_string().starts_with(
+            "cannot run `aws`, which is required to access remote state at s3://bucket"
+        ));
+        let backend = ObjectStoreBackend::new("gs://bucket", WritePolicy::Through).unwrap();
+        assert_eq!(backend.tool(), ("gcloud", "storage"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::{
+    memory_mode, replace_file, retry_io, setting, stable_hash, state_dir, write_state_file, Caller,
+    STATE_FORMAT_VERSION,
+};
+
+/// By default, values at least this many bytes long are stored content-addressed, as a blob
+/// shared by every key holding the same value, with the state file of each key holding a
+/// pointer to the blob. The threshold can be changed via the `intern_min_len` setting.
+pub(crate) const MIN_BLOB_LEN: usize = 4096;
+
+/// Prefixes the contents of state files that point to a blob rather than holding a value.
+pub(crate) const BLOB_POINTER_PREFIX: &str = "\u{0}macro_state_blob:";
+
+/// State files longer than this many bytes can never hold a blob pointer.
+pub(crate) const MAX_POINTER_LEN: u64 = 64;
+
+/// Parses the `intern_min_len` setting into the length at which values are interned as blobs,
+/// falling back to [`MIN_BLOB_LEN`] if it is unset or invalid. Values no longer than a blob
+/// pointer are never interned, since pointing to them would take up more space than storing
+/// them. Lowering the threshold is safe however many values end up interned, since a blob is
+/// only ever reused for the very same value (see [`store_blob`]).
+fn intern_min_len(setting: Option<&str>) -> usize {
+    setting
+        .and_then(|len| len.trim().parse::<usize>().ok())
+        .map_or(MIN_BLOB_LEN, |len| len.max(MAX_POINTER_LEN as usize + 1))
+}
+
+/// Returns the directory holding every blob. Blobs are shared by all generations, so values
+/// that stay the same from one build to the next are only ever written once.
+pub(crate) fn blob_dir() -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push(format!("v{}", STATE_FORMAT_VERSION));
+    buf.push("blobs");
+    buf
+}
+
+/// Returns the name of the blob holding `value`, derived from its hash and length, followed by
+/// the number of earlier blobs with the same hash and length but different values (if any).
+fn blob_name(value: &str, collisions: usize) -> String {
+    match collisions {
+        0 => format!("{:016x}_{}", stable_hash(value), value.len()),
+        n => format!("{:016x}_{}_{}", stable_hash(value), value.len(), n),
+    }
+}
+
+/// Stores `value` as a blob, unless an identical blob has been stored before, returning the
+/// name of the blob. Blobs are never modified once written, and since the hash their name is
+/// derived from is not collision-resistant, an existing blob is only reused if it holds the
+/// very same value: otherwise the next name (see [`blob_name`]) is tried.
+fn store_blob(value: &str) -> Result<String> {
+    fs::create_dir_all(blob_dir())?;
+    let mut collisions = 0;
+    loop {
+        let name = blob_name(value, collisions);
+        let blob = blob_dir().join(&name);
+        match retry_io(|| fs::read(&blob)) {
+            Ok(existing) if existing == value.as_bytes() => {
+                // keeps blobs that are still in use from being evicted as least recently written
+                let _ = File::options()
+                    .write(true)
+                    .open(&blob)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+                return Ok(name);
+            }
+            Ok(_) => collisions += 1,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                replace_file(&blob, value.as_bytes())?;
+                return Ok(name);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writes `value` to the specified state file, storing it as a blob (see [`store_blob`]) if it
+/// is long enough to be interned (see [`MIN_BLOB_LEN`]). Since appending to a key first
+/// replaces its pointer with a private copy of the value (see [`materialize_blob`]), modifying
+/// one key never affects the others.
+pub(crate) fn write_state_value(path: &Path, value: &str, caller: Caller) -> Result<()> {
+    if value.len() < intern_min_len(setting("intern_min_len").as_deref()) || memory_mode() {
+        return write_state_file(path, value, caller);
+    }
+    let name = store_blob(value)?;
+    write_state_file(path, &format!("{}{}", BLOB_POINTER_PREFIX, name), caller)
+}
+
+/// Returns the path of the blob the specified state file points to, if it points to one.
+pub(crate) fn blob_file(path: &Path) -> Option<PathBuf> {
+    if memory_mode() || fs::metadata(path).ok()?.len() > MAX_POINTER_LEN {
+        return None;
+    }
+    let contents = fs::read_to_string(path).ok()?;
+    let name = contents.strip_prefix(BLOB_POINTER_PREFIX)?;
+    Some(blob_dir().join(name))
+}
+
+/// Resolves the raw `contents` of a state file into its value, reading the blob it points to
+/// if it holds a blob pointer.
+pub(crate) fn resolve_blob(contents: String) -> Result<String> {
+    match contents.strip_prefix(BLOB_POINTER_PREFIX) {
+        Some(name) => retry_io(|| fs::read_to_string(blob_dir().join(name))),
+        None => Ok(contents),
+    }
+}
+
+/// Replaces a blob pointer in the specified state file with the value of the blob, so that the
+/// file can be appended to. The file is replaced rather than rewritten in place (see
+/// [`replace_file`]), so memory-mapped views of it are never truncated.
+pub(crate) fn materialize_blob(path: &Path) -> Result<()> {
+    match blob_file(path) {
+        Some(blob) => {
+            let value = retry_io(|| fs::read_to_string(&blob))?;
+            replace_file(path, value.as_bytes())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_blobs() {
+        let schema = "{\"type\": \"object\"}".repeat(MIN_BLOB_LEN / 8);
+        proc_write_state("blob schema a", &schema).unwrap();
+        proc_write_state("blob schema b", &schema).unwrap();
+        let blob = blob_file(&state_file_path("blob schema a")).unwrap();
+        assert_eq!(
+            blob_file(&state_file_path("blob schema b")),
+            Some(blob.clone())
+        );
+        assert_eq!(fs::read_to_string(&blob).unwrap(), schema);
+        cache_invalidate(&state_file_path("blob schema a"));
+        assert_eq!(proc_read_state("blob schema a").unwrap(), schema);
+
+        proc_append_state("blob schema b", "extra").unwrap();
+        assert_eq!(blob_file(&state_file_path("blob schema b")), None);
+        assert_eq!(
+            proc_read_state("blob schema b").unwrap(),
+            format!("{}extra\n", schema)
+        );
+        assert_eq!(proc_read_state("blob schema a").unwrap(), schema);
+
+        proc_write_state("blob small", "tiny").unwrap();
+        assert_eq!(blob_file(&state_file_path("blob small")), None);
+    }
+
+    #[test]
+    fn test_blob_collisions() {
+        if memory_mode() {
+            return;
+        }
+        crate::testing::with_isolated_state(|| {
+            let value = "x".repeat(MIN_BLOB_LEN);
+            // starts the build first, so the colliding blob isn't removed as unused when it does
+            drop(lock_state_dir().unwrap());
+            fs::create_dir_all(blob_dir()).unwrap();
+            fs::write(
+                blob_dir().join(blob_name(&value, 0)),
+                "y".repeat(MIN_BLOB_LEN),
+            )
+            .unwrap();
+            proc_write_state("blob collision", &value).unwrap();
+            let blob = blob_file(&state_file_path("blob collision")).unwrap();
+            assert_eq!(blob, blob_dir().join(blob_name(&value, 1)));
+            assert_eq!(proc_read_state("blob collision").unwrap(), value);
+        });
+    }
+
+    #[test]
+    fn test_intern_min_len() {
+        assert_eq!(intern_min_len(None), MIN_BLOB_LEN);
+        assert_eq!(intern_min_len(Some("256")), 256);
+        assert_eq!(intern_min_len(Some("0")), MAX_POINTER_LEN as usize + 1);
+        assert_eq!(intern_min_len(Some("lots")), MIN_BLOB_LEN);
+
+        if memory_mode() {
+            return;
+        }
+        crate::testing::with_isolated_state(|| {
+            drop(lock_state_dir().unwrap());
+            let value = "s".repeat(MAX_POINTER_LEN as usize + 1);
+            fs::create_dir_all(blob_dir()).unwrap();
+            fs::write(
+                blob_dir().join(blob_name(&value, 0)),
+                "t".repeat(value.len()),
+            )
+            .unwrap();
+            assert_eq!(store_blob(&value).unwrap(), blob_name(&value, 1));
+            assert_eq!(store_blob(&value).unwrap(), blob_name(&value, 1));
+        });
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::Result;
+use std::path::Path;
+use std::process::Command;
+
+use crate::queue::write_list;
+use crate::{
+    cache_write, current_crate_name, file_exists, lock_state_dir, record_key, state_file_path,
+    write_state_file, StateResult,
+};
+
+/// The state key [`proc_capture_build_env`] records the target triple of the build under.
+pub const BUILD_TARGET_KEY: &str = "__macro_state/build_env/target";
+
+/// The state key [`proc_capture_build_env`] records the profile of the build (`"debug"` or
+/// `"release"`) under.
+pub const BUILD_PROFILE_KEY: &str = "__macro_state/build_env/profile";
+
+/// The state key [`proc_capture_build_env`] records the output of `rustc --version` under.
+pub const BUILD_RUSTC_VERSION_KEY: &str = "__macro_state/build_env/rustc_version";
+
+/// Returns the state key [`proc_capture_build_env`] records the list of enabled features of
+/// the crate `crate_name` under. Crate names may be specified with either dashes or
+/// underscores.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert_eq!(
+///     build_features_key("my-crate"),
+///     "__macro_state/build_env/features/my_crate"
+/// );
+/// ```
+pub fn build_features_key(crate_name: &str) -> String {
+    format!(
+        "__macro_state/build_env/features/{}",
+        crate_name.replace('-', "_")
+    )
+}
+
+/// The settings of the current compilation that can be recovered from the command line rustc
+/// was invoked with.
+#[derive(Debug, Default, PartialEq)]
+struct RustcArgs {
+    target: Option<String>,
+    opt_level: Option<String>,
+    features: Vec<String>,
+}
+
+/// Extracts the target triple, optimization level, and enabled features from the specified
+/// rustc command line.
+fn parse_rustc_args(args: impl IntoIterator<Item = String>) -> RustcArgs {
+    let mut parsed = RustcArgs::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| match arg.strip_prefix(flag) {
+            Some("") => args.next(),
+            Some(value) => value.strip_prefix('=').map(str::to_string),
+            None => None,
+        };
+        if let Some(target) = value("--target") {
+            parsed.target = Some(target);
+        } else if let Some(cfg) = value("--cfg") {
+            if let Some(feature) = cfg
+                .strip_prefix("feature=\"")
+                .and_then(|feature| feature.strip_suffix('"'))
+            {
+                parsed.features.push(feature.to_string());
+            }
+        } else if let Some(codegen) = value("-C") {
+            if let Some(opt_level) = codegen.strip_prefix("opt-level=") {
+                parsed.opt_level = Some(opt_level.to_string());
+            }
+        } else if let Some(opt_level) = arg.strip_prefix("-Copt-level=") {
+            parsed.opt_level = Some(opt_level.to_string());
+        }
+    }
+    parsed.features.sort();
+    parsed
+}
+
+/// Runs `rustc -vV`, returning the version line and the host triple. Within a proc macro the
+/// current executable is rustc itself, so the exact compiler performing the build is queried.
+fn rustc_version_info() -> Option<(String, String)> {
+    let rustc = std::env::current_exe()
+        .ok()
+        .filter(|exe| {
+            exe.file_stem()
+                .is_some_and(|stem| stem.to_string_lossy().starts_with("rustc"))
+        })
+        .map(|exe| exe.into_os_string())
+        .or_else(|| std::env::var_os("RUSTC"))
+        .unwrap_or_else(|| "rustc".into());
+    let output = Command::new(Path::new(&rustc)).arg("-vV").output().ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    let version = output.lines().next()?.to_string();
+    let host = output
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))?
+        .to_string();
+    Some((version, host))
+}
+
+/// Writes `value` to `key` (while the state directory is already locked).
+fn write_value(key: &str, value: &str) -> Result<()> {
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    write_state_file(&state_file, value, None)?;
+    cache_write(&state_file, value);
+    Ok(())
+}
+
+/// An analogue for [`capture_build_env!`] that should only be used within proc macros.
+///
+/// Snapshots the build environment into well-known state keys, so that any macro in the build
+/// can make environment-aware decisions without having to inspect the environment itself:
+/// * [`BUILD_TARGET_KEY`] holds the target triple being compiled for.
+/// * [`BUILD_PROFILE_KEY`] holds `"release"` if optimizations are enabled, and `"debug"`
+///   otherwise, mirroring the `PROFILE` variable cargo passes to build scripts.
+/// * [`BUILD_RUSTC_VERSION_KEY`] holds the version of the compiler, as reported by
+///   `rustc --version`.
+/// * [`build_features_key`] (for the crate currently being compiled) holds the list of
+///   enabled cargo features of that crate, sorted, readable via
+///   [`proc_read_state_vec`](crate::proc_read_state_vec).
+///
+/// Since the target, profile, and compiler are shared by the entire build, they are only
+/// captured by the first call in each build, while the features are recorded for every crate
+/// that calls this function.
+///
+/// If an IO error occurs, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_capture_build_env().unwrap();
+/// assert!(proc_read_state(BUILD_RUSTC_VERSION_KEY)
+///     .unwrap()
+///     .starts_with("rustc "));
+/// ```
+pub fn proc_capture_build_env() -> StateResult<()> {
+    let args = parse_rustc_args(std::env::args().skip(1));
+    let _lock = lock_state_dir()?;
+    write_list(&build_features_key(&current_crate_name()), &args.features)?;
+    if file_exists(&state_file_path(BUILD_TARGET_KEY)) {
+        return Ok(());
+    }
+    let (version, host) = rustc_version_info().unwrap_or_default();
+    let profile = match args.opt_level.as_deref() {
+        None | Some("0") => "debug",
+        Some(_) => "release",
+    };
+    write_value(BUILD_PROFILE_KEY, profile)?;
+    write_value(BUILD_RUSTC_VERSION_KEY, &version)?;
+    Ok(write_value(BUILD_TARGET_KEY, &args.target.unwrap_or(host))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_parse_rustc_args() {
+        let args = [
+            "--crate-name",
+            "app",
+            "--cfg",
+            "feature=\"serde\"",
+            "-C",
+            "opt-level=3",
+            "--cfg=feature=\"alloc\"",
+            "--cfg",
+            "test",
+            "--target",
+            "wasm32-unknown-unknown",
+        ];
+        assert_eq!(
+            parse_rustc_args(args.iter().map(|arg| arg.to_string())),
+            RustcArgs {
+                target: Some(String::from("wasm32-unknown-unknown")),
+                opt_level: Some(String::from("3")),
+                features: vec![String::from("alloc"), String::from("serde")],
+            }
+        );
+        assert_eq!(
+            parse_rustc_args(["-Copt-level=0".to_string()]).opt_level,
+            Some(String::from("0"))
+        );
+        assert_eq!(parse_rustc_args(Vec::new()), RustcArgs::default());
+    }
+
+    #[test]
+    fn test_capture_build_env() {
+        proc_capture_build_env().unwrap();
+        assert!(!proc_read_state(BUILD_TARGET_KEY).unwrap().is_empty());
+        assert_eq!(proc_read_state(BUILD_PROFILE_KEY).unwrap(), "debug");
+        assert!(proc_read_state(BUILD_RUSTC_VERSION_KEY)
+            .unwrap()
+            .starts_with("rustc "));
+        assert!(proc_read_state_vec(&build_features_key("macro_state_core")).is_empty());
+
+        capture_build_env!();
+        assert!(read_state!("__macro_state/build_env/rustc_version").starts_with("rustc "));
+        assert!(!read_state!("__macro_state/build_env/target").is_empty());
+    }
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::panic::Location;
+use std::path::{Path, PathBuf};
+
+use crate::{
+    append_state_file, caller_source, crates_dir, current_crate_name, decode_filename,
+    decode_state_row, deterministic_mode, encode_list_item, encode_state_row, generation,
+    is_hashed_filename, memory_mode, now_nanos, proc_read_state_vec, recorded_key, render_records,
+    resolve_blob, retry_io, setting_enabled, stable_hash, state_dir, state_file_path, StateResult,
+    RESERVED_KEY_PREFIX,
+};
+
+/// The kind of change recorded in the [change manifest](changes_log_path).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StateChangeOp {
+    /// The value of the key was replaced.
+    Write,
+    /// An item was appended to the value of the key.
+    Append,
+    /// The key was removed.
+    Remove,
+}
+
+impl StateChangeOp {
+    fn parse(op: &str) -> Option<StateChangeOp> {
+        match op {
+            "write" => Some(StateChangeOp::Write),
+            "append" => Some(StateChangeOp::Append),
+            "remove" => Some(StateChangeOp::Remove),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StateChangeOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StateChangeOp::Write => "write",
+            StateChangeOp::Append => "append",
+            StateChangeOp::Remove => "remove",
+        })
+    }
+}
+
+/// A single entry of the [change manifest](changes_log_path), as returned by
+/// [`proc_read_state_changes`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StateChange {
+    /// The key that changed.
+    pub key: String,
+    /// How the key changed.
+    pub op: StateChangeOp,
+    /// The generation (see [`proc_state_generation`](crate::proc_state_generation)) of the
+    /// build that made the change.
+    pub generation: u128,
+    /// When the change was made, in nanoseconds since the Unix epoch, or `0` in deterministic
+    /// mode.
+    pub time: u128,
+}
+
+impl StateChange {
+    /// Parses a line of the change manifest, as written by [`note_change`].
+    fn parse(line: &str) -> Option<StateChange> {
+        let mut fields = line.split('\t');
+        let change = StateChange {
+            key: fields.next()?.to_string(),
+            op: StateChangeOp::parse(fields.next()?)?,
+            generation: fields.next()?.parse().ok()?,
+            time: fields.next()?.parse().ok()?,
+        };
+        fields.next().is_none().then_some(change)
+    }
+}
+
+/// Returns the path of the append-only change manifest kept within the state directory.
+///
+/// When the `changes_log` setting (or the `MACRO_STATE_CHANGES_LOG` environment variable) is
+/// set to `1`, `true`, or `yes`, every time a key is written to, appended to, or removed, a line
+/// holding the key, the operation (`write`, `append`, or `remove`), the generation of the
+/// build, and the time of the change (in nanoseconds since the Unix epoch) is appended to the
+/// manifest, separated by tabs. External tools such as editors and codegen watchers can tail it
+/// to react to the data collected by macros changing between builds. The manifest only ever
+/// holds the changes of a single generation: the first change of a new build empties it. Keys
+/// reserved for internal use are never recorded, and nothing is recorded in memory mode.
+pub fn changes_log_path() -> PathBuf {
+    state_dir().join("changes.log")
+}
+
+/// Returns the key stored in the specified state file of the current generation, or [`None`]
+/// if `path` isn't one.
+pub(crate) fn state_file_key(path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(crates_dir()).ok()?;
+    let name = relative.iter().skip(2).collect::<PathBuf>();
+    let name = name.to_string_lossy().replace('\\', "/");
+    let encoded = name
+        .strip_prefix("macro_state_")?
+        .strip_suffix(format!("_{}", generation()).as_str())?;
+    let key = is_hashed_filename(encoded).then(|| recorded_key(path));
+    Some(key.flatten().unwrap_or_else(|| decode_filename(encoded)))
+}
+
+/// The source location of the `macro_state` call responsible for a change, if the change was
+/// made on behalf of a caller outside of `macro_state`. Public entry points capture it via
+/// `#[track_caller]` and pass it down explicitly to [`note_change`].
+pub(crate) type Caller = Option<&'static Location<'static>>;
+
+/// Records a change to the specified state file, which was written, appended to, or removed
+/// (as described by `op`) with the specified `contents` on behalf of `caller`, in the change
+/// manifest (see [`changes_log_path`]) and, if enabled, in the write journal (see
+/// [`proc_query_journal`]). Failing to record a change never fails the change itself.
+pub(crate) fn note_change(path: &Path, op: StateChangeOp, contents: &str, caller: Caller) {
+    let Some(key) = state_file_key(path) else {
+        return;
+    };
+    if key.starts_with(RESERVED_KEY_PREFIX) {
+        return;
+    }
+    if setting_enabled("journal") {
+        let _ = journal_change(&key, op, contents, caller);
+    }
+    if memory_mode() || !setting_enabled("changes_log") {
+        return;
+    }
+    let time = match deterministic_mode() {
+        true => 0,
+        false => now_nanos(),
+    };
+    let line = format!("{}\t{}\t{}\t{}\n", key, op, generation(), time);
+    let _ = append_change(&line);
+}
+
+/// Appends `line` to the change manifest (see [`changes_log_path`]), emptying it first if it
+/// holds the changes of another generation, so that it never grows beyond a single build. The
+/// manifest is locked meanwhile, so that the processes of a build empty it only once.
+fn append_change(line: &str) -> std::io::Result<()> {
+    let mut file = retry_io(|| {
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(changes_log_path())
+    })?;
+    file.lock()?;
+    let mut first = String::new();
+    BufReader::new(&file).read_line(&mut first)?;
+    let stale = match StateChange::parse(first.trim_end_matches('\n')) {
+        Some(change) => change.generation != generation(),
+        None => !first.is_empty(),
+    };
+    if stale {
+        file.set_len(0)?;
+    }
+    file.write_all(line.as_bytes())
+}
+
+/// A single entry of the write journal, as returned by [`proc_query_journal`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct JournalEntry {
+    /// How the key changed.
+    pub op: StateChangeOp,
+    /// The name of the crate whose compilation made the change, followed by the source location
+    /// of the call that made it, if known.
+    pub writer: String,
+    /// A hash of the value that was written or the item that was appended, or [`None`] if the
+    /// key was removed. Use [`JournalEntry::is_value`] to check it against a candidate value.
+    pub value_hash: Option<u64>,
+}
+
+impl JournalEntry {
+    /// Returns `true` if `value` is the value that was written (or, for appends, the item that
+    /// was appended, including its trailing newline) by this change.
+    pub fn is_value(&self, value: &str) -> bool {
+        self.value_hash == Some(stable_hash(value))
+    }
+}
+
+/// Returns the key of the internal list holding the write journal of `key`.
+fn journal_key(key: &str) -> String {
+    format!("{}journal/{}", RESERVED_KEY_PREFIX, key)
+}
+
+/// Describes the writer of a change for the write journal: the crate being compiled, followed
+/// by the source location of `caller` (see [`caller_source`]) if there is one.
+fn journal_writer(caller: Caller) -> String {
+    match caller {
+        Some(location) => {
+            let (file, line) = caller_source(location);
+            format!("{} ({}:{})", current_crate_name(), file, line)
+        }
+        None => current_crate_name(),
+    }
+}
+
+/// Appends an entry for a change to `key` made on behalf of `caller` to its write journal. Only
+/// a hash of the value is recorded, so that journaling large values doesn't double the size of
+/// the state directory.
+fn journal_change(
+    key: &str,
+    op: StateChangeOp,
+    contents: &str,
+    caller: Caller,
+) -> std::io::Result<()> {
+    let hash = match op {
+        StateChangeOp::Remove => String::new(),
+        _ => format!(
+            "{:016x}",
+            stable_hash(&render_records(resolve_blob(contents.to_string())?))
+        ),
+    };
+    let row = encode_state_row(&[op.to_string(), journal_writer(caller), hash]);
+    append_state_file(
+        &state_file_path(&journal_key(key)),
+        &encode_list_item(&row),
+        None,
+    )
+}
+
+/// Returns the history of `key` within the current build, oldest first, as recorded by the
+/// optional write journal. The journal is enabled by setting the `journal` setting (or the
+/// `MACRO_STATE_JOURNAL` environment variable) to `1`, `true`, or `yes`, after which every
+/// write, append, and removal of a key is recorded along with a hash of the value and the crate
+/// (and, where known, the source location) that made it. When several macros fight over a key,
+/// the journal is the only way to reconstruct what happened.
+///
+/// Returns an empty [`Vec`] if the journal is disabled or `key` was never changed.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// testing::with_settings(&[("journal", "1")], || {
+///     proc_write_state("journaled key", "first").unwrap();
+///     proc_write_state("journaled key", "second").unwrap();
+/// });
+/// let history = proc_query_journal("journaled key");
+/// assert_eq!(history.len(), 2);
+/// assert!(history[1].is_value("second"));
+/// ```
+#[track_caller]
+pub fn proc_query_journal(key: &str) -> Vec<JournalEntry> {
+    proc_read_state_vec(&journal_key(key))
+        .iter()
+        .filter_map(|item| match decode_state_row(item).as_slice() {
+            [op, writer, hash] => Some(JournalEntry {
+                op: StateChangeOp::parse(op)?,
+                writer: writer.clone(),
+                value_hash: u64::from_str_radix(hash, 16).ok(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns every change recorded in the change manifest (see [`changes_log_path`]), oldest
+/// first, all of which were made by the most recent build that recorded any. Lines that can't
+/// be parsed are skipped.
+///
+/// If any IO error other than the manifest not existing yet occurs, it will be returned as the
+/// [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// testing::with_settings(&[("changes_log", "1")], || {
+///     proc_write_state("watched key", "1").unwrap();
+///     proc_append_state("watched list", "a").unwrap();
+/// });
+/// let changes = proc_read_state_changes().unwrap();
+/// assert!(changes
+///     .iter()
+///     .any(|change| change.key == "watched key" && change.op == StateChangeOp::Write));
+/// assert!(changes
+///     .iter()
+///     .any(|change| change.key == "watched list" && change.op == StateChangeOp::Append));
+/// ```
+pub fn proc_read_state_changes() -> StateResult<Vec<StateChange>> {
+    match retry_io(|| fs::read_to_string(changes_log_path())) {
+        Ok(contents) => Ok(contents.lines().filter_map(StateChange::parse).collect()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_state_change() {
+        assert_eq!(
+            StateChange::parse("models/User\tappend\t12\t34"),
+            Some(StateChange {
+                key: String::from("models/User"),
+                op: StateChangeOp::Append,
+                generation: 12,
+                time: 34,
+            })
+        );
+        assert_eq!(StateChange::parse("models/User\tappend\t12"), None);
+        assert_eq!(StateChange::parse("models/User\tcopy\t12\t34"), None);
+        assert_eq!(StateChange::parse("models/User\twrite\t12\t34\t56"), None);
+    }
+
+    #[test]
+    fn test_changes_log_generation() {
+        crate::testing::with_isolated_state(|| {
+            fs::create_dir_all(state_dir()).unwrap();
+            fs::write(changes_log_path(), "previous build\twrite\t1\t0\n").unwrap();
+            crate::testing::with_settings(&[("changes_log", "1")], || {
+                crate::proc_write_state("current build", "a").unwrap();
+                crate::proc_append_state("current build", "b").unwrap();
+            });
+            let changes = proc_read_state_changes().unwrap();
+            assert_eq!(changes.len(), 2);
+            assert!(changes.iter().all(|change| change.key == "current build"));
+        });
+    }
+}
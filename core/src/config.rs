@@ -0,0 +1,172 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref CONFIG: HashMap<String, String> = load_config();
+}
+
+thread_local! {
+    /// The settings overridden for the current thread via
+    /// [`with_settings`](crate::testing::with_settings), most recent last.
+    pub(crate) static SETTING_OVERRIDES: RefCell<Vec<(String, String)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// The settings that may appear in `macro_state.toml`. Each of them can also be set via the
+/// `MACRO_STATE_<NAME>` environment variable, which takes precedence over the file.
+const SETTINGS: &[&str] = &[
+    "state_dir",
+    "mode",
+    "shared",
+    "remote",
+    "remote_write",
+    "read_only",
+    "policy",
+    "fsync",
+    "diagnose_order",
+    "max_value_len",
+    "strict",
+    "log",
+    "deterministic",
+    "detect_divergence",
+    "journal",
+    "sequence",
+    "changes_log",
+    "metrics",
+    "record_reads",
+    "intern_min_len",
+    "max_size",
+];
+
+/// Returns the path of the project configuration file, which is `MACRO_STATE_CONFIG` if set and
+/// otherwise `macro_state.toml` in the root of the workspace being built (see
+/// [`workspace_root`](crate::workspace_root)), or in the current directory if that could not
+/// be determined.
+pub(crate) fn config_path() -> std::path::PathBuf {
+    match std::env::var_os("MACRO_STATE_CONFIG") {
+        Some(path) => std::path::PathBuf::from(path),
+        None => crate::workspace_root()
+            .unwrap_or_else(|| std::path::Path::new(""))
+            .join("macro_state.toml"),
+    }
+}
+
+/// Loads the project configuration file (see [`config_path`]) once per process. A missing file
+/// is equivalent to an empty one, while a malformed file is reported via a warning and ignored
+/// entirely, so that a typo never silently applies only half of the configuration.
+fn load_config() -> std::collections::HashMap<String, String> {
+    let path = config_path();
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return std::collections::HashMap::new();
+    };
+    match parse_config(&contents) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("warning: macro_state: ignoring {}: {}", path.display(), e);
+            std::collections::HashMap::new()
+        }
+    }
+}
+
+/// Parses the contents of a `macro_state.toml` file, which may hold any of the [`SETTINGS`]
+/// as top-level keys, each set to a string, a boolean, an integer, or an array of strings
+/// (which is returned joined with commas).
+fn parse_config(contents: &str) -> Result<std::collections::HashMap<String, String>, String> {
+    let table = contents
+        .parse::<toml::Table>()
+        .map_err(|e| e.message().to_string())?;
+    let mut config = std::collections::HashMap::new();
+    for (name, value) in table {
+        if !SETTINGS.contains(&name.as_str()) {
+            return Err(format!("unknown setting `{}`", name));
+        }
+        let invalid = || format!("invalid value for setting `{}`", name);
+        let value = match value {
+            toml::Value::String(value) => value,
+            toml::Value::Boolean(value) => value.to_string(),
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    toml::Value::String(item) => Ok(item),
+                    _ => Err(invalid()),
+                })
+                .collect::<Result<Vec<String>, String>>()?
+                .join(","),
+            _ => return Err(invalid()),
+        };
+        config.insert(name, value);
+    }
+    Ok(config)
+}
+
+/// Returns the value the setting `name` is overridden with for the current thread via
+/// [`with_settings`](crate::testing::with_settings), if any. Threads that are already exiting
+/// have no overrides.
+pub(crate) fn overridden_setting(name: &str) -> Option<String> {
+    let overridden = SETTING_OVERRIDES.try_with(|overrides| {
+        let overrides = overrides.borrow();
+        let mut overrides = overrides.iter().rev();
+        overrides
+            .find(|(overridden, _)| overridden == name)
+            .map(|(_, value)| value.clone())
+    });
+    overridden.ok().flatten()
+}
+
+/// Returns the value of the setting `name`, taken from the `MACRO_STATE_<NAME>` environment
+/// variable if it is set, and otherwise from the project configuration file. Settings
+/// overridden for the current thread (see [`with_settings`](crate::testing::with_settings))
+/// take precedence over both (see [`overridden_setting`]).
+pub(crate) fn setting(name: &str) -> Option<String> {
+    let overridden = overridden_setting(name);
+    if overridden.is_some() {
+        return overridden;
+    }
+    match std::env::var(format!("MACRO_STATE_{}", name.to_uppercase())) {
+        Ok(value) => Some(value),
+        Err(_) => CONFIG.get(name).cloned(),
+    }
+}
+
+/// Returns `true` if the setting `name` is set to `1`, `true`, or `yes`.
+pub(crate) fn setting_enabled(name: &str) -> bool {
+    matches!(setting(name).as_deref(), Some("1" | "true" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = parse_config(
+            "# shared settings\n\
+            state_dir = \"/tmp/state # not a comment\"\n\
+            shared = true # share with other workspaces\n\
+            read_only = [\"my-app\", 'my_cli']\n\
+            max_value_len = 65536\n\
+            remote = \"s3://bucket/\\\"quoted\\\"\"\n\
+            policy = \"\\u00e9\"\n",
+        )
+        .unwrap();
+        assert_eq!(config["state_dir"], "/tmp/state # not a comment");
+        assert_eq!(config["shared"], "true");
+        assert_eq!(config["read_only"], "my-app,my_cli");
+        assert_eq!(config["max_value_len"], "65536");
+        assert_eq!(config["remote"], "s3://bucket/\"quoted\"");
+        assert_eq!(config["policy"], "\u{e9}");
+
+        assert_eq!(
+            parse_config("strict = true\nlogging = true").unwrap_err(),
+            "unknown setting `logging`"
+        );
+        assert_eq!(
+            parse_config("[remote]").unwrap_err(),
+            "invalid value for setting `remote`"
+        );
+        assert!(parse_config("mode = sandbox").is_err());
+        assert!(parse_config("read_only = [\"a\" \"b\"]").is_err());
+        assert!(parse_config("read_only = [\"a\", 1]").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+#[cfg(feature = "json_schema")]
+use crate::json_schema::json_schema_violation;
+use crate::queue::{read_list, write_list};
+use crate::{check_key, setting, MacroStateError, StateResult};
+
+/// Separates the fields of a single stored constraint.
+const FIELD_SEPARATOR: char = '\u{1f}';
+
+/// A lightweight constraint on the values of a state key, as declared via
+/// [`proc_declare_state_key`] or [`declare_state_key!`](crate::declare_state_key).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateConstraint {
+    /// The value must be one of the specified values.
+    OneOf(Vec<String>),
+    /// The entire value must match the specified simple regular expression, which supports
+    /// literal characters, `.`, character classes such as `[a-z_]` and `[^0-9]`, the `*`, `+`,
+    /// and `?` repetitions, and `\` to escape any of these.
+    Pattern(String),
+    /// The value must be at least this many characters long.
+    MinLen(usize),
+    /// The value must be at most this many characters long.
+    MaxLen(usize),
+}
+
+impl StateConstraint {
+    fn encode(&self) -> String {
+        let fields = match self {
+            StateConstraint::OneOf(values) => {
+                let mut fields = vec![String::from("one_of")];
+                fields.extend(values.iter().cloned());
+                fields
+            }
+            StateConstraint::Pattern(pattern) => vec![String::from("pattern"), pattern.clone()],
+            StateConstraint::MinLen(len) => vec![String::from("min_len"), len.to_string()],
+            StateConstraint::MaxLen(len) => vec![String::from("max_len"), len.to_string()],
+        };
+        fields.join(&FIELD_SEPARATOR.to_string())
+    }
+
+    fn decode(entry: &str) -> Option<Self> {
+        let mut fields = entry.split(FIELD_SEPARATOR);
+        let constraint = match fields.next()? {
+            "one_of" => StateConstraint::OneOf(fields.map(str::to_string).collect()),
+            "pattern" => StateConstraint::Pattern(fields.next()?.to_string()),
+            "min_len" => StateConstraint::MinLen(fields.next()?.parse().ok()?),
+            "max_len" => StateConstraint::MaxLen(fields.next()?.parse().ok()?),
+            _ => return None,
+        };
+        Some(constraint)
+    }
+
+    /// Returns a description of why `value` violates this constraint, if it does.
+    fn violation(&self, value: &str) -> Option<String> {
+        let len = value.chars().count();
+        match self {
+            StateConstraint::OneOf(values) if !values.iter().any(|v| v == value) => {
+                let values: Vec<String> = values.iter().map(|v| format!("{:?}", v)).collect();
+                Some(format!(
+                    "expected one of {}, found {:?}",
+                    values.join(", "),
+                    value
+                ))
+            }
+            StateConstraint::Pattern(pattern) if !pattern_matches(pattern, value) => Some(format!(
+                "{:?} does not match the pattern {:?}",
+                value, pattern
+            )),
+            StateConstraint::MinLen(min) if len < *min => Some(format!(
+                "expected at least {} characters, found {}",
+                min, len
+            )),
+            StateConstraint::MaxLen(max) if len > *max => Some(format!(
+                "expected at most {} characters, found {}",
+                max, len
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the internal key the constraints declared for `key` are stored under.
+fn constraints_key(key: &str) -> String {
+    format!("__macro_state/key_constraints/{}", key)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PatternRepeat {
+    One,
+    ZeroOrOne,
+    ZeroOrMore,
+    OneOrMore,
+}
+
+enum PatternAtom {
+    Any,
+    Char(char),
+    Class(bool, Vec<(char, char)>),
+}
+
+impl PatternAtom {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            PatternAtom::Any => true,
+            PatternAtom::Char(atom) => *atom == c,
+            PatternAtom::Class(negated, ranges) => {
+                ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+/// Parses the simple regular expression syntax described in [`StateConstraint::Pattern`] into
+/// a list of atoms and their repetitions.
+fn parse_pattern(pattern: &str) -> Vec<(PatternAtom, PatternRepeat)> {
+    let mut parsed: Vec<(PatternAtom, PatternRepeat)> = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let repeat = match c {
+            '*' => Some(PatternRepeat::ZeroOrMore),
+            '+' => Some(PatternRepeat::OneOrMore),
+            '?' => Some(PatternRepeat::ZeroOrOne),
+            _ => None,
+        };
+        match (repeat, parsed.last_mut()) {
+            (Some(repeat), Some(last)) if last.1 == PatternRepeat::One => last.1 = repeat,
+            _ => {
+                let atom = match c {
+                    '.' => PatternAtom::Any,
+                    '\\' => PatternAtom::Char(chars.next().unwrap_or('\\')),
+                    '[' => {
+                        let negated = chars.next_if_eq(&'^').is_some();
+                        let mut ranges = Vec::new();
+                        while let Some(c) = chars.next_if(|c| *c != ']') {
+                            let low = match c {
+                                '\\' => chars.next().unwrap_or('\\'),
+                                c => c,
+                            };
+                            let high = match chars.next_if_eq(&'-') {
+                                Some(_) => chars.next_if(|c| *c != ']').unwrap_or('-'),
+                                None => low,
+                            };
+                            ranges.push((low, high));
+                        }
+                        chars.next();
+                        PatternAtom::Class(negated, ranges)
+                    }
+                    c => PatternAtom::Char(c),
+                };
+                parsed.push((atom, PatternRepeat::One));
+            }
+        }
+    }
+    parsed
+}
+
+fn match_pattern(pattern: &[(PatternAtom, PatternRepeat)], text: &[char]) -> bool {
+    let Some(((atom, repeat), rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    let (min, max) = match repeat {
+        PatternRepeat::One => (1, 1),
+        PatternRepeat::ZeroOrOne => (0, 1),
+        PatternRepeat::ZeroOrMore => (0, usize::MAX),
+        PatternRepeat::OneOrMore => (1, usize::MAX),
+    };
+    let available = text
+        .iter()
+        .take_while(|c| atom.matches(**c))
+        .count()
+        .min(max);
+    (min..=available)
+        .rev()
+        .any(|count| match_pattern(rest, &text[count..]))
+}
+
+/// Returns `true` if the entire `text` matches the simple regular expression `pattern`.
+pub(crate) fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let text: Vec<char> = text.chars().collect();
+    match_pattern(&parse_pattern(pattern), &text)
+}
+
+/// Validates `key` and a value about to be written to it (or, if `item` is `true`, a single item
+/// about to be appended to the list stored at `key`) against the `max_value_len` quota, and
+/// the constraints and the JSON schema (if the `json_schema` feature is enabled) declared for
+/// `key`.
+pub(crate) fn check_write(key: &str, value: &str, item: bool) -> StateResult<()> {
+    check_key(key, true)?;
+    let max_len = setting("max_value_len").and_then(|max| max.parse::<usize>().ok());
+    let reason = max_len
+        .filter(|max| value.len() > *max)
+        .map(|max| {
+            format!(
+                "the value is {} bytes long, exceeding the `max_value_len` quota of {} bytes",
+                value.len(),
+                max
+            )
+        })
+        .or_else(|| {
+            read_list(&constraints_key(key))
+                .iter()
+                .filter_map(|entry| StateConstraint::decode(entry))
+                .find_map(|constraint| constraint.violation(value))
+        })
+        .or_else(|| json_schema_violation(key, value, item));
+    match reason {
+        Some(reason) => Err(MacroStateError::InvalidValue {
+            key: key.to_string(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "json_schema"))]
+fn json_schema_violation(_key: &str, _value: &str, _item: bool) -> Option<String> {
+    None
+}
+
+/// An analogue for [`declare_state_key!`](crate::declare_state_key) that should only be used
+/// within proc macros.
+///
+/// Declares `constraints` that every value written to `key` must satisfy, replacing any
+/// constraints previously declared for `key`. From then on, writing a value that violates any
+/// of them (via [`proc_write_state`](crate::proc_write_state),
+/// [`write_state!`](crate::write_state), and friends) fails with a
+/// [`MacroStateError::InvalidValue`] error describing the violation. When appending to `key`,
+/// each appended item is checked individually.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_declare_state_key(
+///     "my log level",
+///     &[StateConstraint::OneOf(vec![
+///         String::from("debug"),
+///         String::from("info"),
+///     ])],
+/// )
+/// .unwrap();
+/// proc_write_state("my log level", "info").unwrap();
+/// assert_eq!(
+///     proc_write_state("my log level", "verbose").unwrap_err().to_string(),
+///     "invalid value for key \"my log level\": expected one of \"debug\", \"info\", found \
+///     \"verbose\""
+/// );
+/// ```
+pub fn proc_declare_state_key(key: &str, constraints: &[StateConstraint]) -> StateResult<()> {
+    let entries: Vec<String> = constraints.iter().map(StateConstraint::encode).collect();
+    Ok(write_list(&constraints_key(key), &entries)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("[a-z_]+", "log_level"));
+        assert!(!pattern_matches("[a-z_]+", "LogLevel"));
+        assert!(pattern_matches("v[0-9]+\\.[0-9]+", "v1.42"));
+        assert!(!pattern_matches("v[0-9]+\\.[0-9]+", "v1.x"));
+        assert!(pattern_matches("[^/]*", "no slashes"));
+        assert!(!pattern_matches("[^/]*", "a/b"));
+        assert!(pattern_matches("[-+]?[0-9]", "-1"));
+        assert!(pattern_matches("a.c", "abc"));
+    }
+
+    #[test]
+    fn test_state_constraint() {
+        let constraints = [
+            StateConstraint::OneOf(vec![String::from("a"), String::from("b")]),
+            StateConstraint::Pattern(String::from("[a-z]+")),
+            StateConstraint::MinLen(2),
+            StateConstraint::MaxLen(0),
+        ];
+        for constraint in &constraints {
+            assert_eq!(
+                StateConstraint::decode(&constraint.encode()).as_ref(),
+                Some(constraint)
+            );
+        }
+        assert_eq!(
+            constraints[0].violation("c").as_deref(),
+            Some("expected one of \"a\", \"b\", found \"c\"")
+        );
+        assert_eq!(constraints[1].violation("abc"), None);
+        assert_eq!(
+            constraints[2].violation("x").as_deref(),
+            Some("expected at least 2 characters, found 1")
+        );
+    }
+
+    #[test]
+    fn test_declare_state_key() {
+        proc_declare_state_key(
+            "constrained level",
+            &[
+                StateConstraint::Pattern(String::from("[a-z]+")),
+                StateConstraint::MaxLen(5),
+            ],
+        )
+        .unwrap();
+        proc_write_state("constrained level", "warn").unwrap();
+        assert!(matches!(
+            proc_write_state("constrained level", "Warn"),
+            Err(MacroStateError::InvalidValue { .. })
+        ));
+        assert!(proc_write_state("constrained level", "warning").is_err());
+        assert!(proc_append_state("constrained level", "error").is_ok());
+        assert!(proc_append_state("constrained level", "fatal!").is_err());
+        proc_declare_state_key("constrained level", &[]).unwrap();
+        proc_write_state("constrained level", "Anything Goes").unwrap();
+
+        declare_state_key!("constrained macro", one_of = ["debug", "info"], min_len = 4);
+        write_state!("constrained macro", "info");
+        assert_eq!(read_state!("constrained macro"), "info");
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::{Error, ErrorKind};
+
+use crate::{
+    cache_invalidate, cache_write, cached_read, check_key, lock_state_dir, record_key,
+    remove_state_file, state_file_path, trace_call, write_state_file, MacroStateError, StateResult,
+};
+
+/// Reads the counter stored for `key`, or [`None`] if it has never been written to.
+fn read_integer(key: &str) -> StateResult<Option<i64>> {
+    let value = match cached_read(&state_file_path(key)) {
+        Ok(value) => value,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    value
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|e| MacroStateError::Corrupted {
+            key: key.to_string(),
+            reason: format!("not a valid counter: \"{}\" ({})", value, e),
+        })
+}
+
+/// Reads the counter stored for `key`, treating a missing key as `0`.
+fn read_counter(key: &str) -> StateResult<i64> {
+    Ok(read_integer(key)?.unwrap_or(0))
+}
+
+/// Folds `value` into the counter stored for `key` via `fold` while holding an exclusive lock
+/// over the state directory, storing `value` itself if the counter has never been written to,
+/// and returns the new value of the counter. `fold` returns [`None`] on overflow.
+fn fold_counter(key: &str, value: i64, fold: fn(i64, i64) -> Option<i64>) -> StateResult<i64> {
+    check_key(key, true)?;
+    let _lock = lock_state_dir()?;
+    let value = match read_integer(key)? {
+        Some(current) => fold(current, value).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("the counter for key \"{}\" overflowed", key),
+            )
+        })?,
+        None => value,
+    };
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    write_state_file(&state_file, &value.to_string(), None)?;
+    cache_write(&state_file, &value.to_string());
+    Ok(value)
+}
+
+/// An analogue for [`add_to_counter!`] that should only be used within proc macros.
+///
+/// Adds `amount` (which may be negative) to the counter stored for `key`, returning the new
+/// value of the counter. Counters that have never been written to start out at `0`. The read
+/// and the write happen under a single exclusive lock, so concurrent additions are never lost.
+///
+/// Counters are stored as regular state values, so they can also be read via
+/// [`proc_read_state`](crate::proc_read_state).
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert_eq!(proc_add_to_counter("my endpoints", 2).unwrap(), 2);
+/// assert_eq!(proc_add_to_counter("my endpoints", 3).unwrap(), 5);
+/// assert_eq!(proc_read_state("my endpoints").unwrap(), "5");
+/// ```
+pub fn proc_add_to_counter(key: &str, amount: i64) -> StateResult<i64> {
+    let _span = trace_call("proc_add_to_counter", Some(key));
+    fold_counter(key, amount, i64::checked_add)
+}
+
+/// An analogue for [`add_state!`] that should only be used within proc macros.
+///
+/// Adds `value` (which may be negative) to the integer aggregate stored for `key`, returning its
+/// new value, exactly like [`proc_add_to_counter`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_add_state("my total size", 128).unwrap();
+/// assert_eq!(proc_add_state("my total size", 64).unwrap(), 192);
+/// ```
+pub fn proc_add_state(key: &str, value: i64) -> StateResult<i64> {
+    let _span = trace_call("proc_add_state", Some(key));
+    fold_counter(key, value, i64::checked_add)
+}
+
+/// An analogue for [`max_state!`] that should only be used within proc macros.
+///
+/// Replaces the integer aggregate stored for `key` with `value` if it is greater (or if the key
+/// has never been written to), returning the resulting maximum. The read and the write happen
+/// under a single exclusive lock, so concurrent updates are never lost.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_max_state("my max arity", 3).unwrap();
+/// proc_max_state("my max arity", 5).unwrap();
+/// assert_eq!(proc_max_state("my max arity", 4).unwrap(), 5);
+/// ```
+pub fn proc_max_state(key: &str, value: i64) -> StateResult<i64> {
+    let _span = trace_call("proc_max_state", Some(key));
+    fold_counter(key, value, |current, value| Some(current.max(value)))
+}
+
+/// An analogue for [`min_state!`] that should only be used within proc macros.
+///
+/// Replaces the integer aggregate stored for `key` with `value` if it is smaller (or if the key
+/// has never been written to), returning the resulting minimum. The read and the write happen
+/// under a single exclusive lock, so concurrent updates are never lost.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_min_state("my min version", 3).unwrap();
+/// assert_eq!(proc_min_state("my min version", 5).unwrap(), 3);
+/// ```
+pub fn proc_min_state(key: &str, value: i64) -> StateResult<i64> {
+    let _span = trace_call("proc_min_state", Some(key));
+    fold_counter(key, value, |current, value| Some(current.min(value)))
+}
+
+/// An analogue for [`read_counter!`] that should only be used within proc macros.
+///
+/// Returns the current value of the counter stored for `key`, or `0` if the counter has never
+/// been written to. If the stored value is not a valid counter, a
+/// [`MacroStateError::Corrupted`] error is returned.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert_eq!(proc_read_counter("my widgets").unwrap(), 0);
+/// proc_add_to_counter("my widgets", 7).unwrap();
+/// assert_eq!(proc_read_counter("my widgets").unwrap(), 7);
+/// ```
+pub fn proc_read_counter(key: &str) -> StateResult<i64> {
+    let _span = trace_call("proc_read_counter", Some(key));
+    read_counter(key)
+}
+
+/// An analogue for [`reset_counter!`] that should only be used within proc macros.
+///
+/// Resets the counter stored for `key` back to `0`.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_add_to_counter("my errors", 3).unwrap();
+/// proc_reset_counter("my errors").unwrap();
+/// assert_eq!(proc_read_counter("my errors").unwrap(), 0);
+/// ```
+pub fn proc_reset_counter(key: &str) -> StateResult<()> {
+    let _span = trace_call("proc_reset_counter", Some(key));
+    check_key(key, true)?;
+    let _lock = lock_state_dir()?;
+    let state_file = state_file_path(key);
+    cache_invalidate(&state_file);
+    match remove_state_file(&state_file, None) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_counters() {
+        assert_eq!(proc_read_counter("counter test").unwrap(), 0);
+        assert_eq!(proc_add_to_counter("counter test", 10).unwrap(), 10);
+        assert_eq!(proc_add_to_counter("counter test", -3).unwrap(), 7);
+        assert_eq!(proc_read_counter("counter test").unwrap(), 7);
+        proc_reset_counter("counter test").unwrap();
+        assert_eq!(proc_read_counter("counter test").unwrap(), 0);
+        proc_write_state("counter test", "not a number").unwrap();
+        assert_eq!(
+            proc_add_to_counter("counter test", 1).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        add_to_counter!("macro counter test", 4);
+        add_to_counter!("macro counter test", 1);
+        add_to_counter!("macro counter test", -2);
+        const COUNT: i64 = read_counter!("macro counter test");
+        assert_eq!(COUNT, 3);
+        reset_counter!("macro counter test");
+        assert_eq!(read_counter!("macro counter test"), 0);
+    }
+
+    #[test]
+    fn test_aggregates() {
+        assert_eq!(proc_max_state("aggregate test max", -4).unwrap(), -4);
+        assert_eq!(proc_max_state("aggregate test max", -9).unwrap(), -4);
+        assert_eq!(proc_min_state("aggregate test min", 7).unwrap(), 7);
+        assert_eq!(proc_min_state("aggregate test min", 2).unwrap(), 2);
+        proc_add_state("aggregate test add", i64::MAX).unwrap();
+        assert_eq!(
+            proc_add_state("aggregate test add", 1).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+
+        add_state!("macro aggregate total", 128);
+        add_state!("macro aggregate total", 64);
+        max_state!("macro aggregate max", 2);
+        max_state!("macro aggregate max", 5);
+        max_state!("macro aggregate max", 3);
+        min_state!("macro aggregate min", 2);
+        min_state!("macro aggregate min", -1);
+        const TOTAL: i64 = read_counter!("macro aggregate total");
+        assert_eq!(TOTAL, 192);
+        assert_eq!(read_counter!("macro aggregate max"), 5);
+        assert_eq!(read_state_i64!("macro aggregate min"), -1);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::{state_keys, RESERVED_KEY_PREFIX};
+
+/// The error type returned by the `proc_*` functions of `macro_state`.
+///
+/// Unlike a raw [`std::io::Error`], the variants of this type describe what actually went
+/// wrong in terms of state, so callers can respond programmatically (for example by falling
+/// back to a default when a key is missing) instead of string-matching error messages. Any
+/// [`std::io::Error`] converts into a [`MacroStateError`] and vice versa, so `?` keeps working
+/// in functions returning either.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MacroStateError {
+    /// No value exists for `key`. Existing keys that are close to `key`, which often reveal a
+    /// typo, are available via [`MacroStateError::similar_keys`].
+    KeyNotFound {
+        /// The key that was requested.
+        key: String,
+    },
+    /// The state directory at `path` cannot be accessed.
+    StateDirUnavailable {
+        /// The state directory in use.
+        path: PathBuf,
+        /// The underlying IO error.
+        source: Error,
+    },
+    /// The value stored for `key` is not in the expected format.
+    Corrupted {
+        /// The key whose value is corrupted.
+        key: String,
+        /// A description of what is wrong with the value.
+        reason: String,
+    },
+    /// `key` cannot be used as a state key, either because it violates the key character
+    /// policy or because it is reserved for internal use.
+    InvalidKey {
+        /// The rejected key.
+        key: String,
+        /// A description of why the key was rejected.
+        reason: String,
+    },
+    /// The crate named `crate_name` is not allowed to write to `key`.
+    WriteDenied {
+        /// The key that was written to.
+        key: String,
+        /// The name of the crate attempting the write.
+        crate_name: String,
+        /// A description of why the write was denied.
+        reason: String,
+    },
+    /// A value written to `key` was rejected by a constraint declared for `key`, such as a
+    /// JSON schema.
+    InvalidValue {
+        /// The key the value was written to.
+        key: String,
+        /// A description of why the value was rejected.
+        reason: String,
+    },
+    /// Any other IO error.
+    Io(Error),
+}
+
+/// A [`Result`](std::result::Result) whose error type is [`MacroStateError`].
+pub type StateResult<T> = std::result::Result<T, MacroStateError>;
+
+impl MacroStateError {
+    /// Returns the [`ErrorKind`] that best describes this error, matching the kind of the IO
+    /// error it would convert into.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            MacroStateError::KeyNotFound { .. } => ErrorKind::NotFound,
+            MacroStateError::StateDirUnavailable { source, .. } => source.kind(),
+            MacroStateError::Corrupted { .. } => ErrorKind::InvalidData,
+            MacroStateError::InvalidKey { .. } => ErrorKind::InvalidInput,
+            MacroStateError::WriteDenied { .. } => ErrorKind::PermissionDenied,
+            MacroStateError::InvalidValue { .. } => ErrorKind::InvalidInput,
+            MacroStateError::Io(e) => e.kind(),
+        }
+    }
+
+    /// Returns up to three existing keys (other than internal ones) with names similar to the
+    /// missing key of a [`MacroStateError::KeyNotFound`] error, most similar first, or nothing
+    /// for any other error. Finding them requires walking the entire store, so they are only
+    /// looked up when asked for.
+    pub fn similar_keys(&self) -> Vec<String> {
+        match self {
+            MacroStateError::KeyNotFound { key } => similar_keys(key),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Converts an IO error that occurred while accessing the value of `key` into the most
+    /// descriptive [`MacroStateError`].
+    pub(crate) fn for_key(key: &str, e: Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => MacroStateError::KeyNotFound {
+                key: key.to_string(),
+            },
+            ErrorKind::InvalidData => MacroStateError::Corrupted {
+                key: key.to_string(),
+                reason: e.to_string(),
+            },
+            _ => e.into(),
+        }
+    }
+}
+
+impl fmt::Display for MacroStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacroStateError::KeyNotFound { key } => {
+                write!(f, "no state value exists for key \"{}\"", key)?;
+                let similar = similar_keys(key);
+                if !similar.is_empty() {
+                    let similar: Vec<String> =
+                        similar.iter().map(|key| format!("\"{}\"", key)).collect();
+                    write!(f, " (did you mean {}?)", similar.join(" or "))?;
+                }
+                Ok(())
+            }
+            MacroStateError::StateDirUnavailable { path, source } => write!(
+                f,
+                "the state directory {} is unavailable: {}",
+                path.display(),
+                source
+            ),
+            MacroStateError::Corrupted { key, reason } => {
+                write!(
+                    f,
+                    "the state value for key \"{}\" is corrupted: {}",
+                    key, reason
+                )
+            }
+            MacroStateError::InvalidKey { key, reason } => {
+                write!(f, "invalid state key \"{}\": {}", key, reason)
+            }
+            MacroStateError::WriteDenied {
+                key,
+                crate_name,
+                reason,
+            } => write!(
+                f,
+                "crate \"{}\" may not write to key \"{}\": {}",
+                crate_name, key, reason
+            ),
+            MacroStateError::InvalidValue { key, reason } => {
+                write!(f, "invalid value for key \"{}\": {}", key, reason)
+            }
+            MacroStateError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MacroStateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MacroStateError::StateDirUnavailable { source, .. } => Some(source),
+            MacroStateError::Io(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for MacroStateError {
+    fn from(e: Error) -> Self {
+        // errors that started out as a `MacroStateError` convert back into what they were
+        if e.get_ref()
+            .is_some_and(|inner| inner.is::<MacroStateError>())
+        {
+            let inner = e.into_inner().unwrap();
+            return *inner.downcast::<MacroStateError>().unwrap();
+        }
+        MacroStateError::Io(e)
+    }
+}
+
+impl From<MacroStateError> for Error {
+    fn from(e: MacroStateError) -> Self {
+        match e {
+            MacroStateError::Io(e) => e,
+            e => Error::new(e.kind(), e),
+        }
+    }
+}
+
+/// Returns the Levenshtein distance between `a` and `b`, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = previous + usize::from(a != *b);
+            previous = row[j + 1];
+            row[j + 1] = substituted.min(previous + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Returns up to three existing keys (other than internal ones) that are similar to `key`,
+/// most similar first.
+fn similar_keys(key: &str) -> Vec<String> {
+    let threshold = (key.chars().count() / 3).max(2);
+    let mut similar: Vec<(usize, String)> = state_keys()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|existing| !existing.starts_with(RESERVED_KEY_PREFIX))
+        .map(|existing| (edit_distance(key, &existing), existing))
+        .filter(|(distance, _)| *distance <= threshold)
+        .collect();
+    similar.sort();
+    similar.into_iter().take(3).map(|(_, key)| key).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("models", "models"), 0);
+        assert_eq!(edit_distance("models", "modles"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_macro_state_error() {
+        proc_write_state("error test registry", "a").unwrap();
+        let err = proc_read_state("error test regsitry").unwrap_err();
+        assert!(matches!(
+            &err,
+            MacroStateError::KeyNotFound { key } if key == "error test regsitry"
+        ));
+        assert_eq!(err.similar_keys(), ["error test registry"]);
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "no state value exists for key \"error test regsitry\" (did you mean \
+            \"error test registry\"?)"
+        );
+
+        proc_write_state("error test counter", "abc").unwrap();
+        let err = proc_read_counter("error test counter").unwrap_err();
+        assert!(matches!(err, MacroStateError::Corrupted { .. }));
+
+        let io: Error = MacroStateError::StateDirUnavailable {
+            path: PathBuf::from("/state"),
+            source: Error::from(ErrorKind::PermissionDenied),
+        }
+        .into();
+        assert_eq!(io.kind(), ErrorKind::PermissionDenied);
+        assert!(matches!(
+            MacroStateError::from(io),
+            MacroStateError::StateDirUnavailable { .. }
+        ));
+        let err: MacroStateError = Error::from(ErrorKind::PermissionDenied).into();
+        assert!(matches!(err, MacroStateError::Io(_)));
+        let err: MacroStateError = Error::other("boom").into();
+        assert!(matches!(err, MacroStateError::Io(_)));
+        assert_eq!(err.to_string(), "boom");
+    }
+}
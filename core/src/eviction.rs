@@ -0,0 +1,429 @@
+use std::io::Result;
+
+use crate::{
+    generation, lock_state_dir, memory_mode, setting, state_dir, StateResult, BLOB_POINTER_PREFIX,
+    MAX_POINTER_LEN, STARTED, STATE_FORMAT_VERSION,
+};
+
+/// Parses a size in bytes, optionally followed by a `K`, `M`, or `G` (binary) unit, which may
+/// itself be followed by `B` or `iB`, such as `1048576`, `512M`, or `2GiB`.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let unit = unit.trim_start().to_ascii_uppercase();
+    let unit = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Recursively collects every file within `dir`, along with its length and modification time.
+fn collect_dir_files(
+    dir: &std::path::Path,
+    files: &mut Vec<(std::path::PathBuf, u64, std::time::SystemTime)>,
+) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            collect_dir_files(&entry.path(), files)?;
+        } else {
+            let modified = metadata.modified().unwrap_or(std::time::UNIX_EPOCH);
+            files.push((entry.path(), metadata.len(), modified));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the suffixes (`_<generation>`) that the files and directories of the `current`
+/// generation and of every other running build end with, along with the time at which the
+/// oldest of those builds started, given that the current one started at `started`.
+fn active_generations(
+    generations: &std::path::Path,
+    current: u128,
+    mut started: std::time::SystemTime,
+) -> (Vec<String>, std::time::SystemTime) {
+    let mut suffixes = vec![format!("_{}", current)];
+    if let Ok(entries) = std::fs::read_dir(generations) {
+        for entry in entries.flatten() {
+            if let Ok(generation) = std::fs::read_to_string(entry.path()) {
+                suffixes.push(format!("_{}", generation.trim()));
+            }
+            if let Ok(modified) = entry.metadata().and_then(|metadata| metadata.modified()) {
+                started = started.min(modified);
+            }
+        }
+    }
+    (suffixes, started)
+}
+
+/// Returns `true` if the file at `relative` (to the versioned state directory) belongs to one
+/// of the generations ending with the specified `suffixes`.
+fn is_active(relative: &std::path::Path, suffixes: &[String]) -> bool {
+    relative.iter().any(|component| {
+        let component = component.to_string_lossy();
+        let component = component.strip_suffix(".meta").unwrap_or(&component);
+        suffixes
+            .iter()
+            .any(|suffix| component.ends_with(suffix.as_str()))
+    })
+}
+
+/// Returns the path of the blob that the state file at `path` (of length `len`) points to, if
+/// it points to one.
+fn blob_target(
+    blobs: &std::path::Path,
+    path: &std::path::Path,
+    len: u64,
+) -> Option<std::path::PathBuf> {
+    if len > MAX_POINTER_LEN {
+        return None;
+    }
+    let contents = std::fs::read_to_string(path).ok()?;
+    let name = contents.strip_prefix(BLOB_POINTER_PREFIX)?;
+    Some(blobs.join(name))
+}
+
+/// A group of files that are evicted together: a state file along with its metadata file, or
+/// a blob no longer referenced by any state file.
+#[derive(Default)]
+struct Evictable {
+    paths: Vec<std::path::PathBuf>,
+    len: u64,
+    written: Option<std::time::SystemTime>,
+    blob: Option<std::path::PathBuf>,
+}
+
+/// Removes the least recently written state of past builds until the versioned state
+/// directory `root` takes up no more than `max_size` bytes, returning the number of bytes
+/// freed. The state of the `current` generation and of every other running build is never
+/// evicted, and a blob is only evicted once no remaining state file points to it.
+///
+/// Blobs and temporary files are written before anything points to them, so those written (or
+/// reused) since the oldest running build started (the current one at `started`) are never
+/// evicted either: a running build may be about to point to them. The caller must hold the
+/// state directory lock.
+fn evict_state_dir(
+    root: &std::path::Path,
+    max_size: u64,
+    current: u128,
+    started: std::time::SystemTime,
+) -> std::io::Result<u64> {
+    let mut files = Vec::new();
+    collect_dir_files(root, &mut files)?;
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    if total <= max_size {
+        return Ok(0);
+    }
+    let (blobs, generations) = (root.join("blobs"), root.join("generations"));
+    let (suffixes, started) = active_generations(&generations, current, started);
+    let mut blob_files = std::collections::HashMap::new();
+    let mut references: std::collections::HashMap<std::path::PathBuf, usize> =
+        std::collections::HashMap::new();
+    let mut evictable: std::collections::HashMap<std::path::PathBuf, Evictable> =
+        std::collections::HashMap::new();
+    for (path, len, modified) in files {
+        if path.starts_with(&generations) {
+            continue;
+        }
+        let temporary = path.extension().is_some_and(|extension| extension == "tmp");
+        if (temporary || path.starts_with(&blobs)) && modified >= started {
+            continue;
+        }
+        if path.starts_with(&blobs) {
+            blob_files.insert(path, (len, modified));
+            continue;
+        }
+        let blob = blob_target(&blobs, &path, len);
+        if let Some(blob) = &blob {
+            *references.entry(blob.clone()).or_default() += 1;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if !temporary && is_active(relative, &suffixes) {
+            continue;
+        }
+        let owner = match path.to_string_lossy().strip_suffix(".meta") {
+            Some(state_file) => std::path::PathBuf::from(state_file),
+            None => path.clone(),
+        };
+        let entry = evictable.entry(owner).or_default();
+        entry.paths.push(path);
+        entry.len += len;
+        entry.written = entry.written.max(Some(modified));
+        entry.blob = entry.blob.take().or(blob);
+    }
+    let mut evictable: Vec<Evictable> = evictable.into_values().collect();
+    for (blob, (len, modified)) in &blob_files {
+        if !references.contains_key(blob) {
+            evictable.push(Evictable {
+                paths: vec![blob.clone()],
+                len: *len,
+                written: Some(*modified),
+                blob: None,
+            });
+        }
+    }
+    evictable.sort_by_key(|entry| entry.written);
+
+    let mut freed = 0;
+    for entry in evictable {
+        if total <= max_size {
+            break;
+        }
+        for path in &entry.paths {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        total = total.saturating_sub(entry.len);
+        freed += entry.len;
+        let Some(blob) = entry.blob else {
+            continue;
+        };
+        let Some(count) = references.get_mut(&blob) else {
+            continue;
+        };
+        *count -= 1;
+        if *count == 0 {
+            if let Some((len, _)) = blob_files.remove(&blob) {
+                match std::fs::remove_file(&blob) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+                total = total.saturating_sub(len);
+                freed += len;
+            }
+        }
+    }
+    Ok(freed)
+}
+
+/// Removes the blobs within the versioned state directory `root` that no state file points to
+/// anymore, such as those holding values that have since been overwritten, returning the number
+/// of bytes freed. As with [`evict_state_dir`], blobs written (or reused) since the oldest
+/// running build started are kept. The caller must hold the state directory lock.
+fn collect_blob_garbage(
+    root: &std::path::Path,
+    current: u128,
+    started: std::time::SystemTime,
+) -> std::io::Result<u64> {
+    let blobs = root.join("blobs");
+    let mut blob_files = Vec::new();
+    collect_dir_files(&blobs, &mut blob_files)?;
+    if blob_files.is_empty() {
+        return Ok(0);
+    }
+    let (_, started) = active_generations(&root.join("generations"), current, started);
+    let mut files = Vec::new();
+    collect_dir_files(root, &mut files)?;
+    let referenced: std::collections::HashSet<std::path::PathBuf> = files
+        .into_iter()
+        .filter(|(path, _, _)| !path.starts_with(&blobs))
+        .filter_map(|(path, len, _)| blob_target(&blobs, &path, len))
+        .collect();
+    let mut freed = 0;
+    for (blob, len, modified) in blob_files {
+        if modified >= started || referenced.contains(&blob) {
+            continue;
+        }
+        match std::fs::remove_file(&blob) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => freed += len,
+        }
+    }
+    Ok(freed)
+}
+
+/// Returns the maximum total size of the state directory configured via the `max_size`
+/// setting, if any.
+fn max_state_size() -> Option<u64> {
+    let size = setting("max_size")?;
+    let max_size = parse_size(&size);
+    if max_size.is_none() {
+        eprintln!("warning: macro_state: ignoring invalid max_size `{}`", size);
+    }
+    max_size
+}
+
+/// Removes the least recently written state of past builds until the versioned state directory
+/// takes up no more than `max_size` bytes, returning the number of bytes freed (see
+/// `evict_state_dir` in `eviction.rs` of `macro_state_macros`). The caller must hold the state
+/// directory lock.
+pub(crate) fn evict_state(max_size: u64, current: u128) -> Result<u64> {
+    let root = state_dir().join(format!("v{}", STATE_FORMAT_VERSION));
+    evict_state_dir(&root, max_size, current, *STARTED)
+}
+
+/// Returns the suffixes (`_<generation>`) that the files and directories of the current build
+/// and of every other running build end with (see `active_generations` in `eviction.rs` of
+/// `macro_state_macros`).
+pub(crate) fn active_suffixes() -> Vec<String> {
+    let root = state_dir().join(format!("v{}", STATE_FORMAT_VERSION));
+    active_generations(&root.join("generations"), generation(), *STARTED).0
+}
+
+/// Tidies up the state directory before the build using the `current` generation starts:
+/// removes the blobs no state file points to anymore (see `collect_blob_garbage` in
+/// `eviction.rs` of `macro_state_macros`) and, if a maximum size has been configured via the
+/// `max_size` setting, evicts the least recently written state of past builds (see
+/// [`evict_state`]). Failing to do so never fails the build. The caller must hold the state
+/// directory lock.
+pub(crate) fn tidy_state_dir(current: u128) {
+    let root = state_dir().join(format!("v{}", STATE_FORMAT_VERSION));
+    if let Err(e) = collect_blob_garbage(&root, current, *STARTED) {
+        eprintln!("warning: macro_state: failed to remove unused blobs: {}", e);
+    }
+    let Some(max_size) = max_state_size() else {
+        return;
+    };
+    if let Err(e) = evict_state(max_size, current) {
+        eprintln!("warning: macro_state: failed to evict state: {}", e);
+    }
+}
+
+/// Removes the least recently written state of past builds until the state directory of the
+/// current workspace takes up no more than `max_size` bytes, returning the number of bytes
+/// freed.
+///
+/// State written by a build outlives it on disk, so a long-lived developer machine would
+/// otherwise accumulate state without bound. Setting the `max_size` setting (or the
+/// `MACRO_STATE_MAX_SIZE` environment variable) to a size such as `512M` or `2G` makes every
+/// build do this automatically when it starts. Keys are evicted whole, oldest first, and the
+/// state of the current build (as well as of any other build still running) is never
+/// evicted. Values shared by several keys (see [`proc_write_state`](crate::proc_write_state))
+/// are only evicted along with the last key holding them. Nothing is evicted in memory mode.
+///
+/// If any IO error occurs, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("evicted key", "kept, since it belongs to the current build").unwrap();
+/// proc_evict_state(0).unwrap();
+/// assert!(proc_has_state("evicted key"));
+/// ```
+pub fn proc_evict_state(max_size: u64) -> StateResult<u64> {
+    if memory_mode() {
+        return Ok(0);
+    }
+    let _lock = lock_state_dir()?;
+    Ok(evict_state(max_size, generation())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::with_isolated_state;
+    use crate::*;
+    use std::fs::File;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1048576"), Some(1 << 20));
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+        assert_eq!(parse_size("2 GiB"), Some(2 << 30));
+        assert_eq!(parse_size("64kb"), Some(64 << 10));
+        assert_eq!(parse_size("12T"), None);
+        assert_eq!(parse_size("M"), None);
+    }
+
+    #[test]
+    fn test_collect_blob_garbage() {
+        if memory_mode() {
+            return;
+        }
+        with_isolated_state(|| {
+            proc_write_state("blob kept", &"k".repeat(MIN_BLOB_LEN)).unwrap();
+            proc_write_state("blob dropped", &"d".repeat(MIN_BLOB_LEN)).unwrap();
+            let kept = blob_file(&state_file_path("blob kept")).unwrap();
+            let dropped = blob_file(&state_file_path("blob dropped")).unwrap();
+            proc_write_state("blob dropped", "small now").unwrap();
+            let root = state_dir().join(format!("v{}", STATE_FORMAT_VERSION));
+            // blobs written since the build started are kept, as a build may be about to use them
+            collect_blob_garbage(&root, generation(), SystemTime::UNIX_EPOCH).unwrap();
+            assert!(dropped.exists());
+            let later = SystemTime::now() + Duration::from_secs(60);
+            let freed = collect_blob_garbage(&root, generation(), later).unwrap();
+            assert_eq!(freed, MIN_BLOB_LEN as u64);
+            assert!(kept.exists() && !dropped.exists());
+        });
+    }
+
+    #[test]
+    fn test_evict_state() {
+        if memory_mode() {
+            return;
+        }
+        with_isolated_state(|| {
+            let schema = "{\"type\": \"object\"}".repeat(MIN_BLOB_LEN / 8);
+            proc_write_state("evict current", &schema).unwrap();
+            let dir = state_file_path("evict current")
+                .parent()
+                .unwrap()
+                .to_path_buf();
+            let old = |name: &str, contents: &str, age: u64| {
+                let path = dir.join(name);
+                fs::write(&path, contents).unwrap();
+                let modified = SystemTime::now() - Duration::from_secs(age);
+                File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(modified)
+                    .unwrap();
+                path
+            };
+            let pointer = fs::read_to_string(state_file_path("evict current")).unwrap();
+            let oldest = old("macro_state_oldest_1", &"a".repeat(100), 300);
+            let shared = old("macro_state_shared_1", &pointer, 200);
+            let newest = old("macro_state_newest_1", &"b".repeat(100), 100);
+
+            let root = state_dir().join(format!("v{}", STATE_FORMAT_VERSION));
+            let mut files = Vec::new();
+            collect_dir_files(&root, &mut files).unwrap();
+            let total: u64 = files.iter().map(|(_, len, _)| len).sum();
+            let freed = proc_evict_state(total - 50).unwrap();
+            assert_eq!(freed, 100);
+            assert!(!oldest.exists() && shared.exists() && newest.exists());
+
+            proc_evict_state(0).unwrap();
+            assert!(!shared.exists() && !newest.exists());
+            assert!(blob_file(&state_file_path("evict current"))
+                .unwrap()
+                .exists());
+            assert_eq!(proc_read_state("evict current").unwrap(), schema);
+
+            // blobs and temporary files may be about to be pointed to by a running build
+            let recent = [
+                blob_dir().join("recent_1"),
+                dir.join("macro_state_x_1.1.1.tmp"),
+            ];
+            for path in &recent {
+                fs::write(path, "pending").unwrap();
+            }
+            let stale = old("macro_state_y_1.1.1.tmp", "abandoned", 86400);
+            proc_evict_state(0).unwrap();
+            assert!(recent.iter().all(|path| path.exists()), "{:?}", recent);
+            assert!(!stale.exists());
+        });
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::{decode_list, flatten_records, read_state_handled, state_keys, StateResult};
+
+/// Converts the specified key into a `SCREAMING_SNAKE_CASE` Rust identifier, replacing any
+/// characters that are not valid in identifiers with underscores.
+fn const_name(key: &str) -> String {
+    let mut name: String = key
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// An analogue for [`proc_read_state`] that, rather than reading a single key, writes every
+/// key starting with `key_prefix` to a Rust source file at `path`, so that crates which do not
+/// depend on `macro_state` can still `include!` the collected data. Relative paths are
+/// resolved against the `OUT_DIR` of the crate currently being compiled.
+///
+/// For each key, the generated file contains a `pub const NAME: &str` holding the value (with
+/// list items escaped one per line, as older versions of `macro_state` stored them), along with
+/// a `pub const NAME_ITEMS: &[&str]` holding the value decoded as a list (as with
+/// [`proc_read_state_vec`](crate::proc_read_state_vec)). `NAME` is the key, with the prefix
+/// removed, converted to `SCREAMING_SNAKE_CASE`. Keys are emitted in lexicographic order, so
+/// the file only changes when the exported state does.
+///
+/// If two keys map to the same constant name, if `path` is relative and `OUT_DIR` is not
+/// set, or in the event of any sort of IO error, the error will be returned as the [`Err`]
+/// result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("routes/home", "/").unwrap();
+/// proc_append_state("routes/users", "/users").unwrap();
+/// let path = std::path::Path::new(STATE_DIR).join("routes.rs");
+/// proc_export_state_as_rust("routes/", path.to_str().unwrap()).unwrap();
+/// let source = std::fs::read_to_string(path).unwrap();
+/// assert!(source.contains("pub const HOME: &str = \"/\";"));
+/// assert!(source.contains("pub const USERS_ITEMS: &[&str] = &[\"/users\"];"));
+/// ```
+pub fn proc_export_state_as_rust(key_prefix: &str, path: &str) -> StateResult<()> {
+    let mut file = PathBuf::new();
+    if PathBuf::from(path).is_relative() {
+        let out_dir = std::env::var("OUT_DIR").map_err(|_| {
+            Error::new(
+                ErrorKind::NotFound,
+                "OUT_DIR is not set, so a relative export path cannot be resolved",
+            )
+        })?;
+        file.push(out_dir);
+    }
+    file.push(path);
+    let mut source = String::from("// @generated by macro_state. Do not edit.\n");
+    let mut names = HashMap::new();
+    for key in state_keys()? {
+        let Some(suffix) = key.strip_prefix(key_prefix) else {
+            continue;
+        };
+        let name = const_name(suffix);
+        if let Some(existing) = names.insert(name.clone(), key.clone()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "keys \"{}\" and \"{}\" both map to the constant {}",
+                    existing, key, name
+                ),
+            )
+            .into());
+        }
+        let contents = read_state_handled(&key)?;
+        let value = flatten_records(contents.clone());
+        let items = decode_list(contents)
+            .iter()
+            .map(|item| format!("{:?}", item))
+            .collect::<Vec<_>>()
+            .join(", ");
+        source.push_str(&format!(
+            "\npub const {}: &str = {:?};\npub const {}_ITEMS: &[&str] = &[{}];\n",
+            name, value, name, items
+        ));
+    }
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(fs::write(file, source)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_export_state_as_rust() {
+        proc_write_state("export test/name", "users").unwrap();
+        proc_append_state("export test/columns", "id").unwrap();
+        proc_append_state("export test/columns", "the \"email\"\ncolumn").unwrap();
+        proc_write_state("export test/Mixed Case", "x").unwrap();
+        let path = std::path::Path::new(STATE_DIR).join("export_test.rs");
+        proc_export_state_as_rust("export test/", path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            concat!(
+                "// @generated by macro_state. Do not edit.\n",
+                "\npub const MIXED_CASE: &str = \"x\";\n",
+                "pub const MIXED_CASE_ITEMS: &[&str] = &[\"x\"];\n",
+                "\npub const COLUMNS: &str = \"id\\nthe \\\"email\\\"\\\\ncolumn\\n\";\n",
+                "pub const COLUMNS_ITEMS: &[&str] = &[\"id\", \"the \\\"email\\\"\\ncolumn\"];\n",
+                "\npub const NAME: &str = \"users\";\n",
+                "pub const NAME_ITEMS: &[&str] = &[\"users\"];\n",
+            )
+        );
+
+        proc_write_state("export clash/a-b", "1").unwrap();
+        proc_write_state("export clash/a_b", "2").unwrap();
+        assert!(proc_export_state_as_rust("export clash/", path.to_str().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use crate::{
+    encode_filename, file_exists, state_dir, write_state_file, StateResult, STATE_FORMAT_VERSION,
+};
+
+/// Returns the path of the internal file that marks the specified flag as set within the
+/// current generation. Flags live apart from regular state keys, so a flag and a key with the
+/// same name never interfere with each other.
+fn flag_file_path(name: &str) -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push(format!("v{}", STATE_FORMAT_VERSION));
+    buf.push("flags");
+    buf.push(format!(
+        "macro_state_flag_{}_{}",
+        encode_filename(name),
+        crate::proc_state_generation()
+    ));
+    buf
+}
+
+/// An analogue for [`set_state_flag!`] that should only be used within proc macros.
+///
+/// Marks the flag with the specified `name` as set. Setting a flag that is already set has no
+/// effect.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert!(!proc_state_flag("my flag"));
+/// proc_set_state_flag("my flag").unwrap();
+/// assert!(proc_state_flag("my flag"));
+/// ```
+pub fn proc_set_state_flag(name: &str) -> StateResult<()> {
+    Ok(write_state_file(&flag_file_path(name), "", None)?)
+}
+
+/// An analogue for [`state_flag!`] that should only be used within proc macros.
+///
+/// Returns `true` if the flag with the specified `name` has been set via
+/// [`proc_set_state_flag`] or [`set_state_flag!`], otherwise `false`.
+pub fn proc_state_flag(name: &str) -> bool {
+    file_exists(&flag_file_path(name))
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_state_flags() {
+        assert!(!proc_state_flag("flag test"));
+        proc_set_state_flag("flag test").unwrap();
+        proc_set_state_flag("flag test").unwrap();
+        assert!(proc_state_flag("flag test"));
+        assert!(!proc_has_state("flag test"));
+
+        assert_eq!(state_flag!("macro flag test"), false);
+        set_state_flag!("macro flag test");
+        assert_eq!(state_flag!("macro flag test"), true);
+    }
+}
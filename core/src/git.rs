@@ -0,0 +1,106 @@
+use std::io::Result;
+use std::process::Command;
+
+use crate::{
+    cache_write, deterministic_mode, file_exists, lock_state_dir, now_nanos, record_key,
+    state_file_path, write_state_file, StateResult,
+};
+
+/// The state key [`proc_capture_build_metadata`] records the hash of the `HEAD` commit under.
+pub const GIT_COMMIT_KEY: &str = "__macro_state/build_meta/git_commit";
+
+/// The state key [`proc_capture_build_metadata`] records whether the working tree has
+/// uncommitted changes (`"true"` or `"false"`) under.
+pub const GIT_DIRTY_KEY: &str = "__macro_state/build_meta/git_dirty";
+
+/// The state key [`proc_capture_build_metadata`] records the build timestamp (in seconds since
+/// the UNIX epoch) under.
+pub const BUILD_TIMESTAMP_KEY: &str = "__macro_state/build_meta/timestamp";
+
+/// Runs `git` with the specified arguments within the manifest directory of the crate being
+/// compiled, returning its trimmed output, or `None` if git is unavailable or fails.
+fn git(args: &[&str]) -> Option<String> {
+    let mut command = Command::new("git");
+    if let Ok(dir) = std::env::var("CARGO_MANIFEST_DIR") {
+        command.current_dir(dir);
+    }
+    let output = command.args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Writes `value` to `key` (while the state directory is already locked).
+fn write_value(key: &str, value: &str) -> Result<()> {
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    write_state_file(&state_file, value, None)?;
+    cache_write(&state_file, value);
+    Ok(())
+}
+
+/// An analogue for [`capture_build_metadata!`] that should only be used within proc macros.
+/// Only available with the `git` feature enabled.
+///
+/// Records metadata about the current build into well-known state keys, so that multiple
+/// macros can embed consistent build metadata without each of them invoking `git`:
+/// * [`GIT_COMMIT_KEY`] holds the hash of the `HEAD` commit, or an empty string if the crate
+///   being compiled is not within a git repository (or git is not installed).
+/// * [`GIT_DIRTY_KEY`] holds `"true"` if the working tree has uncommitted changes, and
+///   `"false"` otherwise.
+/// * [`BUILD_TIMESTAMP_KEY`] holds the time of the build in seconds since the UNIX epoch,
+///   taken from the `SOURCE_DATE_EPOCH` environment variable if it is set, for reproducible
+///   builds (or `0` in deterministic mode if it isn't).
+///
+/// The metadata is only captured by the first call in each build (under an exclusive lock),
+/// so every macro sees the same values.
+///
+/// If an IO error occurs, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_capture_build_metadata().unwrap();
+/// assert!(proc_read_state(BUILD_TIMESTAMP_KEY).unwrap().parse::<u64>().is_ok());
+/// ```
+pub fn proc_capture_build_metadata() -> StateResult<()> {
+    let _lock = lock_state_dir()?;
+    if file_exists(&state_file_path(BUILD_TIMESTAMP_KEY)) {
+        return Ok(());
+    }
+    let commit = git(&["rev-parse", "HEAD"]).unwrap_or_default();
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty());
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or(match deterministic_mode() {
+            true => 0,
+            false => (now_nanos() / 1_000_000_000) as u64,
+        });
+    write_value(GIT_COMMIT_KEY, &commit)?;
+    write_value(GIT_DIRTY_KEY, &dirty.to_string())?;
+    Ok(write_value(BUILD_TIMESTAMP_KEY, &timestamp.to_string())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_capture_build_metadata() {
+        proc_capture_build_metadata().unwrap();
+        let commit = proc_read_state(GIT_COMMIT_KEY).unwrap();
+        assert!(commit.is_empty() || commit.chars().all(|c| c.is_ascii_hexdigit()));
+        assert!(["true", "false"].contains(&proc_read_state(GIT_DIRTY_KEY).unwrap().as_str()));
+        let timestamp = proc_read_state(BUILD_TIMESTAMP_KEY).unwrap();
+        proc_capture_build_metadata().unwrap();
+        assert_eq!(proc_read_state(BUILD_TIMESTAMP_KEY).unwrap(), timestamp);
+
+        capture_build_metadata!();
+        assert!(read_state!("__macro_state/build_meta/timestamp")
+            .parse::<u64>()
+            .is_ok());
+    }
+}
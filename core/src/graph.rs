@@ -0,0 +1,176 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+
+use crate::queue::read_list;
+use crate::{append_state_file, encode_list_item, lock_state_dir, state_file_path, StateResult};
+
+/// The internal key under which every declared producer/consumer relationship is recorded.
+const STATE_GRAPH_KEY: &str = "__macro_state/state_graph";
+
+/// A single edge of a dependency cycle: `consumer` consumes `key`, which is produced by
+/// `producer`.
+type CycleStep = (String, String, String);
+
+/// Finds a cycle passing through `start` in the dependency graph described by the recorded
+/// `entries`, returning the steps making up the cycle. Nodes that consume a key they produce
+/// themselves are not considered cyclic.
+fn find_cycle(entries: &[String], start: &str) -> Option<Vec<CycleStep>> {
+    let mut producers: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut consumes: HashMap<&str, Vec<&str>> = HashMap::new();
+    for entry in entries {
+        let fields: Vec<&str> = entry.split('\u{1f}').collect();
+        match fields.as_slice() {
+            [node, "produces", key] => producers.entry(key).or_default().push(node),
+            [node, "consumes", key] => consumes.entry(node).or_default().push(key),
+            _ => {}
+        }
+    }
+    fn visit(
+        node: &str,
+        start: &str,
+        producers: &HashMap<&str, Vec<&str>>,
+        consumes: &HashMap<&str, Vec<&str>>,
+        visited: &mut HashSet<String>,
+        path: &mut Vec<CycleStep>,
+    ) -> bool {
+        for key in consumes.get(node).into_iter().flatten() {
+            for producer in producers.get(key).into_iter().flatten() {
+                if *producer == node {
+                    continue;
+                }
+                path.push((node.to_string(), key.to_string(), producer.to_string()));
+                if *producer == start
+                    || (visited.insert(producer.to_string())
+                        && visit(producer, start, producers, consumes, visited, path))
+                {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+    let mut path = Vec::new();
+    visit(
+        start,
+        start,
+        &producers,
+        &consumes,
+        &mut HashSet::new(),
+        &mut path,
+    )
+    .then_some(path)
+}
+
+/// Describes the specified dependency cycle in a single human-readable message.
+fn describe_cycle(cycle: &[CycleStep]) -> String {
+    let mut nodes: Vec<&str> = cycle.iter().map(|(node, _, _)| node.as_str()).collect();
+    nodes.extend(cycle.first().map(|(node, _, _)| node.as_str()));
+    let steps: Vec<String> = cycle
+        .iter()
+        .map(|(consumer, key, producer)| {
+            format!("{} consumes \"{}\" produced by {}", consumer, key, producer)
+        })
+        .collect();
+    format!(
+        "state dependency cycle detected: {} ({})",
+        nodes.join(" -> "),
+        steps.join("; ")
+    )
+}
+
+/// An analogue for [`declare_state_node!`] that should only be used within proc macros.
+///
+/// Declares that the component (typically a macro) called `name` writes the state keys listed
+/// in `produces` and reads the state keys listed in `consumes`. Declarations are shared by
+/// every crate in the build, and each one is checked against all of the declarations made
+/// before it: if the new declaration closes a cycle (`A` consumes a key produced by `B`, while
+/// `B` consumes a key produced by `A`), no ordering of the two can ever work, and an
+/// [`ErrorKind::InvalidInput`] error listing the entire cycle is returned. Reporting the cycle
+/// itself is far more actionable than the unrelated missing-key errors both sides would
+/// otherwise run into.
+///
+/// Declaring the same relationships more than once has no effect.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_declare_state_node("models", &["my models"], &["my routes"]).unwrap();
+/// let err = proc_declare_state_node("routes", &["my routes"], &["my models"]).unwrap_err();
+/// assert!(err.to_string().contains("routes -> models -> routes"));
+/// ```
+pub fn proc_declare_state_node(
+    name: &str,
+    produces: &[&str],
+    consumes: &[&str],
+) -> StateResult<()> {
+    let _lock = lock_state_dir()?;
+    let mut entries = read_list(STATE_GRAPH_KEY);
+    let declared = produces
+        .iter()
+        .map(|key| ("produces", key))
+        .chain(consumes.iter().map(|key| ("consumes", key)));
+    for (relation, key) in declared {
+        let entry = format!("{}\u{1f}{}\u{1f}{}", name, relation, key);
+        if !entries.contains(&entry) {
+            append_state_file(
+                &state_file_path(STATE_GRAPH_KEY),
+                &encode_list_item(&entry),
+                None,
+            )?;
+            entries.push(entry);
+        }
+    }
+    match find_cycle(&entries, name) {
+        Some(cycle) => Err(Error::new(ErrorKind::InvalidInput, describe_cycle(&cycle)).into()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_cycle() {
+        let entries: Vec<String> = [
+            "a\u{1f}produces\u{1f}x",
+            "a\u{1f}consumes\u{1f}z",
+            "a\u{1f}consumes\u{1f}x",
+            "b\u{1f}produces\u{1f}y",
+            "b\u{1f}consumes\u{1f}x",
+            "c\u{1f}produces\u{1f}z",
+            "c\u{1f}consumes\u{1f}y",
+            "d\u{1f}consumes\u{1f}z",
+        ]
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect();
+        let cycle = find_cycle(&entries, "b").unwrap();
+        assert_eq!(
+            describe_cycle(&cycle),
+            "state dependency cycle detected: b -> a -> c -> b (b consumes \"x\" produced by a; \
+            a consumes \"z\" produced by c; c consumes \"y\" produced by b)"
+        );
+        assert!(find_cycle(&entries, "d").is_none());
+        assert!(find_cycle(&entries[..2], "a").is_none());
+    }
+
+    #[test]
+    fn test_declare_state_node() {
+        proc_declare_state_node("graph a", &["graph x"], &[]).unwrap();
+        proc_declare_state_node("graph b", &["graph y"], &["graph x"]).unwrap();
+        proc_declare_state_node("graph b", &["graph y"], &["graph x"]).unwrap();
+        let err = proc_declare_state_node("graph a", &[], &["graph y"]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("graph a -> graph b -> graph a"));
+
+        declare_state_node!("graph macro producer", produces("graph macro key"));
+        declare_state_node!(
+            "graph macro consumer",
+            consumes("graph macro key"),
+            produces("graph macro other")
+        );
+    }
+}
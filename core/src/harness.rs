@@ -0,0 +1,15 @@
+/// A test function recorded via [`#[collect_test]`](macro@crate::collect_test), as found in the
+/// static table emitted by [`emit_test_manifest!`](crate::emit_test_manifest).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CollectedTest {
+    /// The name of the test function.
+    pub name: &'static str,
+    /// The free-form metadata attached to the test, or an empty string if there is none.
+    pub meta: &'static str,
+    /// The tags attached to the test.
+    pub tags: &'static [&'static str],
+    /// The source file in which the test was recorded.
+    pub file: &'static str,
+    /// The line on which the test was recorded.
+    pub line: u32,
+}
@@ -0,0 +1,326 @@
+use std::io::{Error, ErrorKind};
+
+use serde_json::Value;
+
+use crate::{cached_read, state_file_path, write_state_file, StateResult};
+
+/// Returns the internal key the JSON schema declared for `key` is stored under.
+fn schema_key(key: &str) -> String {
+    format!("__macro_state/json_schemas/{}", key)
+}
+
+/// Interprets a state value as JSON, treating values that are not valid JSON (such as `info`)
+/// as plain JSON strings.
+fn parse_value(value: &str) -> Value {
+    serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+fn json_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn json_type_matches(name: &str, value: &Value) -> bool {
+    match (name, value) {
+        ("integer", Value::Number(n)) => {
+            n.is_i64() || n.is_u64() || n.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => name == json_type_name(value),
+    }
+}
+
+/// Validates `value` against the supported subset of JSON Schema, returning a description of
+/// the first violation found. `path` is the JSON pointer of `value` within the whole value.
+fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let fail = |msg: String| match path.is_empty() {
+        true => Err(msg),
+        false => Err(format!("at {}: {}", path, msg)),
+    };
+    let schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(false) => return fail(String::from("no value is allowed here")),
+        _ => return Ok(()),
+    };
+    let number = |name: &str| schema.get(name).and_then(Value::as_f64);
+    let count = |name: &str| schema.get(name).and_then(Value::as_u64);
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| json_type_matches(name, value)) {
+            return fail(format!(
+                "expected {}, found {}",
+                types.join(" or "),
+                json_type_name(value)
+            ));
+        }
+    }
+    if let Some(Value::Array(options)) = schema.get("enum") {
+        if !options.contains(value) {
+            let options: Vec<String> = options.iter().map(Value::to_string).collect();
+            return fail(format!(
+                "expected one of {}, found {}",
+                options.join(", "),
+                value
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return fail(format!("expected {}, found {}", expected, value));
+        }
+    }
+    match value {
+        Value::String(string) => {
+            let len = string.chars().count() as u64;
+            if let Some(min) = count("minLength").filter(|min| len < *min) {
+                return fail(format!("string is shorter than {} characters", min));
+            }
+            if let Some(max) = count("maxLength").filter(|max| len > *max) {
+                return fail(format!("string is longer than {} characters", max));
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            if let Some(min) = number("minimum").filter(|min| n < *min) {
+                return fail(format!("{} is less than the minimum of {}", n, min));
+            }
+            if let Some(max) = number("maximum").filter(|max| n > *max) {
+                return fail(format!("{} is greater than the maximum of {}", n, max));
+            }
+            if let Some(min) = number("exclusiveMinimum").filter(|min| n <= *min) {
+                return fail(format!("{} is not greater than {}", n, min));
+            }
+            if let Some(max) = number("exclusiveMaximum").filter(|max| n >= *max) {
+                return fail(format!("{} is not less than {}", n, max));
+            }
+        }
+        Value::Array(items) => {
+            if let Some(min) = count("minItems").filter(|min| (items.len() as u64) < *min) {
+                return fail(format!(
+                    "expected at least {} items, found {}",
+                    min,
+                    items.len()
+                ));
+            }
+            if let Some(max) = count("maxItems").filter(|max| items.len() as u64 > *max) {
+                return fail(format!(
+                    "expected at most {} items, found {}",
+                    max,
+                    items.len()
+                ));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate(item_schema, item, &format!("{}/{}", path, i))?;
+                }
+            }
+        }
+        Value::Object(fields) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !fields.contains_key(name) {
+                        return fail(format!("missing required property \"{}\"", name));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, field) in fields {
+                let field_path = format!("{}/{}", path, name);
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(field_schema) => validate(field_schema, field, &field_path)?,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            return fail(format!("unexpected property \"{}\"", name));
+                        }
+                        Some(extra) => validate(extra, field, &field_path)?,
+                        None => {}
+                    },
+                }
+            }
+        }
+        _ => {}
+    }
+    if let Some(Value::Array(schemas)) = schema.get("allOf") {
+        for schema in schemas {
+            validate(schema, value, path)?;
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("anyOf") {
+        if !schemas
+            .iter()
+            .any(|schema| validate(schema, value, path).is_ok())
+        {
+            return fail(String::from("value matches none of the schemas in `anyOf`"));
+        }
+    }
+    if let Some(Value::Array(schemas)) = schema.get("oneOf") {
+        let matches = schemas
+            .iter()
+            .filter(|schema| validate(schema, value, path).is_ok())
+            .count();
+        if matches != 1 {
+            return fail(format!(
+                "value matches {} of the schemas in `oneOf` instead of exactly one",
+                matches
+            ));
+        }
+    }
+    if let Some(schema) = schema.get("not") {
+        if validate(schema, value, path).is_ok() {
+            return fail(String::from("value matches the schema in `not`"));
+        }
+    }
+    Ok(())
+}
+
+/// Validates a value about to be written to `key` (or, if `item` is `true`, a single item
+/// about to be appended to the list stored at `key`) against the JSON schema declared for
+/// `key`, returning a description of the violation if it does not conform.
+pub(crate) fn json_schema_violation(key: &str, value: &str, item: bool) -> Option<String> {
+    let schema = cached_read(&state_file_path(&schema_key(key))).ok()?;
+    let schema: Value = serde_json::from_str(&schema).ok()?;
+    let schema = match item {
+        true => schema.get("items")?,
+        false => &schema,
+    };
+    let reason = validate(schema, &parse_value(value), "").err()?;
+    match item {
+        true => Some(format!("appended item is invalid: {}", reason)),
+        false => Some(reason),
+    }
+}
+
+/// An analogue for [`declare_state_schema!`](crate::declare_state_schema) that should only be
+/// used within proc macros.
+///
+/// Attaches the specified JSON `schema` to `key`. From then on, every value written to `key`
+/// (via [`proc_write_state`](crate::proc_write_state), [`write_state!`](crate::write_state),
+/// and friends) is validated against the schema, and rejected with a
+/// [`MacroStateError::InvalidValue`](crate::MacroStateError::InvalidValue) error describing
+/// the exact violation if it does not conform. Items appended to `key` are validated
+/// individually against the `items` keyword of the schema, since appending builds up a list.
+///
+/// Values are interpreted as JSON, except that values which are not valid JSON are treated as
+/// JSON strings, so `info` and `"info"` are equivalent. The supported keywords are `type`,
+/// `enum`, `const`, `minLength`, `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`,
+/// `exclusiveMaximum`, `minItems`, `maxItems`, `items`, `required`, `properties`,
+/// `additionalProperties`, `allOf`, `anyOf`, `oneOf`, and `not`. Other keywords are ignored.
+///
+/// Returns an [`Err`] of kind [`ErrorKind::InvalidInput`] if `schema` is not valid JSON.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_declare_state_schema("schema routes", r#"{
+///     "type": "array",
+///     "items": { "type": "object", "required": ["path"] }
+/// }"#)
+/// .unwrap();
+/// proc_write_state("schema routes", r#"[{ "path": "/" }]"#).unwrap();
+/// let err = proc_write_state("schema routes", r#"[{ "url": "/" }]"#).unwrap_err();
+/// assert_eq!(
+///     err.to_string(),
+///     "invalid value for key \"schema routes\": at /0: missing required property \"path\""
+/// );
+/// ```
+pub fn proc_declare_state_schema(key: &str, schema: &str) -> StateResult<()> {
+    if let Err(e) = serde_json::from_str::<Value>(schema) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("the schema for key \"{}\" is not valid JSON: {}", key, e),
+        )
+        .into());
+    }
+    Ok(write_state_file(
+        &state_file_path(&schema_key(key)),
+        schema,
+        None,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "fields"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "fields": {
+                    "type": "array",
+                    "items": { "enum": ["id", "email"] },
+                    "maxItems": 2
+                },
+                "version": { "type": "integer", "minimum": 1 }
+            }
+        });
+        let check = |value: Value| validate(&schema, &value, "");
+        assert_eq!(check(json!({ "name": "User", "fields": ["id"] })), Ok(()));
+        assert_eq!(
+            check(json!({ "name": "User" })),
+            Err(String::from("missing required property \"fields\""))
+        );
+        assert_eq!(
+            check(json!({ "name": "User", "fields": ["id", "phone"] })),
+            Err(String::from(
+                "at /fields/1: expected one of \"id\", \"email\", found \"phone\""
+            ))
+        );
+        assert_eq!(
+            check(json!({ "name": "User", "fields": [], "version": 1.5 })),
+            Err(String::from("at /version: expected integer, found number"))
+        );
+        assert_eq!(
+            check(json!({ "name": "User", "fields": [], "extra": true })),
+            Err(String::from("unexpected property \"extra\""))
+        );
+        assert_eq!(
+            check(json!([])),
+            Err(String::from("expected object, found array"))
+        );
+        let one_of = json!({ "oneOf": [{ "type": "integer" }, { "type": "number" }] });
+        assert!(validate(&one_of, &json!(1.5), "").is_ok());
+        assert!(validate(&one_of, &json!(1), "").is_err());
+    }
+
+    #[test]
+    fn test_declare_state_schema() {
+        assert!(proc_declare_state_schema("schema level", "{ not json").is_err());
+        proc_declare_state_schema("schema level", r#"{ "enum": ["debug", "info"] }"#).unwrap();
+        proc_write_state("schema level", "info").unwrap();
+        let err = proc_write_state("schema level", "verbose").unwrap_err();
+        assert!(matches!(err, MacroStateError::InvalidValue { .. }));
+        assert_eq!(proc_read_state("schema level").unwrap(), "info");
+
+        proc_declare_state_schema("schema ports", r#"{ "items": { "type": "integer" } }"#).unwrap();
+        proc_append_state("schema ports", "8080").unwrap();
+        assert!(proc_append_state("schema ports", "http").is_err());
+        let mut batch = StateBatch::new();
+        batch.append("schema ports", "https");
+        assert!(batch.commit().is_err());
+        assert_eq!(proc_read_state_vec("schema ports"), vec!["8080"]);
+
+        declare_state_schema!(
+            "schema macro",
+            r#"{ "type": "object", "required": ["id"] }"#
+        );
+        write_state!("schema macro", r#"{ "id": 1 }"#);
+        assert_eq!(read_state!("schema macro"), r#"{ "id": 1 }"#);
+    }
+}
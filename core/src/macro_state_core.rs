@@ -0,0 +1,5284 @@
+#[cfg(test)]
+#[macro_use]
+extern crate macro_state_macros;
+
+#[macro_use]
+extern crate lazy_static;
+extern crate proc_macro;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::panic::Location;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod audit;
+pub use audit::*;
+
+mod backend;
+pub use backend::*;
+
+mod batch;
+pub use batch::*;
+
+mod blobs;
+use blobs::*;
+
+mod build_env;
+pub use build_env::*;
+
+mod changes;
+pub use changes::*;
+
+mod config;
+use config::*;
+
+mod constraints;
+pub use constraints::*;
+
+mod counters;
+pub use counters::*;
+
+mod error;
+pub use error::*;
+
+mod eviction;
+pub use eviction::*;
+
+mod export;
+pub use export::*;
+
+mod flags;
+pub use flags::*;
+
+mod graph;
+pub use graph::*;
+
+mod harness;
+pub use harness::*;
+
+#[cfg(feature = "json_schema")]
+mod json_schema;
+#[cfg(feature = "json_schema")]
+pub use json_schema::*;
+
+mod memory;
+use memory::*;
+
+mod metrics;
+pub use metrics::*;
+
+mod queue;
+pub use queue::*;
+
+mod search;
+pub use search::*;
+
+mod session;
+pub use session::*;
+
+pub mod testing;
+use testing::{overridden_generation, overridden_state_dir};
+
+mod transaction;
+pub use transaction::*;
+
+#[cfg(feature = "git")]
+mod git;
+#[cfg(feature = "git")]
+pub use git::*;
+
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "mmap")]
+pub use mmap::*;
+
+#[cfg(feature = "serde")]
+mod state_key;
+#[cfg(feature = "serde")]
+pub use state_key::*;
+
+#[doc(hidden)]
+#[path = "private.rs"]
+pub mod __private;
+
+lazy_static! {
+    static ref STATE_ROOT: PathBuf = resolve_state_dir();
+    static ref GENERATION: u128 = build_generation();
+    static ref STARTED: SystemTime = SystemTime::now();
+    static ref READ_CACHE: Mutex<HashMap<PathBuf, CachedValue>> = Mutex::new(HashMap::new());
+    static ref OWNED_PATHS: Mutex<HashMap<PathBuf, PathBuf>> = Mutex::new(HashMap::new());
+    static ref MISSING_KEY_HANDLER: Mutex<Option<Arc<MissingKeyHandler>>> = Mutex::new(None);
+    static ref WRITE_POLICY: Option<(PathBuf, String)> = load_write_policy();
+    static ref KEY_LOCKS: Mutex<HashMap<PathBuf, &'static Mutex<()>>> = Mutex::new(HashMap::new());
+}
+
+/// A constant that will always resolve to the directory `macro_state`
+/// will use to store state files. This is typically some sub-directory
+/// of the `target` directory for the specified build environment.
+/// You should never use this directly unless you know what you're doing.
+///
+/// If this directory no longer exists when state is accessed, a fallback directory is used
+/// instead (see [`proc_state_dir`]).
+pub const STATE_DIR: &str = env!("MACRO_STATE_DIR");
+
+/// Derives a state directory from the `OUT_DIR` (or, failing that, the `CARGO_TARGET_DIR`) of
+/// the crate being expanded. The directory is placed alongside the `build` directory of the
+/// current profile, so it is shared by every crate in the build and removed by `cargo clean`.
+fn derived_state_dir(out_dir: Option<&str>, target_dir: Option<&str>) -> Option<PathBuf> {
+    if let Some(out_dir) = out_dir {
+        let out_dir = Path::new(out_dir);
+        let profile_dir = out_dir
+            .ancestors()
+            .find(|dir| dir.file_name().is_some_and(|name| name == "build"))
+            .and_then(Path::parent)
+            .unwrap_or(out_dir);
+        return Some(profile_dir.join("macro_state"));
+    }
+    target_dir.map(|target_dir| Path::new(target_dir).join("macro_state"))
+}
+
+/// Returns the directory state should preferably be stored in. This is the `state_dir` setting
+/// if set, and otherwise [`STATE_DIR`] whenever it exists, but if it does not (for example
+/// because the workspace was moved, or `macro_state` was compiled against a shared cache), a
+/// directory derived from the build environment of the crate being expanded is used instead.
+fn preferred_state_dir() -> PathBuf {
+    if let Some(dir) = setting("state_dir") {
+        return PathBuf::from(dir);
+    }
+    if Path::new(STATE_DIR).is_dir() {
+        return PathBuf::from(STATE_DIR);
+    }
+    derived_state_dir(
+        std::env::var("OUT_DIR").ok().as_deref(),
+        std::env::var("CARGO_TARGET_DIR").ok().as_deref(),
+    )
+    .unwrap_or_else(|| PathBuf::from(STATE_DIR))
+}
+
+/// Checks that state can be stored in the specified directory by creating it (if needed) and
+/// writing a probe file to it.
+fn check_state_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".macro_state_probe_{}", std::process::id()));
+    fs::write(&probe, "")?;
+    fs::remove_file(&probe)
+}
+
+/// Returns the temporary directory used in place of `dir` when `dir` cannot be written to.
+/// The name is derived from `dir`, so every process that falls back from the same directory
+/// still shares its state with the others.
+fn fallback_state_dir(dir: &Path) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "macro_state_{:016x}",
+        stable_hash(&dir.to_string_lossy())
+    ))
+}
+
+/// Returns `true` if sandbox mode has been requested by setting the `MACRO_STATE_MODE`
+/// environment variable to `sandbox`.
+fn sandbox_mode() -> bool {
+    setting("mode").as_deref() == Some("sandbox")
+}
+
+/// Returns the directory state is stored in when sandbox mode is enabled. Each build (each
+/// cargo invocation, see [`invocation_id`]) gets a fresh directory within the system temporary
+/// directory, so nothing is ever written to the target directory. Since there is no way to run
+/// anything once a build has finished, the directories of builds that have since finished are
+/// removed by the next build instead.
+fn sandbox_state_dir() -> PathBuf {
+    let root = std::env::temp_dir();
+    remove_stale_entries(&root, SANDBOX_PREFIX);
+    root.join(format!("{}{}", SANDBOX_PREFIX, invocation_id(*STARTED)))
+}
+
+/// The prefix of the names of the directories returned by [`sandbox_state_dir`].
+const SANDBOX_PREFIX: &str = "macro_state_sandbox_";
+
+/// Returns `true` if state should be shared between every workspace building into the same
+/// target directory, as requested by setting the `MACRO_STATE_SHARED` environment variable to
+/// `1`, `true`, or `yes`.
+fn shared_mode() -> bool {
+    setting_enabled("shared")
+}
+
+/// Returns the value of the `--out-dir` argument the compiler was invoked with, which cargo
+/// always passes. Within a proc macro the current process is the compiler itself.
+fn rustc_out_dir() -> Option<std::ffi::OsString> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--out-dir" {
+            return args.next();
+        }
+        if let Some(dir) = arg.to_str().and_then(|arg| arg.strip_prefix("--out-dir=")) {
+            return Some(dir.into());
+        }
+    }
+    None
+}
+
+/// Returns the root of the workspace that a crate built into `build_dir` (its `OUT_DIR`, its
+/// `--out-dir`, or the target directory itself) belongs to. The root of the target directory
+/// is the directory cargo marks with a `CACHEDIR.TAG` (or, failing that, the one holding the
+/// `build` or `deps` directory of the profile), and the workspace is the nearest directory
+/// above it holding a `Cargo.lock`, since the target directory lives within the workspace by
+/// default. A target directory outside of any workspace (such as a `CARGO_TARGET_DIR` shared
+/// by several of them) stands for the workspace itself, since cargo shares the build output
+/// of dependencies between such workspaces anyway.
+fn workspace_of_build_dir(build_dir: &Path) -> PathBuf {
+    let target_root = build_dir
+        .ancestors()
+        .find(|dir| dir.join("CACHEDIR.TAG").is_file())
+        .or_else(|| {
+            build_dir
+                .ancestors()
+                .find(|dir| {
+                    dir.file_name()
+                        .is_some_and(|name| name == "build" || name == "deps")
+                })
+                .and_then(Path::parent)
+                .and_then(Path::parent)
+        })
+        .unwrap_or(build_dir);
+    target_root
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join("Cargo.lock").is_file())
+        .unwrap_or(target_root)
+        .to_path_buf()
+}
+
+lazy_static! {
+    static ref WORKSPACE_ROOT: Option<PathBuf> = find_workspace_root();
+}
+
+/// Locates the root of the workspace being built. Cargo only runs the compiler from the root
+/// of the workspace for the crates of the workspace itself (registry and git dependencies are
+/// compiled from their own package directories), so every crate of the build is instead
+/// traced back to the workspace via the target directory it is built into (see
+/// [`workspace_of_build_dir`]). Outside of a cargo build (for example when running tests),
+/// the nearest directory above the current directory holding a `Cargo.lock` is used.
+fn find_workspace_root() -> Option<PathBuf> {
+    let build_dir = std::env::var_os("OUT_DIR")
+        .or_else(rustc_out_dir)
+        .or_else(|| std::env::var_os("CARGO_TARGET_DIR"));
+    if let Some(dir) = build_dir {
+        let dir = PathBuf::from(dir);
+        return Some(workspace_of_build_dir(&dir.canonicalize().unwrap_or(dir)));
+    }
+    let cwd = std::env::current_dir().ok()?;
+    let cwd = cwd.canonicalize().unwrap_or(cwd);
+    let root = cwd.ancestors().find(|dir| dir.join("Cargo.lock").is_file());
+    Some(root.unwrap_or(&cwd).to_path_buf())
+}
+
+/// Returns the root of the workspace being built (see [`find_workspace_root`]), if it could be
+/// determined.
+pub(crate) fn workspace_root() -> Option<&'static Path> {
+    WORKSPACE_ROOT.as_deref()
+}
+
+/// Returns the sub-directory of `dir` holding the state of the workspace currently being
+/// built (see [`workspace_root`]), so that workspaces sharing a single state directory never
+/// see each other's keys. Unless [`shared_mode`] is enabled, in which case `dir` is used as is.
+fn workspace_state_dir(dir: PathBuf) -> PathBuf {
+    if shared_mode() {
+        return dir;
+    }
+    let Some(root) = workspace_root() else {
+        return dir;
+    };
+    dir.join(format!(
+        "workspace_{:016x}",
+        stable_hash(&root.to_string_lossy())
+    ))
+}
+
+/// Resolves the directory state is stored in, starting from the [`workspace_state_dir`] within
+/// [`preferred_state_dir`] (or [`sandbox_state_dir`], if sandbox mode is enabled). If that
+/// directory is missing and cannot be created, or is not writable, a single warning is printed
+/// and state is stored in a [`fallback_state_dir`] instead. If even that fails, the preferred
+/// directory is kept so that the underlying IO errors surface from the state operations
+/// themselves. The choice is made once per build (see [`invocation_id`]): the first process to
+/// resolve it records it in a marker file next to the fallback directory while holding a lock,
+/// and every other process of the build reuses it, so the crates of a single build never end up
+/// split between two directories even if the preferred one only becomes writable midway.
+fn resolve_state_dir() -> PathBuf {
+    let dir = workspace_state_dir(match sandbox_mode() {
+        true => sandbox_state_dir(),
+        false => preferred_state_dir(),
+    });
+    if memory_mode() {
+        return dir;
+    }
+    let fallback = fallback_state_dir(&dir);
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(fallback.with_extension("lock"))
+        .ok()
+        .filter(|file| file.lock().is_ok());
+    let marker = fallback.with_extension("choice");
+    let invocation = invocation_id(*STARTED);
+    if lock.is_some() {
+        if let Ok(contents) = fs::read_to_string(&marker) {
+            match contents.split_once('\n') {
+                Some((id, chosen)) if id == invocation => return PathBuf::from(chosen),
+                _ => (),
+            }
+        }
+    }
+    let chosen = choose_state_dir(dir, fallback);
+    if lock.is_some() {
+        let _ = fs::write(
+            &marker,
+            format!("{}\n{}", invocation, chosen.to_string_lossy()),
+        );
+    }
+    chosen
+}
+
+/// Chooses between `dir` and its `fallback`, as described in [`resolve_state_dir`].
+fn choose_state_dir(dir: PathBuf, fallback: PathBuf) -> PathBuf {
+    let Err(e) = check_state_dir(&dir) else {
+        return dir;
+    };
+    if check_state_dir(&fallback).is_err() {
+        return dir;
+    }
+    eprintln!(
+        "warning: macro_state: cannot use state directory {} ({}), falling back to {}. \
+        State is still shared between the crates of this build, but is no longer removed by \
+        `cargo clean` and may be shared with other builds that fall back to the same directory.",
+        dir.display(),
+        e,
+        fallback.display()
+    );
+    fallback
+}
+
+/// Returns the directory state is stored in, as resolved by [`resolve_state_dir`], unless the
+/// current thread is running within [`testing::with_isolated_state`] or
+/// [`testing::StateFixture::with`].
+fn state_dir() -> PathBuf {
+    overridden_state_dir().unwrap_or_else(|| STATE_ROOT.to_path_buf())
+}
+
+/// Returns the generation state is read and written in, which is [`GENERATION`] unless the
+/// current thread is running within [`testing::StateFixture::with`].
+fn generation() -> u128 {
+    overridden_generation().unwrap_or(*GENERATION)
+}
+
+/// Returns the directory `macro_state` actually stores state in for the current build. This is
+/// normally a sub-directory of [`STATE_DIR`], but if that directory no longer exists (for
+/// example because the workspace was moved), a `macro_state` directory within the target
+/// directory of the crate being expanded is used instead, derived from its `OUT_DIR` or from
+/// `CARGO_TARGET_DIR`. The `state_dir` setting (set via `macro_state.toml` or the
+/// `MACRO_STATE_STATE_DIR` environment variable) overrides both.
+///
+/// The sub-directory is named after a hash of the root of the workspace being built, so that
+/// workspaces sharing a single `state_dir` never see each other's keys. The workspace is
+/// located via the target directory of the crate being expanded (the nearest directory above
+/// it holding a `Cargo.lock`), so the crates of a build agree on it even when they are
+/// registry or git dependencies. Workspaces sharing a `CARGO_TARGET_DIR` outside of all of
+/// them are treated as one, since cargo shares the build output of their dependencies too.
+/// Set the `MACRO_STATE_SHARED` environment variable to `1` to share state between all
+/// workspaces instead.
+///
+/// If the resulting directory cannot be created or written to, a warning is printed and a
+/// directory within the system temporary directory is used instead.
+///
+/// If the `MACRO_STATE_MODE` environment variable is set to `sandbox`, state is instead stored
+/// in a fresh directory within the system temporary directory for every build, which is
+/// removed by the next build once it has finished. This is useful for hermetic build systems
+/// that forbid writing anything into the target directory. If it is set to `memory`, no
+/// directory is used at all: the files that would be stored within it are kept in a
+/// process-local map instead.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert!(proc_state_dir().starts_with(STATE_DIR));
+/// ```
+pub fn proc_state_dir() -> PathBuf {
+    state_dir().to_path_buf()
+}
+
+/// The version of the on-disk layout used for state files. State files live in a
+/// sub-directory of [`STATE_DIR`] named after this version, so state written by a version of
+/// `macro_state` with a different layout is never misinterpreted.
+pub const STATE_FORMAT_VERSION: u32 = 5;
+
+/// Computes the 64-bit FNV-1a hash of the specified string. Unlike the hashers in the standard
+/// library, the result of this function is guaranteed to be stable across Rust versions and
+/// platforms, which is a requirement for anything that ends up in a file name.
+fn stable_hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in value.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Returns the current time in nanoseconds since the UNIX epoch.
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos()
+}
+
+/// Determines the generation that state files written by this process belong to.
+///
+/// All processes spawned by the same cargo invocation (i.e. every `rustc` process taking part
+/// in a single build) share a single generation, which is recorded in a marker file inside the
+/// state directory by whichever process gets there first. The generation can also be pinned
+/// explicitly via the `MACRO_STATE_GENERATION` environment variable. If neither is possible,
+/// the time at which `macro_state` was first used by this process is used instead, or, in
+/// [`deterministic_mode`], the most recent generation number, so that processes running
+/// outside of cargo share the state of the last build rather than each starting a new one.
+fn build_generation() -> u128 {
+    lazy_static::initialize(&STARTED);
+    if let Some(generation) = std::env::var("MACRO_STATE_GENERATION")
+        .ok()
+        .and_then(|generation| generation.parse::<u128>().ok())
+    {
+        return generation;
+    }
+    let now = now_nanos();
+    if memory_mode() {
+        return now;
+    }
+    match cargo_invocation_id() {
+        Some(id) => shared_generation(id.as_str(), now).unwrap_or(now),
+        None if deterministic_mode() => acquire_state_dir_lock()
+            .and_then(|_lock| latest_generation_number(&generations_dir()))
+            .unwrap_or(now),
+        None => now,
+    }
+}
+
+/// Returns the parent PID, command name, and start time of the specified process.
+#[cfg(target_os = "linux")]
+fn process_info(pid: u32) -> Option<(u32, String, String)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (head, rest) = stat.rsplit_once(')')?;
+    let (_, comm) = head.split_once('(')?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let ppid = fields.get(1)?.parse::<u32>().ok()?;
+    let start_time = fields.get(19)?.to_string();
+    Some((ppid, comm.to_string(), start_time))
+}
+
+/// Returns the parent PID, command name, and start time of the specified process, as reported
+/// by `ps`, since there is no `/proc` to read them from.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_info(pid: u32) -> Option<(u32, String, String)> {
+    let output = std::process::Command::new("ps")
+        .args(["-o", "ppid=,lstart=,comm=", "-p", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    let fields: Vec<&str> = output.split_whitespace().collect();
+    let ppid = fields.first()?.parse::<u32>().ok()?;
+    // `lstart` is made up of five fields, such as `Thu Oct 15 11:04:01 2026`
+    let start_time = fields.get(1..6)?.join("-").replace(':', "-");
+    let comm = fields.get(6..)?.join(" ");
+    let comm = std::path::Path::new(&comm).file_name()?.to_string_lossy();
+    Some((ppid, comm.to_string(), start_time))
+}
+
+/// Returns the parent PID, command name (without the `.exe` extension), and creation time of
+/// the specified process, by searching a Toolhelp snapshot of the running processes.
+#[cfg(windows)]
+fn process_info(pid: u32) -> Option<(u32, String, String)> {
+    // `PROCESSENTRY32W`, as laid out in `tlhelp32.h`
+    #[repr(C)]
+    #[allow(dead_code)]
+    struct ProcessEntry {
+        size: u32,
+        usage: u32,
+        pid: u32,
+        default_heap_id: usize,
+        module_id: u32,
+        threads: u32,
+        ppid: u32,
+        base_priority: i32,
+        flags: u32,
+        exe_file: [u16; 260],
+    }
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateToolhelp32Snapshot(flags: u32, pid: u32) -> isize;
+        fn Process32FirstW(snapshot: isize, entry: *mut ProcessEntry) -> i32;
+        fn Process32NextW(snapshot: isize, entry: *mut ProcessEntry) -> i32;
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> isize;
+        fn GetProcessTimes(
+            process: isize,
+            creation: *mut FileTime,
+            exit: *mut FileTime,
+            kernel: *mut FileTime,
+            user: *mut FileTime,
+        ) -> i32;
+        fn CloseHandle(handle: isize) -> i32;
+    }
+    const TH32CS_SNAPPROCESS: u32 = 0x2;
+    const INVALID_HANDLE_VALUE: isize = -1;
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+
+    let mut entry = ProcessEntry {
+        size: std::mem::size_of::<ProcessEntry>() as u32,
+        usage: 0,
+        pid: 0,
+        default_heap_id: 0,
+        module_id: 0,
+        threads: 0,
+        ppid: 0,
+        base_priority: 0,
+        flags: 0,
+        exe_file: [0; 260],
+    };
+    // SAFETY: `entry` is a correctly sized `PROCESSENTRY32W`, and the snapshot handle is
+    // closed before it goes out of scope
+    let found = unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return None;
+        }
+        let mut more = Process32FirstW(snapshot, &mut entry) != 0;
+        while more && entry.pid != pid {
+            more = Process32NextW(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+        more
+    };
+    if !found {
+        return None;
+    }
+    let mut creation = FileTime::default();
+    let (mut exit, mut kernel, mut user) = (
+        FileTime::default(),
+        FileTime::default(),
+        FileTime::default(),
+    );
+    // SAFETY: the process handle is closed before it goes out of scope, and every out
+    // parameter is a valid `FILETIME`
+    let created = unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process == 0 {
+            return None;
+        }
+        let ok = GetProcessTimes(process, &mut creation, &mut exit, &mut kernel, &mut user);
+        CloseHandle(process);
+        ok != 0
+    };
+    if !created {
+        return None;
+    }
+    let FileTime { low, high } = creation;
+    let len = entry.exe_file.iter().position(|&c| c == 0).unwrap_or(260);
+    let exe = String::from_utf16_lossy(&entry.exe_file[..len]);
+    let comm = std::path::Path::new(&exe).file_stem()?.to_string_lossy();
+    let start_time = (((high as u64) << 32) | low as u64).to_string();
+    Some((entry.ppid, comm.to_string(), start_time))
+}
+
+/// Walks up the process tree (skipping over any configured `RUSTC_WRAPPER`) to find the
+/// cargo process that spawned us, returning an identifier that is unique to that particular
+/// cargo invocation: its PID, followed by `_` and its start time.
+#[cfg(any(unix, windows))]
+fn cargo_invocation_id() -> Option<String> {
+    let wrappers: Vec<String> = ["RUSTC_WRAPPER", "RUSTC_WORKSPACE_WRAPPER"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .filter_map(|wrapper| {
+            std::path::Path::new(&wrapper)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+        })
+        .collect();
+    #[cfg(unix)]
+    let mut pid = std::os::unix::process::parent_id();
+    #[cfg(windows)]
+    let mut pid = process_info(std::process::id())?.0;
+    for _ in 0..4 {
+        let (ppid, comm, start_time) = process_info(pid)?;
+        if comm == "cargo" {
+            return Some(format!("{}_{}", pid, start_time));
+        }
+        if !wrappers.contains(&comm) {
+            return None;
+        }
+        pid = ppid;
+    }
+    None
+}
+
+/// Returns an identifier unique to the cargo invocation that spawned us. There is no way to
+/// find the parent of a process on other platforms, so there is none, and every process is
+/// treated as an invocation of its own (see [`invocation_id`]).
+#[cfg(not(any(unix, windows)))]
+fn cargo_invocation_id() -> Option<String> {
+    None
+}
+
+/// Returns `true` if the process with the specified PID is still running.
+fn process_alive(pid: &str) -> bool {
+    if cfg!(target_os = "linux") {
+        return std::path::Path::new("/proc").join(pid).exists();
+    }
+    let output = match cfg!(windows) {
+        true => std::process::Command::new("tasklist")
+            .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+            .output(),
+        false => std::process::Command::new("ps")
+            .args(["-o", "pid=", "-p", pid])
+            .output(),
+    };
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .any(|field| field == pid),
+        // if there is no way to tell, assume the process is still running
+        Err(_) => true,
+    }
+}
+
+/// Returns the identifier of the invocation the current process belongs to: the
+/// [`cargo_invocation_id`], if there is one, or else an identifier of the current process
+/// alone (which `started` at the specified time), in the same `<pid>_<start>` form.
+fn invocation_id(started: std::time::SystemTime) -> String {
+    cargo_invocation_id().unwrap_or_else(|| {
+        let started = started
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos());
+        format!("{}_{}", std::process::id(), started)
+    })
+}
+
+/// Removes the entries of `dir` named `<prefix><pid>_...` whose owning process (a cargo
+/// invocation, or a single process running outside of cargo) has exited, such as the sandbox
+/// directories of finished builds.
+fn remove_stale_entries(dir: &std::path::Path, prefix: &str) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((pid, _)) = name.strip_prefix(prefix).and_then(|id| id.split_once('_')) else {
+            continue;
+        };
+        if !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()) && !process_alive(pid) {
+            let path = entry.path();
+            let _ = match path.is_dir() {
+                true => std::fs::remove_dir_all(path),
+                false => std::fs::remove_file(path),
+            };
+        }
+    }
+}
+
+/// Returns the key of the internal list recording who appended each value of the list stored
+/// for `key` via `append_state_unique_or_error!` or `proc_append_state_unique`.
+fn provenance_key(key: &str) -> String {
+    format!("__macro_state/provenance/{}", key)
+}
+
+/// Describes the writer of a unique value: the crate being compiled, along with the source
+/// location of the invocation responsible.
+fn provenance_writer(crate_name: &str, file: &str, line: usize) -> String {
+    format!("{} ({}:{})", crate_name, file, line)
+}
+
+/// Decides whether `writer` may append `value` to the list stored for `key`, given the
+/// provenance `entries` recorded for that list so far and whether `value` is already present
+/// in it. Returns the provenance entry to record alongside the append, [`None`] if `writer`
+/// itself appended the value before (the same invocation being expanded again, such as when a
+/// crate is compiled once as a library and once more for its unit tests), or an
+/// [`AlreadyExists`](std::io::ErrorKind::AlreadyExists) error naming whoever appended the value
+/// first.
+fn claim_unique_value(
+    key: &str,
+    value: &str,
+    writer: &str,
+    entries: &[String],
+    exists: bool,
+) -> std::io::Result<Option<String>> {
+    let earlier = entries.iter().find_map(|entry| {
+        let (existing, earlier) = entry.rsplit_once('\u{1f}')?;
+        (existing == value).then_some(earlier)
+    });
+    let earlier = match earlier {
+        Some(earlier) if earlier == writer => return Ok(None),
+        Some(earlier) => earlier,
+        None if exists => "an unknown writer",
+        None => return Ok(Some(format!("{}\u{1f}{}", value, writer))),
+    };
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!(
+            "the value \"{}\" was already appended to key \"{}\" by {}",
+            value, key, earlier
+        ),
+    ))
+}
+
+/// Returns `true` if deterministic mode has been requested via the `deterministic` setting.
+/// In deterministic mode, generations are numbered sequentially rather than named after the
+/// time the build started, no creation times are recorded, and lists are sorted when read (see
+/// [`canonical_list`]), so that builds from the same inputs produce byte-identical state and
+/// expansions.
+fn deterministic_mode() -> bool {
+    setting_enabled("deterministic")
+}
+
+/// Returns the directory holding the generation markers of running cargo invocations.
+fn generations_dir() -> PathBuf {
+    let mut dir = state_dir();
+    dir.push(format!("v{}", STATE_FORMAT_VERSION));
+    dir.push("generations");
+    dir
+}
+
+/// Returns the generation following the most recent one recorded in `dir`, recording it as the
+/// most recent one. Used in place of timestamps in [`deterministic_mode`], so the first build
+/// after a `cargo clean` always uses generation 1. The caller must hold the state directory
+/// lock.
+fn next_generation_number(dir: &Path) -> Result<u128> {
+    let counter = dir.join("latest");
+    let generation = fs::read_to_string(&counter)
+        .ok()
+        .and_then(|latest| latest.trim().parse::<u128>().ok())
+        .unwrap_or(0)
+        + 1;
+    fs::create_dir_all(dir)?;
+    fs::write(&counter, generation.to_string())?;
+    Ok(generation)
+}
+
+/// Returns the most recent generation recorded in `dir` (see [`next_generation_number`]),
+/// recording the first one if there is none yet. The caller must hold the state directory
+/// lock.
+fn latest_generation_number(dir: &Path) -> Result<u128> {
+    match fs::read_to_string(dir.join("latest"))
+        .ok()
+        .and_then(|latest| latest.trim().parse::<u128>().ok())
+    {
+        Some(generation) => Ok(generation),
+        None => next_generation_number(dir),
+    }
+}
+
+/// Returns the generation recorded for the specified cargo invocation, recording a new one
+/// (`now`, or the next generation number in [`deterministic_mode`]) if this is the first
+/// process of the invocation to ask.
+fn shared_generation(invocation_id: &str, now: u128) -> Result<u128> {
+    let _lock = acquire_state_dir_lock()?;
+    let dir = generations_dir();
+    let marker = dir.join(invocation_id);
+    if let Some(generation) = fs::read_to_string(&marker)
+        .ok()
+        .and_then(|generation| generation.trim().parse::<u128>().ok())
+    {
+        return Ok(generation);
+    }
+    fs::create_dir_all(&dir)?;
+    // markers belonging to cargo invocations that have since exited are no longer needed
+    remove_stale_entries(&dir, "");
+    let generation = match deterministic_mode() {
+        true => next_generation_number(&dir)?,
+        false => now,
+    };
+    fs::write(&marker, generation.to_string())?;
+    tidy_state_dir(generation);
+    Ok(generation)
+}
+
+/// Encodes the specified key (or channel, session, or crate name) for use within a file name,
+/// such that two keys differing only in case never map to the same file, even on
+/// case-insensitive filesystems such as those used by default on macOS and Windows. `^` itself
+/// is escaped as `^^`, and any other character that changes when lowered is escaped as well:
+/// as `^` followed by its lower-case form if upper-casing that gives the character back (so
+/// `A` becomes `^a`), or else as `^#`, its code point in hexadecimal, and `;` (so `İ`, whose
+/// lower-case form is two characters long, becomes `^#130;`).
+///
+/// Since state directories may be shared with (or copied to) Windows, the characters Windows
+/// does not allow in file names (see [`INVALID_FILENAME_CHARS`]) and control characters are
+/// escaped in the same `^#` form, as is a trailing dot or space (which Windows strips), and the
+/// first character of a name Windows reserves for a device (see [`RESERVED_FILENAMES`]).
+fn encode_filename(key: &str) -> String {
+    let escape = |c: char| format!("^#{:x};", c as u32);
+    let mut encoded = String::with_capacity(key.len());
+    for c in key.chars() {
+        if c == '^' {
+            encoded.push_str("^^");
+            continue;
+        }
+        if INVALID_FILENAME_CHARS.contains(&c) || c.is_control() {
+            encoded.push_str(&escape(c));
+            continue;
+        }
+        let mut lower = c.to_lowercase();
+        match (lower.next(), lower.next()) {
+            (Some(l), None) if l == c => encoded.push(c),
+            (Some(l), None) if l.to_uppercase().eq(std::iter::once(c)) => {
+                encoded.push('^');
+                encoded.push(l);
+            }
+            _ => encoded.push_str(&escape(c)),
+        }
+    }
+    if let Some(last) = encoded.pop() {
+        match last {
+            '.' | ' ' => encoded.push_str(&escape(last)),
+            _ => encoded.push(last),
+        }
+    }
+    // device names are reserved regardless of any extension, and of trailing spaces before it
+    let stem = encoded.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_FILENAMES.contains(&stem) {
+        let first = encoded.remove(0);
+        encoded.insert_str(0, &escape(first));
+    }
+    encoded
+}
+
+/// The characters Windows does not allow in file names, which [`encode_filename`] escapes.
+const INVALID_FILENAME_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*', '\\'];
+
+/// The names Windows reserves for devices, which [`encode_filename`] escapes. Upper-case
+/// letters are escaped anyway, so only the lower-case spellings can come up.
+const RESERVED_FILENAMES: &[&str] = &[
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// Reverses [`encode_filename`], returning the original key.
+fn decode_filename(encoded: &str) -> String {
+    let mut decoded = String::with_capacity(encoded.len());
+    let mut chars = encoded.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '^' => match chars.next() {
+                Some('^') | None => decoded.push('^'),
+                Some('#') => {
+                    let hex: String = chars.by_ref().take_while(|c| *c != ';').collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .unwrap_or(char::REPLACEMENT_CHARACTER);
+                    decoded.push(c);
+                }
+                Some(c) => decoded.extend(c.to_uppercase()),
+            },
+            c => decoded.push(c),
+        }
+    }
+    decoded
+}
+
+/// Encoded keys longer than this many bytes are replaced by a hashed file name, since many
+/// platforms limit file names to around 255 bytes.
+const MAX_KEY_FILENAME_LEN: usize = 128;
+
+/// The number of bytes of the encoded key kept as a readable prefix of hashed file names.
+const HASHED_KEY_PREFIX_LEN: usize = 48;
+
+/// Returns the portion of a file name used to represent the specified key. Keys that would
+/// produce overly long file names are shortened to a readable prefix followed by a hash of
+/// the full key.
+fn key_filename(key: &str) -> String {
+    let encoded = encode_filename(key);
+    if encoded.len() <= MAX_KEY_FILENAME_LEN {
+        return encoded;
+    }
+    let mut end = HASHED_KEY_PREFIX_LEN;
+    while !encoded.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}~{:016x}", &encoded[..end], stable_hash(key))
+}
+
+/// Returns `true` if `encoded` (the file name of a state file, stripped of its prefix and
+/// generation suffix) was shortened by [`key_filename`], in which case the full key can only
+/// be recovered from the metadata of the file (see [`recorded_key`]).
+fn is_hashed_filename(encoded: &str) -> bool {
+    encoded
+        .rsplit_once('~')
+        .is_some_and(|(_, hash)| hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Records the full `key` in the metadata of the state file at `path` if the file is stored
+/// under a hashed name (see [`key_filename`]), so that the key can still be recovered when
+/// listing keys. Writers call this before their first write to the file. The key is stored as
+/// `key=<length>:<key>`, with backslashes and line breaks escaped, so that it round-trips
+/// exactly whatever characters it contains.
+fn record_key(path: &Path, key: &str) -> Result<()> {
+    if encode_filename(key).len() <= MAX_KEY_FILENAME_LEN {
+        return Ok(());
+    }
+    let fields = read_metadata_fields(path);
+    if fields.iter().any(|(name, _)| name == "key") {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut contents = format!("key={}\n", encode_key_field(key));
+    for (name, value) in fields {
+        contents.push_str(&format!("{}={}\n", name, value));
+    }
+    write_file(&metadata_file_path(path), &contents)
+}
+
+/// Encodes `key` for the `key` field of a metadata file (see [`record_key`]).
+fn encode_key_field(key: &str) -> String {
+    let escaped = key
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    format!("{}:{}", key.len(), escaped)
+}
+
+/// Reverses [`encode_key_field`], returning [`None`] if `field` is malformed.
+fn decode_key_field(field: &str) -> Option<String> {
+    let (len, escaped) = field.split_once(':')?;
+    let mut key = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                'n' => key.push('\n'),
+                'r' => key.push('\r'),
+                c => key.push(c),
+            },
+            c => key.push(c),
+        }
+    }
+    (len.parse::<usize>().ok()? == key.len()).then_some(key)
+}
+
+/// Returns the full key recorded in the metadata of the specified state file by
+/// [`record_key`], if any.
+fn recorded_key(path: &Path) -> Option<String> {
+    read_metadata_fields(path)
+        .into_iter()
+        .find(|(name, _)| name == "key")
+        .and_then(|(_, field)| decode_key_field(&field))
+}
+
+/// Returns the path of the internal file that would be used to
+/// store state for the specified key, as a [PathBuf](std::path::PathBuf).
+/// You should never use this directly unless you know what you're doing.
+///
+/// State files are grouped by the crate that first wrote each key, as
+/// `v<STATE_FORMAT_VERSION>/crates/<crate>/<shard>/`, so it is easy to tell which crate
+/// produced what. Other crates writing to or reading the same key use the existing file of the
+/// owning crate, as recorded by the owner marker the first write leaves under
+/// `v<STATE_FORMAT_VERSION>/owners/<shard>/`. Within each crate, state files are sharded into
+/// 256 sub-directories based on a hash of the key, so that directories stay small even when
+/// thousands of keys are in use.
+/// The case of the key is encoded into the file name, so keys that differ only in case (such
+/// as `"Config"` and `"config"`) never alias, even on case-insensitive filesystems.
+///
+/// Very long keys are stored under a hashed file name that keeps a readable prefix of the
+/// key, with the full key recorded in the accompanying metadata file when it is first written.
+pub fn state_file_path(key: &str) -> PathBuf {
+    let generation = generation();
+    let mut relative = PathBuf::new();
+    relative.push(format!("{:02x}", stable_hash(key) as u8));
+    relative.push(format!("macro_state_{}_{}", key_filename(key), generation));
+    let marker = owners_dir().join(&relative);
+    if let Some(path) = OWNED_PATHS.lock().unwrap().get(&marker) {
+        return path.clone();
+    }
+    let owner = match read_file(&marker) {
+        Ok(owner) if !owner.is_empty() => owner,
+        _ => return crate_state_dir(&current_crate_name()).join(relative),
+    };
+    let path = crates_dir().join(owner).join(relative);
+    OWNED_PATHS.lock().unwrap().insert(marker, path.clone());
+    path
+}
+
+/// Returns the directory holding the owner markers that record which crate owns each key.
+fn owners_dir() -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push(format!("v{}", STATE_FORMAT_VERSION));
+    buf.push("owners");
+    buf
+}
+
+/// Splits the path of a state file (as returned by [`state_file_path`]) into the name of the
+/// crate directory holding it and its path relative to that directory, unless `path` is not a
+/// state file.
+fn split_state_file_path(path: &Path) -> Option<(String, PathBuf)> {
+    let relative = path.strip_prefix(crates_dir()).ok()?;
+    let mut components = relative.components();
+    let dir = components.next()?.as_os_str().to_string_lossy().to_string();
+    Some((dir, components.as_path().to_path_buf()))
+}
+
+/// Claims the key stored at `path` (as returned by [`state_file_path`]) for the crate whose
+/// directory `path` is in, unless another crate already owns it, returning the path of the
+/// state file within the directory of the owning crate.
+///
+/// Only the first write of a key makes a claim. The owner marker is locked while it is read and
+/// written, so crates writing the same new key at once agree on a single owner, and readers
+/// never see a partially written marker as anything but unclaimed.
+fn claim_state_file(path: &Path) -> Result<PathBuf> {
+    let Some((dir, relative)) = split_state_file_path(path) else {
+        return Ok(path.to_path_buf());
+    };
+    if file_exists(path) {
+        return Ok(path.to_path_buf());
+    }
+    let marker = owners_dir().join(&relative);
+    let owner = match memory_mode() {
+        true => match read_file(&marker) {
+            Ok(owner) => owner,
+            Err(_) => {
+                create_dir_all(marker.parent().unwrap_or(&marker))?;
+                write_file(&marker, &dir)?;
+                dir
+            }
+        },
+        false => {
+            fs::create_dir_all(marker.parent().unwrap_or(&marker))?;
+            let mut file = retry_io(|| {
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .read(true)
+                    .write(true)
+                    .open(&marker)
+            })?;
+            file.lock()?;
+            let mut owner = String::new();
+            file.read_to_string(&mut owner)?;
+            if owner.is_empty() {
+                file.write_all(dir.as_bytes())?;
+                owner = dir;
+            }
+            owner
+        }
+    };
+    let path = crates_dir().join(owner).join(relative);
+    OWNED_PATHS.lock().unwrap().insert(marker, path.clone());
+    Ok(path)
+}
+
+/// Releases the ownership of the keys stored in the specified state files of the crate
+/// directory named `dir`, which are being removed along with that directory.
+fn release_state_files(dir: &str, files: &[PathBuf]) {
+    let mut owned = OWNED_PATHS.lock().unwrap();
+    for file in files {
+        let Some((_, relative)) = split_state_file_path(file) else {
+            continue;
+        };
+        let marker = owners_dir().join(relative);
+        owned.remove(&marker);
+        if read_file(&marker).is_ok_and(|owner| owner == dir) {
+            let _ = remove_file(&marker);
+        }
+    }
+}
+
+/// Returns the directory holding one sub-directory of state files per writing crate.
+fn crates_dir() -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push(format!("v{}", STATE_FORMAT_VERSION));
+    buf.push("crates");
+    buf
+}
+
+/// Returns the directory holding the state files of the keys first written by the crate named
+/// `crate_name`.
+fn crate_state_dir(crate_name: &str) -> PathBuf {
+    crates_dir().join(encode_filename(crate_name.replace('-', "_").as_str()))
+}
+
+/// Returns the state directories of every crate that has written state, sorted by path.
+fn crate_state_dirs() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = match list_dir(&crates_dir()) {
+        Ok(entries) => entries.into_iter().map(|(path, _)| path).collect(),
+        Err(_) => Vec::new(),
+    };
+    dirs.sort();
+    dirs
+}
+
+/// The prefix reserved for the metadata `macro_state` keeps about user state (generation
+/// markers, provenance, declared schemas, and so on). Keys starting with this prefix can be
+/// read, but never written, by users.
+pub const RESERVED_KEY_PREFIX: &str = "__macro_state/";
+
+/// Returns a description of why `key` violates the key character policy, if it does.
+fn key_policy_violation(key: &str) -> Option<String> {
+    if key.is_empty() {
+        return Some(String::from("keys must not be empty"));
+    }
+    if let Some(c) = key.chars().find(|c| c.is_control()) {
+        return Some(format!(
+            "keys must not contain control characters (found {:?})",
+            c
+        ));
+    }
+    if key.contains('\\') {
+        return Some(String::from("keys must not contain `\\`"));
+    }
+    if key.split('/').any(str::is_empty) {
+        return Some(String::from(
+            "keys must not start or end with `/` or contain `//`",
+        ));
+    }
+    if key
+        .split('/')
+        .any(|segment| segment == "." || segment == "..")
+    {
+        return Some(String::from("keys must not contain `.` or `..` segments"));
+    }
+    None
+}
+
+/// Returns `true` if the `read_only` setting `setting` (a comma-separated list of
+/// crate names, or `*` for every crate) makes the crate named `crate_name` read-only. Dashes
+/// and underscores in crate names are interchangeable.
+fn is_read_only(crate_name: &str, setting: &str) -> bool {
+    let crate_name = crate_name.replace('-', "_");
+    setting
+        .split(',')
+        .map(str::trim)
+        .any(|entry| entry == "*" || entry.replace('-', "_") == crate_name)
+}
+
+/// Returns the path of the write policy file, which is the `policy` setting if set and
+/// otherwise `macro_state.policy`. Relative paths are resolved against the directory of the
+/// project configuration file (see [`config_path`]), so the policy sits next to
+/// `macro_state.toml` by default.
+fn write_policy_path() -> PathBuf {
+    let config = config_path();
+    let dir = config.parent().unwrap_or_else(|| Path::new(""));
+    dir.join(setting("policy").unwrap_or_else(|| String::from("macro_state.policy")))
+}
+
+/// Loads the write policy file (see [`write_policy_path`]), if there is one.
+fn load_write_policy() -> Option<(PathBuf, String)> {
+    let path = write_policy_path();
+    let policy = fs::read_to_string(&path).ok()?;
+    Some((path, policy))
+}
+
+/// Returns a description of why the crate named `crate_name` may not write to `key` under the
+/// write policy `policy`, if it may not.
+///
+/// Each non-empty line of a policy that does not start with `#` has the form
+/// `<pattern> = <crate>, <crate>, ...`, where `<pattern>` is either an exact key or a key
+/// prefix followed by `*`. Keys matched by a pattern may only be written by the listed crates,
+/// with the longest matching pattern taking precedence, while keys matched by no pattern may be
+/// written by any crate. Dashes and underscores in crate names are interchangeable.
+fn write_policy_violation(key: &str, crate_name: &str, policy: &str) -> Option<String> {
+    let (pattern, writers) = policy
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(pattern, writers)| (pattern.trim(), writers.trim()))
+        .filter(|(pattern, _)| match pattern.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == *pattern,
+        })
+        .max_by_key(|(pattern, _)| pattern.len())?;
+    if is_read_only(crate_name, writers) {
+        return None;
+    }
+    let writers: Vec<String> = writers
+        .split(',')
+        .map(str::trim)
+        .filter(|writer| !writer.is_empty())
+        .map(|writer| format!("\"{}\"", writer))
+        .collect();
+    if writers.is_empty() {
+        return Some(format!("no crate may write keys matching `{}`", pattern));
+    }
+    Some(format!(
+        "only {} may write keys matching `{}`",
+        writers.join(", "),
+        pattern
+    ))
+}
+
+/// Returns the name of the crate owning `key` (the crate that first wrote it), with dashes
+/// replaced by underscores, or [`None`] if `key` has no value.
+fn key_owner(key: &str) -> Option<String> {
+    let path = state_file_path(key);
+    if !file_exists(&path) {
+        return None;
+    }
+    let relative = path.strip_prefix(crates_dir()).ok()?;
+    let dir = relative.components().next()?;
+    Some(decode_filename(&dir.as_os_str().to_string_lossy()))
+}
+
+/// Returns a description of why the crate named `crate_name` may not write to `key`, if it may
+/// not: because the crate is listed in the `read_only` setting, because strict mode is enabled
+/// and `key` is owned by another crate, or because the write policy file forbids it.
+fn write_denial(key: &str, crate_name: &str) -> Option<String> {
+    if is_read_only(crate_name, &setting("read_only").unwrap_or_default()) {
+        return Some(String::from(
+            "the crate is read-only (it is listed in the `read_only` setting)",
+        ));
+    }
+    if setting_enabled("strict") {
+        let owner = key_owner(key).filter(|owner| *owner != crate_name.replace('-', "_"));
+        if let Some(owner) = owner {
+            return Some(format!(
+                "in strict mode, keys may only be modified by the crate that first wrote them \
+                (\"{}\")",
+                owner
+            ));
+        }
+    }
+    let (path, policy) = WRITE_POLICY.as_ref()?;
+    let reason = write_policy_violation(key, crate_name, policy)?;
+    Some(format!("{} (per {})", reason, path.display()))
+}
+
+/// Returns an error if the crate being compiled may not modify the specified file of the state
+/// directory. Every function that modifies state files checks this, so that no code path can
+/// bypass [`check_key`]: read-only crates may not modify any file, while strict mode and the
+/// write policy (see [`write_denial`]) also govern the files of user keys.
+fn check_file_write(path: &Path) -> Result<()> {
+    let crate_name = current_crate_name();
+    let denial = match state_file_key(path) {
+        Some(key) if !key.starts_with(RESERVED_KEY_PREFIX) => {
+            write_denial(&key, &crate_name).map(|reason| (key, reason))
+        }
+        key => is_read_only(&crate_name, &setting("read_only").unwrap_or_default()).then(|| {
+            let key = key.unwrap_or_else(|| path.display().to_string());
+            let reason = "the crate is read-only (it is listed in the `read_only` setting)";
+            (key, reason.to_string())
+        }),
+    };
+    match denial {
+        Some((key, reason)) => Err(MacroStateError::WriteDenied {
+            key,
+            crate_name,
+            reason,
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Validates a user-supplied `key` against the key character policy documented in the README
+/// and, if `write` is `true`, rejects keys within the [`RESERVED_KEY_PREFIX`] namespace, so
+/// that bad keys surface as a [`MacroStateError::InvalidKey`] error rather than an obscure
+/// OS-level failure (or a file outside of the state directory). Writes are also rejected with
+/// a [`MacroStateError::WriteDenied`] error if the crate being compiled may not write to `key`
+/// (see [`write_denial`]), and logged if the `log` setting is enabled.
+pub(crate) fn check_key(key: &str, write: bool) -> StateResult<()> {
+    let reason = match key_policy_violation(key) {
+        Some(reason) => reason,
+        None if write && key.starts_with(RESERVED_KEY_PREFIX) => format!(
+            "the `{}` prefix is reserved for internal metadata",
+            RESERVED_KEY_PREFIX
+        ),
+        None if write => {
+            let crate_name = current_crate_name();
+            let Some(reason) = write_denial(key, &crate_name) else {
+                if setting_enabled("log") {
+                    eprintln!(
+                        "macro_state: crate \"{}\" modifies key \"{}\"",
+                        crate_name, key
+                    );
+                }
+                return Ok(());
+            };
+            return Err(MacroStateError::WriteDenied {
+                key: key.to_string(),
+                crate_name,
+                reason,
+            });
+        }
+        None => return Ok(()),
+    };
+    Err(MacroStateError::InvalidKey {
+        key: key.to_string(),
+        reason,
+    })
+}
+
+/// Collects the paths of every file under `dir`, descending into sub-directories since keys
+/// containing path separators are stored in nested directories.
+fn collect_state_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for (path, is_dir) in list_dir(dir)? {
+        if is_dir {
+            collect_state_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns every key that currently has a value in the current generation, sorted
+/// lexicographically. Keys stored under a hashed file name are recovered from the metadata
+/// header of their state file.
+fn state_keys() -> Result<Vec<String>> {
+    Ok(state_files()?.into_iter().map(|(key, _)| key).collect())
+}
+
+/// Returns every key that currently has a value in the current generation along with the path
+/// of its state file, sorted by key, via a single scan of the state directory.
+fn state_files() -> Result<Vec<(String, PathBuf)>> {
+    let suffix = format!("_{}", generation());
+    let mut keys = Vec::new();
+    let mut shards = Vec::new();
+    for dir in crate_state_dirs() {
+        match list_dir(&dir) {
+            Ok(entries) => shards.extend(entries),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    for (shard, _) in shards {
+        let name = shard.file_name().unwrap_or_default().to_string_lossy();
+        if name.len() != 2 || u8::from_str_radix(&name, 16).is_err() {
+            continue;
+        }
+        let mut files = Vec::new();
+        collect_state_files(&shard, &mut files)?;
+        for file in files {
+            let Ok(relative) = file.strip_prefix(&shard) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let Some(encoded) = relative
+                .strip_prefix("macro_state_")
+                .and_then(|name| name.strip_suffix(suffix.as_str()))
+            else {
+                continue;
+            };
+            let key = is_hashed_filename(encoded)
+                .then(|| recorded_key(&file))
+                .flatten()
+                .unwrap_or_else(|| decode_filename(encoded));
+            keys.push((key, file));
+        }
+    }
+    keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+    keys.dedup_by(|(a, _), (b, _)| a == b);
+    Ok(keys)
+}
+
+/// The maximum number of attempts made for a single file operation that keeps failing with a
+/// transient error. The delay between attempts doubles each time, starting at 10ms.
+const MAX_IO_ATTEMPTS: u32 = 6;
+
+/// Returns `true` if the specified IO error is likely to go away on its own. On Windows this
+/// includes the sharing and lock violations caused by antivirus and indexing services briefly
+/// holding on to freshly created files.
+fn is_transient_io_error(e: &Error) -> bool {
+    if e.kind() == ErrorKind::Interrupted {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        // ERROR_ACCESS_DENIED, ERROR_SHARING_VIOLATION, ERROR_LOCK_VIOLATION
+        matches!(e.raw_os_error(), Some(5) | Some(32) | Some(33))
+    }
+    #[cfg(not(windows))]
+    {
+        false
+    }
+}
+
+/// Runs the specified file operation, retrying it with exponential backoff for as long as it
+/// fails with a transient error (see [`is_transient_io_error`]), up to [`MAX_IO_ATTEMPTS`]
+/// attempts in total.
+fn retry_io<T>(mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = std::time::Duration::from_millis(10);
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if attempt < MAX_IO_ATTEMPTS && is_transient_io_error(&e) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Opens the specified state file for appending, creating it (and any missing parent
+/// directories) if it does not exist yet.
+fn open_state_file_for_append(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let existed = path.exists();
+    if existed {
+        materialize_blob(path)?;
+    }
+    let file = retry_io(|| OpenOptions::new().append(true).create(true).open(path))?;
+    record_write(path, existed)?;
+    Ok(file)
+}
+
+/// Returns `true` if durable writes have been requested by setting the `MACRO_STATE_FSYNC`
+/// environment variable to `1`, `true`, or `yes`.
+fn durable_writes() -> bool {
+    setting_enabled("fsync")
+}
+
+/// Flushes the specified state file, as well as the directory containing it, to disk.
+fn sync_state_file(file: &File, path: &Path) -> Result<()> {
+    file.sync_all()?;
+    sync_parent_dir(path)
+}
+
+/// Flushes the directory containing the specified file to disk, so that a file created in
+/// (or renamed into) it survives a crash.
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    // directories can only be opened (and thus synced) like this on unix platforms
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Distinguishes the temporary files written by concurrent calls to [`replace_file`] within
+/// the same process.
+static NEXT_TEMP_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Replaces the contents of the specified file on disk with `contents`, without ever exposing
+/// a truncated or partially written file to readers: the contents are written to a temporary
+/// file next to it, which is then renamed into place. If durable writes are enabled, the
+/// temporary file is synced before the rename and the directory after it, so that either the
+/// old or the new contents survive a crash.
+fn replace_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TEMP_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp = PathBuf::from(temp);
+    let written = retry_io(|| File::create(&temp)).and_then(|mut file| {
+        file.write_all(contents)?;
+        match durable_writes() {
+            true => file.sync_all(),
+            false => Ok(()),
+        }
+    });
+    if let Err(e) = written.and_then(|_| retry_io(|| fs::rename(&temp, path))) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    match durable_writes() {
+        true => sync_parent_dir(path),
+        false => Ok(()),
+    }
+}
+
+/// Acquires the process-local lock over the specified state file, held until the returned
+/// guard is dropped.
+///
+/// The state directory lock only serializes whole read-modify-write operations, and only
+/// between processes that take it, while the parallel rustc frontend (`-Zthreads`) expands
+/// macros on several threads of a single process at once. Every read, write, append, and
+/// removal of a state file therefore also holds this lock, so that threads never observe (or
+/// interleave with) each other's partial writes. Locks are per file, so unrelated keys are
+/// never serialized, and they are only ever held for a single file operation, so they can't
+/// deadlock with each other or with the state directory lock.
+fn lock_state_file(path: &Path) -> MutexGuard<'static, ()> {
+    let lock = *KEY_LOCKS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(path.to_path_buf())
+        .or_insert_with(|| Box::leak(Box::new(Mutex::new(()))));
+    lock.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Replaces the contents of the specified state file with `contents` (see [`replace_file`]),
+/// creating any missing parent directories along the way.
+fn write_state_file(path: &Path, contents: &str, caller: Caller) -> Result<()> {
+    check_file_write(path)?;
+    let path = &claim_state_file(path)?;
+    let _guard = lock_state_file(path);
+    let op = start_op(path, "write");
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let existed = file_exists(path);
+    write_file(path, contents)?;
+    record_write(path, existed)?;
+    op.finish();
+    note_change(path, StateChangeOp::Write, contents, caller);
+    Ok(())
+}
+
+/// Appends `contents` to the specified state file, syncing the file to disk afterwards if
+/// durable writes are enabled.
+fn append_state_file(path: &Path, contents: &str, caller: Caller) -> Result<()> {
+    check_file_write(path)?;
+    let path = &claim_state_file(path)?;
+    let _guard = lock_state_file(path);
+    let op = start_op(path, "append");
+    if memory_mode() {
+        let existed = file_exists(path);
+        memory_append(path, contents);
+        record_write(path, existed)?;
+        op.finish();
+        note_change(path, StateChangeOp::Append, contents, caller);
+        return Ok(());
+    }
+    let mut file = open_state_file_for_append(path)?;
+    file.write_all(contents.as_bytes())?;
+    if durable_writes() {
+        sync_state_file(&file, path)?;
+    }
+    op.finish();
+    note_change(path, StateChangeOp::Append, contents, caller);
+    Ok(())
+}
+
+/// Removes the specified state file along with its metadata file.
+fn remove_state_file(path: &Path, caller: Caller) -> Result<()> {
+    check_file_write(path)?;
+    let _guard = lock_state_file(path);
+    let op = start_op(path, "remove");
+    remove_file(path)?;
+    op.finish();
+    note_change(path, StateChangeOp::Remove, "", caller);
+    match remove_file(&metadata_file_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the path of the metadata file that accompanies the specified state file.
+fn metadata_file_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".meta");
+    PathBuf::from(path)
+}
+
+/// Parses the `name=value` lines of the metadata file that accompanies the specified state
+/// file, returning an empty list if there is no metadata file.
+fn read_metadata_fields(path: &Path) -> Vec<(String, String)> {
+    read_file(&metadata_file_path(path))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+thread_local! {
+    /// The write sequence numbers reserved via [`reserve_sequences`] by the current thread, as
+    /// the next one to hand out and the end of the reserved range.
+    static RESERVED_SEQUENCES: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+/// Returns `true` if writes are stamped with sequence numbers (see [`proc_state_sequence`]),
+/// which is enabled via the `sequence` setting, since claiming one takes a lock shared by every
+/// write of the build.
+fn sequence_enabled() -> bool {
+    setting_enabled("sequence")
+}
+
+/// Returns the next write sequence number of the current generation. Sequence numbers start
+/// at 1 and are shared by every process taking part in the build, so they reflect the order in
+/// which writes actually happened. The counter lives in its own file with its own lock, since
+/// writes are often made while the state directory lock is already held.
+fn next_sequence() -> Result<u64> {
+    if memory_mode() {
+        return Ok(memory_next_sequence());
+    }
+    let reserved = RESERVED_SEQUENCES.with(|reserved| {
+        let (next, end) = reserved.get();
+        (next < end).then(|| {
+            reserved.set((next + 1, end));
+            next
+        })
+    });
+    match reserved {
+        Some(sequence) => Ok(sequence),
+        None => advance_sequence(1),
+    }
+}
+
+/// The write sequence numbers reserved via [`reserve_sequences`], released when dropped.
+pub(crate) struct SequenceReservation {
+    /// The first reserved sequence number and the end of the reserved range, if any were
+    /// reserved from the shared counter.
+    range: Option<(u64, u64)>,
+}
+
+impl SequenceReservation {
+    /// Hands the reserved sequence numbers back to the shared counter, so that writes that were
+    /// rolled back leave no gap, unless later writes have already claimed numbers beyond them.
+    pub(crate) fn rewind(&self) -> Result<()> {
+        if let Some((first, end)) = self.range {
+            update_sequence(|last| (last == end - 1).then_some(first - 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SequenceReservation {
+    fn drop(&mut self) {
+        RESERVED_SEQUENCES.with(|reserved| reserved.set((0, 0)));
+    }
+}
+
+/// Reserves the next `count` write sequence numbers (see [`next_sequence`]) for the writes the
+/// current thread is about to make, so that a batch of writes updates the shared counter in a
+/// single pass rather than once per key. Should only be called while holding the state
+/// directory lock, so that the batch still appears to happen at once. Writes beyond the
+/// reserved numbers fall back to the shared counter.
+pub(crate) fn reserve_sequences(count: usize) -> Result<SequenceReservation> {
+    let mut range = None;
+    if sequence_enabled() && !memory_mode() && count > 0 {
+        let first = advance_sequence(count as u64)?;
+        RESERVED_SEQUENCES.with(|reserved| reserved.set((first, first + count as u64)));
+        range = Some((first, first + count as u64));
+    }
+    Ok(SequenceReservation { range })
+}
+
+/// Advances the shared write sequence counter by `count`, returning the first of the claimed
+/// sequence numbers.
+fn advance_sequence(count: u64) -> Result<u64> {
+    Ok(update_sequence(|last| Some(last + count))? + 1)
+}
+
+/// Locks the shared write sequence counter and replaces the last sequence number it handed out
+/// with the one returned by `update`, if any, returning the number it held before.
+fn update_sequence(update: impl FnOnce(u64) -> Option<u64>) -> Result<u64> {
+    let mut path = state_dir().to_path_buf();
+    path.push(format!("v{}", STATE_FORMAT_VERSION));
+    fs::create_dir_all(&path)?;
+    path.push(format!("sequence_{}", generation()));
+    let mut file = retry_io(|| {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)
+    })?;
+    file.lock()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let last = contents.trim().parse::<u64>().unwrap_or(0);
+    if let Some(updated) = update(last) {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(updated.to_string().as_bytes())?;
+    }
+    Ok(last)
+}
+
+/// Records that the specified state file was opened for writing by the crate currently being
+/// compiled, preserving the original creation time if the key already `existed`, and stamps
+/// it with the next write sequence number if enabled (see [`sequence_enabled`]). The full key
+/// recorded by [`record_key`], if any, is kept.
+fn record_write(path: &Path, existed: bool) -> Result<()> {
+    let fields = read_metadata_fields(path);
+    let created = match fields.iter().find(|(name, _)| name == "created") {
+        Some((_, created)) if existed => created.clone(),
+        _ if deterministic_mode() => String::from("0"),
+        _ => file_stamp(path)?
+            .0
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string(),
+    };
+    let mut contents = String::new();
+    if let Some((_, key)) = fields.iter().find(|(name, _)| name == "key") {
+        contents.push_str(&format!("key={}\n", key));
+    }
+    contents.push_str(&format!(
+        "created={}\nwriter={}\n",
+        created,
+        current_crate_name()
+    ));
+    if sequence_enabled() {
+        contents.push_str(&format!("sequence={}\n", next_sequence()?));
+    }
+    write_file(&metadata_file_path(path), &contents)
+}
+
+/// Identifies the contents of a state file without reading them: its modification time and
+/// length, along with the blob it points to, if any. Pointers to blobs of the same length are
+/// themselves of the same length, so the blob has to be part of the stamp as well.
+#[derive(PartialEq)]
+struct StateStamp {
+    modified: SystemTime,
+    len: u64,
+    blob: Option<PathBuf>,
+}
+
+/// Returns the [`StateStamp`] of the specified state file.
+fn state_stamp(path: &Path) -> Result<StateStamp> {
+    let (modified, len) = file_stamp(path)?;
+    Ok(StateStamp {
+        modified,
+        len,
+        blob: blob_file(path),
+    })
+}
+
+/// A state value held in the process-local read cache, along with the [`StateStamp`] of the
+/// state file it was read from. A cached value is only used if the state file still has the
+/// same stamp, so writes made by other processes are picked up.
+struct CachedValue {
+    stamp: StateStamp,
+    value: String,
+}
+
+/// Reads the specified state file, consulting the process-local read cache first. Heavy proc
+/// macros tend to read the same keys over and over within a single expansion pass, so this
+/// saves a considerable amount of filesystem traffic.
+fn cached_read(path: &Path) -> Result<String> {
+    let _guard = lock_state_file(path);
+    let op = start_op(path, "read");
+    let stamp = state_stamp(path)?;
+    let mut cache = READ_CACHE.lock().unwrap();
+    if let Some(cached) = cache.get(path) {
+        if cached.stamp == stamp {
+            op.finish();
+            return Ok(cached.value.clone());
+        }
+    }
+    let value = read_state_contents(path)?;
+    op.finish();
+    let cached = CachedValue {
+        stamp,
+        value: value.clone(),
+    };
+    cache.insert(path.to_path_buf(), cached);
+    Ok(value)
+}
+
+/// Updates the process-local read cache after the specified state file has been written to,
+/// so that subsequent reads of the same key are served from memory (write-through).
+fn cache_write(path: &Path, value: &str) {
+    let mut cache = READ_CACHE.lock().unwrap();
+    match state_stamp(path) {
+        Ok(stamp) => {
+            let cached = CachedValue {
+                stamp,
+                value: value.to_string(),
+            };
+            cache.insert(path.to_path_buf(), cached);
+        }
+        Err(_) => {
+            cache.remove(path);
+        }
+    }
+}
+
+/// An exclusive lock over the state directory, released when dropped. Backed by a locked file,
+/// or by a process-local mutex in memory mode.
+struct StateDirLock {
+    _file: Option<File>,
+    _guard: Option<MutexGuard<'static, ()>>,
+}
+
+/// Acquires an exclusive, cross-process lock over the state directory. The lock is held until
+/// the returned [`StateDirLock`] is dropped.
+///
+/// The current generation is resolved before the lock is taken, since resolving it may itself
+/// need the lock and locks are not re-entrant.
+fn lock_state_dir() -> Result<StateDirLock> {
+    lazy_static::initialize(&GENERATION);
+    acquire_state_dir_lock()
+}
+
+/// Acquires the lock described in [`lock_state_dir`] without first resolving the current
+/// generation. Only used while resolving the generation itself.
+fn acquire_state_dir_lock() -> Result<StateDirLock> {
+    if memory_mode() {
+        return Ok(StateDirLock {
+            _file: None,
+            _guard: Some(memory_lock()),
+        });
+    }
+    // failing to even create the lock file means the state directory itself is unusable
+    let unavailable = |source| {
+        Error::from(MacroStateError::StateDirUnavailable {
+            path: state_dir().to_path_buf(),
+            source,
+        })
+    };
+    fs::create_dir_all(state_dir()).map_err(unavailable)?;
+    let mut path = state_dir().to_path_buf();
+    path.push("macro_state.lock");
+    let file = retry_io(|| {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+    })
+    .map_err(unavailable)?;
+    file.lock()?;
+    Ok(StateDirLock {
+        _file: Some(file),
+        _guard: None,
+    })
+}
+
+/// The character marking the start of every framed record (see [`frame_record`]).
+const RECORD_START: char = '\u{1e}';
+
+/// Frames the specified list `item` as a self-delimiting record: [`RECORD_START`], followed by
+/// the length of `item` in bytes, the sort `priority` of the item (if any) prefixed by `p`, a
+/// `#` and the checksum of `item` (see [`record_checksum`]), a `:`, `item` itself, and a
+/// trailing newline. Since the length delimits the item, it is stored verbatim, so items can
+/// hold any data whatsoever, including newlines and backslashes.
+///
+/// Each record is appended via a single write. If an appender is interrupted mid-write, the
+/// length and checksum let readers detect the torn record and skip it, rather than merging it
+/// with the record appended after it (even if the length of the torn record happens to land on
+/// a newline within the next one).
+fn frame_record(item: &str, priority: Option<i64>) -> String {
+    let checksum = record_checksum(item);
+    match priority {
+        Some(priority) => format!(
+            "{}{}p{}#{:08x}:{}\n",
+            RECORD_START,
+            item.len(),
+            priority,
+            checksum,
+            item
+        ),
+        None => format!("{}{}#{:08x}:{}\n", RECORD_START, item.len(), checksum, item),
+    }
+}
+
+/// Returns the checksum stored in the header of every record framing `item`: the lower 32 bits
+/// of its [`stable_hash`].
+fn record_checksum(item: &str) -> u32 {
+    stable_hash(item) as u32
+}
+
+/// Encodes a `value` that is written as a whole (rather than appended to a list) as the
+/// contents of its state file. Values are stored as is, unless they contain a [`RECORD_START`]
+/// that would otherwise be parsed as the start of a record when the value is read back. Those
+/// are framed as a single verbatim record instead, marked by a `v` in place of a priority and
+/// lacking the trailing newline of list items, which [`parse_records`] returns as plain text.
+fn encode_value(value: &str) -> String {
+    match value.contains(RECORD_START) {
+        true => format!(
+            "{}{}v#{:08x}:{}",
+            RECORD_START,
+            value.len(),
+            record_checksum(value),
+            value
+        ),
+        false => value.to_string(),
+    }
+}
+
+/// A segment of the contents of a state file, as split up by [`parse_records`].
+enum Segment<'a> {
+    /// Text outside of any record, such as the items of a list appended to by an older version
+    /// of `macro_state` (see [`decode_list_item`]), or a value that was written rather than
+    /// appended (including one framed as a verbatim record by [`encode_value`]).
+    Text(&'a str),
+    /// A single framed record (see [`frame_record`]).
+    Record { item: &'a str, priority: i64 },
+}
+
+/// The outcome of parsing whatever follows a [`RECORD_START`].
+enum ParsedRecord<'a> {
+    /// A complete record, followed by `rest`. Verbatim records hold a value written as a whole
+    /// (see [`encode_value`]) rather than a list item.
+    Complete {
+        item: &'a str,
+        priority: i64,
+        verbatim: bool,
+        rest: &'a str,
+    },
+    /// A record torn by an interrupted appender.
+    Torn,
+    /// Not a record at all, so the [`RECORD_START`] is simply part of a value.
+    Invalid,
+}
+
+/// Parses the record whose [`RECORD_START`] immediately precedes `contents`.
+fn parse_record(contents: &str) -> ParsedRecord<'_> {
+    let digits = |text: &str, radix: u32| {
+        text.len() - text.trim_start_matches(|c: char| c.is_digit(radix)).len()
+    };
+    let len_end = digits(contents, 10);
+    let mut header_end = len_end;
+    if contents[len_end..].starts_with('p') {
+        let sign = usize::from(contents[len_end + 1..].starts_with('-'));
+        header_end = len_end + 1 + sign + digits(&contents[len_end + 1 + sign..], 10);
+    } else if contents[len_end..].starts_with('v') {
+        header_end = len_end + 1;
+    }
+    let checksum_start = header_end;
+    if contents[header_end..].starts_with('#') {
+        header_end += 1 + digits(&contents[header_end + 1..], 16);
+    }
+    let rest = &contents[header_end..];
+    let Some(body) = rest.strip_prefix(':') else {
+        // a header cut short by the end of the file (or by the next record) was torn
+        return match rest.is_empty() || rest.starts_with(RECORD_START) {
+            true => ParsedRecord::Torn,
+            false => ParsedRecord::Invalid,
+        };
+    };
+    let Ok(len) = contents[..len_end].parse::<usize>() else {
+        return ParsedRecord::Invalid;
+    };
+    let (priority, verbatim) = match &contents[len_end..checksum_start] {
+        "v" => (0, true),
+        header => match header.strip_prefix('p').map(str::parse::<i64>) {
+            Some(Ok(priority)) => (priority, false),
+            Some(Err(_)) => return ParsedRecord::Invalid,
+            None => (0, false),
+        },
+    };
+    let checksum = match contents[checksum_start..header_end].strip_prefix('#') {
+        Some(checksum) => match u32::from_str_radix(checksum, 16) {
+            Ok(checksum) => Some(checksum),
+            Err(_) => return ParsedRecord::Invalid,
+        },
+        None => None,
+    };
+    let Some(item) = body.get(..len) else {
+        return ParsedRecord::Torn;
+    };
+    if checksum.is_some_and(|checksum| checksum != record_checksum(item)) {
+        return ParsedRecord::Torn;
+    }
+    let rest = match verbatim {
+        true => Some(&body[len..]),
+        false => body[len..].strip_prefix('\n'),
+    };
+    match rest {
+        Some(rest) => ParsedRecord::Complete {
+            item,
+            priority,
+            verbatim,
+            rest,
+        },
+        None => ParsedRecord::Torn,
+    }
+}
+
+/// Splits the contents of a state file into its framed records and the text between them,
+/// dropping any torn records.
+fn parse_records(contents: &str) -> Vec<Segment<'_>> {
+    if !contents.contains(RECORD_START) {
+        return vec![Segment::Text(contents)];
+    }
+    let mut segments = Vec::new();
+    let mut text_start = 0;
+    let mut search = 0;
+    while let Some(offset) = contents[search..].find(RECORD_START) {
+        let start = search + offset;
+        let header = start + RECORD_START.len_utf8();
+        let parsed = parse_record(&contents[header..]);
+        if let ParsedRecord::Invalid = parsed {
+            search = header;
+            continue;
+        }
+        if start > text_start {
+            segments.push(Segment::Text(&contents[text_start..start]));
+        }
+        text_start = match parsed {
+            ParsedRecord::Complete {
+                item,
+                priority,
+                verbatim,
+                rest,
+            } => {
+                segments.push(match verbatim {
+                    true => Segment::Text(item),
+                    false => Segment::Record { item, priority },
+                });
+                contents.len() - rest.len()
+            }
+            _ => contents[header..]
+                .find(RECORD_START)
+                .map_or(contents.len(), |i| header + i),
+        };
+        search = text_start;
+    }
+    if text_start < contents.len() {
+        segments.push(Segment::Text(&contents[text_start..]));
+    }
+    segments
+}
+
+/// Renders the contents of a state file as the value [`proc_read_state`] returns for it: the
+/// items of framed records (see [`frame_record`]) are listed one per line, while everything
+/// else is kept as is. Lists made up only of records are listed in the order given by
+/// [`canonical_list`].
+fn render_records(contents: String) -> String {
+    if !contents.contains(RECORD_START) {
+        return contents;
+    }
+    let mut rendered = String::with_capacity(contents.len());
+    let mut segments = parse_records(&contents);
+    if deterministic_mode() {
+        canonicalize_records(&mut segments);
+    }
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => rendered.push_str(text),
+            Segment::Record { item, .. } => {
+                rendered.push_str(item);
+                rendered.push('\n');
+            }
+        }
+    }
+    rendered
+}
+
+/// Sorts `segments` by priority and then by item if they are all records, in the same way as
+/// [`canonical_list`].
+fn canonicalize_records(segments: &mut [Segment]) {
+    let mut records = Vec::with_capacity(segments.len());
+    for segment in segments.iter() {
+        match segment {
+            Segment::Record { item, priority } => records.push((*priority, *item)),
+            Segment::Text(_) => return,
+        }
+    }
+    records.sort();
+    for (segment, (priority, item)) in segments.iter_mut().zip(records) {
+        *segment = Segment::Record { item, priority };
+    }
+}
+
+/// Renders the contents of a state file in the line-based format used by older versions of
+/// `macro_state`, for values leaving the state directory (exported files and remote backends),
+/// which are read line by line. Unlike [`render_records`], the items of framed records are
+/// escaped (see [`decode_list_item`]) and keep their priorities, so that lists holding
+/// multi-line items survive the round trip.
+fn flatten_records(contents: String) -> String {
+    if !contents.contains(RECORD_START) {
+        return contents;
+    }
+    let mut flattened = String::with_capacity(contents.len());
+    for segment in parse_records(&contents) {
+        match segment {
+            Segment::Text(text) => flattened.push_str(text),
+            Segment::Record { item, priority } => {
+                if priority != 0 {
+                    flattened.push_str(&format!("\\p{}:", priority));
+                }
+                flattened.push_str(&item.replace('\\', "\\\\").replace('\n', "\\n"));
+                flattened.push('\n');
+            }
+        }
+    }
+    flattened
+}
+
+/// Reads the raw contents of the specified state file, resolving blobs.
+fn read_state_contents(path: &Path) -> Result<String> {
+    resolve_blob(read_file(path)?)
+}
+
+/// Encodes `value` as a single list item, framed as a record (see [`frame_record`]).
+fn encode_list_item(value: &str) -> String {
+    frame_record(value, None)
+}
+
+/// Reverses the escaping applied to the items of lists appended to by older versions of
+/// `macro_state`, which stored each item on a line of its own, escaping newlines as `\n` and
+/// backslashes as `\\`. Those files are still read, but since a value written as a whole may
+/// legitimately contain a backslash followed by `n`, the escaping was ambiguous.
+fn decode_list_item(item: &str) -> String {
+    let mut decoded = String::with_capacity(item.len());
+    let mut chars = item.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => decoded.push('\n'),
+            Some('\\') => decoded.push('\\'),
+            Some(other) => {
+                decoded.push('\\');
+                decoded.push(other);
+            }
+            None => decoded.push('\\'),
+        }
+    }
+    decoded
+}
+
+/// Encodes `value` as a single list item carrying the specified sort `priority`, framed as a
+/// record (see [`frame_record`]).
+fn encode_sorted_list_item(value: &str, priority: i64) -> String {
+    frame_record(value, Some(priority))
+}
+
+/// Splits the sort priority off of a single list item written by an older version of
+/// `macro_state`, which prefixed it with `\p<priority>:`. Items appended without a priority
+/// have a priority of `0`.
+fn split_list_item_priority(item: &str) -> (i64, &str) {
+    item.strip_prefix("\\p")
+        .and_then(|rest| rest.split_once(':'))
+        .and_then(|(priority, item)| Some((priority.parse().ok()?, item)))
+        .unwrap_or((0, item))
+}
+
+/// Encodes the `columns` of a row as a single list item, prefixing each column with `\u{1f}`
+/// and its length so that columns may contain anything, including the separator itself.
+fn encode_state_row<S: AsRef<str>>(columns: &[S]) -> String {
+    columns
+        .iter()
+        .map(|column| format!("\u{1f}{}:{}", column.as_ref().len(), column.as_ref()))
+        .collect()
+}
+
+/// Decodes a list item written by [`encode_state_row`] back into its columns. Items that
+/// aren't encoded rows (such as ones appended via [`proc_append_state`]) are returned as a row
+/// with a single column.
+fn decode_state_row(item: &str) -> Vec<String> {
+    let mut columns = Vec::new();
+    let mut rest = item;
+    while let Some(column) = rest.strip_prefix('\u{1f}') {
+        let Some((len, column)) = column.split_once(':') else {
+            return vec![item.to_string()];
+        };
+        let len = len.parse::<usize>().ok();
+        let Some(value) = len.and_then(|len| column.get(..len)) else {
+            return vec![item.to_string()];
+        };
+        columns.push(value.to_string());
+        rest = &column[value.len()..];
+    }
+    match rest.is_empty() && !item.is_empty() {
+        true => columns,
+        false => vec![item.to_string()],
+    }
+}
+
+/// Decodes the raw contents of a list's state file, as written by [`encode_list_item`] and
+/// [`encode_sorted_list_item`]. Text outside of any record (including the entire contents of
+/// lists written by older versions of `macro_state`) is split into newline-delimited items. If
+/// any item carries a priority, the list is stably sorted by ascending priority.
+fn decode_list(value: String) -> Vec<String> {
+    decode_prioritized_list(value)
+        .into_iter()
+        .map(|(_, item)| item)
+        .collect()
+}
+
+/// Like [`decode_list`], but keeps the sort priority of each item (`0` for items appended
+/// without one), so that lists rewritten in place (see [`encode_prioritized_list`]) keep them.
+fn decode_prioritized_list(value: String) -> Vec<(i64, String)> {
+    let mut items = Vec::new();
+    for segment in parse_records(&value) {
+        match segment {
+            Segment::Record { item, priority } => items.push((priority, item.to_string())),
+            Segment::Text(text) => items.extend(
+                text.strip_suffix('\n')
+                    .unwrap_or(text)
+                    .split('\n')
+                    .map(split_list_item_priority)
+                    .map(|(priority, item)| (priority, decode_list_item(item))),
+            ),
+        }
+    }
+    items.sort_by_key(|(priority, _)| *priority);
+    items
+}
+
+/// Encodes `items` (as returned by [`decode_prioritized_list`]) as the contents of a list's
+/// state file, framing items with a nonzero priority via [`encode_sorted_list_item`].
+fn encode_prioritized_list(items: &[(i64, String)]) -> String {
+    items
+        .iter()
+        .map(|(priority, item)| match priority {
+            0 => encode_list_item(item),
+            priority => encode_sorted_list_item(item, *priority),
+        })
+        .collect()
+}
+
+/// Removes the specified state file from the process-local read cache.
+fn cache_invalidate(path: &Path) {
+    READ_CACHE.lock().unwrap().remove(path);
+}
+
+/// Returns `true` if read-before-write diagnostics have been requested by setting the
+/// `MACRO_STATE_DIAGNOSE_ORDER` environment variable to `1`, `true`, or `yes`.
+fn diagnose_order() -> bool {
+    setting_enabled("diagnose_order")
+}
+
+/// Returns the internal key under which the reads of `key` that found no value are recorded.
+fn missed_reads_key(key: &str) -> String {
+    format!("__macro_state/missed_reads/{}", key)
+}
+
+/// Describes the location of the caller as the crate currently being compiled, followed by the
+/// source location of the call (see [`caller_source`]).
+#[track_caller]
+fn caller_location() -> String {
+    let (file, line) = caller_source(Location::caller());
+    format!("{} ({}:{})", current_crate_name(), file, line)
+}
+
+/// Records that a read of `key` made at `location` found no value.
+fn record_missed_read(key: &str, location: &str) -> Result<()> {
+    let state_file = state_file_path(&missed_reads_key(key));
+    append_state_file(&state_file, &encode_list_item(location), None)
+}
+
+/// Removes and returns the locations of every read of `key` that found no value.
+fn take_missed_reads(key: &str) -> Result<Vec<String>> {
+    let state_file = state_file_path(&missed_reads_key(key));
+    let value = match read_state_contents(&state_file) {
+        Ok(value) => value,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    remove_state_file(&state_file, None)?;
+    Ok(decode_list(value))
+}
+
+/// Formats the warning emitted when `key` is written by `writer` after it was already read by
+/// each of the `readers`.
+fn missed_read_warning(key: &str, readers: &[String], writer: &str) -> String {
+    format!(
+        "warning: macro_state: state key \"{}\" was read by {} before it was written by {}, so \
+        the reader saw no value. Make sure the writing macro expands before the reading one.",
+        key,
+        readers.join(", "),
+        writer
+    )
+}
+
+/// If read-before-write diagnostics are enabled, records that the caller's read of `key`
+/// found no value.
+#[track_caller]
+fn note_missed_read(key: &str) {
+    if diagnose_order() {
+        let _ = record_missed_read(key, &caller_location());
+    }
+}
+
+/// If read-before-write diagnostics are enabled, emits a warning listing every earlier read of
+/// `key` that found no value, now that the caller has written it.
+#[track_caller]
+fn report_missed_reads(key: &str) {
+    if !diagnose_order() {
+        return;
+    }
+    if let Ok(readers) = take_missed_reads(key) {
+        if !readers.is_empty() {
+            eprintln!("{}", missed_read_warning(key, &readers, &caller_location()));
+        }
+    }
+}
+
+/// Returns `true` if divergence detection has been requested via the `detect_divergence`
+/// setting. Only state stored on disk outlives a build, so it never applies in memory or
+/// sandbox mode.
+fn detect_divergence() -> bool {
+    setting_enabled("detect_divergence") && !memory_mode() && !sandbox_mode()
+}
+
+/// Returns the value the specified state file held in the most recent earlier generation, if
+/// any.
+fn previous_generation_value(path: &Path) -> Option<String> {
+    let current = generation();
+    let name = path.file_name()?.to_str()?;
+    let prefix = name.strip_suffix(current.to_string().as_str())?;
+    let (_, previous) = list_dir(path.parent()?)
+        .ok()?
+        .into_iter()
+        .filter_map(|(file, _)| {
+            let suffix = file.file_name()?.to_str()?.strip_prefix(prefix)?;
+            let generation = suffix.parse::<u128>().ok()?;
+            (generation < current).then_some((generation, file))
+        })
+        .max()?;
+    read_state_contents(&previous).ok()
+}
+
+/// Formats the warning emitted when `writer` writes a `value` for `key` that differs from the
+/// `previous` value written by the previous build, or returns [`None`] if they are identical.
+fn divergence_warning(key: &str, previous: &str, value: &str, writer: &str) -> Option<String> {
+    if previous == value {
+        return None;
+    }
+    let mut previous_lines: Vec<&str> = previous.lines().collect();
+    let mut lines: Vec<&str> = value.lines().collect();
+    previous_lines.sort_unstable();
+    lines.sort_unstable();
+    let reason = match previous_lines == lines {
+        true => {
+            "holds the same lines in a different order, which usually means it was \
+            generated by iterating over a collection with a nondeterministic order (such as a \
+            `HashMap`)"
+        }
+        false => {
+            "holds a different value, so unless its inputs changed since, the writing macro \
+            is nondeterministic"
+        }
+    };
+    Some(format!(
+        "warning: macro_state: the value {} wrote to state key \"{}\" diverges from the one \
+        written by the previous build: it {}. Generated code depending on this key is unstable \
+        across builds.",
+        writer, key, reason
+    ))
+}
+
+/// If divergence detection is enabled, warns if `value` differs from the value the previous
+/// build wrote to the specified state file of `key`.
+#[track_caller]
+fn report_divergence(path: &Path, key: &str, value: &str) {
+    if !detect_divergence() {
+        return;
+    }
+    if let Some(previous) = previous_generation_value(path) {
+        if let Some(warning) = divergence_warning(key, &previous, value, &caller_location()) {
+            eprintln!("{}", warning);
+        }
+    }
+}
+
+/// An analogue for [`write_state!`] that should only be used within proc macros.
+///
+/// Writes the specified `value` as the state for the specified state `key`. `macro_state`
+/// itself functions as a compile-time key-value store, and this is how you write a value to a
+/// specific key.
+///
+/// If any IO error occurs while attempting to write to the specified state key, the IO error
+/// will be be returned as the [`Err`] result.
+///
+/// Calling [`proc_read_state`] with the same key that was written to in a [`proc_write_state`]
+/// call should result in a string literal that matches what was written via
+/// [`proc_write_state`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("my key", "some value").unwrap();
+/// assert_eq!(proc_read_state("my key").unwrap(), "some value");
+/// ```
+#[track_caller]
+pub fn proc_write_state(key: &str, value: &str) -> StateResult<()> {
+    check_write(key, value, false)?;
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    let contents = encode_value(value);
+    write_state_value(&state_file, &contents, Some(Location::caller()))?;
+    cache_write(&state_file, &contents);
+    report_missed_reads(key);
+    report_divergence(&state_file, key, value);
+    store_remote_state(key)
+}
+
+/// An analogue for [`read_state!`] that should only be used within proc macros.
+///
+/// Reads the state value for the specified `key`. Since `macro_state` functions as a
+/// compile-time key-value store, [`proc_read_state`] attempts to read the state value
+/// associaed with the specified key.
+///
+/// The macro will expand into a string literal representing the state value in the event that
+/// a value exists for the provided key.
+///
+/// If no value can be found for the provided key (or in the event of any sort of IO error),
+/// the macro will raise a compile-time IO error.
+///
+/// Values are kept in a process-local cache that is validated against the modification time
+/// and length of the underlying state file, so repeated reads of the same key within a proc
+/// macro only hit the filesystem for a cheap metadata check.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("cool key", "something").unwrap();
+/// assert_eq!(proc_read_state("cool key").unwrap(), "something");
+/// let result = proc_read_state("undefined key");
+/// assert!(matches!(result, Err(_)));
+/// ```
+#[track_caller]
+pub fn proc_read_state(key: &str) -> StateResult<String> {
+    read_state_handled(key).map(render_records)
+}
+
+/// Reads the raw contents of the state file for the specified `key` (see [`render_records`]),
+/// consulting the missing-key handler if it does not exist.
+#[track_caller]
+fn read_state_handled(key: &str) -> StateResult<String> {
+    check_key(key, false)?;
+    match read_state_value(key) {
+        Ok(value) => Ok(value),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            if let Some(value) = fetch_remote_state(key)? {
+                return Ok(value);
+            }
+            note_missed_read(key);
+            let e = MacroStateError::for_key(key, e);
+            let handler = MISSING_KEY_HANDLER.lock().unwrap().clone();
+            match handler {
+                Some(handler) => handler(key, e),
+                None => Err(e),
+            }
+        }
+        Err(e) => Err(MacroStateError::for_key(key, e)),
+    }
+}
+
+/// The type of the handlers that can be installed via [`proc_set_missing_key_handler`].
+pub type MissingKeyHandler = dyn Fn(&str, MacroStateError) -> StateResult<String> + Send + Sync;
+
+/// Installs a process-local `handler` that is consulted whenever [`proc_read_state`] (or
+/// [`proc_read_state_vec`]) finds no value for a key, replacing any previously installed
+/// handler.
+///
+/// The handler receives the key along with the original [`MacroStateError::KeyNotFound`]
+/// error, and
+/// can either supply a default value by returning [`Ok`], or return an [`Err`] of its own,
+/// such as a custom diagnostic explaining which macro should have produced the key. Returning
+/// the original error unchanged preserves the default behavior. This lets proc macro authors
+/// centralize their handling of missing state, rather than matching on the result of every
+/// read.
+///
+/// Since the handler applies to every read made by the current process, it is best installed
+/// once, at the start of each proc macro.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+/// use std::io::{Error, ErrorKind};
+///
+/// proc_set_missing_key_handler(|key, e| match key.strip_prefix("config/") {
+///     Some(_) => Ok(String::from("default")),
+///     None => Err(Error::new(
+///         ErrorKind::NotFound,
+///         format!("{} (is the producing macro invoked first?)", e),
+///     )
+///     .into()),
+/// });
+/// assert_eq!(proc_read_state("config/mode").unwrap(), "default");
+/// assert!(proc_read_state("models")
+///     .unwrap_err()
+///     .to_string()
+///     .contains("is the producing macro invoked first?"));
+/// proc_clear_missing_key_handler();
+/// assert!(proc_read_state("config/mode").is_err());
+/// ```
+pub fn proc_set_missing_key_handler<F>(handler: F)
+where
+    F: Fn(&str, MacroStateError) -> StateResult<String> + Send + Sync + 'static,
+{
+    *MISSING_KEY_HANDLER.lock().unwrap() = Some(Arc::new(handler));
+}
+
+/// Removes the handler installed via [`proc_set_missing_key_handler`], if any, restoring the
+/// default behavior of returning a [`MacroStateError::KeyNotFound`] error for missing keys.
+pub fn proc_clear_missing_key_handler() {
+    *MISSING_KEY_HANDLER.lock().unwrap() = None;
+}
+
+/// Reads the state value for the specified `key`, without taking part in read-before-write
+/// diagnostics, recording the read if reads are being recorded.
+fn read_state_value(key: &str) -> Result<String> {
+    let value = cached_read(&state_file_path(key));
+    note_read(key, value.as_deref().ok());
+    value
+}
+
+/// An analogue for [`has_state!`] that should only be used within proc macros.
+///
+/// Checks if an existing state value can be found for the specified `key`.
+///
+/// Note that this function is infallible -- it should never panic and will always return
+/// `true` or `false`.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("my key", "hey").unwrap();
+/// assert_eq!(proc_has_state("my key"), true);
+/// assert_eq!(proc_has_state("unknown key"), false);
+/// ```
+///
+/// Internally this function simply reads the state value, returning `false` in the event of an
+/// IO error. Unlike [`proc_read_state`], a missing value is never reported by read-before-write
+/// diagnostics, since checking for a value is a legitimate thing to do before it is written.
+pub fn proc_has_state(key: &str) -> bool {
+    read_state_value(key).is_ok()
+}
+
+/// An analogue for [`clear_state!`] that should only be used within proc macros.
+///
+/// Clears the value for the specified `key`, if it exists.
+///
+/// If an error occurs while trying to clear the state file for the specified key, the error
+/// will be returned as the [`Err`] variant of the [`std::io::Result`].
+///
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("my key", "test").unwrap();
+/// assert_eq!(proc_read_state("my key").unwrap(), "test");
+/// proc_clear_state("my key").unwrap();
+/// assert_eq!(proc_has_state("my key"), false);
+/// ```
+#[track_caller]
+pub fn proc_clear_state(key: &str) -> StateResult<()> {
+    check_key(key, true)?;
+    let state_file = state_file_path(key);
+    if proc_has_state(key) {
+        remove_state_file(&state_file, Some(Location::caller()))?;
+    }
+    cache_invalidate(&state_file);
+    Ok(())
+}
+
+/// Clears every key whose state file is owned by the crate named `crate_name` (that is, every
+/// key that crate wrote first), in all generations. Should only be used within proc macros.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_clear_crate_state("my_dependency").unwrap();
+/// ```
+pub fn proc_clear_crate_state(crate_name: &str) -> StateResult<()> {
+    let _lock = lock_state_dir()?;
+    let dir = crate_state_dir(crate_name);
+    READ_CACHE
+        .lock()
+        .unwrap()
+        .retain(|path, _| !path.starts_with(&dir));
+    let mut files = Vec::new();
+    let _ = collect_state_files(&dir, &mut files);
+    for file in &files {
+        check_file_write(file)?;
+    }
+    match remove_dir_all(&dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => {
+            let name = dir.file_name().unwrap_or_default().to_string_lossy();
+            release_state_files(&name, &files);
+            for file in files {
+                note_change(&file, StateChangeOp::Remove, "", None);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// An analogue for [`clear_state!`] that should only be used within proc macros.
+///
+/// Returns the value for the specified `key`, if it exists. If it does not exist, the key is
+/// created and set to the specified `default_value`, and then the `default_value` is returned.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("my key", "A").unwrap();
+/// assert_eq!(proc_init_state("my key", "B").unwrap(), "A");
+/// assert_eq!(proc_init_state("other key", "B").unwrap(), "B");
+/// ```
+#[track_caller]
+pub fn proc_init_state(key: &str, default_value: &str) -> StateResult<String> {
+    match read_state_value(key) {
+        Ok(existing) => Ok(render_records(existing)),
+        Err(_) => match proc_write_state(key, default_value) {
+            Ok(_) => Ok(String::from(default_value)),
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// An analogue for [`append_state!`] that should only be used within proc macros.
+///
+/// Like [`proc_write_state`], but instead appends the specified `value` to the state file as a
+/// length-prefixed record. The `value` is stored verbatim and may contain anything, including
+/// newlines, so you can think of this as appending to a [`Vec<String>`] for all intents and
+/// purposes. Calling
+/// [`proc_append_state`] is also more efficient than re-writing an entire state file via
+/// [`proc_write_state`] since the low level append IO option is not used by
+/// [`proc_write_state`].
+///
+/// If no state file for the specified `key` exists, it will be created automatically. In this
+/// way, [`proc_append_state`] functions similar to how [`proc_init_state`] functions,
+/// especially in the no-existing-file case.
+///
+/// Note that if [`proc_read_state`] is called on a [`proc_append_state`]-based state file,
+/// newlines will be returned in the response.
+///
+/// # Examples
+///
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state("my_key", "apples");
+/// proc_append_state("my_key", "pears");
+/// proc_append_state("my_key", "oh my!");
+/// assert_eq!(proc_read_state("my_key").unwrap(), "apples\npears\noh my!\n");
+/// assert_eq!(proc_read_state_vec("my_key"), vec!["apples", "pears", "oh my!"]);
+/// ```
+#[track_caller]
+pub fn proc_append_state(key: &str, value: &str) -> StateResult<()> {
+    check_write(key, value, true)?;
+    append_state_item(key, value, Some(Location::caller()))?;
+    report_missed_reads(key);
+    store_remote_state(key)
+}
+
+/// Appends `value` to the list stored for `key` without validating either, for use with
+/// internal keys.
+fn append_state_item(key: &str, value: &str, caller: Caller) -> Result<()> {
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    cache_invalidate(&state_file);
+    append_state_file(&state_file, &encode_list_item(value), caller)
+}
+
+/// An analogue for [`extend_state!`] that should only be used within proc macros.
+///
+/// Like [`proc_append_state`], but appends all of the specified `values` (in order) while
+/// opening the state file only once, which is considerably cheaper than calling
+/// [`proc_append_state`] once per item.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_extend_state("my_list", &["apples", "pears"]).unwrap();
+/// proc_extend_state("my_list", &["oh my!"]).unwrap();
+/// assert_eq!(proc_read_state_vec("my_list"), vec!["apples", "pears", "oh my!"]);
+/// ```
+#[track_caller]
+pub fn proc_extend_state(key: &str, values: &[&str]) -> StateResult<()> {
+    for value in values {
+        check_write(key, value, true)?;
+    }
+    let value: String = values.iter().map(|value| encode_list_item(value)).collect();
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    cache_invalidate(&state_file);
+    Ok(append_state_file(
+        &state_file,
+        &value,
+        Some(Location::caller()),
+    )?)
+}
+
+/// An analogue for [`append_state_sorted!`] that should only be used within proc macros.
+///
+/// Like [`proc_append_state`], but stores a sort `priority` along with the `value`. Whenever
+/// the list is read via [`proc_read_state_vec`], its items are returned in ascending priority
+/// order (items appended without a priority count as priority `0`), with ties kept in the
+/// order they were appended. This allows registries to have a deterministic, user-controlled
+/// order that does not depend on the order in which macros happen to be expanded.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state_sorted("my handlers", "fallback", 100).unwrap();
+/// proc_append_state_sorted("my handlers", "auth", -10).unwrap();
+/// proc_append_state("my handlers", "logging").unwrap();
+/// assert_eq!(
+///     proc_read_state_vec("my handlers"),
+///     vec!["auth", "logging", "fallback"]
+/// );
+/// ```
+#[track_caller]
+pub fn proc_append_state_sorted(key: &str, value: &str, priority: i64) -> StateResult<()> {
+    check_write(key, value, true)?;
+    let value = encode_sorted_list_item(value, priority);
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    cache_invalidate(&state_file);
+    Ok(append_state_file(
+        &state_file,
+        &value,
+        Some(Location::caller()),
+    )?)
+}
+
+/// An analogue for [`append_state_row!`] that should only be used within proc macros.
+///
+/// Like [`proc_append_state`], but appends a row made up of several `columns`, which is read
+/// back as a whole via [`proc_read_state_rows`]. Each column is stored verbatim, so there is
+/// no need to join columns with (and escape) an ad-hoc separator.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state_row("my routes", &["users", "/users", "10"]).unwrap();
+/// proc_append_state_row("my routes", &["a|b", "/a,b", "20"]).unwrap();
+/// assert_eq!(
+///     proc_read_state_rows("my routes"),
+///     vec![vec!["users", "/users", "10"], vec!["a|b", "/a,b", "20"]]
+/// );
+/// ```
+#[track_caller]
+pub fn proc_append_state_row<S: AsRef<str>>(key: &str, columns: &[S]) -> StateResult<()> {
+    proc_append_state(key, &encode_state_row(columns))
+}
+
+/// An analogue for [`append_state_record!`] that should only be used within proc macros.
+///
+/// Like [`proc_append_state_row`], but appends a record made up of named `fields`, which is
+/// read back via [`proc_read_state_records`]. The value of each field is the Rust source of
+/// an expression (usually a literal, such as `"GET"` or `10`), so that
+/// [`read_state_records!`] can expand records into struct literals.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state_record("my endpoints", &[("method", "\"GET\""), ("weight", "10")]).unwrap();
+/// assert_eq!(
+///     proc_read_state_records("my endpoints"),
+///     vec![vec![
+///         (String::from("method"), String::from("\"GET\"")),
+///         (String::from("weight"), String::from("10")),
+///     ]]
+/// );
+/// ```
+#[track_caller]
+pub fn proc_append_state_record(key: &str, fields: &[(&str, &str)]) -> StateResult<()> {
+    let columns: Vec<&str> = fields
+        .iter()
+        .flat_map(|(name, value)| [*name, *value])
+        .collect();
+    proc_append_state_row(key, &columns)
+}
+
+/// Returns the source file and line of `caller`. Within a proc macro, that is the location of
+/// the macro invocation being expanded instead, so that every invocation is told apart even
+/// when they all reach this crate from the same line of the proc macro.
+fn caller_source(caller: &Location) -> (String, usize) {
+    match proc_macro::is_available() {
+        true => {
+            let span = proc_macro::Span::call_site();
+            (span.file(), span.line())
+        }
+        false => (caller.file().to_string(), caller.line() as usize),
+    }
+}
+
+/// Returns the [`provenance_writer`] of a value appended on behalf of `caller` (see
+/// [`caller_source`]).
+fn unique_value_writer(caller: &Location) -> String {
+    let (file, line) = caller_source(caller);
+    provenance_writer(&current_crate_name(), &file, line)
+}
+
+/// An analogue for [`append_state_unique_or_error!`] that should only be used within proc
+/// macros.
+///
+/// Like [`proc_append_state`], but fails with an [`ErrorKind::AlreadyExists`] error if `value`
+/// is already present in the list stored for `key`, naming whoever appended it first. The
+/// check and the append happen under a single exclusive lock, so two writers can never both
+/// claim the same value. Appending a value again from the same place (such as when a crate is
+/// compiled a second time for its unit tests) does nothing.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state_unique("my discriminants", "0x1F").unwrap();
+/// proc_append_state_unique("my discriminants", "0x20").unwrap();
+/// assert!(proc_append_state_unique("my discriminants", "0x1F").is_err());
+/// assert_eq!(proc_read_state_vec("my discriminants"), vec!["0x1F", "0x20"]);
+/// ```
+#[track_caller]
+pub fn proc_append_state_unique(key: &str, value: &str) -> StateResult<()> {
+    check_write(key, value, true)?;
+    let _lock = lock_state_dir()?;
+    let writer = unique_value_writer(Location::caller());
+    let entries = proc_read_state_vec(&provenance_key(key));
+    let exists = proc_read_state_vec(key)
+        .iter()
+        .any(|existing| existing == value);
+    let Some(provenance) = claim_unique_value(key, value, &writer, &entries, exists)? else {
+        return Ok(());
+    };
+    append_state_item(&provenance_key(key), &provenance, Some(Location::caller()))?;
+    proc_append_state(key, value)
+}
+
+/// An analogue for [`read_state_vec!`] that should only be used within proc macros.
+///
+/// Reads the state value for the specified key and parses it as a [`Vec<String>`] where each
+/// new line is treated as a separate element in the [`Vec`]. Should be used in conjunction
+/// with [`proc_append_state`] to read and write lists of values from macro state storage.
+///
+/// Returns: a [`Vec<String>`]. Throws a compiler error if the specified state key cannot be
+/// found.
+///
+/// Note: If you need to initialize a state vec to an empty list, you can use
+/// `proc_init_state("key", "\n")` which should result in an empty [`Vec<String>`] when the
+/// state file is read by [`proc_read_state_vec`].
+///
+/// Note: This function is infallible -- if any issue occurs trying to read the specified key,
+/// it is assumed that we should return an empty [`Vec`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state("my_key", "first item").unwrap();
+/// proc_append_state("my_key", "2nd item").unwrap();
+/// assert_eq!(proc_read_state_vec("my_key"), vec!["first item", "2nd item"]);
+/// ```
+#[track_caller]
+pub fn proc_read_state_vec(key: &str) -> Vec<String> {
+    match read_state_handled(key) {
+        Ok(value) => canonical_list(decode_prioritized_list(value)),
+        Err(_) => Vec::<String>::new(),
+    }
+}
+
+/// An analogue for [`read_state_rows!`] that should only be used within proc macros.
+///
+/// Reads the rows appended to the list stored for `key` via [`proc_append_state_row`], in the
+/// same order [`proc_read_state_vec`] returns items. Items appended via [`proc_append_state`]
+/// are returned as rows with a single column.
+///
+/// Note: This function is infallible -- if any issue occurs trying to read the specified key,
+/// it is assumed that we should return an empty [`Vec`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state_row("my columns", &["id", "u64"]).unwrap();
+/// proc_append_state_row("my columns", &["name", "String"]).unwrap();
+/// for row in proc_read_state_rows("my columns") {
+///     assert_eq!(row.len(), 2);
+/// }
+/// ```
+#[track_caller]
+pub fn proc_read_state_rows(key: &str) -> Vec<Vec<String>> {
+    proc_read_state_vec(key)
+        .iter()
+        .map(|item| decode_state_row(item))
+        .collect()
+}
+
+/// An analogue for [`read_state_records!`] that should only be used within proc macros.
+///
+/// Reads the records appended to the list stored for `key` via [`proc_append_state_record`]
+/// (or [`append_state_record!`]), each as a list of `(field, value)` pairs in the order the
+/// fields were written, where each value is the Rust source of an expression. Items of the
+/// list that aren't records are skipped.
+///
+/// Note: This function is infallible -- if any issue occurs trying to read the specified key,
+/// it is assumed that we should return an empty [`Vec`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state_record("my models", &[("name", "\"User\"")]).unwrap();
+/// proc_append_state("my models", "not a record").unwrap();
+/// assert_eq!(proc_read_state_records("my models").len(), 1);
+/// ```
+#[track_caller]
+pub fn proc_read_state_records(key: &str) -> Vec<Vec<(String, String)>> {
+    proc_read_state_rows(key)
+        .into_iter()
+        .filter_map(|row| decode_state_record(&row))
+        .collect()
+}
+
+/// Pairs up the columns of a row written by [`proc_append_state_record`] into its fields, or
+/// returns [`None`] if the row isn't a record.
+fn decode_state_record(row: &[String]) -> Option<Vec<(String, String)>> {
+    if row.is_empty()
+        || !row.len().is_multiple_of(2)
+        || row.chunks(2).any(|field| field[0].is_empty())
+    {
+        return None;
+    }
+    let fields = row
+        .chunks(2)
+        .map(|field| (field[0].clone(), field[1].clone()));
+    Some(fields.collect())
+}
+
+/// Calls `f` with the key and value of every key starting with `prefix` that currently has a
+/// value, in lexicographic order of the keys. Keys are found via a single scan of the state
+/// directory, and values are read one at a time (bypassing the process-local read cache), so
+/// consumers of hundreds of registered keys can process them with bounded memory. Keys starting
+/// with [`RESERVED_KEY_PREFIX`] are only visited if `prefix` starts with it too.
+///
+/// Values are passed exactly as [`proc_read_state`] would return them. Keys removed while the
+/// iteration is in progress are skipped, while any other IO error stops the iteration and is
+/// returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("my plugins/alpha", "1").unwrap();
+/// proc_write_state("my plugins/beta", "2").unwrap();
+/// let mut total = 0;
+/// proc_for_each_state("my plugins/", |key, value| {
+///     assert!(key.starts_with("my plugins/"));
+///     total += value.parse::<i32>().unwrap();
+/// })
+/// .unwrap();
+/// assert_eq!(total, 3);
+/// ```
+pub fn proc_for_each_state<F: FnMut(&str, &str)>(prefix: &str, mut f: F) -> StateResult<()> {
+    let _span = trace_call("proc_for_each_state", Some(prefix));
+    let reserved = prefix.starts_with(RESERVED_KEY_PREFIX);
+    for (key, file) in state_files()? {
+        if !key.starts_with(prefix) || (!reserved && key.starts_with(RESERVED_KEY_PREFIX)) {
+            continue;
+        }
+        let contents = {
+            let _guard = lock_state_file(&file);
+            read_state_contents(&file)
+        };
+        let value = match contents {
+            Ok(contents) => render_records(contents),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(MacroStateError::for_key(&key, e)),
+        };
+        note_read(&key, Some(&value));
+        f(&key, &value);
+    }
+    Ok(())
+}
+
+/// Returns the items of a list read by the user (as returned by [`decode_prioritized_list`]).
+/// If [`deterministic_mode`] is enabled, items of equal priority are sorted, since the order in
+/// which parallel builds append to a list is not reproducible, while items of different
+/// priorities keep the order their priorities give them.
+fn canonical_list(mut items: Vec<(i64, String)>) -> Vec<String> {
+    if deterministic_mode() {
+        items.sort();
+    }
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Returns the name of the crate currently being compiled, as reported by cargo, falling back
+/// to `"unknown"` when `macro_state` is used outside of a cargo build.
+fn current_crate_name() -> String {
+    std::env::var("CARGO_PKG_NAME").unwrap_or_else(|_| String::from("unknown"))
+}
+
+/// Returns the path of the internal file that would be used to store the most recently
+/// published value for the specified `channel`. Unlike regular state keys, channel files are
+/// not tied to the current compilation, so they remain visible to every crate that shares the
+/// same build directory.
+fn channel_file_path(channel: &str) -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push("channels");
+    buf.push(format!("macro_state_channel_{}", encode_filename(channel)));
+    buf
+}
+
+/// An analogue for [`publish_state!`] that should only be used within proc macros.
+///
+/// Publishes the specified `value` on the specified `channel`. Channels live in a shared area
+/// of the state directory rather than in the per-compilation key-value store, making them the
+/// sanctioned way for the macros of an upstream crate to hand data to the macros of a
+/// downstream crate. The name of the publishing crate is recorded alongside the value and can
+/// be retrieved via [`proc_state_publisher`].
+///
+/// Publishing to a channel that already has a value replaces that value.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_publish_state("my channel", "some value").unwrap();
+/// assert_eq!(proc_subscribe_state("my channel").unwrap(), "some value");
+/// ```
+pub fn proc_publish_state(channel: &str, value: &str) -> StateResult<()> {
+    let contents = format!("{}\n{}", current_crate_name(), value);
+    Ok(write_state_file(
+        &channel_file_path(channel),
+        &contents,
+        None,
+    )?)
+}
+
+fn read_channel(channel: &str) -> Result<(String, String)> {
+    let contents = read_file(&channel_file_path(channel))?;
+    match contents.split_once('\n') {
+        Some((publisher, value)) => Ok((publisher.to_string(), value.to_string())),
+        None => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("malformed channel file for channel \"{}\"", channel),
+        )),
+    }
+}
+
+/// An analogue for [`subscribe_state!`] that should only be used within proc macros.
+///
+/// Reads the value most recently published on the specified `channel` via
+/// [`proc_publish_state`] (or [`publish_state!`]), regardless of which crate published it.
+///
+/// If nothing has been published on the channel (or in the event of any sort of IO error), the
+/// IO error will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_publish_state("cool channel", "something").unwrap();
+/// assert_eq!(proc_subscribe_state("cool channel").unwrap(), "something");
+/// assert!(proc_subscribe_state("silent channel").is_err());
+/// ```
+pub fn proc_subscribe_state(channel: &str) -> StateResult<String> {
+    Ok(read_channel(channel)?.1)
+}
+
+/// Returns the name of the crate that most recently published a value on the specified
+/// `channel`.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_publish_state("announcements", "hello").unwrap();
+/// assert_eq!(
+///     proc_state_publisher("announcements").unwrap(),
+///     env!("CARGO_PKG_NAME")
+/// );
+/// ```
+pub fn proc_state_publisher(channel: &str) -> StateResult<String> {
+    Ok(read_channel(channel)?.0)
+}
+
+/// Returns the path of the internal file that would be used to store the value of `key` as
+/// exported by the crate `crate_name` for its dependents. Like channel files, export files are
+/// not tied to the current compilation.
+fn export_file_path(crate_name: &str, key: &str) -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push("exports");
+    buf.push(encode_filename(crate_name.replace('-', "_").as_str()));
+    buf.push(format!("macro_state_export_{}", key_filename(key)));
+    buf
+}
+
+/// An analogue for [`export_state_for_dependents!`] that should only be used within proc
+/// macros.
+///
+/// Copies the current value of the specified state `key` to a stable location associated with
+/// the crate currently being compiled, so that the macros of dependent crates can later read
+/// it via [`proc_import_dependency_state`] (or [`import_dependency_state!`]). This allows
+/// registry data collected in a library crate to be consumed by the macros of an application
+/// crate.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("models", "User\nPost").unwrap();
+/// proc_export_state_for_dependents("models").unwrap();
+/// assert_eq!(
+///     proc_import_dependency_state(env!("CARGO_PKG_NAME"), "models").unwrap(),
+///     "User\nPost"
+/// );
+/// ```
+pub fn proc_export_state_for_dependents(key: &str) -> StateResult<()> {
+    let value = read_state_handled(key)?;
+    let export_file = export_file_path(current_crate_name().as_str(), key);
+    Ok(write_state_file(&export_file, &value, None)?)
+}
+
+/// An analogue for [`import_dependency_state!`] that should only be used within proc macros.
+///
+/// Reads the value of `key` as exported by the crate `crate_name` via
+/// [`proc_export_state_for_dependents`] (or [`export_state_for_dependents!`]). Crate names
+/// may be specified with either dashes or underscores.
+///
+/// If the specified crate has not exported `key` (or in the event of any sort of IO error),
+/// the IO error will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert!(proc_import_dependency_state("some_crate", "never exported").is_err());
+/// ```
+pub fn proc_import_dependency_state(crate_name: &str, key: &str) -> StateResult<String> {
+    Ok(render_records(read_file(&export_file_path(
+        crate_name, key,
+    ))?))
+}
+
+/// Returns the internal key under which tokens deferred to `slot` by the crate currently being
+/// compiled are stored.
+fn deferred_tokens_key(slot: &str) -> String {
+    format!("__macro_state/deferred/{}/{}", current_crate_name(), slot)
+}
+
+/// An analogue for [`defer_tokens!`] that should only be used within proc macros.
+///
+/// Defers the specified `tokens` to the specified `slot`, so that they are emitted by a later
+/// [`flush_deferred!`] call for the same slot, rather than by the calling proc macro itself.
+/// Any token stream type can be used, including [`proc_macro::TokenStream`] and
+/// `proc_macro2::TokenStream`. Contributing the exact same tokens to a slot more than once has
+/// no effect.
+///
+/// If an IO error occurs, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_defer_tokens("my slot", &"const A: usize = 1;").unwrap();
+/// proc_defer_tokens("my slot", &"const A: usize = 1;").unwrap();
+/// ```
+pub fn proc_defer_tokens<T: ToString>(slot: &str, tokens: &T) -> StateResult<()> {
+    let key = deferred_tokens_key(slot);
+    let tokens = tokens.to_string();
+    let _lock = lock_state_dir()?;
+    if proc_read_state_vec(&key).contains(&tokens) {
+        return Ok(());
+    }
+    Ok(append_state_item(&key, &tokens, None)?)
+}
+
+/// Stores the specified `tokens` as the state value for the specified `key`, mirroring the
+/// behavior of the [`export_state_tokens`] attribute. Any token stream type can be used,
+/// including [`proc_macro::TokenStream`] and `proc_macro2::TokenStream`.
+///
+/// This makes it possible to combine token exports in the style of `macro_magic` with the
+/// key-value state stored by `macro_state`.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state_tokens("my tokens", &"struct Foo;").unwrap();
+/// assert_eq!(proc_read_state("my tokens").unwrap(), "struct Foo;");
+/// ```
+pub fn proc_write_state_tokens<T: ToString>(key: &str, tokens: &T) -> StateResult<()> {
+    proc_write_state(key, tokens.to_string().as_str())
+}
+
+/// Reads the state value for the specified `key` and parses it into a token stream of the
+/// requested type, mirroring the behavior of [`import_state_tokens!`]. Any type implementing
+/// [`FromStr`](std::str::FromStr) can be requested, including [`proc_macro::TokenStream`] and
+/// `proc_macro2::TokenStream`.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result. If the value cannot be parsed, a
+/// [`MacroStateError::Corrupted`] error will be returned.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state_tokens("numeric tokens", &"1234").unwrap();
+/// let parsed: usize = proc_read_state_tokens("numeric tokens").unwrap();
+/// assert_eq!(parsed, 1234);
+/// ```
+pub fn proc_read_state_tokens<T>(key: &str) -> StateResult<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = proc_read_state(key)?;
+    value.parse::<T>().map_err(|e| MacroStateError::Corrupted {
+        key: key.to_string(),
+        reason: format!("not a valid token stream: {}", e),
+    })
+}
+
+/// An analogue for [`state_mtime!`] that should only be used within proc macros.
+///
+/// Returns the time at which the value for the specified `key` was last written to (or
+/// appended to).
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+/// use std::time::SystemTime;
+///
+/// proc_write_state("timestamped", "value").unwrap();
+/// assert!(proc_state_mtime("timestamped").unwrap() <= SystemTime::now());
+/// ```
+pub fn proc_state_mtime(key: &str) -> StateResult<SystemTime> {
+    file_stamp(&state_file_path(key))
+        .map(|(modified, _)| modified)
+        .map_err(|e| MacroStateError::for_key(key, e))
+}
+
+/// Returns `true` if the value for the specified `key` was written to (or appended to) after
+/// the specified `time`. Useful for implementing cache-invalidation logic on top of state.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+/// use std::time::{Duration, SystemTime};
+///
+/// let before = SystemTime::now() - Duration::from_secs(60);
+/// proc_write_state("fresh key", "value").unwrap();
+/// assert!(proc_state_modified_since("fresh key", before).unwrap());
+/// assert!(!proc_state_modified_since("fresh key", SystemTime::now()).unwrap());
+/// ```
+pub fn proc_state_modified_since(key: &str, time: SystemTime) -> StateResult<bool> {
+    Ok(proc_state_mtime(key)? > time)
+}
+
+/// Metadata about the value stored for a particular state key, as returned by
+/// [`proc_state_metadata`] and [`state_metadata!`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateMetadata {
+    /// The size of the stored value, in bytes, including the framing of any appended list
+    /// items.
+    pub size: u64,
+    /// The time at which the key was first written to.
+    pub created: SystemTime,
+    /// The time at which the key was last written to (or appended to).
+    pub modified: SystemTime,
+    /// The name of the crate that last wrote to the key, if known.
+    pub writer_crate: Option<String>,
+}
+
+/// An analogue for [`state_metadata!`] that should only be used within proc macros.
+///
+/// Returns [`StateMetadata`] describing the value stored for the specified `key` without
+/// reading the value itself. This is useful for diagnostics and for implementing policies
+/// such as quotas, expiry, or provenance checks.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("described key", "12345").unwrap();
+/// let metadata = proc_state_metadata("described key").unwrap();
+/// assert_eq!(metadata.size, 5);
+/// assert_eq!(metadata.writer_crate.unwrap(), env!("CARGO_PKG_NAME"));
+/// assert!(metadata.created <= metadata.modified);
+/// ```
+pub fn proc_state_metadata(key: &str) -> StateResult<StateMetadata> {
+    let state_file = state_file_path(key);
+    let (mut modified, len) = file_stamp(&state_file)?;
+    if deterministic_mode() {
+        modified = UNIX_EPOCH;
+    }
+    let fields = read_metadata_fields(&state_file);
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.clone())
+    };
+    let created = field("created")
+        .and_then(|nanos| nanos.parse::<u64>().ok())
+        .map(|nanos| UNIX_EPOCH + std::time::Duration::from_nanos(nanos))
+        .unwrap_or(modified);
+    Ok(StateMetadata {
+        size: len,
+        created,
+        modified,
+        writer_crate: field("writer"),
+    })
+}
+
+/// A state value together with the metadata needed to validate caches built on top of it and
+/// to trace where it came from, as returned by [`proc_read_state_ext`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateValue {
+    /// The stored value, exactly as [`proc_read_state`] would return it.
+    pub value: String,
+    /// The time at which the key was last written to (or appended to).
+    pub mtime: SystemTime,
+    /// The generation the value belongs to, as returned by [`proc_state_generation`].
+    pub generation: u128,
+    /// The name of the crate that last wrote to the key, if known.
+    pub writer: Option<String>,
+}
+
+/// Like [`proc_read_state`], but returns a [`StateValue`] that bundles the value with its
+/// modification time, generation, and writer, so consumers that need both the value and its
+/// provenance do not have to make separate metadata calls.
+///
+/// The modification time is captured before the value is read, so if the key is written to
+/// concurrently, `mtime` errs on the side of being older than `value`, never newer.
+///
+/// Unlike [`proc_read_state`], the missing-key handler installed via
+/// [`proc_set_missing_key_handler`] is not consulted, since a value supplied by the handler
+/// has no metadata.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("provenance key", "value").unwrap();
+/// let state = proc_read_state_ext("provenance key").unwrap();
+/// assert_eq!(state.value, "value");
+/// assert_eq!(state.writer.as_deref(), Some(env!("CARGO_PKG_NAME")));
+/// assert_eq!(state.generation, proc_state_generation());
+/// ```
+#[track_caller]
+pub fn proc_read_state_ext(key: &str) -> StateResult<StateValue> {
+    let state_file = state_file_path(key);
+    let mtime = match file_stamp(&state_file) {
+        Ok((mtime, _)) => mtime,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                note_missed_read(key);
+            }
+            return Err(MacroStateError::for_key(key, e));
+        }
+    };
+    let value = read_state_value(key).map_err(|e| MacroStateError::for_key(key, e))?;
+    let writer = read_metadata_fields(&state_file)
+        .into_iter()
+        .find(|(name, _)| name == "writer")
+        .map(|(_, writer)| writer);
+    Ok(StateValue {
+        value: render_records(value),
+        mtime,
+        generation: proc_state_generation(),
+        writer,
+    })
+}
+
+/// Returns the sequence number of the most recent write (or append) to the specified `key`.
+///
+/// Every write and append made during a build is stamped with a number that is higher than
+/// that of any write made before it, across every crate and process taking part in the build.
+/// Comparing the sequence numbers of different keys therefore reveals the order in which the
+/// macros that wrote them actually executed, which is invaluable when debugging ordering
+/// issues. Since every write then contends for the same counter, sequence numbers are only
+/// recorded when the `sequence` setting (or the `MACRO_STATE_SEQUENCE` environment variable)
+/// is set to `1`, `true`, or `yes`.
+///
+/// If no value exists for `key`, no sequence number was recorded for it (or in the event of any
+/// sort of IO error), the IO error will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// testing::with_settings(&[("sequence", "1")], || {
+///     proc_write_state("written first", "a").unwrap();
+///     proc_append_state("written second", "b").unwrap();
+/// });
+/// assert!(
+///     proc_state_sequence("written first").unwrap()
+///         < proc_state_sequence("written second").unwrap()
+/// );
+/// ```
+pub fn proc_state_sequence(key: &str) -> StateResult<u64> {
+    let state_file = state_file_path(key);
+    file_stamp(&state_file).map_err(|e| MacroStateError::for_key(key, e))?;
+    read_metadata_fields(&state_file)
+        .into_iter()
+        .find(|(name, _)| name == "sequence")
+        .and_then(|(_, sequence)| sequence.parse::<u64>().ok())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("no sequence number was recorded for key \"{}\"", key),
+            )
+            .into()
+        })
+}
+
+/// Returns the generation that state written by the current process belongs to.
+///
+/// Every process taking part in a single cargo invocation shares the same generation, so state
+/// written by one `rustc` process during a build is visible to all others, while state from
+/// previous builds is not. The generation can be pinned explicitly by setting the
+/// `MACRO_STATE_GENERATION` environment variable to an integer.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// assert_eq!(proc_state_generation(), proc_state_generation());
+/// ```
+pub fn proc_state_generation() -> u128 {
+    generation()
+}
+
+/// Flushes the state file for the specified `key` (along with the directory containing it) to
+/// disk, guaranteeing that the current value survives a crash or power loss.
+///
+/// This is the per-call alternative to setting the `MACRO_STATE_FSYNC` environment variable
+/// to `1`, which makes every write and append durable automatically.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("important key", "important value").unwrap();
+/// proc_sync_state("important key").unwrap();
+/// ```
+pub fn proc_sync_state(key: &str) -> StateResult<()> {
+    let state_file = state_file_path(key);
+    if memory_mode() {
+        file_stamp(&state_file).map_err(|e| MacroStateError::for_key(key, e))?;
+        return Ok(());
+    }
+    let file =
+        retry_io(|| File::open(&state_file)).map_err(|e| MacroStateError::for_key(key, e))?;
+    Ok(sync_state_file(&file, &state_file)?)
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use crate::*;
+    write_state!("top of module", "value 2");
+
+    #[export_state_tokens]
+    pub(crate) fn exported_answer() -> usize {
+        42
+    }
+
+    mod imported {
+        import_state_tokens!("exported_answer");
+    }
+
+    #[linkme::distributed_slice]
+    static LINKME_REGISTRY: [&'static str] = [..];
+
+    append_state!("linkme items", "first");
+    append_state!("linkme items", "second");
+    register_state_linkme!("linkme items", LINKME_REGISTRY);
+
+    struct InventoryItem(&'static str);
+
+    impl InventoryItem {
+        const fn new(value: &'static str) -> Self {
+            InventoryItem(value)
+        }
+    }
+
+    inventory::collect!(InventoryItem);
+
+    append_state!("inventory items", "alpha");
+    append_state!("inventory items", "beta");
+    submit_state_inventory!("inventory items", InventoryItem::new);
+
+    #[test]
+    fn test_write_state() {
+        write_state!("top of method", "value 3");
+        assert_eq!(read_state!("top of module"), "value 2");
+        assert_eq!(read_state!("top of method"), "value 3");
+    }
+
+    write_state!("const value", "forty two");
+    append_state!("const items", "one");
+    append_state!("const items", "two");
+
+    const CONST_VALUE: &str = read_state_const!("const value");
+    const CONST_RESULT: std::result::Result<&str, &str> = read_state_result!("const value");
+    const CONST_ITEMS: &[&str] = read_state_slice!("const items");
+    static CONST_ARRAY: [&str; 2] = read_state_array!("const items");
+    const CONST_HAS: bool = has_state!("const value");
+    const _: () = assert!(CONST_HAS);
+
+    #[test]
+    fn test_const_contexts() {
+        assert_eq!(CONST_VALUE, "forty two");
+        assert_eq!(CONST_RESULT, Ok("forty two"));
+        assert_eq!(CONST_ITEMS, &["one", "two"]);
+        assert_eq!(CONST_ARRAY, ["one", "two"]);
+    }
+
+    append_state_row!("lookup config", "host", "localhost");
+    append_state_row!("lookup config", "port", "8080");
+    append_state_row!("lookup config", "host", "example.com");
+    append_state_row!("lookup config", "", "empty");
+    emit_state_lookup_fn!(
+        "lookup config" as const fn lookup_config(key: &str) -> Option<&'static str>
+    );
+    const LOOKUP_PORT: Option<&str> = lookup_config("port");
+
+    #[test]
+    fn test_emit_state_lookup_fn() {
+        assert_eq!(LOOKUP_PORT, Some("8080"));
+        assert_eq!(lookup_config("host"), Some("example.com"));
+        assert_eq!(lookup_config(""), Some("empty"));
+        assert_eq!(lookup_config("hos"), None);
+    }
+
+    write_state!("paste suffix", "world");
+    state_paste! {
+        fn [<hello_ read_state!("paste suffix")>]() -> &'static str {
+            "pasted"
+        }
+        mod pasted {
+            pub const [<VALUE_ "COUNT" _ 2>]: usize = 2;
+        }
+    }
+
+    macro_rules! paste_greeting {
+        ($suffix:expr) => {
+            state_paste! {
+                const [<GREETING_ $suffix>]: &str = "fragment";
+            }
+        };
+    }
+    paste_greeting!("FRAGMENT");
+
+    #[test]
+    fn test_state_paste() {
+        assert_eq!(hello_world(), "pasted");
+        assert_eq!(pasted::VALUE_COUNT_2, 2);
+        assert_eq!(GREETING_FRAGMENT, "fragment");
+    }
+
+    write_state!("doc notes", "Collected notes.");
+    append_state!("doc list", "first");
+    append_state!("doc list", "second\nline");
+
+    /// Documented via state.
+    ///
+    #[doc = state_doc!("doc notes")]
+    #[allow(dead_code)]
+    struct StateDocumented;
+
+    #[test]
+    fn test_state_doc() {
+        assert_eq!(state_doc!("doc notes"), "Collected notes.");
+        assert_eq!(state_doc!("doc list"), "- first\n- second\n  line\n");
+    }
+
+    write_state!("attr note", "use the new API instead");
+
+    #[state_attrs]
+    #[deprecated(note = state_attr!("attr note"))]
+    #[allow(dead_code)]
+    fn state_attr_deprecated() {}
+
+    #[test]
+    fn test_state_attr() {
+        assert_eq!(state_attr!("attr note"), "use the new API instead");
+    }
+
+    write_state!("match backend", "sqlite");
+    match_state!("match backend" {
+        "postgres" | "mysql" => { const MATCHED_PORT: u16 = 5432; },
+        "sqlite" => { const MATCHED_PORT: u16 = 0; },
+        _ => { compile_error!("unsupported backend"); },
+    });
+
+    #[test]
+    fn test_match_state() {
+        assert_eq!(MATCHED_PORT, 0);
+        let kind = match_state!("match backend" { "sqlite" => { "file" } _ => { "network" } });
+        assert_eq!(kind, "file");
+        let fallback = match_state!("unknown backend" { "sqlite" => { 1 }, _ => { 2 } });
+        assert_eq!(fallback, 2);
+    }
+
+    append_state!("reduced sizes", "128");
+    append_state!("reduced sizes", "-8");
+    append_state!("reduced sizes", "64");
+    const REDUCED_LEN: usize = state_vec_len!("reduced sizes");
+    const REDUCED_SUM: i64 = state_vec_sum!("reduced sizes");
+    const REDUCED_MAX: Option<i64> = state_vec_max!("reduced sizes");
+
+    #[test]
+    fn test_state_vec_reductions() {
+        assert_eq!(REDUCED_LEN, 3);
+        assert_eq!(REDUCED_SUM, 184);
+        assert_eq!(REDUCED_MAX, Some(128));
+        assert_eq!(state_vec_min!("reduced sizes"), Some(-8));
+        assert_eq!(state_vec_len!("unknown reduced sizes"), 0);
+        assert_eq!(state_vec_sum!("unknown reduced sizes"), 0);
+        assert_eq!(state_vec_min!("unknown reduced sizes"), None);
+    }
+
+    #[test]
+    fn test_read_state_owned() {
+        write_state!("owned key", "owned value");
+        let value: String = read_state!("owned key", String);
+        assert_eq!(value, "owned value");
+    }
+
+    #[test]
+    fn test_rewriting_state() {
+        write_state!("key 1", "value 4");
+        assert_eq!(read_state!("key 1"), "value 4");
+        write_state!("key 1", "value 5");
+        assert_eq!(read_state!("key 1"), "value 5");
+    }
+
+    #[test]
+    fn test_has_state() {
+        assert_eq!(has_state!("key A"), false);
+        write_state!("key A", "value 6");
+        assert_eq!(has_state!("key A"), true);
+        assert_eq!(read_state!("key A"), "value 6");
+    }
+
+    #[test]
+    fn test_clear_state() {
+        write_state!("key B", "value 7");
+        assert_eq!(read_state!("key B"), "value 7");
+        clear_state!("key B");
+        assert_eq!(has_state!("key B"), false);
+    }
+
+    #[test]
+    fn test_init_state() {
+        write_state!("key C", "value 8");
+        assert_eq!(init_state!("key C", "value -8"), "value 8");
+        assert_eq!(init_state!("key D", "value 9"), "value 9");
+        assert_eq!(init_state!("key C", "value -8"), "value 8");
+        assert_eq!(init_state!("key D", "value 9"), "value 9");
+    }
+
+    #[test]
+    fn test_append_state() {
+        append_state!("append_key", "first line");
+        assert_eq!(read_state!("append_key"), "first line\n");
+        append_state!("append_key", "2nd line");
+        assert_eq!(read_state!("append_key"), "first line\n2nd line\n");
+        append_state!("append_key", "3rd line");
+        assert_eq!(
+            read_state!("append_key"),
+            "first line\n2nd line\n3rd line\n"
+        );
+        write_state!("append_key", "");
+        append_state!("append_key", "first line");
+        assert_eq!(read_state!("append_key"), "first line\n");
+    }
+
+    #[test]
+    fn test_proc_append_state() {
+        proc_append_state("proc append_key", "first line").unwrap();
+        assert_eq!(proc_read_state("proc append_key").unwrap(), "first line\n");
+        proc_append_state("proc append_key", "2nd line").unwrap();
+        assert_eq!(
+            proc_read_state("proc append_key").unwrap(),
+            "first line\n2nd line\n"
+        );
+        proc_append_state("proc append_key", "3rd line").unwrap();
+        assert_eq!(
+            proc_read_state("proc append_key").unwrap(),
+            "first line\n2nd line\n3rd line\n"
+        );
+        proc_write_state("proc append_key", "").unwrap();
+        proc_append_state("proc append_key", "first line").unwrap();
+        assert_eq!(proc_read_state("proc append_key").unwrap(), "first line\n");
+    }
+
+    #[test]
+    fn test_read_state_vec() {
+        append_state!("append2", "line 1");
+        assert_eq!(read_state_vec!("append2"), vec!["line 1"]);
+        append_state!("append2", "line 2");
+        assert_eq!(read_state_vec!("append2"), vec!["line 1", "line 2"]);
+        append_state!("append2", "line 3");
+        assert_eq!(
+            read_state_vec!("append2"),
+            vec!["line 1", "line 2", "line 3"]
+        );
+        append_state!("append2", "");
+        assert_eq!(
+            read_state_vec!("append2"),
+            vec!["line 1", "line 2", "line 3", ""]
+        );
+        assert_eq!(read_state_vec!("append748"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_proc_read_state_vec() {
+        proc_append_state("proc append2", "line 1").unwrap();
+        assert_eq!(proc_read_state_vec("proc append2"), vec!["line 1"]);
+        proc_append_state("proc append2", "line 2").unwrap();
+        assert_eq!(
+            proc_read_state_vec("proc append2"),
+            vec!["line 1", "line 2"]
+        );
+        proc_append_state("proc append2", "line 3").unwrap();
+        assert_eq!(
+            proc_read_state_vec("proc append2"),
+            vec!["line 1", "line 2", "line 3"]
+        );
+        proc_append_state("proc append2", "").unwrap();
+        assert_eq!(
+            proc_read_state_vec("proc append2"),
+            vec!["line 1", "line 2", "line 3", ""]
+        );
+    }
+
+    #[test]
+    fn test_append_state_newline_escaping() {
+        append_state!("append3", "line 1");
+        append_state!("append3", "hey\nwhat");
+        append_state!("append3", "line 3");
+        assert_eq!(
+            read_state_vec!("append3"),
+            vec!["line 1", "hey\nwhat", "line 3"]
+        );
+        append_state!("append4", "\n");
+        assert_eq!(read_state_vec!("append4"), vec!["\n"]);
+    }
+
+    #[test]
+    fn test_proc_append_state_newline_escaping() {
+        proc_append_state("proc append3", "line 1").unwrap();
+        proc_append_state("proc append3", "hey\nwhat").unwrap();
+        proc_append_state("proc append3", "line 3").unwrap();
+        assert_eq!(
+            proc_read_state_vec("proc append3"),
+            vec!["line 1", "hey\nwhat", "line 3"]
+        );
+        proc_append_state("proc append4", "\n").unwrap();
+        assert_eq!(proc_read_state_vec("proc append4"), vec!["\n"]);
+    }
+
+    #[test]
+    fn test_proc_state_functions() {
+        assert_eq!(proc_has_state("proc A"), false);
+        assert!(proc_read_state("proc B").is_err());
+        proc_write_state("proc A", "val A").unwrap();
+        assert!(proc_has_state("proc A"));
+        assert_eq!(proc_read_state("proc A").unwrap(), "val A");
+        assert_eq!(proc_init_state("proc A", "val B").unwrap(), "val A");
+        proc_init_state("proc B", "val B").unwrap();
+        assert_eq!(proc_read_state("proc B").unwrap(), "val B");
+        assert!(proc_has_state("proc B"));
+        proc_clear_state("proc B").unwrap();
+        proc_clear_state("proc A").unwrap();
+        assert_eq!(proc_has_state("proc A"), false);
+        assert_eq!(proc_has_state("proc B"), false);
+        assert!(proc_read_state("proc B").is_err());
+        assert!(proc_read_state("proc A").is_err());
+    }
+
+    #[test]
+    fn test_publish_subscribe_state() {
+        publish_state!("channel A", "published value");
+        assert_eq!(subscribe_state!("channel A"), "published value");
+        publish_state!("channel A", "replaced value");
+        assert_eq!(subscribe_state!("channel A"), "replaced value");
+    }
+
+    #[test]
+    fn test_proc_publish_subscribe_state() {
+        assert!(proc_subscribe_state("proc channel Z").is_err());
+        proc_publish_state("proc channel A", "line 1\nline 2").unwrap();
+        assert_eq!(
+            proc_subscribe_state("proc channel A").unwrap(),
+            "line 1\nline 2"
+        );
+        assert_eq!(
+            proc_state_publisher("proc channel A").unwrap(),
+            "macro_state_core"
+        );
+    }
+
+    #[test]
+    fn test_export_import_state() {
+        write_state!("export key", "exported value");
+        export_state_for_dependents!("export key");
+        assert_eq!(
+            import_dependency_state!("macro_state_core", "export key"),
+            "exported value"
+        );
+    }
+
+    #[test]
+    fn test_proc_export_import_state() {
+        assert!(proc_export_state_for_dependents("proc export missing").is_err());
+        proc_write_state("proc export key", "A").unwrap();
+        proc_export_state_for_dependents("proc export key").unwrap();
+        assert_eq!(
+            proc_import_dependency_state("macro_state_core", "proc export key").unwrap(),
+            "A"
+        );
+        assert!(proc_import_dependency_state("other_crate", "proc export key").is_err());
+    }
+
+    #[test]
+    fn test_export_import_state_tokens() {
+        assert_eq!(exported_answer(), 42);
+        assert_eq!(imported::exported_answer(), 42);
+        assert!(read_state!("exported_answer").contains("fn exported_answer"));
+    }
+
+    #[test]
+    fn test_proc_state_tokens() {
+        proc_write_state_tokens("proc tokens", &"vec![1, 2, 3]").unwrap();
+        assert_eq!(proc_read_state("proc tokens").unwrap(), "vec![1, 2, 3]");
+        proc_write_state("proc tokens", "not a number").unwrap();
+        let result: StateResult<u32> = proc_read_state_tokens("proc tokens");
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_register_state_linkme() {
+        let mut items = LINKME_REGISTRY.to_vec();
+        items.sort();
+        assert_eq!(items, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_submit_state_inventory() {
+        let mut items = inventory::iter::<InventoryItem>
+            .into_iter()
+            .map(|item| item.0)
+            .collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(items, vec!["alpha", "beta"]);
+    }
+
+    #[test]
+    fn test_proc_read_state_cache() {
+        proc_write_state("cached key", "first").unwrap();
+        assert_eq!(proc_read_state("cached key").unwrap(), "first");
+        proc_write_state("cached key", "second").unwrap();
+        assert_eq!(proc_read_state("cached key").unwrap(), "second");
+        fs::write(state_file_path("cached key"), "written elsewhere").unwrap();
+        assert_eq!(proc_read_state("cached key").unwrap(), "written elsewhere");
+        proc_append_state("cached key", "appended").unwrap();
+        assert_eq!(
+            proc_read_state("cached key").unwrap(),
+            "written elsewhereappended\n"
+        );
+        proc_clear_state("cached key").unwrap();
+        assert!(proc_read_state("cached key").is_err());
+    }
+
+    #[test]
+    fn test_state_file_path_sharding() {
+        let path = state_file_path("sharded key");
+        let shard = path.parent().unwrap();
+        assert_eq!(shard.file_name().unwrap().len(), 2);
+        assert_eq!(shard.parent().unwrap(), crate_state_dir("macro_state_core"));
+        assert!(crate_state_dir("macro_state_core")
+            .starts_with(state_dir().join(format!("v{}", STATE_FORMAT_VERSION))));
+        assert_eq!(stable_hash("sharded key"), stable_hash("sharded key"));
+        assert_ne!(stable_hash("sharded key"), stable_hash("sharded key 2"));
+        proc_write_state("sharded key", "value").unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_state_mtime() {
+        write_state!("mtime key", "value");
+        let mtime: u128 = state_mtime!("mtime key");
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        assert!(mtime > 0);
+        assert!(mtime <= now);
+    }
+
+    #[test]
+    fn test_proc_state_modified_since() {
+        assert!(proc_state_mtime("proc mtime key").is_err());
+        assert!(proc_state_modified_since("proc mtime key", SystemTime::now()).is_err());
+        let before = SystemTime::now() - std::time::Duration::from_secs(60);
+        proc_write_state("proc mtime key", "value").unwrap();
+        assert!(proc_state_modified_since("proc mtime key", before).unwrap());
+        let written = proc_state_mtime("proc mtime key").unwrap();
+        assert!(!proc_state_modified_since("proc mtime key", written).unwrap());
+    }
+
+    #[test]
+    fn test_state_metadata() {
+        write_state!("metadata key", "1234567");
+        let metadata = state_metadata!("metadata key");
+        assert_eq!(metadata.size, 7);
+        assert_eq!(metadata.writer_crate.unwrap(), "macro_state_core");
+        assert!(metadata.created <= metadata.modified);
+    }
+
+    #[test]
+    fn test_proc_state_metadata() {
+        assert!(proc_state_metadata("proc metadata key").is_err());
+        proc_write_state("proc metadata key", "abc").unwrap();
+        let first = proc_state_metadata("proc metadata key").unwrap();
+        assert_eq!(first.size, 3);
+        assert_eq!(first.writer_crate.as_deref(), Some("macro_state_core"));
+        proc_append_state("proc metadata key", "def").unwrap();
+        let second = proc_state_metadata("proc metadata key").unwrap();
+        assert_eq!(second.size, 3 + frame_record("def", None).len() as u64);
+        assert_eq!(second.created, first.created);
+        proc_clear_state("proc metadata key").unwrap();
+        assert!(!metadata_file_path(&state_file_path("proc metadata key")).exists());
+    }
+
+    #[test]
+    fn test_shared_generation() {
+        let generation = proc_state_generation();
+        assert!(generation > 0);
+        assert!(state_file_path("generation key")
+            .to_string_lossy()
+            .ends_with(&generation.to_string()));
+        let id = format!("{}_test", u32::MAX);
+        assert_eq!(shared_generation(&id, 17).unwrap(), 17);
+        assert_eq!(shared_generation(&id, 42).unwrap(), 17);
+    }
+
+    #[test]
+    fn test_retry_io() {
+        let mut attempts = 0;
+        let result = retry_io(|| {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(Error::from(ErrorKind::Interrupted)),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+        let mut attempts = 0;
+        let result: Result<()> = retry_io(|| {
+            attempts += 1;
+            Err(Error::from(ErrorKind::Interrupted))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_IO_ATTEMPTS);
+        let mut attempts = 0;
+        let result: Result<()> = retry_io(|| {
+            attempts += 1;
+            Err(Error::from(ErrorKind::NotFound))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_proc_sync_state() {
+        assert!(proc_sync_state("sync key").is_err());
+        proc_write_state("sync key", "value").unwrap();
+        proc_sync_state("sync key").unwrap();
+        let path = state_file_path("sync key");
+        let file = File::open(&path).unwrap();
+        sync_state_file(&file, &path).unwrap();
+        assert_eq!(proc_read_state("sync key").unwrap(), "value");
+    }
+
+    #[test]
+    fn test_case_sensitive_keys() {
+        write_state!("Case Key", "upper");
+        write_state!("case key", "lower");
+        assert_eq!(read_state!("Case Key"), "upper");
+        assert_eq!(read_state!("case key"), "lower");
+        assert_eq!(encode_filename("Config"), "^config");
+        assert_eq!(encode_filename("^config"), "^^config");
+        for key in [
+            "İstanbul",
+            "ẞ",
+            "ß",
+            "\u{212a}elvin",
+            "ǅ",
+            "^#41;",
+            "Ünïcode ^ Keys",
+        ] {
+            assert_eq!(decode_filename(&encode_filename(key)), key);
+        }
+        assert_ne!(
+            encode_filename("ẞ").to_lowercase(),
+            encode_filename("ß").to_lowercase()
+        );
+        let upper = state_file_path("Config").to_string_lossy().to_lowercase();
+        let lower = state_file_path("config").to_string_lossy().to_lowercase();
+        assert_ne!(upper, lower);
+        proc_write_state("Proc Case", "upper").unwrap();
+        proc_write_state("proc case", "lower").unwrap();
+        assert_eq!(proc_read_state("Proc Case").unwrap(), "upper");
+        assert_eq!(proc_read_state("proc case").unwrap(), "lower");
+    }
+
+    #[test]
+    fn test_encode_filename_windows() {
+        // characters Windows does not allow in file names
+        assert_eq!(encode_filename("a<b>c"), "a^#3c;b^#3e;c");
+        assert_eq!(encode_filename("c:\\x"), "c^#3a;^#5c;x");
+        assert_eq!(encode_filename("\"a|b\""), "^#22;a^#7c;b^#22;");
+        assert_eq!(encode_filename("what?*"), "what^#3f;^#2a;");
+        // control characters
+        assert_eq!(encode_filename("tab\tnewline\n"), "tab^#9;newline^#a;");
+        // reserved device names, with or without an extension
+        assert_eq!(encode_filename("con"), "^#63;on");
+        assert_eq!(encode_filename("nul.txt"), "^#6e;ul.txt");
+        assert_eq!(encode_filename("com1"), "^#63;om1");
+        assert_eq!(encode_filename("lpt9 .log"), "^#6c;pt9 .log");
+        assert_eq!(encode_filename("aux"), "^#61;ux");
+        assert_eq!(encode_filename("CON"), "^c^o^n");
+        assert_eq!(encode_filename("console"), "console");
+        assert_eq!(encode_filename("com10"), "com10");
+        // trailing dots and spaces
+        assert_eq!(encode_filename("end."), "end^#2e;");
+        assert_eq!(encode_filename("end "), "end^#20;");
+        assert_eq!(encode_filename("a.b"), "a.b");
+        for key in [
+            "a<b>c",
+            "c:\\x",
+            "\"a|b\"",
+            "what?*",
+            "tab\tnewline\n",
+            "con",
+            "nul.txt",
+            "lpt9 .log",
+            "end.",
+            "end ",
+            ".",
+        ] {
+            assert_eq!(decode_filename(&encode_filename(key)), key);
+        }
+    }
+
+    #[test]
+    fn test_long_keys() {
+        let key = "a very long key ".repeat(32);
+        proc_write_state(&key, "long value").unwrap();
+        assert_eq!(proc_read_state(&key).unwrap(), "long value");
+        let path = state_file_path(&key);
+        let filename = path.file_name().unwrap().to_string_lossy().to_string();
+        assert!(filename.len() < 255);
+        assert!(filename.starts_with("macro_state_a very long key"));
+        assert_eq!(recorded_key(&path), Some(key.clone()));
+        assert!(state_keys().unwrap().contains(&key));
+        let other = format!("{}!", key);
+        assert_ne!(state_file_path(&other), path);
+        assert!(!proc_has_state(&other));
+        let tricky = format!("{}key=3:abc", key);
+        proc_append_state(&tricky, "item").unwrap();
+        assert_eq!(
+            recorded_key(&state_file_path(&tricky)),
+            Some(tricky.clone())
+        );
+        assert!(state_keys().unwrap().contains(&tricky));
+        let escaped = "a\\n\nb\r\\";
+        assert_eq!(
+            decode_key_field(&encode_key_field(escaped)).unwrap(),
+            escaped
+        );
+        assert_eq!(decode_key_field("3:ab"), None);
+        write_state!(
+            "a macro key that is long enough that it would normally blow right past the file \
+            name limits of many platforms, and so must be hashed instead",
+            "ok"
+        );
+        assert_eq!(
+            read_state!(
+                "a macro key that is long enough that it would normally blow right past the file \
+                name limits of many platforms, and so must be hashed instead"
+            ),
+            "ok"
+        );
+    }
+
+    #[test]
+    fn test_read_state_slice() {
+        append_state!("slice key", "a");
+        append_state!("slice key", "b");
+        const ITEMS: &[&str] = read_state_slice!("slice key");
+        assert_eq!(ITEMS, &["a", "b"]);
+        assert!(read_state_slice!("missing slice key").is_empty());
+    }
+
+    #[no_implicit_prelude]
+    mod no_prelude {
+        #[test]
+        fn test_qualified_expansions() {
+            ::macro_state::append_state!("no prelude list", "a");
+            let items = ::macro_state::read_state_vec!("no prelude list");
+            ::std::assert_eq!(items.len(), 1);
+            ::std::assert_eq!(
+                ::macro_state::read_state_vec!("no prelude missing").len(),
+                0
+            );
+            ::std::assert_eq!(::macro_state::read_state_slice!("no prelude list"), &["a"]);
+            let owned: ::std::string::String =
+                ::macro_state::read_state!("no prelude list", String);
+            ::std::assert_eq!(owned.len(), 2);
+            let metadata = ::macro_state::state_metadata!("no prelude list");
+            ::std::assert!(metadata.writer_crate.is_some());
+        }
+    }
+
+    #[test]
+    fn test_append_state_backslash_escaping() {
+        append_state!("append backslash", r"C:\new\dir");
+        append_state!("append backslash", "real\nnewline\\");
+        assert_eq!(
+            read_state_vec!("append backslash"),
+            vec![r"C:\new\dir", "real\nnewline\\"]
+        );
+        proc_append_state("proc append backslash", r"\n").unwrap();
+        proc_append_state("proc append backslash", "\\\n").unwrap();
+        assert_eq!(
+            proc_read_state_vec("proc append backslash"),
+            vec![r"\n", "\\\n"]
+        );
+    }
+
+    #[test]
+    fn test_append_state_sorted() {
+        append_state_sorted!("sorted list", "c", 30);
+        append_state!("sorted list", "zero");
+        append_state_sorted!("sorted list", "a", -5);
+        append_state_sorted!("sorted list", r"\p1:b", 30);
+        assert_eq!(
+            read_state_vec!("sorted list"),
+            vec!["a", "zero", "c", r"\p1:b"]
+        );
+        proc_append_state_sorted("proc sorted list", "last", i64::MAX).unwrap();
+        proc_append_state_sorted("proc sorted list", "first", i64::MIN).unwrap();
+        proc_append_state("proc sorted list", r"\p-1:middle").unwrap();
+        assert_eq!(
+            proc_read_state_vec("proc sorted list"),
+            vec!["first", r"\p-1:middle", "last"]
+        );
+    }
+
+    #[test]
+    fn test_deterministic_lists() {
+        proc_append_state_sorted("deterministic list", "z", 2).unwrap();
+        proc_append_state("deterministic list", "y").unwrap();
+        proc_append_state_sorted("deterministic list", "a", 2).unwrap();
+        proc_append_state("deterministic list", "x").unwrap();
+        testing::with_settings(&[("deterministic", "1")], || {
+            assert_eq!(
+                proc_read_state_vec("deterministic list"),
+                vec!["x", "y", "a", "z"]
+            );
+            assert_eq!(
+                proc_read_state("deterministic list").unwrap(),
+                "x\ny\na\nz\n"
+            );
+            let mut visited = Vec::new();
+            proc_for_each_state("deterministic list", |_, value| {
+                visited.push(value.to_string())
+            })
+            .unwrap();
+            assert_eq!(visited, vec!["x\ny\na\nz\n"]);
+        });
+        assert_eq!(
+            proc_read_state_vec("deterministic list"),
+            vec!["y", "x", "z", "a"]
+        );
+    }
+
+    #[test]
+    fn test_deterministic_emitter_lists() {
+        // what the emitting macros (such as `emit_registry_enum!` and `all_flags!`) expand to
+        // is generated from these readers, so appending in either order must expand the same
+        for (key, order) in [
+            ("emitter order 1", ["b", "a"]),
+            ("emitter order 2", ["a", "b"]),
+        ] {
+            for item in order {
+                proc_append_state(key, item).unwrap();
+                __private::append_item(&format!("__macro_state/{}", key), item).unwrap();
+            }
+        }
+        testing::with_settings(&[("deterministic", "1")], || {
+            for key in ["emitter order 1", "emitter order 2"] {
+                assert_eq!(__private::read_state_list(key).unwrap(), vec!["a", "b"]);
+                let internal = format!("__macro_state/{}", key);
+                assert_eq!(__private::read_items(&internal), vec!["a", "b"]);
+            }
+        });
+        assert_eq!(
+            __private::read_state_list("emitter order 1").unwrap(),
+            vec!["b", "a"]
+        );
+    }
+
+    #[test]
+    fn test_extend_state() {
+        extend_state!("extend list", "a", "b\nc");
+        extend_state!("extend list", "d",);
+        assert_eq!(read_state_vec!("extend list"), vec!["a", "b\nc", "d"]);
+        proc_extend_state("proc extend list", &["x", "y"]).unwrap();
+        proc_extend_state("proc extend list", &[]).unwrap();
+        proc_extend_state("proc extend list", &["z"]).unwrap();
+        assert_eq!(proc_read_state_vec("proc extend list"), vec!["x", "y", "z"]);
+    }
+
+    #[test]
+    fn test_read_state_array() {
+        extend_state!("array key", "a", "b", "c");
+        const ITEMS: [&str; 3] = read_state_array!("array key");
+        assert_eq!(ITEMS, ["a", "b", "c"]);
+        let empty: [&str; 0] = read_state_array!("missing array key");
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_read_state_integers() {
+        write_state!("usize key", "42");
+        write_state!("i64 key", "-9000");
+        const COUNT: usize = read_state_usize!("usize key");
+        assert_eq!(COUNT, 42);
+        assert_eq!(read_state_i64!("i64 key"), -9000i64);
+        assert_eq!(read_state_i64!("usize key") * 2, 84);
+    }
+
+    #[test]
+    fn test_translations() {
+        assert_eq!(t!("greeting.hello"), "greeting.hello");
+        assert_eq!(t!("errors.codes"), "errors.codes");
+        assert_eq!(t!("count"), "count");
+        assert_eq!(t!("café"), "café");
+        assert_eq!(t!("smile 😀"), "smile 😀");
+    }
+
+    validate_translations!("tests/fixtures/en.json");
+
+    declare_flag!("flag registry one");
+    declare_flag!("flag registry two");
+
+    #[test]
+    fn test_feature_flag_registry() {
+        let flags = all_flags!();
+        let one = flags.iter().position(|flag| *flag == "flag registry one");
+        let two = flags.iter().position(|flag| *flag == "flag registry two");
+        assert!(one.unwrap() < two.unwrap());
+    }
+
+    #[derive(RecordSchema)]
+    #[table_name = "users"]
+    #[allow(dead_code)]
+    struct SchemaUser {
+        id: i64,
+        display_name: String,
+        email: String,
+    }
+
+    check_schema!("tests/fixtures/migrations/");
+
+    trait TestMessage {
+        const NAME: &'static str;
+    }
+
+    struct PingMessage;
+    impl TestMessage for PingMessage {
+        const NAME: &'static str = "ping";
+    }
+
+    mod messages {
+        pub struct Pong;
+        impl super::TestMessage for Pong {
+            const NAME: &'static str = "pong";
+        }
+    }
+
+    register_message!(PingMessage = 17);
+    register_message!(messages::Pong = 18);
+
+    #[test]
+    fn test_message_registry() {
+        let name = |id: u32| dispatch_message!(id, T => <T as TestMessage>::NAME);
+        assert_eq!(name(17), Some("ping"));
+        assert_eq!(name(18), Some("pong"));
+        assert_eq!(name(19), None);
+    }
+
+    #[collect_test(meta = "checks the manifest", tags = "meta, fast")]
+    #[test]
+    fn test_collected_manifest() {
+        let manifest = emit_test_manifest!();
+        let test = manifest
+            .iter()
+            .find(|test| test.name == "test_collected_manifest")
+            .unwrap();
+        assert_eq!(test.meta, "checks the manifest");
+        assert_eq!(test.tags, &["meta", "fast"]);
+        assert!(test.file.ends_with("macro_state_core.rs"));
+        assert!(test.line > 0);
+        assert_eq!(
+            manifest
+                .iter()
+                .filter(|test| test.name == "test_collected_manifest")
+                .count(),
+            1
+        );
+    }
+
+    static BENCH_RUNS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    #[collect_bench]
+    fn bench_counted() -> usize {
+        BENCH_RUNS.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+    }
+
+    mod bench_harness {
+        use super::*;
+
+        emit_bench_main!(5);
+
+        #[test]
+        fn test_emit_bench_main() {
+            // the generated main treats the test harness' own filter arguments as filters too
+            let filters: Vec<String> = std::env::args()
+                .skip(1)
+                .filter(|arg| !arg.starts_with('-'))
+                .collect();
+            let filtered_out = !filters.is_empty()
+                && !filters
+                    .iter()
+                    .any(|filter| "bench_counted".contains(filter.as_str()));
+            main();
+            let runs = BENCH_RUNS.load(std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(runs, if filtered_out { 0 } else { 5 });
+        }
+    }
+
+    trait TestAudioPlugin {
+        fn name(&self) -> &'static str;
+    }
+
+    #[state_plugin("test audio")]
+    #[derive(Default)]
+    struct TestReverb;
+
+    impl TestAudioPlugin for TestReverb {
+        fn name(&self) -> &'static str {
+            "reverb"
+        }
+    }
+
+    #[state_plugin("test audio")]
+    #[derive(Default)]
+    enum TestDelay {
+        #[default]
+        Short,
+    }
+
+    impl TestAudioPlugin for TestDelay {
+        fn name(&self) -> &'static str {
+            match self {
+                TestDelay::Short => "delay",
+            }
+        }
+    }
+
+    #[test]
+    fn test_plugin_dispatcher() {
+        let plugins = emit_plugin_dispatcher!("test audio", trait TestAudioPlugin);
+        let names: Vec<&str> = plugins.iter().map(|plugin| plugin.name()).collect();
+        assert_eq!(names, vec!["reverb", "delay"]);
+        let empty = emit_plugin_dispatcher!("test video", trait TestAudioPlugin);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_append_state_unique() {
+        append_state_unique_or_error!("unique discriminants", "0x1F");
+        append_state_unique_or_error!("unique discriminants", "0x20");
+        assert_eq!(
+            read_state_vec!("unique discriminants"),
+            vec!["0x1F", "0x20"]
+        );
+        // the macro delegates to `proc_append_state_unique`, which checks declared constraints
+        let key = "proc unique constrained";
+        proc_declare_state_key(
+            key,
+            &[StateConstraint::Pattern(String::from("0x[0-9A-F]+"))],
+        )
+        .unwrap();
+        proc_append_state_unique(key, "0x1F").unwrap();
+        let err = proc_append_state_unique(key, "31").unwrap_err();
+        assert!(matches!(err, MacroStateError::InvalidValue { .. }));
+        assert_eq!(proc_read_state_vec(key), vec!["0x1F"]);
+        proc_append_state_unique("proc unique discriminants", "a").unwrap();
+        let err = proc_append_state_unique("proc unique discriminants", "a").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(err.to_string().contains("macro_state"));
+        proc_append_state("proc unique discriminants", "b").unwrap();
+        let err = proc_append_state_unique("proc unique discriminants", "b").unwrap_err();
+        assert!(err.to_string().contains("an unknown writer"));
+        for _ in 0..2 {
+            proc_append_state_unique("proc unique discriminants", "c").unwrap();
+        }
+        assert_eq!(
+            proc_read_state_vec("proc unique discriminants"),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    trait TestShape {
+        fn sides(&self) -> usize;
+    }
+
+    mod shapes {
+        #[derive(Default)]
+        pub struct Triangle;
+        impl super::TestShape for Triangle {
+            fn sides(&self) -> usize {
+                3
+            }
+        }
+
+        #[derive(Default)]
+        pub struct Square;
+        impl super::TestShape for Square {
+            fn sides(&self) -> usize {
+                4
+            }
+        }
+    }
+
+    enum TestShapeKind {
+        Triangle,
+        Square,
+    }
+
+    register_variant!("test shapes", Triangle => shapes::Triangle);
+    register_variant!("test shapes", Square => shapes::Square);
+
+    #[test]
+    fn test_registry_match() {
+        let build = |kind: TestShapeKind| -> Box<dyn TestShape> {
+            registry_match!("test shapes", kind, TestShapeKind, |T| Box::new(
+                T::default()
+            ))
+        };
+        assert_eq!(build(TestShapeKind::Triangle).sides(), 3);
+        assert_eq!(build(TestShapeKind::Square).sides(), 4);
+    }
+
+    append_state!("registry enum widgets", "button");
+    append_state!("registry enum widgets", "text_input");
+    append_state!("registry enum widgets", "button");
+
+    emit_registry_enum!("registry enum widgets" as pub(crate) TestWidgetKind);
+    emit_registry_enum!("registry enum missing" as TestEmptyKind);
+
+    #[test]
+    fn test_emit_registry_enum() {
+        assert_eq!(
+            TestWidgetKind::ALL,
+            &[TestWidgetKind::Button, TestWidgetKind::TextInput]
+        );
+        assert_eq!(TestWidgetKind::TextInput.as_str(), "text_input");
+        assert_eq!("button".parse(), Ok(TestWidgetKind::Button));
+        assert_eq!("slider".parse::<TestWidgetKind>(), Err(()));
+        assert!(TestEmptyKind::ALL.is_empty());
+    }
+
+    /// Deploys the current project.
+    ///
+    /// Requires "credentials".
+    #[harvest_docs("harvested commands", attrs(allow))]
+    #[allow(dead_code)]
+    struct HarvestedDeploy {
+        /// Skips confirmation.
+        force: bool,
+        target: String,
+    }
+
+    #[test]
+    fn test_harvest_docs() {
+        assert_eq!(
+            read_state_vec!("harvested commands"),
+            vec![concat!(
+                r#"{"name":"HarvestedDeploy","kind":"struct","#,
+                r#""docs":"Deploys the current project.\n\nRequires \"credentials\".","#,
+                r#""attributes":{"allow":"(dead_code)"},"members":["#,
+                r#"{"name":"force","docs":"Skips confirmation.","attributes":{}},"#,
+                r#"{"name":"target","docs":"","attributes":{}}]}"#
+            )]
+        );
+    }
+
+    record_module!();
+    record_module!("crate::tests::recorded");
+    record_module!("crate::tests");
+
+    mod recorded {
+        pub const RECORDED: &str = "recorded";
+    }
+
+    mod recorded_prelude {
+        emit_module_tree!(pub use);
+    }
+
+    #[test]
+    fn test_record_module() {
+        assert_eq!(
+            emit_module_tree!(),
+            ["crate", "crate::tests", "crate::tests::recorded"]
+        );
+        assert_eq!(recorded_prelude::RECORDED, "recorded");
+    }
+
+    write_state_from_file!("file test", "tests/fixtures/en.json");
+    write_state_from_file!(
+        "file test with a key long enough that its file name is hashed, which only works if the key is recorded alongside its value like every other write does",
+        "tests/fixtures/en.json"
+    );
+
+    #[test]
+    fn test_write_state_from_file() {
+        assert_eq!(
+            read_state!("file test"),
+            include_str!("../tests/fixtures/en.json")
+        );
+        // the long key is only listed if the file went through the regular write path
+        assert_eq!(read_keys_matching!("file test with a key long*").len(), 1);
+    }
+
+    write_state_glob!("glob test sql", "tests/fixtures/**/*.sql");
+    write_state_glob!("glob test json", "./tests/fixtures/?n.*");
+    write_state_glob!("glob test none", "tests/fixtures/*.png");
+    write_state_glob!("glob test cached", "tests/fixtures/.cache/*.sql");
+
+    #[test]
+    fn test_write_state_glob() {
+        assert_eq!(
+            read_state_vec!("glob test sql"),
+            vec![
+                "tests/fixtures/migrations/0001_create_users.sql",
+                "tests/fixtures/migrations/0002_update_users.sql",
+            ]
+        );
+        assert_eq!(
+            read_state_vec!("glob test json"),
+            vec!["tests/fixtures/en.json"]
+        );
+        assert!(read_state_vec!("glob test none").is_empty());
+        assert_eq!(
+            read_state_vec!("glob test cached"),
+            vec!["tests/fixtures/.cache/cached.sql"]
+        );
+    }
+
+    #[test]
+    fn test_write_state_glob_validation() {
+        let key = "glob validation";
+        proc_declare_state_key(key, &[StateConstraint::Pattern(String::from(".*\\.sql"))]).unwrap();
+        let items = |items: &[&str]| {
+            items
+                .iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>()
+        };
+        __private::write_list(key, &items(&["a.sql", "b.sql"])).unwrap();
+        let err = __private::write_list(key, &items(&["c.sql", "en.json"])).unwrap_err();
+        assert!(matches!(err, MacroStateError::InvalidValue { .. }));
+        // a single rejected item rejects the whole list
+        assert_eq!(proc_read_state_vec(key), vec!["a.sql", "b.sql"]);
+    }
+
+    #[test]
+    fn test_derived_state_dir() {
+        assert_eq!(
+            proc_state_dir(),
+            workspace_state_dir(PathBuf::from(STATE_DIR))
+        );
+        assert_eq!(
+            derived_state_dir(Some("/ws/target/debug/build/app-1234/out"), Some("/other")),
+            Some(PathBuf::from("/ws/target/debug/macro_state"))
+        );
+        assert_eq!(
+            derived_state_dir(Some("/custom/out"), None),
+            Some(PathBuf::from("/custom/out/macro_state"))
+        );
+        assert_eq!(
+            derived_state_dir(None, Some("/ws/target")),
+            Some(PathBuf::from("/ws/target/macro_state"))
+        );
+        assert_eq!(derived_state_dir(None, None), None);
+    }
+
+    #[test]
+    fn test_state_dir_fallback() {
+        check_state_dir(Path::new(STATE_DIR)).unwrap();
+        let file = Path::new(STATE_DIR).join("fallback test");
+        fs::write(&file, "").unwrap();
+        let blocked = file.join("nested");
+        assert!(check_state_dir(&blocked).is_err());
+        let fallback = fallback_state_dir(&blocked);
+        assert!(fallback.starts_with(std::env::temp_dir()));
+        assert_eq!(fallback, fallback_state_dir(&blocked));
+        assert_ne!(fallback, fallback_state_dir(Path::new(STATE_DIR)));
+
+        let settings = [("shared", "1"), ("state_dir", blocked.to_str().unwrap())];
+        let resolved = testing::with_settings(&settings, resolve_state_dir);
+        assert_eq!(resolved, fallback);
+        fs::remove_file(&file).unwrap();
+        assert!(check_state_dir(&blocked).is_ok());
+        let resolved = testing::with_settings(&settings, resolve_state_dir);
+        assert_eq!(resolved, fallback);
+        fs::remove_dir_all(&file).unwrap();
+    }
+
+    #[test]
+    fn test_sandbox_state_dir() {
+        let sandbox = sandbox_state_dir();
+        assert!(sandbox.starts_with(std::env::temp_dir()));
+        assert_eq!(sandbox, sandbox_state_dir());
+
+        let root = Path::new(STATE_DIR).join("sandbox test");
+        let stale = root.join("macro_state_sandbox_4294967295_1");
+        let active = root.join(format!("macro_state_sandbox_{}_1", std::process::id()));
+        let unrelated = root.join("unrelated_4294967295_1");
+        for dir in [&stale, &active, &unrelated] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        remove_stale_entries(&root, SANDBOX_PREFIX);
+        assert!(!stale.exists());
+        assert!(active.exists());
+        assert!(unrelated.exists());
+    }
+
+    #[test]
+    fn test_state_sequence() {
+        testing::with_settings(&[("sequence", "1")], || {
+            proc_write_state("sequence a", "1").unwrap();
+            proc_write_state("sequence b", "1").unwrap();
+            let a = proc_state_sequence("sequence a").unwrap();
+            let b = proc_state_sequence("sequence b").unwrap();
+            assert!(a < b);
+            proc_append_state("sequence a", "2").unwrap();
+            assert!(proc_state_sequence("sequence a").unwrap() > b);
+        });
+        assert!(proc_state_sequence("sequence missing").is_err());
+        proc_write_state("sequence disabled", "1").unwrap();
+        assert!(proc_state_sequence("sequence disabled").is_err());
+    }
+
+    #[test]
+    fn test_missed_reads() {
+        assert!(take_missed_reads("missed read test").unwrap().is_empty());
+        record_missed_read("missed read test", "consumer (src/a.rs:1)").unwrap();
+        record_missed_read("missed read test", "other (src/b.rs:2)").unwrap();
+        let readers = take_missed_reads("missed read test").unwrap();
+        assert_eq!(readers, vec!["consumer (src/a.rs:1)", "other (src/b.rs:2)"]);
+        assert!(take_missed_reads("missed read test").unwrap().is_empty());
+        assert_eq!(
+            missed_read_warning("missed read test", &readers, "producer (src/c.rs:3)"),
+            "warning: macro_state: state key \"missed read test\" was read by consumer \
+            (src/a.rs:1), other (src/b.rs:2) before it was written by producer (src/c.rs:3), so \
+            the reader saw no value. Make sure the writing macro expands before the reading one."
+        );
+        let location = caller_location();
+        assert!(location.starts_with("macro_state_core (core/src/macro_state_core.rs:"));
+    }
+
+    defer_tokens!("deferred consts", {
+        const DEFERRED_A: usize = 1;
+    });
+    defer_tokens!("deferred consts", const DEFERRED_B: usize = DEFERRED_A + 1;);
+    defer_tokens!("deferred consts", {
+        const DEFERRED_A: usize = 1;
+    });
+
+    flush_deferred!("deferred consts");
+
+    #[test]
+    fn test_deferred_tokens() {
+        assert_eq!(DEFERRED_A, 1);
+        assert_eq!(DEFERRED_B, 2);
+
+        proc_defer_tokens("proc deferred", &"struct A;").unwrap();
+        proc_defer_tokens("proc deferred", &"struct B;").unwrap();
+        proc_defer_tokens("proc deferred", &"struct A;").unwrap();
+        assert_eq!(
+            proc_read_state_vec(&deferred_tokens_key("proc deferred")),
+            vec!["struct A;", "struct B;"]
+        );
+    }
+
+    write_state!("finalize/models/user", "User");
+    append_state!("finalize codes", "E001");
+    append_state!("finalize codes", "E002");
+    write_state!("finalize version", "3");
+
+    finalize_state! {
+        assert_has("finalize/models/..*");
+        assert_has("finalize/model?s/us.r");
+        assert_unique("finalize codes");
+        assert_eq("finalize version", "3");
+    }
+
+    #[test]
+    fn test_missing_key_handler() {
+        /// Removes the handler once the test ends, even if it fails, so that it never leaks
+        /// into the reads of other tests.
+        struct HandlerGuard;
+
+        impl Drop for HandlerGuard {
+            fn drop(&mut self) {
+                proc_clear_missing_key_handler();
+            }
+        }
+
+        proc_set_missing_key_handler(|key, e| match key.strip_prefix("handled/") {
+            Some(name) => Ok(format!("default {}", name)),
+            None => Err(e),
+        });
+        let _guard = HandlerGuard;
+        assert_eq!(proc_read_state("handled/a").unwrap(), "default a");
+        assert_eq!(proc_read_state_vec("handled/b"), vec!["default b"]);
+        assert!(!proc_has_state("handled/a"));
+        assert_eq!(
+            proc_read_state("unhandled key").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        proc_write_state("handled/c", "written").unwrap();
+        assert_eq!(proc_read_state("handled/c").unwrap(), "written");
+        proc_state_transaction(|tx| {
+            assert!(tx.read("handled/d").is_err());
+            tx.append("handled/d", "item");
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(proc_read_state_vec("handled/d"), vec!["item"]);
+    }
+
+    #[test]
+    fn test_read_state_result() {
+        write_state!("result key", "present");
+        assert_eq!(read_state_result!("result key"), Ok("present"));
+        assert_eq!(
+            read_state_result!("result missing key"),
+            Err("no state value exists for key \"result missing key\"")
+        );
+        const RESULT: std::result::Result<&str, &str> = read_state_result!("result key");
+        assert_eq!(RESULT.unwrap_or("absent"), "present");
+    }
+
+    #[test]
+    fn test_proc_read_state_ext() {
+        assert!(matches!(
+            proc_read_state_ext("ext key"),
+            Err(MacroStateError::KeyNotFound { .. })
+        ));
+        proc_write_state("ext key", "first").unwrap();
+        let first = proc_read_state_ext("ext key").unwrap();
+        assert_eq!(first.value, "first");
+        assert_eq!(first.writer.as_deref(), Some("macro_state_core"));
+        assert_eq!(first.generation, proc_state_generation());
+        assert_eq!(first.mtime, proc_state_mtime("ext key").unwrap());
+        proc_append_state("ext key", "second").unwrap();
+        let second = proc_read_state_ext("ext key").unwrap();
+        assert_eq!(second.value, "firstsecond\n");
+        assert!(second.mtime >= first.mtime);
+    }
+
+    #[test]
+    fn test_check_key() {
+        assert!(check_key("models/User", true).is_ok());
+        assert!(check_key("__macro_state/build_env/target", false).is_ok());
+        for key in ["", "a//b", "/a", "a/", "a/../b", "./a", "a\\b", "a\nb"] {
+            assert!(matches!(
+                proc_write_state(key, "x"),
+                Err(MacroStateError::InvalidKey { .. })
+            ));
+        }
+        let err = proc_write_state("__macro_state/state_graph", "x").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert_eq!(
+            err.to_string(),
+            "invalid state key \"__macro_state/state_graph\": the `__macro_state/` prefix is \
+            reserved for internal metadata"
+        );
+        assert!(proc_append_state("__macro_state/modules/x", "y").is_err());
+        assert!(proc_clear_state("__macro_state/state_graph").is_err());
+        assert_eq!(
+            proc_read_state("a/../b").unwrap_err().to_string(),
+            "invalid state key \"a/../b\": keys must not contain `.` or `..` segments"
+        );
+        proc_append_state_unique("check key unique", "a").unwrap();
+        proc_defer_tokens("check key deferred", &"struct A;").unwrap();
+    }
+
+    #[test]
+    fn test_crate_state_dirs() {
+        let relative = state_file_path("crate owned key")
+            .strip_prefix(crate_state_dir("macro_state_core"))
+            .unwrap()
+            .to_path_buf();
+        let owned = crate_state_dir("other-crate").join(&relative);
+        assert_eq!(claim_state_file(&owned).unwrap(), owned);
+        fs::create_dir_all(owned.parent().unwrap()).unwrap();
+        fs::write(&owned, "from other crate").unwrap();
+        assert_eq!(state_file_path("crate owned key"), owned);
+        let current = crate_state_dir("macro_state_core").join(&relative);
+        assert_eq!(claim_state_file(&current).unwrap(), owned);
+        assert_eq!(
+            proc_read_state("crate owned key").unwrap(),
+            "from other crate"
+        );
+        proc_append_state("crate owned key", "more").unwrap();
+        assert!(crate_state_dirs().contains(&crate_state_dir("other_crate")));
+        assert!(state_keys()
+            .unwrap()
+            .contains(&String::from("crate owned key")));
+        proc_clear_crate_state("other-crate").unwrap();
+        assert!(!owned.exists());
+        assert!(!proc_has_state("crate owned key"));
+        let dir = crate_state_dir("macro_state_core");
+        assert!(state_file_path("crate owned key").starts_with(dir));
+    }
+
+    #[test]
+    fn test_workspace_state_dir() {
+        let dir = workspace_state_dir(PathBuf::from("/target/macro_state"));
+        assert_eq!(dir.parent().unwrap(), Path::new("/target/macro_state"));
+        let name = dir.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("workspace_"));
+        assert_eq!(name.len(), "workspace_".len() + 16);
+        assert_eq!(
+            dir,
+            workspace_state_dir(PathBuf::from("/target/macro_state"))
+        );
+    }
+
+    #[test]
+    fn test_workspace_of_build_dir() {
+        let root = std::env::temp_dir().join(format!("macro_state_ws_{}", std::process::id()));
+        let workspace = |name: &str| {
+            let dir = root.join(name);
+            fs::create_dir_all(dir.join("target/debug/build/app-1234/out")).unwrap();
+            fs::create_dir_all(dir.join("target/debug/deps")).unwrap();
+            fs::write(dir.join("target/CACHEDIR.TAG"), "").unwrap();
+            fs::write(dir.join("Cargo.lock"), "").unwrap();
+            dir
+        };
+        let (first, second) = (workspace("first"), workspace("second"));
+        for dir in [&first, &second] {
+            assert_eq!(
+                workspace_of_build_dir(&dir.join("target/debug/build/app-1234/out")),
+                *dir
+            );
+            assert_eq!(workspace_of_build_dir(&dir.join("target/debug/deps")), *dir);
+            assert_eq!(workspace_of_build_dir(&dir.join("target")), *dir);
+        }
+        // a target directory outside of any workspace stands for the workspace itself
+        let shared = root.join("shared/target");
+        fs::create_dir_all(shared.join("x86_64-unknown-linux-gnu/debug/deps")).unwrap();
+        fs::write(shared.join("CACHEDIR.TAG"), "").unwrap();
+        assert_eq!(
+            workspace_of_build_dir(&shared.join("x86_64-unknown-linux-gnu/debug/deps")),
+            shared
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_is_read_only() {
+        assert!(is_read_only("my-app", "my_app"));
+        assert!(is_read_only("my_app", "models, my-app"));
+        assert!(is_read_only("anything", "*"));
+        assert!(!is_read_only("my_app", ""));
+        assert!(!is_read_only("my_app", "my_app_models"));
+        let err = MacroStateError::WriteDenied {
+            key: String::from("models"),
+            crate_name: String::from("my_app"),
+            reason: String::from("the crate is read-only"),
+        };
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(
+            err.to_string(),
+            "crate \"my_app\" may not write to key \"models\": the crate is read-only"
+        );
+    }
+
+    #[test]
+    fn test_read_only_file_writes() {
+        if memory_mode() {
+            return;
+        }
+        testing::with_isolated_state(|| {
+            proc_write_state("read only key", "value").unwrap();
+            let session = proc_state_session_begin("read only session").unwrap();
+            testing::with_settings(&[("read_only", "macro_state_core")], || {
+                let denied = |result: StateResult<()>| {
+                    matches!(result, Err(MacroStateError::WriteDenied { .. }))
+                };
+                assert!(denied(proc_clear_crate_state("macro_state_core")));
+                assert!(denied(proc_publish_state("read only channel", "value")));
+                assert!(denied(proc_defer_tokens("read only slot", &"struct A;")));
+                assert!(denied(proc_export_state_for_dependents("read only key")));
+                assert!(denied(proc_state_session_end("read only session")));
+                assert!(denied(proc_state_transaction(|tx| {
+                    tx.write("read only key", "changed");
+                    Ok(())
+                })));
+            });
+            assert_eq!(proc_read_state("read only key").unwrap(), "value");
+            session.end().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_write_policy_violation() {
+        let policy = "# ORM metadata\n\
+            orm/* = my-orm-derive\n\
+            orm/shared/* = my-orm-derive, my_app\n\
+            locked =\n";
+        assert_eq!(
+            write_policy_violation("orm/User", "my_orm_derive", policy),
+            None
+        );
+        assert_eq!(
+            write_policy_violation("orm/User", "my_app", policy).as_deref(),
+            Some("only \"my-orm-derive\" may write keys matching `orm/*`")
+        );
+        assert_eq!(
+            write_policy_violation("orm/shared/ids", "my-app", policy),
+            None
+        );
+        assert_eq!(write_policy_violation("routes", "my_app", policy), None);
+        assert_eq!(
+            write_policy_violation("locked", "my_app", policy).as_deref(),
+            Some("no crate may write keys matching `locked`")
+        );
+        assert_eq!(write_policy_violation("locked/x", "my_app", policy), None);
+        let config_dir = config_path().parent().unwrap().to_path_buf();
+        crate::testing::with_settings(&[("policy", "custom.policy")], || {
+            assert_eq!(write_policy_path(), config_dir.join("custom.policy"));
+        });
+        let absolute = std::env::temp_dir().join("custom.policy");
+        crate::testing::with_settings(&[("policy", absolute.to_str().unwrap())], || {
+            assert_eq!(write_policy_path(), absolute);
+        });
+    }
+
+    #[test]
+    fn test_next_generation_number() {
+        let dir = std::env::temp_dir().join(format!("macro_state_gen_{}", std::process::id()));
+        assert_eq!(next_generation_number(&dir).unwrap(), 1);
+        assert_eq!(next_generation_number(&dir).unwrap(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(next_generation_number(&dir).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_divergence_warning() {
+        assert_eq!(
+            divergence_warning("routes", "a\nb\n", "a\nb\n", "my_app"),
+            None
+        );
+        let reordered = divergence_warning("routes", "a\nb\n", "b\na\n", "my_app").unwrap();
+        assert!(reordered.contains("my_app wrote to state key \"routes\""));
+        assert!(reordered.contains("nondeterministic order"));
+        let changed = divergence_warning("routes", "a\nb\n", "a\nc\n", "my_app").unwrap();
+        assert!(changed.contains("holds a different value"));
+    }
+
+    #[test]
+    fn test_concurrent_threads() {
+        let values = ["a".repeat(1000), "b".repeat(2000)];
+        proc_write_state("threaded key", &values[0]).unwrap();
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let values = &values;
+                scope.spawn(move || {
+                    for j in 0..25 {
+                        proc_append_state("threaded list", &format!("{}-{}", i, j)).unwrap();
+                        proc_write_state("threaded key", &values[j % 2]).unwrap();
+                        let value = proc_read_state("threaded key").unwrap();
+                        assert!(values.contains(&value));
+                    }
+                });
+            }
+        });
+        assert_eq!(proc_read_state_vec("threaded list").len(), 200);
+    }
+
+    #[test]
+    fn test_parse_records() {
+        let contents = format!(
+            "legacy\\n\n\\p-2:old\n{}{}{}",
+            encode_list_item("a\nb"),
+            encode_sorted_list_item("c", -1),
+            encode_list_item("d\\n")
+        );
+        assert_eq!(
+            render_records(contents.clone()),
+            "legacy\\n\n\\p-2:old\na\nb\nc\nd\\n\n"
+        );
+        assert_eq!(
+            decode_list(contents),
+            vec!["old", "c", "legacy\n", "a\nb", "d\\n"]
+        );
+
+        // torn records, whether trailing or followed by later appends, are skipped
+        let torn = format!("{}\u{1e}5:ab", encode_list_item("a"));
+        assert_eq!(decode_list(torn.clone()), vec!["a"]);
+        let torn = format!("{}{}", torn, encode_list_item("b"));
+        assert_eq!(decode_list(torn), vec!["a", "b"]);
+        assert_eq!(render_records(String::from("\u{1e}3")), "");
+
+        // a torn record whose length happens to land on a newline fails its checksum
+        let next = encode_list_item("xy");
+        let len = "abc".len() + next.len() - 1;
+        let torn = format!("\u{1e}{}#{:08x}:abc{}", len, record_checksum("abc"), next);
+        assert_eq!(decode_list(torn), vec!["xy"]);
+
+        // a record start that isn't followed by a length is just part of the value
+        assert_eq!(render_records(String::from("a\u{1e}b")), "a\u{1e}b");
+        assert_eq!(render_records(String::from("plain value")), "plain value");
+
+        // written values that look like records are kept verbatim
+        let value = format!("x{}y", encode_list_item("z"));
+        assert_eq!(render_records(encode_value(&value)), value);
+        assert_eq!(
+            decode_list(encode_value(&value)),
+            value.lines().collect::<Vec<_>>()
+        );
+        assert_eq!(encode_value("plain value"), "plain value");
+        proc_write_state("record lookalike", &value).unwrap();
+        assert_eq!(proc_read_state("record lookalike").unwrap(), value);
+        proc_append_state("record lookalike", "w").unwrap();
+        assert_eq!(proc_read_state_vec("record lookalike").last().unwrap(), "w");
+        assert!(proc_read_state("record lookalike")
+            .unwrap()
+            .starts_with(&value));
+    }
+
+    #[test]
+    fn test_state_rows() {
+        append_state_row!("rows list", "users", "/users", "10");
+        append_state_row!("rows list", "a\u{1f}3:b", "", "x\ny");
+        assert_eq!(
+            read_state_rows!("rows list", 3),
+            &[("users", "/users", "10"), ("a\u{1f}3:b", "", "x\ny")]
+        );
+        assert_eq!(read_state_rows!("rows missing", 2).len(), 0);
+
+        proc_append_state_row("proc rows list", &["name", "String"]).unwrap();
+        proc_append_state("proc rows list", "plain").unwrap();
+        proc_append_state("proc rows list", "\u{1f}9:torn").unwrap();
+        assert_eq!(
+            proc_read_state_rows("proc rows list"),
+            vec![vec!["name", "String"], vec!["plain"], vec!["\u{1f}9:torn"]]
+        );
+        assert_eq!(decode_state_row(""), vec![""]);
+    }
+
+    #[test]
+    fn test_state_records() {
+        #[derive(Debug, PartialEq)]
+        struct Route {
+            method: &'static str,
+            path: &'static str,
+            weight: i64,
+        }
+
+        append_state_record!("records list", { method: "GET", path: "/x", weight: -1 });
+        append_state_record!("records list", { path: "/y\n", method: "POST", weight: 2 + 3 });
+        append_state!("records list", "not a record");
+        assert_eq!(
+            read_state_records!("records list", Route),
+            &[
+                Route {
+                    method: "GET",
+                    path: "/x",
+                    weight: -1
+                },
+                Route {
+                    method: "POST",
+                    path: "/y\n",
+                    weight: 5
+                }
+            ]
+        );
+        assert!(read_state_records!("records missing", Route).is_empty());
+
+        proc_append_state_record("proc records list", &[("name", "\"User\"")]).unwrap();
+        proc_append_state_row("proc records list", &["a", "b", "c"]).unwrap();
+        assert_eq!(
+            proc_read_state_records("proc records list"),
+            vec![vec![(String::from("name"), String::from("\"User\""))]]
+        );
+    }
+
+    #[test]
+    fn test_proc_for_each_state() {
+        proc_write_state("each/b", "two").unwrap();
+        proc_append_state("each/a", "one\nline").unwrap();
+        proc_write_state("each/Long ".repeat(20).as_str(), "long").unwrap();
+        proc_write_state("eachother", "skipped").unwrap();
+        let mut entries = Vec::new();
+        proc_for_each_state("each/", |key, value| {
+            entries.push((key.to_string(), value.to_string()))
+        })
+        .unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], ("each/Long ".repeat(20), String::from("long")));
+        assert_eq!(
+            entries[1],
+            (String::from("each/a"), String::from("one\nline\n"))
+        );
+        assert_eq!(entries[2], (String::from("each/b"), String::from("two")));
+
+        let mut reserved = 0;
+        proc_for_each_state("", |key, _| {
+            reserved += usize::from(key.starts_with(RESERVED_KEY_PREFIX))
+        })
+        .unwrap();
+        assert_eq!(reserved, 0);
+    }
+
+    #[test]
+    fn test_read_keys_matching() {
+        write_state!("keys matching/User/fields", "id");
+        write_state!("keys matching/Post/fields", "id");
+        write_state!("keys matching/Post/table", "posts");
+        assert_eq!(
+            read_keys_matching!("keys matching/*/fields"),
+            &["keys matching/Post/fields", "keys matching/User/fields"]
+        );
+        assert!(read_keys_matching!("keys matching/*/missing").is_empty());
+
+        proc_write_state("proc keys matching/Post/fields", "id").unwrap();
+        proc_write_state("proc keys matching/Post/table", "posts").unwrap();
+        assert_eq!(
+            proc_read_keys_matching("proc keys matching/Post/*").unwrap(),
+            vec![
+                "proc keys matching/Post/fields",
+                "proc keys matching/Post/table"
+            ]
+        );
+        assert!(proc_read_keys_matching("__macro_state/**")
+            .unwrap()
+            .iter()
+            .all(|key| key.starts_with(RESERVED_KEY_PREFIX)));
+    }
+
+    #[test]
+    fn test_changes_log() {
+        testing::with_settings(&[("changes_log", "1")], || {
+            proc_write_state("changes probe", "a").unwrap();
+            proc_append_state("changes probe", "b").unwrap();
+            proc_clear_state("changes probe").unwrap();
+            proc_state_transaction(|tx| {
+                tx.write("changes probe", "c");
+                Ok(())
+            })
+            .unwrap();
+        });
+        proc_write_state("changes probe", "d").unwrap();
+        let ops: Vec<StateChangeOp> = proc_read_state_changes()
+            .unwrap()
+            .into_iter()
+            .filter(|change| {
+                change.key == "changes probe" && change.generation == proc_state_generation()
+            })
+            .map(|change| change.op)
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                StateChangeOp::Write,
+                StateChangeOp::Append,
+                StateChangeOp::Remove,
+                StateChangeOp::Write
+            ]
+        );
+        assert!(proc_read_state_changes()
+            .unwrap()
+            .iter()
+            .all(|change| !change.key.starts_with(RESERVED_KEY_PREFIX)));
+    }
+
+    #[test]
+    fn test_record_reads_setting() {
+        proc_write_state("audit setting probe", "value").unwrap();
+        testing::with_settings(&[("record_reads", "1")], || {
+            proc_read_state("audit setting probe").unwrap();
+        });
+        assert!(proc_read_audit().iter().any(|read| {
+            read.key == "audit setting probe" && read.value_hash == Some(stable_hash("value"))
+        }));
+    }
+
+    #[test]
+    fn test_proc_query_journal() {
+        testing::with_settings(&[("journal", "1")], || {
+            proc_write_state("journal probe", "first").unwrap();
+            proc_append_state("journal probe", "item\nline").unwrap();
+            proc_clear_state("journal probe").unwrap();
+        });
+        let history = proc_query_journal("journal probe");
+        let ops: Vec<StateChangeOp> = history.iter().map(|entry| entry.op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                StateChangeOp::Write,
+                StateChangeOp::Append,
+                StateChangeOp::Remove
+            ]
+        );
+        assert!(history[0].is_value("first"));
+        assert!(!history[0].is_value("second"));
+        assert!(history[1].is_value("item\nline\n"));
+        assert_eq!(history[2].value_hash, None);
+        assert!(history[0].writer.starts_with(&format!(
+            "{} (core/src/macro_state_core.rs:",
+            current_crate_name()
+        )));
+        assert!(proc_query_journal("journal missing").is_empty());
+    }
+}
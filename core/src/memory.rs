@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+use crate::config::overridden_setting;
+use crate::{replace_file, retry_io, setting};
+
+lazy_static! {
+    static ref MEMORY_MODE: bool = setting("mode").as_deref() == Some("memory");
+    static ref MEMORY_FILES: Mutex<BTreeMap<PathBuf, MemoryEntry>> = Mutex::new(BTreeMap::new());
+    static ref MEMORY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// The most recent write sequence number handed out in memory mode.
+static MEMORY_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// A file (or an explicitly created directory) within the in-memory store.
+enum MemoryEntry {
+    Dir,
+    File {
+        contents: String,
+        modified: SystemTime,
+    },
+}
+
+/// Returns `true` if memory mode has been requested by setting the `mode` setting (or the
+/// `MACRO_STATE_MODE` environment variable) to `memory`. In memory mode, the files that would
+/// normally make up the state directory are kept in a process-local map instead, so nothing
+/// is ever read from or written to disk and every process starts out with empty state.
+///
+/// Unlike most settings, `mode` is only read once per process, unless it is overridden for the
+/// current thread via [`with_settings`](crate::testing::with_settings), which is how tests
+/// should enable memory mode.
+pub(crate) fn memory_mode() -> bool {
+    match overridden_setting("mode") {
+        Some(mode) => mode == "memory",
+        None => *MEMORY_MODE,
+    }
+}
+
+fn memory_files() -> MutexGuard<'static, BTreeMap<PathBuf, MemoryEntry>> {
+    MEMORY_FILES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("{} does not exist", path.display()),
+    )
+}
+
+/// Acquires the process-local lock standing in for the state directory lock in memory mode.
+pub(crate) fn memory_lock() -> MutexGuard<'static, ()> {
+    MEMORY_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Returns the next write sequence number in memory mode.
+pub(crate) fn memory_next_sequence() -> u64 {
+    MEMORY_SEQUENCE.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Reads the specified file of the state directory.
+pub(crate) fn read_file(path: &Path) -> Result<String> {
+    if !memory_mode() {
+        return retry_io(|| fs::read_to_string(path));
+    }
+    match memory_files().get(path) {
+        Some(MemoryEntry::File { contents, .. }) => Ok(contents.clone()),
+        _ => Err(not_found(path)),
+    }
+}
+
+/// Replaces the contents of the specified file of the state directory (see
+/// [`replace_file`](crate::replace_file)), without creating any missing parent directories.
+pub(crate) fn write_file(path: &Path, contents: &str) -> Result<()> {
+    if !memory_mode() {
+        return replace_file(path, contents.as_bytes());
+    }
+    let entry = MemoryEntry::File {
+        contents: contents.to_string(),
+        modified: SystemTime::now(),
+    };
+    memory_files().insert(path.to_path_buf(), entry);
+    Ok(())
+}
+
+/// Appends to the specified file of the state directory in memory mode, creating it if needed.
+pub(crate) fn memory_append(path: &Path, contents: &str) {
+    let mut files = memory_files();
+    let existing = match files.remove(path) {
+        Some(MemoryEntry::File { contents, .. }) => contents,
+        _ => String::new(),
+    };
+    let entry = MemoryEntry::File {
+        contents: existing + contents,
+        modified: SystemTime::now(),
+    };
+    files.insert(path.to_path_buf(), entry);
+}
+
+/// Creates the specified directory of the state directory, along with any missing parents.
+pub(crate) fn create_dir_all(dir: &Path) -> Result<()> {
+    if !memory_mode() {
+        return fs::create_dir_all(dir);
+    }
+    memory_files()
+        .entry(dir.to_path_buf())
+        .or_insert(MemoryEntry::Dir);
+    Ok(())
+}
+
+/// Returns `true` if the specified file or directory of the state directory exists.
+pub(crate) fn file_exists(path: &Path) -> bool {
+    if !memory_mode() {
+        return path.exists();
+    }
+    let files = memory_files();
+    let next = files.range(path.to_path_buf()..).next();
+    next.is_some_and(|(file, _)| file.starts_with(path))
+}
+
+/// Returns the modification time and length of the specified file of the state directory.
+pub(crate) fn file_stamp(path: &Path) -> Result<(SystemTime, u64)> {
+    if !memory_mode() {
+        let metadata = fs::metadata(path)?;
+        return Ok((metadata.modified()?, metadata.len()));
+    }
+    match memory_files().get(path) {
+        Some(MemoryEntry::File { contents, modified }) => Ok((*modified, contents.len() as u64)),
+        _ => Err(not_found(path)),
+    }
+}
+
+/// Lists the entries of the specified directory of the state directory, along with whether
+/// each of them is a directory itself.
+pub(crate) fn list_dir(dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+    if !memory_mode() {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            entries.push((entry.path(), entry.file_type()?.is_dir()));
+        }
+        return Ok(entries);
+    }
+    let files = memory_files();
+    let mut entries: Vec<(PathBuf, bool)> = Vec::new();
+    let mut found = false;
+    for (path, entry) in files.range(dir.to_path_buf()..) {
+        let Ok(relative) = path.strip_prefix(dir) else {
+            break;
+        };
+        found = true;
+        let mut components = relative.components();
+        let Some(name) = components.next() else {
+            continue;
+        };
+        let is_dir = components.next().is_some() || matches!(entry, MemoryEntry::Dir);
+        if entries
+            .last()
+            .is_some_and(|(last, _)| *last == dir.join(name))
+        {
+            continue;
+        }
+        entries.push((dir.join(name), is_dir));
+    }
+    match found {
+        true => Ok(entries),
+        false => Err(not_found(dir)),
+    }
+}
+
+/// Removes the specified file of the state directory.
+pub(crate) fn remove_file(path: &Path) -> Result<()> {
+    if !memory_mode() {
+        return retry_io(|| fs::remove_file(path));
+    }
+    match memory_files().remove(path) {
+        Some(_) => Ok(()),
+        None => Err(not_found(path)),
+    }
+}
+
+/// Removes the specified directory of the state directory along with everything in it.
+pub(crate) fn remove_dir_all(dir: &Path) -> Result<()> {
+    if !memory_mode() {
+        return retry_io(|| fs::remove_dir_all(dir));
+    }
+    let mut files = memory_files();
+    let len = files.len();
+    files.retain(|path, _| !path.starts_with(dir));
+    match files.len() < len {
+        true => Ok(()),
+        false => Err(not_found(dir)),
+    }
+}
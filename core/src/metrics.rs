@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{
+    generation, memory_append, memory_mode, read_file, read_state_contents, setting_enabled,
+    state_dir, state_file_key, state_files, StateResult, RESERVED_KEY_PREFIX, STATE_FORMAT_VERSION,
+};
+
+/// The number of keys listed in each ranked section of a [state report](proc_state_report).
+const REPORT_LEN: usize = 20;
+
+/// The operation counts and cumulative IO time recorded for a single key, as returned by
+/// [`proc_state_metrics`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateMetrics {
+    /// The key the operations were made on.
+    pub key: String,
+    /// The number of times the value of the key was read.
+    pub reads: u64,
+    /// The number of times the value of the key was replaced.
+    pub writes: u64,
+    /// The number of times an item was appended to the value of the key.
+    pub appends: u64,
+    /// The number of times the key was removed.
+    pub removes: u64,
+    /// The total time spent reading from and writing to the state file of the key.
+    pub io_time: Duration,
+}
+
+/// Returns `true` if metrics have been requested via the `metrics` setting.
+fn metrics_enabled() -> bool {
+    setting_enabled("metrics")
+}
+
+/// Returns the path of the file recording the operations made during the current build.
+fn metrics_log_path() -> PathBuf {
+    let mut path = state_dir();
+    path.push(format!("v{}", STATE_FORMAT_VERSION));
+    path.push(format!("metrics_{}", generation()));
+    path
+}
+
+/// An operation (`read`, `write`, `append`, or `remove`) on a single state file, started via
+/// [`start_op`]. With the `tracing` feature enabled, a `macro_state` span covering the
+/// operation is entered until it is finished or dropped.
+pub(crate) struct StateOp<'a> {
+    path: &'a Path,
+    op: &'static str,
+    started: Instant,
+    #[cfg(feature = "tracing")]
+    span: Option<tracing::span::EnteredSpan>,
+}
+
+/// A `macro_state` span covering a whole call to a proc API function, started via
+/// [`trace_call`] and entered until dropped. Without the `tracing` feature, it does nothing.
+pub(crate) struct CallSpan {
+    #[cfg(feature = "tracing")]
+    _span: tracing::span::EnteredSpan,
+}
+
+/// Starts a [`CallSpan`] for a call to the proc API function named `call`, recording the key,
+/// prefix, or pattern it was called with (if any) as its `arg`. The spans of the individual
+/// state operations made by the call nest within it.
+pub(crate) fn trace_call(call: &'static str, arg: Option<&str>) -> CallSpan {
+    #[cfg(not(feature = "tracing"))]
+    let _ = (call, arg);
+    CallSpan {
+        #[cfg(feature = "tracing")]
+        _span: tracing::debug_span!("macro_state", call, arg).entered(),
+    }
+}
+
+/// Starts timing `op` on the specified state file (see [`StateOp`]).
+pub(crate) fn start_op<'a>(path: &'a Path, op: &'static str) -> StateOp<'a> {
+    StateOp {
+        path,
+        op,
+        started: Instant::now(),
+        #[cfg(feature = "tracing")]
+        span: state_file_key(path)
+            .filter(|key| !key.starts_with(RESERVED_KEY_PREFIX))
+            .map(|key| tracing::debug_span!("macro_state", op, key = %key).entered()),
+    }
+}
+
+impl StateOp<'_> {
+    /// Finishes the operation once it succeeded, recording it if metrics are enabled (see
+    /// [`proc_state_metrics`]) and, with the `tracing` feature enabled, emitting an event with
+    /// the time it took. Failing to record an operation never fails the operation itself.
+    pub(crate) fn finish(self) {
+        let elapsed = self.started.elapsed();
+        #[cfg(feature = "tracing")]
+        if self.span.is_some() {
+            tracing::debug!(elapsed_us = elapsed.as_micros() as u64, "finished");
+        }
+        if !metrics_enabled() {
+            return;
+        }
+        let Some(key) = state_file_key(self.path) else {
+            return;
+        };
+        if key.starts_with(RESERVED_KEY_PREFIX) {
+            return;
+        }
+        let log = metrics_log_path();
+        if memory_mode() {
+            let line = format!("{}\t{}\t{}\n", key, self.op, elapsed.as_nanos());
+            memory_append(&log, &line);
+            return;
+        }
+        buffer_metrics_line(&log, &key, self.op, elapsed);
+    }
+}
+
+/// The lines recorded by the current process that have yet to be appended to the metrics log
+/// they belong to, keyed by the path of that log.
+static METRICS_BUFFER: std::sync::Mutex<std::collections::BTreeMap<std::path::PathBuf, String>> =
+    std::sync::Mutex::new(std::collections::BTreeMap::new());
+
+/// Records that `op` (`read`, `write`, `append`, or `remove`) was made on `key` and took
+/// `elapsed`, as a tab-separated line of the metrics log at `log`. Lines are buffered in memory
+/// and appended to the log all at once when the process exits (or when
+/// [`flush_metrics_buffer`] is called), so that recording metrics costs no IO per operation.
+fn buffer_metrics_line(log: &std::path::Path, key: &str, op: &str, elapsed: std::time::Duration) {
+    static FLUSH_AT_EXIT: std::sync::Once = std::sync::Once::new();
+    FLUSH_AT_EXIT.call_once(|| {
+        extern "C" {
+            fn atexit(callback: extern "C" fn()) -> std::os::raw::c_int;
+        }
+        extern "C" fn flush() {
+            flush_metrics_buffer();
+        }
+        // SAFETY: `flush` is a plain function that remains valid until the process exits
+        unsafe {
+            atexit(flush);
+        }
+    });
+    let line = format!("{}\t{}\t{}\n", key, op, elapsed.as_nanos());
+    METRICS_BUFFER
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .entry(log.to_path_buf())
+        .or_default()
+        .push_str(&line);
+}
+
+/// Appends every line buffered by [`buffer_metrics_line`] to its metrics log, with a single
+/// write per log. Failing to write metrics never fails anything else, so errors are ignored.
+fn flush_metrics_buffer() {
+    let pending = std::mem::take(
+        &mut *METRICS_BUFFER
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner),
+    );
+    for (log, lines) in pending {
+        let _ = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log)
+            .and_then(|mut file| std::io::Write::write_all(&mut file, lines.as_bytes()));
+    }
+}
+
+/// Aggregates the lines of a metrics log, as written by [`buffer_metrics_line`], per key.
+/// Lines that can't be parsed (such as one being written at the same time) are skipped.
+fn aggregate_metrics(log: &str) -> Vec<StateMetrics> {
+    let mut metrics: BTreeMap<&str, StateMetrics> = BTreeMap::new();
+    for line in log.lines() {
+        let mut fields = line.split('\t');
+        let (Some(key), Some(op), Some(nanos), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let Ok(nanos) = nanos.parse::<u64>() else {
+            continue;
+        };
+        let entry = metrics.entry(key).or_insert_with(|| StateMetrics {
+            key: key.to_string(),
+            ..StateMetrics::default()
+        });
+        match op {
+            "read" => entry.reads += 1,
+            "write" => entry.writes += 1,
+            "append" => entry.appends += 1,
+            "remove" => entry.removes += 1,
+            _ => continue,
+        }
+        entry.io_time += Duration::from_nanos(nanos);
+    }
+    let mut metrics: Vec<StateMetrics> = metrics.into_values().collect();
+    metrics.sort_by(|a, b| b.io_time.cmp(&a.io_time).then_with(|| a.key.cmp(&b.key)));
+    metrics
+}
+
+/// Returns the operation counts and cumulative IO time of every key used during the current
+/// build, by any crate, with the keys that took the most IO time first, so that authors of
+/// heavy macro pipelines can find their hot spots.
+///
+/// Metrics are only recorded once the `metrics` setting (or the `MACRO_STATE_METRICS`
+/// environment variable) is set to `1`, `true`, or `yes`, in which case every read, write,
+/// append, and removal of a key is timed. Keys `macro_state` keeps internally are never
+/// included. Each process buffers the operations it makes and appends them to the metrics of
+/// the build once it exits, so the returned metrics cover every process that already finished
+/// (such as the compilations of upstream crates), along with the calling process itself.
+///
+/// If any IO error occurs while reading the recorded metrics, it will be returned as the
+/// [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::testing::with_settings;
+/// use macro_state::*;
+///
+/// with_settings(&[("metrics", "1")], || {
+///     proc_write_state("measured key", "value").unwrap();
+///     proc_read_state("measured key").unwrap();
+/// });
+/// let metrics = proc_state_metrics().unwrap();
+/// let measured = metrics.iter().find(|m| m.key == "measured key").unwrap();
+/// assert_eq!((measured.reads, measured.writes), (1, 1));
+/// ```
+pub fn proc_state_metrics() -> StateResult<Vec<StateMetrics>> {
+    flush_metrics_buffer();
+    match read_file(&metrics_log_path()) {
+        Ok(log) => Ok(aggregate_metrics(&log)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Formats the specified metrics as the contents of a metrics report, with one tab-separated
+/// line per key.
+fn format_metrics_report(metrics: &[StateMetrics]) -> String {
+    let mut report = String::from(
+        "# macro_state metrics: key, reads, writes, appends, removes, IO time (microseconds)\n",
+    );
+    for m in metrics {
+        report.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            m.key,
+            m.reads,
+            m.writes,
+            m.appends,
+            m.removes,
+            m.io_time.as_micros()
+        ));
+    }
+    report
+}
+
+/// Writes the metrics returned by [`proc_state_metrics`] to the file at `path` as an
+/// end-of-build report, with one tab-separated `key`, `reads`, `writes`, `appends`, `removes`,
+/// and `IO time` (in microseconds) line per key, hottest keys first. Call this from the last
+/// macro to expand (or from a build script of a downstream crate) to capture the whole build.
+///
+/// If any IO error occurs, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::testing::with_settings;
+/// use macro_state::*;
+///
+/// with_settings(&[("metrics", "1")], || proc_append_state("measured list", "a")).unwrap();
+/// let path = std::path::Path::new(STATE_DIR).join("metrics.tsv");
+/// proc_export_state_metrics(&path).unwrap();
+/// let report = std::fs::read_to_string(path).unwrap();
+/// assert!(report.contains("measured list\t0\t0\t1\t0\t"));
+/// ```
+pub fn proc_export_state_metrics(path: impl AsRef<Path>) -> StateResult<()> {
+    fs::write(path, format_metrics_report(&proc_state_metrics()?))?;
+    Ok(())
+}
+
+/// Formats `bytes` as a human-readable size, such as `512 B` or `1.5 KiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Formats the contents of a state report (see [`proc_state_report`]) from the size of the
+/// value of every key and the metrics recorded during the build.
+fn format_state_report(sizes: &[(String, u64)], metrics: &[StateMetrics]) -> String {
+    let mut report = format!(
+        "macro_state report\n\n{} keys, {} in total\n",
+        sizes.len(),
+        format_size(sizes.iter().map(|(_, size)| size).sum())
+    );
+
+    let mut largest: Vec<&(String, u64)> = sizes.iter().collect();
+    largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    report.push_str("\nLargest values:\n");
+    for (key, size) in largest.into_iter().take(REPORT_LEN) {
+        report.push_str(&format!("  {:>10}  {}\n", format_size(*size), key));
+    }
+
+    report.push_str("\nMost frequently accessed keys:\n");
+    if metrics.is_empty() {
+        report.push_str("  (none recorded; enable the `metrics` setting to record accesses)\n");
+        return report;
+    }
+    let ops = |m: &StateMetrics| m.reads + m.writes + m.appends + m.removes;
+    let mut hottest: Vec<&StateMetrics> = metrics.iter().collect();
+    hottest.sort_by(|a, b| ops(b).cmp(&ops(a)).then_with(|| a.key.cmp(&b.key)));
+    for m in hottest.into_iter().take(REPORT_LEN) {
+        report.push_str(&format!(
+            "  {:>6} ops ({} reads, {} writes, {} appends, {} removes)  {}\n",
+            ops(m),
+            m.reads,
+            m.writes,
+            m.appends,
+            m.removes,
+            m.key
+        ));
+    }
+
+    report.push_str("\nOrphaned keys (written but never read):\n");
+    let read = |key: &str| metrics.iter().any(|m| m.key == key && m.reads > 0);
+    let mut orphaned = 0;
+    for (key, _) in sizes.iter().filter(|(key, _)| !read(key)) {
+        report.push_str(&format!("  {}\n", key));
+        orphaned += 1;
+    }
+    if orphaned == 0 {
+        report.push_str("  (none)\n");
+    }
+    report
+}
+
+/// Writes a human-readable report of the state of the current build to the file at `path`, to
+/// guide the cleanup of bloated macro pipelines. The report lists the keys with the largest
+/// values, the most frequently accessed keys, and the orphaned keys, which have a value but
+/// were never read during the build.
+///
+/// Accesses are taken from the metrics recorded during the build (see
+/// [`proc_state_metrics`]), so the last two sections are only filled in once the `metrics`
+/// setting is enabled. Like [`proc_export_state_metrics`], this is best called from the last
+/// macro to expand. Keys `macro_state` keeps internally are never included.
+///
+/// If any IO error occurs, it will be returned as the [`Err`] result.
+///
+/// # Example
+/// ```
+/// use macro_state::testing::with_settings;
+/// use macro_state::*;
+///
+/// with_settings(&[("metrics", "1")], || proc_write_state("reported key", "never read")).unwrap();
+/// let path = std::path::Path::new(STATE_DIR).join("report.txt");
+/// proc_state_report(&path).unwrap();
+/// let report = std::fs::read_to_string(path).unwrap();
+/// assert!(report.contains("Orphaned keys (written but never read):"));
+/// assert!(report.contains("  reported key\n"));
+/// ```
+pub fn proc_state_report(path: impl AsRef<Path>) -> StateResult<()> {
+    let mut sizes = Vec::new();
+    for (key, file) in state_files()? {
+        if key.starts_with(RESERVED_KEY_PREFIX) {
+            continue;
+        }
+        match read_state_contents(&file) {
+            Ok(contents) => sizes.push((key, contents.len() as u64)),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let report = format_state_report(&sizes, &proc_state_metrics()?);
+    fs::write(path, report)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregate_metrics() {
+        let metrics = aggregate_metrics(
+            "models\tread\t1000\n\
+            routes\tappend\t5000\n\
+            models\twrite\t3000\n\
+            routes\tappend\t5000\n\
+            torn\tread\n\
+            models\tread\t1000",
+        );
+        assert_eq!(
+            metrics,
+            vec![
+                StateMetrics {
+                    key: String::from("routes"),
+                    appends: 2,
+                    io_time: Duration::from_micros(10),
+                    ..StateMetrics::default()
+                },
+                StateMetrics {
+                    key: String::from("models"),
+                    reads: 2,
+                    writes: 1,
+                    io_time: Duration::from_micros(5),
+                    ..StateMetrics::default()
+                },
+            ]
+        );
+        assert_eq!(
+            format_metrics_report(&metrics[..1]),
+            "# macro_state metrics: key, reads, writes, appends, removes, IO time (microseconds)\n\
+            routes\t0\t0\t2\t0\t10\n"
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_trace_call() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        /// Records the `call` and `arg` of every span created.
+        struct CallLayer(Arc<Mutex<Vec<String>>>);
+
+        struct CallVisitor(Vec<String>);
+
+        impl Visit for CallVisitor {
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.0.push(format!("{}={}", field.name(), value));
+            }
+
+            fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+        }
+
+        impl<S: tracing::Subscriber> Layer<S> for CallLayer {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _id: &tracing::span::Id,
+                _ctx: Context<'_, S>,
+            ) {
+                let mut visitor = CallVisitor(Vec::new());
+                attrs.record(&mut visitor);
+                if visitor.0.iter().any(|field| field.starts_with("call=")) {
+                    self.0.lock().unwrap().push(visitor.0.join(" "));
+                }
+            }
+        }
+
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(CallLayer(spans.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            crate::proc_push_state("traced queue", "a").unwrap();
+            crate::proc_add_to_counter("traced counter", 1).unwrap();
+            crate::proc_read_keys_matching("traced *").unwrap();
+            crate::proc_write_many(&[("traced key", "a")]).unwrap();
+        });
+        assert_eq!(
+            *spans.lock().unwrap(),
+            [
+                "call=proc_push_state arg=traced queue",
+                "call=proc_add_to_counter arg=traced counter",
+                "call=proc_read_keys_matching arg=traced *",
+                "call=StateBatch::commit",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format_state_report() {
+        let sizes = vec![
+            (String::from("models"), 2048),
+            (String::from("routes"), 100),
+            (String::from("unused"), 3),
+        ];
+        let metrics = aggregate_metrics(
+            "models\tread\t1000\n\
+            models\tread\t1000\n\
+            models\twrite\t1000\n\
+            routes\tread\t1000\n\
+            unused\tappend\t1000\n",
+        );
+        assert_eq!(
+            format_state_report(&sizes, &metrics),
+            "macro_state report\n\
+            \n\
+            3 keys, 2.1 KiB in total\n\
+            \n\
+            Largest values:\n\
+            \x20    2.0 KiB  models\n\
+            \x20      100 B  routes\n\
+            \x20        3 B  unused\n\
+            \n\
+            Most frequently accessed keys:\n\
+            \x20      3 ops (2 reads, 1 writes, 0 appends, 0 removes)  models\n\
+            \x20      1 ops (1 reads, 0 writes, 0 appends, 0 removes)  routes\n\
+            \x20      1 ops (0 reads, 0 writes, 1 appends, 0 removes)  unused\n\
+            \n\
+            Orphaned keys (written but never read):\n\
+            \x20 unused\n"
+        );
+        assert!(format_state_report(&sizes, &[]).ends_with(
+            "Most frequently accessed keys:\n  \
+            (none recorded; enable the `metrics` setting to record accesses)\n"
+        ));
+    }
+}
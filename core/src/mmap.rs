@@ -0,0 +1,99 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::ops::Deref;
+
+use memmap2::Mmap;
+
+use crate::{blob_file, memory_mode, retry_io, state_file_path, StateResult};
+
+/// A zero-copy, memory-mapped view of a state value, as returned by [`proc_read_state_mmap`].
+///
+/// Dereferences to the raw bytes of the value. Use [`StateMmap::as_str`] to view the value as
+/// a string slice without copying it.
+pub struct StateMmap {
+    map: Option<Mmap>,
+}
+
+impl StateMmap {
+    /// Returns the mapped state value as a string slice, or an [`Err`] of kind
+    /// [`ErrorKind::InvalidData`] if the value is not valid UTF-8.
+    pub fn as_str(&self) -> StateResult<&str> {
+        Ok(std::str::from_utf8(self).map_err(|e| Error::new(ErrorKind::InvalidData, e))?)
+    }
+}
+
+impl Deref for StateMmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.map {
+            Some(map) => map,
+            None => &[],
+        }
+    }
+}
+
+/// A variant of [`proc_read_state`](crate::proc_read_state) that memory-maps the state file
+/// for the specified `key` instead of reading it into a [`String`]. Should only be used within
+/// proc macros, and is only available with the `mmap` feature enabled.
+///
+/// This cuts peak memory usage considerably for proc macros that embed large (multi-megabyte)
+/// assets via state, since the value is never copied onto the heap.
+///
+/// macro_state never modifies a state file in place: writes replace the file with a new one
+/// that is renamed into place, and appends only ever add bytes past the end of the mapped
+/// range, so the view keeps seeing the value as it was when mapped.
+/// On Windows, however, a file can't be replaced while it is mapped, so writes to `key` fail
+/// until the view has been dropped.
+///
+/// The view holds the raw contents of the state file, so it is only suitable for values that
+/// were written rather than appended to: the items of lists are stored as framed records.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the IO error will
+/// be returned as the [`Err`] result. In memory mode there is no file to map, so an error of
+/// kind [`ErrorKind::Unsupported`] is returned instead.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("large asset", "lots of bytes").unwrap();
+/// let view = proc_read_state_mmap("large asset").unwrap();
+/// assert_eq!(view.as_str().unwrap(), "lots of bytes");
+/// ```
+pub fn proc_read_state_mmap(key: &str) -> StateResult<StateMmap> {
+    if memory_mode() {
+        let msg = "memory-mapped reads are not available in memory mode";
+        return Err(Error::new(ErrorKind::Unsupported, msg).into());
+    }
+    let state_file = state_file_path(key);
+    let path = blob_file(&state_file).unwrap_or(state_file);
+    let file = retry_io(|| File::open(&path))?;
+    if file.metadata()?.len() == 0 {
+        return Ok(StateMmap { map: None });
+    }
+    // SAFETY: macro_state only ever replaces state files via rename or appends to them, and
+    // never truncates or rewrites them in place, so the mapped bytes can't change under us.
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(StateMmap { map: Some(map) })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_proc_read_state_mmap() {
+        assert!(proc_read_state_mmap("mmap missing").is_err());
+        proc_write_state("mmap key", "mapped value").unwrap();
+        let view = proc_read_state_mmap("mmap key").unwrap();
+        assert_eq!(&view[..], b"mapped value");
+        assert_eq!(view.as_str().unwrap(), "mapped value");
+        if cfg!(unix) {
+            proc_write_state("mmap key", "replaced").unwrap();
+            assert_eq!(view.as_str().unwrap(), "mapped value");
+        }
+        proc_write_state("mmap empty", "").unwrap();
+        assert_eq!(proc_read_state_mmap("mmap empty").unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,129 @@
+//! Internals used by the macros of `macro_state` (which live in `macro_state_macros` and are
+//! implemented on top of this crate) that have no public analogue. Not part of the public API,
+//! and may change at any time.
+
+use std::io::Result;
+use std::path::PathBuf;
+
+/// Returns the name of the crate currently being compiled.
+pub fn current_crate_name() -> String {
+    crate::current_crate_name()
+}
+
+/// Checks that `key` may be read from (or, if `write` is `true`, written to) by the crate
+/// currently being compiled.
+pub fn check_key(key: &str, write: bool) -> crate::StateResult<()> {
+    crate::check_key(key, write)
+}
+
+/// Reads the state value for `key` as [`read_state_result!`](crate::read_state_result) sees
+/// it, without consulting the missing-key handler or taking part in read-before-write
+/// diagnostics.
+pub fn read_state_value(key: &str) -> Result<String> {
+    crate::read_state_value(key).map(crate::render_records)
+}
+
+/// Reads the items of the list stored for `key`, in the order described in
+/// [`proc_read_state_vec`](crate::proc_read_state_vec), without consulting the missing-key
+/// handler or taking part in read-before-write diagnostics.
+pub fn read_state_list(key: &str) -> Result<Vec<String>> {
+    crate::read_state_value(key)
+        .map(crate::decode_prioritized_list)
+        .map(crate::canonical_list)
+}
+
+/// Returns `true` if the value stored for `key` holds list items (see
+/// [`proc_append_state`](crate::proc_append_state)).
+pub fn holds_list(key: &str) -> bool {
+    crate::cached_read(&crate::state_file_path(key)).is_ok_and(|raw| {
+        crate::parse_records(&raw)
+            .iter()
+            .any(|segment| matches!(segment, crate::Segment::Record { .. }))
+    })
+}
+
+/// Splits a list item appended via [`proc_append_state_row`](crate::proc_append_state_row)
+/// back into its columns.
+pub fn decode_state_row(item: &str) -> Vec<String> {
+    crate::decode_state_row(item)
+}
+
+/// If read-before-write diagnostics are enabled, records that the read of `key` made by the
+/// macro invocation being expanded found no value.
+pub fn note_missed_read(key: &str) {
+    crate::note_missed_read(key)
+}
+
+/// Reads the list stored for the internal `key`, returning an empty list if there is no value.
+/// The items are listed in the same order as by [`read_state_list`], so that everything
+/// generated from them is as reproducible as the lists of the user.
+pub fn read_items(key: &str) -> Vec<String> {
+    crate::cached_read(&crate::state_file_path(key))
+        .map(crate::decode_prioritized_list)
+        .map(crate::canonical_list)
+        .unwrap_or_default()
+}
+
+/// Appends `value` to the list stored for the internal `key`.
+pub fn append_item(key: &str, value: &str) -> Result<()> {
+    crate::append_state_item(key, value, None)
+}
+
+/// Replaces the list stored for `key` with `items`, taking the same write path as
+/// [`proc_write_state`](crate::proc_write_state). The key and every item are validated (as
+/// [`proc_append_state`](crate::proc_append_state) validates them) before anything is written,
+/// so nothing is written at all if any of them is rejected.
+pub fn write_list(key: &str, items: &[String]) -> crate::StateResult<()> {
+    crate::check_key(key, true)?;
+    for item in items {
+        crate::check_write(key, item, true)?;
+    }
+    crate::queue::write_list(key, items)?;
+    crate::report_missed_reads(key);
+    crate::store_remote_state(key)
+}
+
+/// Runs `op` while holding the exclusive lock over the state directory.
+pub fn with_state_dir_lock<T>(op: impl FnOnce() -> Result<T>) -> Result<T> {
+    let _lock = crate::lock_state_dir()?;
+    op()
+}
+
+/// Appends `entry` to the list stored for the internal `key`, unless it is already present.
+pub fn record_unique_entry(key: &str, entry: &str) -> Result<()> {
+    with_state_dir_lock(|| {
+        if read_items(key).iter().any(|existing| existing == entry) {
+            return Ok(());
+        }
+        append_item(key, entry)
+    })
+}
+
+/// Returns the tokens deferred to `slot` by the crate currently being compiled (see
+/// [`proc_defer_tokens`](crate::proc_defer_tokens)), in the order they were deferred.
+pub fn deferred_tokens(slot: &str) -> Vec<String> {
+    read_items(&crate::deferred_tokens_key(slot))
+}
+
+/// Returns every key that currently has a value in the current generation, sorted
+/// lexicographically.
+pub fn state_keys() -> Result<Vec<String>> {
+    crate::state_keys()
+}
+
+/// Returns `true` if the entire `text` matches the simple regular expression `pattern`, as
+/// used by [`StateConstraint::Pattern`](crate::StateConstraint::Pattern).
+pub fn pattern_matches(pattern: &str, text: &str) -> bool {
+    crate::constraints::pattern_matches(pattern, text)
+}
+
+/// Returns the path of the write policy file, if there is one.
+pub fn write_policy_path() -> Option<PathBuf> {
+    crate::WRITE_POLICY.as_ref().map(|(path, _)| path.clone())
+}
+
+/// Runs the specified file operation, retrying it for as long as it fails with a transient
+/// error.
+pub fn retry_io<T>(op: impl FnMut() -> Result<T>) -> Result<T> {
+    crate::retry_io(op)
+}
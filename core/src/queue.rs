@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::io::Result;
+
+use crate::{
+    append_state_file, cache_invalidate, cache_write, cached_read, check_key, check_write,
+    decode_list, decode_prioritized_list, encode_list_item, encode_prioritized_list, file_exists,
+    lock_state_dir, record_key, remove_state_file, state_file_path, trace_call, write_state_file,
+    StateResult,
+};
+
+/// Reads the list stored for `key`, returning an empty list if there is no value.
+pub(crate) fn read_list(key: &str) -> Vec<String> {
+    match cached_read(&state_file_path(key)) {
+        Ok(value) => decode_list(value),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Like [`read_list`], but keeps the sort priority of each item (see
+/// [`proc_append_state_sorted`](crate::proc_append_state_sorted)).
+fn read_prioritized_list(key: &str) -> Vec<(i64, String)> {
+    match cached_read(&state_file_path(key)) {
+        Ok(value) => decode_prioritized_list(value),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Replaces the list stored for `key` with `items`, removing the key entirely if there are no
+/// items left.
+pub(crate) fn write_list(key: &str, items: &[String]) -> Result<()> {
+    let items: Vec<(i64, String)> = items.iter().map(|item| (0, item.clone())).collect();
+    write_prioritized_list(key, &items)
+}
+
+/// Like [`write_list`], but keeps the sort priority of each item, so that rewriting a list in
+/// place never changes the order in which it is read back.
+fn write_prioritized_list(key: &str, items: &[(i64, String)]) -> Result<()> {
+    let state_file = state_file_path(key);
+    if items.is_empty() {
+        cache_invalidate(&state_file);
+        if file_exists(&state_file) {
+            remove_state_file(&state_file, None)?;
+        }
+        return Ok(());
+    }
+    let value = encode_prioritized_list(items);
+    record_key(&state_file, key)?;
+    write_state_file(&state_file, &value, None)?;
+    cache_write(&state_file, &value);
+    Ok(())
+}
+
+/// An analogue for [`push_state!`] that should only be used within proc macros.
+///
+/// Pushes `value` onto the back of the FIFO queue stored for `key` while holding an exclusive
+/// lock over the state directory. Queues are regular list state, so they can also be read via
+/// [`proc_read_state_vec`](crate::proc_read_state_vec).
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_push_state("my queue", "first").unwrap();
+/// proc_push_state("my queue", "second").unwrap();
+/// assert_eq!(proc_read_state_vec("my queue"), vec!["first", "second"]);
+/// ```
+pub fn proc_push_state(key: &str, value: &str) -> StateResult<()> {
+    let _span = trace_call("proc_push_state", Some(key));
+    check_write(key, value, true)?;
+    let _lock = lock_state_dir()?;
+    let state_file = state_file_path(key);
+    record_key(&state_file, key)?;
+    cache_invalidate(&state_file);
+    Ok(append_state_file(
+        &state_file,
+        &encode_list_item(value),
+        None,
+    )?)
+}
+
+/// An analogue for [`dequeue_state!`] that should only be used within proc macros.
+///
+/// Removes and returns the item at the front of the FIFO queue stored for `key`, or [`None`] if
+/// the queue is empty. The read and the removal happen under a single exclusive lock, so two
+/// consumers can never dequeue the same item.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_push_state("my jobs", "a").unwrap();
+/// proc_push_state("my jobs", "b").unwrap();
+/// assert_eq!(proc_dequeue_state("my jobs").unwrap(), Some(String::from("a")));
+/// assert_eq!(proc_dequeue_state("my jobs").unwrap(), Some(String::from("b")));
+/// assert_eq!(proc_dequeue_state("my jobs").unwrap(), None);
+/// ```
+pub fn proc_dequeue_state(key: &str) -> StateResult<Option<String>> {
+    let _span = trace_call("proc_dequeue_state", Some(key));
+    check_key(key, true)?;
+    let _lock = lock_state_dir()?;
+    let mut items = read_prioritized_list(key);
+    if items.is_empty() {
+        return Ok(None);
+    }
+    let (_, item) = items.remove(0);
+    write_prioritized_list(key, &items)?;
+    Ok(Some(item))
+}
+
+/// An analogue for [`drain_state!`] that should only be used within proc macros.
+///
+/// Removes and returns every item of the FIFO queue stored for `key`, in the order they were
+/// pushed. The read and the removal happen under a single exclusive lock, so items pushed
+/// concurrently are either returned by this call or left in the queue, never lost.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_push_state("my work", "a").unwrap();
+/// proc_push_state("my work", "b").unwrap();
+/// assert_eq!(proc_drain_state("my work").unwrap(), vec!["a", "b"]);
+/// assert!(proc_drain_state("my work").unwrap().is_empty());
+/// ```
+pub fn proc_drain_state(key: &str) -> StateResult<Vec<String>> {
+    let _span = trace_call("proc_drain_state", Some(key));
+    check_key(key, true)?;
+    let _lock = lock_state_dir()?;
+    let items = read_list(key);
+    write_list(key, &[])?;
+    Ok(items)
+}
+
+/// An analogue for [`dedup_state!`] that should only be used within proc macros.
+///
+/// Removes duplicate items from the list stored for `key` in place, keeping the first
+/// occurrence of each item, while holding an exclusive lock over the state directory. If
+/// `sort` is `true`, the remaining items are also sorted lexicographically. This is useful for
+/// normalizing registries built up by many independent appends before they are consumed.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_append_state("my types", "u8").unwrap();
+/// proc_append_state("my types", "bool").unwrap();
+/// proc_append_state("my types", "u8").unwrap();
+/// proc_dedup_state("my types", false).unwrap();
+/// assert_eq!(proc_read_state_vec("my types"), vec!["u8", "bool"]);
+/// proc_dedup_state("my types", true).unwrap();
+/// assert_eq!(proc_read_state_vec("my types"), vec!["bool", "u8"]);
+/// ```
+pub fn proc_dedup_state(key: &str, sort: bool) -> StateResult<()> {
+    let _span = trace_call("proc_dedup_state", Some(key));
+    check_key(key, true)?;
+    let _lock = lock_state_dir()?;
+    let mut items = read_prioritized_list(key);
+    let mut seen = HashSet::new();
+    items.retain(|(_, item)| seen.insert(item.clone()));
+    if sort {
+        items.sort();
+    }
+    Ok(write_prioritized_list(key, &items)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_state_queue() {
+        proc_push_state("queue test", "one").unwrap();
+        proc_push_state("queue test", "two\nlines").unwrap();
+        proc_push_state("queue test", "three").unwrap();
+        assert_eq!(
+            proc_dequeue_state("queue test").unwrap().as_deref(),
+            Some("one")
+        );
+        assert_eq!(
+            proc_drain_state("queue test").unwrap(),
+            vec!["two\nlines", "three"]
+        );
+        assert!(!proc_has_state("queue test"));
+        assert_eq!(proc_dequeue_state("queue test").unwrap(), None);
+
+        push_state!("macro queue test", "a");
+        push_state!("macro queue test", "b");
+        push_state!("macro queue test", "c");
+        assert_eq!(dequeue_state!("macro queue test"), Some("a"));
+        assert_eq!(drain_state!("macro queue test"), vec!["b", "c"]);
+        assert_eq!(drain_state!("macro queue test"), Vec::<String>::new());
+        assert_eq!(dequeue_state!("macro queue test"), None);
+    }
+
+    #[test]
+    fn test_dedup_state() {
+        proc_append_state("dedup test", "b").unwrap();
+        proc_append_state("dedup test", "a").unwrap();
+        proc_append_state("dedup test", "b").unwrap();
+        proc_append_state("dedup test", "c").unwrap();
+        proc_append_state("dedup test", "a").unwrap();
+        proc_dedup_state("dedup test", false).unwrap();
+        assert_eq!(proc_read_state_vec("dedup test"), vec!["b", "a", "c"]);
+        proc_dedup_state("dedup test", true).unwrap();
+        assert_eq!(proc_read_state_vec("dedup test"), vec!["a", "b", "c"]);
+
+        append_state!("macro dedup test", "y");
+        append_state!("macro dedup test", "x");
+        append_state!("macro dedup test", "y");
+        dedup_state!("macro dedup test");
+        assert_eq!(read_state_vec!("macro dedup test"), vec!["y", "x"]);
+        dedup_state!("macro dedup test", sort);
+        assert_eq!(read_state_vec!("macro dedup test"), vec!["x", "y"]);
+    }
+
+    #[test]
+    fn test_rewritten_list_keeps_priorities() {
+        proc_append_state_sorted("prioritized queue", "late", 10).unwrap();
+        proc_append_state_sorted("prioritized queue", "early", -10).unwrap();
+        proc_append_state("prioritized queue", "middle").unwrap();
+        proc_append_state("prioritized queue", "middle").unwrap();
+        proc_dedup_state("prioritized queue", false).unwrap();
+        assert_eq!(
+            proc_dequeue_state("prioritized queue").unwrap().as_deref(),
+            Some("early")
+        );
+        proc_append_state_sorted("prioritized queue", "first", i64::MIN).unwrap();
+        proc_append_state("prioritized queue", "after middle").unwrap();
+        assert_eq!(
+            proc_read_state_vec("prioritized queue"),
+            vec!["first", "middle", "after middle", "late"]
+        );
+
+        append_state_sorted!("macro prioritized queue", "late", 10);
+        append_state!("macro prioritized queue", "middle");
+        append_state_sorted!("macro prioritized queue", "early", -9223372036854775808);
+        assert_eq!(dequeue_state!("macro prioritized queue"), Some("early"));
+        append_state!("macro prioritized queue", "after middle");
+        assert_eq!(
+            read_state_vec!("macro prioritized queue"),
+            vec!["middle", "after middle", "late"]
+        );
+    }
+}
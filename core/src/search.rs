@@ -0,0 +1,165 @@
+#[cfg(feature = "regex")]
+use std::io::{Error, ErrorKind};
+
+use crate::{proc_for_each_state, state_keys, trace_call, StateResult, RESERVED_KEY_PREFIX};
+
+/// Returns the key and value of every key whose value satisfies `predicate`, which is called
+/// with each key and its value, sorted by key. Keys are visited via [`proc_for_each_state`],
+/// so only the matching entries are kept in memory.
+///
+/// This allows consumer macros to locate entries by content (for example, to find out which
+/// model declared a given column) without reading every key manually.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("find models/User", "id\nuser_id\nname").unwrap();
+/// proc_write_state("find models/Post", "id\ntitle").unwrap();
+/// let found = proc_find_state(|key, value| {
+///     key.starts_with("find models/") && value.lines().any(|column| column == "user_id")
+/// })
+/// .unwrap();
+/// assert_eq!(found.len(), 1);
+/// assert_eq!(found[0].0, "find models/User");
+/// ```
+pub fn proc_find_state<F: FnMut(&str, &str) -> bool>(
+    mut predicate: F,
+) -> StateResult<Vec<(String, String)>> {
+    let _span = trace_call("proc_find_state", None);
+    let mut found = Vec::new();
+    proc_for_each_state("", |key, value| {
+        if predicate(key, value) {
+            found.push((key.to_string(), value.to_string()));
+        }
+    })?;
+    Ok(found)
+}
+
+/// Like [`proc_find_state`], but returns every key whose value has a line matching the
+/// regular expression `pattern`, much like `grep`. Only available with the `regex` feature.
+///
+/// Patterns use the syntax of the [`regex`](https://docs.rs/regex) crate, which matches in
+/// time linear in the length of each line whatever the pattern, so no pattern can stall the
+/// build. The `^` and `$` anchors match at the start and end of each line.
+///
+/// If `pattern` is invalid, an [`ErrorKind::InvalidInput`] error is returned.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("grep models/Account", "id\nowner_id: u64").unwrap();
+/// let found = proc_grep_state(r"^\w+_id: u\d+$").unwrap();
+/// assert!(found.iter().any(|(key, _)| key == "grep models/Account"));
+/// assert!(proc_grep_state("(unclosed").is_err());
+/// ```
+#[cfg(feature = "regex")]
+pub fn proc_grep_state(pattern: &str) -> StateResult<Vec<(String, String)>> {
+    let _span = trace_call("proc_grep_state", Some(pattern));
+    let regex = regex::Regex::new(pattern).map_err(|reason| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid pattern \"{}\": {}", pattern, reason),
+        )
+    })?;
+    proc_find_state(|_, value| value.lines().any(|line| regex.is_match(line)))
+}
+
+/// An analogue for [`read_keys_matching!`](crate::read_keys_matching) that should only be used
+/// within proc macros.
+///
+/// Returns every key that currently has a value and matches the glob `pattern`, sorted
+/// lexicographically, so that consumers that only know the shape of producer-generated keys
+/// can find them. Keys are matched segment by segment, where segments are separated by `/`.
+/// Within a segment, `*` matches any sequence of characters and `?` matches any single
+/// character, while a `**` segment matches any number of segments. Keys starting with
+/// [`RESERVED_KEY_PREFIX`] are only returned if `pattern` starts with it too.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_write_state("glob models/User/fields", "id").unwrap();
+/// proc_write_state("glob models/Post/fields", "title").unwrap();
+/// proc_write_state("glob models/Post/table", "posts").unwrap();
+/// assert_eq!(
+///     proc_read_keys_matching("glob models/*/fields").unwrap(),
+///     vec!["glob models/Post/fields", "glob models/User/fields"]
+/// );
+/// assert_eq!(proc_read_keys_matching("glob models/**").unwrap().len(), 3);
+/// ```
+pub fn proc_read_keys_matching(pattern: &str) -> StateResult<Vec<String>> {
+    let _span = trace_call("proc_read_keys_matching", Some(pattern));
+    let reserved = pattern.starts_with(RESERVED_KEY_PREFIX);
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let mut keys = state_keys()?;
+    keys.retain(|key| {
+        let segments: Vec<&str> = key.split('/').collect();
+        (reserved || !key.starts_with(RESERVED_KEY_PREFIX)) && glob_matches(&pattern, &segments)
+    });
+    Ok(keys)
+}
+
+fn glob_segment_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| glob_segment_matches(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && glob_segment_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_segment_matches(rest, &name[1..]),
+    }
+}
+
+/// Returns `true` if the segments of a key match the segments of a glob pattern, as described
+/// in [`proc_read_keys_matching`].
+fn glob_matches(pattern: &[&str], segments: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => segments.is_empty(),
+        Some((&"**", rest)) => (0..=segments.len()).any(|i| glob_matches(rest, &segments[i..])),
+        Some((pattern_segment, rest)) => match segments.split_first() {
+            Some((segment, segments_rest)) => {
+                let pattern_segment: Vec<char> = pattern_segment.chars().collect();
+                let segment: Vec<char> = segment.chars().collect();
+                glob_segment_matches(&pattern_segment, &segment)
+                    && glob_matches(rest, segments_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_grep_state() {
+        crate::proc_write_state("grep routes", "get_users\npost_users").unwrap();
+        let found = proc_grep_state("^(get|post)_[a-z]+$").unwrap();
+        assert!(found.iter().any(|(key, _)| key == "grep routes"));
+        assert!(proc_grep_state("^put_").unwrap().is_empty());
+        crate::proc_write_state("grep pathological", &"a".repeat(10_000)).unwrap();
+        let found = proc_grep_state("^(a|a)*b$").unwrap();
+        assert!(!found.iter().any(|(key, _)| key == "grep pathological"));
+        let err = proc_grep_state("(a").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        let matches = |pattern: &str, key: &str| {
+            let pattern: Vec<&str> = pattern.split('/').collect();
+            let segments: Vec<&str> = key.split('/').collect();
+            glob_matches(&pattern, &segments)
+        };
+        assert!(matches("models/*/fields", "models/User/fields"));
+        assert!(!matches("models/*/fields", "models/User/Admin/fields"));
+        assert!(matches("models/**/fields", "models/User/Admin/fields"));
+        assert!(matches("models/**/fields", "models/fields"));
+        assert!(matches("models/U?er", "models/User"));
+        assert!(!matches("models/*", "models"));
+        assert!(matches("*", "config"));
+        assert!(!matches("*", "models/User"));
+    }
+}
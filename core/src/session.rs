@@ -0,0 +1,322 @@
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use crate::{
+    append_state_file, cached_read, canonical_list, check_file_write, check_key, create_dir_all,
+    current_crate_name, decode_prioritized_list, encode_filename, encode_list_item, encode_value,
+    file_exists, key_filename, remove_dir_all, render_records, state_dir, write_state_file,
+    MacroStateError, StateResult, STATE_FORMAT_VERSION,
+};
+
+/// Determines the kind of target being compiled from the arguments the compiler was invoked
+/// with: `test` for test harnesses (built with `--test`), and otherwise the crate type (such as
+/// `lib`, `bin`, or `proc-macro`), or `unknown` outside of a compiler process.
+fn target_kind(args: impl IntoIterator<Item = String>) -> String {
+    let mut kind = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--test" {
+            return String::from("test");
+        }
+        if arg == "--crate-type" {
+            kind = args.next();
+        } else if let Some(crate_type) = arg.strip_prefix("--crate-type=") {
+            kind = Some(crate_type.to_string());
+        }
+    }
+    kind.unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Returns the compilation unit sessions are scoped to: the crate currently being compiled,
+/// followed by `.` and the kind of its target (see [`target_kind`]). Cargo compiles every
+/// target of a package (such as its library and its tests) in a separate, possibly concurrent,
+/// compiler process, so two targets beginning a session of the same name never share it.
+fn compilation_unit() -> String {
+    let crate_name = std::env::var("CARGO_CRATE_NAME").unwrap_or_else(|_| current_crate_name());
+    format!("{}.{}", crate_name, target_kind(std::env::args().skip(1)))
+}
+
+/// Returns the directory holding all state for the specified session within the current
+/// generation and [`compilation_unit`].
+fn session_dir(name: &str) -> PathBuf {
+    let mut buf = PathBuf::new();
+    buf.push(state_dir());
+    buf.push(format!("v{}", STATE_FORMAT_VERSION));
+    buf.push("sessions");
+    buf.push(encode_filename(&compilation_unit()));
+    buf.push(format!(
+        "{}_{}",
+        encode_filename(name),
+        crate::proc_state_generation()
+    ));
+    buf
+}
+
+fn session_not_active(name: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("state session \"{}\" is not active", name),
+    )
+}
+
+/// A handle to an isolated sub-store of state that exists for a bounded phase of macro
+/// expansion, as created by [`proc_state_session_begin`]. Should only be used within proc
+/// macros.
+///
+/// Keys written through a session never collide with keys in the main store (or in other
+/// sessions), and everything written through a session is removed when the session ends,
+/// keeping intermediate scratch data from polluting the main store. Sessions are scoped to the
+/// target being compiled, so the library and the tests of a crate never share a session.
+///
+/// The handle returned by [`proc_state_session_begin`] ends the session when it is dropped
+/// without [`end`](StateSession::end) having been called (for example because the macro
+/// returned early with an error), unless it was [`detach`](StateSession::detach)ed. Handles
+/// returned by [`proc_state_session`] never end the session on their own.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// let session = proc_state_session_begin("codegen pass").unwrap();
+/// session.write("scratch", "temporary").unwrap();
+/// assert_eq!(session.read("scratch").unwrap(), "temporary");
+/// assert!(!proc_has_state("scratch"));
+/// session.end().unwrap();
+/// ```
+pub struct StateSession {
+    name: String,
+    owned: bool,
+}
+
+impl StateSession {
+    /// Returns the name of this session.
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn file_path(&self, key: &str) -> PathBuf {
+        session_dir(self.name.as_str()).join(format!("macro_state_{}", key_filename(key)))
+    }
+
+    /// Writes `value` to `key` within this session, analogous to
+    /// [`proc_write_state`](crate::proc_write_state).
+    pub fn write(&self, key: &str, value: &str) -> StateResult<()> {
+        check_key(key, false)?;
+        Ok(write_state_file(
+            &self.file_path(key),
+            &encode_value(value),
+            None,
+        )?)
+    }
+
+    /// Appends `value` to the list stored at `key` within this session, analogous to
+    /// [`proc_append_state`](crate::proc_append_state).
+    pub fn append(&self, key: &str, value: &str) -> StateResult<()> {
+        check_key(key, false)?;
+        Ok(append_state_file(
+            &self.file_path(key),
+            &encode_list_item(value),
+            None,
+        )?)
+    }
+
+    /// Reads the value of `key` within this session, analogous to
+    /// [`proc_read_state`](crate::proc_read_state).
+    pub fn read(&self, key: &str) -> StateResult<String> {
+        self.read_contents(key).map(render_records)
+    }
+
+    /// Reads the raw contents of the state file of `key` within this session.
+    fn read_contents(&self, key: &str) -> StateResult<String> {
+        cached_read(&self.file_path(key)).map_err(|e| MacroStateError::for_key(key, e))
+    }
+
+    /// Reads the list stored at `key` within this session, analogous to
+    /// [`proc_read_state_vec`](crate::proc_read_state_vec).
+    pub fn read_vec(&self, key: &str) -> Vec<String> {
+        match self.read_contents(key) {
+            Ok(value) => canonical_list(decode_prioritized_list(value)),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Returns `true` if a value exists for `key` within this session, analogous to
+    /// [`proc_has_state`](crate::proc_has_state).
+    pub fn has(&self, key: &str) -> bool {
+        file_exists(&self.file_path(key))
+    }
+
+    /// Ends this session, removing everything that was written to it. Equivalent to calling
+    /// [`proc_state_session_end`] with the name of this session.
+    pub fn end(mut self) -> StateResult<()> {
+        self.owned = false;
+        proc_state_session_end(self.name.as_str())
+    }
+
+    /// Leaves the session active once this handle is dropped, so that it can span several
+    /// macro invocations until it is ended via [`proc_state_session_end`].
+    pub fn detach(mut self) {
+        self.owned = false;
+    }
+}
+
+impl Drop for StateSession {
+    fn drop(&mut self) {
+        if self.owned {
+            // there is no way to report a failure from here, and the session belongs to the
+            // current generation anyway, so it is removed along with it at worst
+            let _ = proc_state_session_end(self.name.as_str());
+        }
+    }
+}
+
+/// An analogue for [`state_session_begin!`](crate::state_session_begin) that should only be
+/// used within proc macros.
+///
+/// Begins a new state session with the specified `name`, returning a [`StateSession`] handle
+/// that can be used to read and write the isolated sub-store belonging to the session.
+///
+/// Returns an [`Err`] of kind [`ErrorKind::AlreadyExists`] if a session with the same name is
+/// already active, which usually indicates a missing call to [`proc_state_session_end`].
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// let session = proc_state_session_begin("my session").unwrap();
+/// assert!(proc_state_session_begin("my session").is_err());
+/// session.end().unwrap();
+/// ```
+pub fn proc_state_session_begin(name: &str) -> StateResult<StateSession> {
+    let dir = session_dir(name);
+    if file_exists(&dir) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("state session \"{}\" is already active", name),
+        )
+        .into());
+    }
+    check_file_write(&dir)?;
+    create_dir_all(&dir)?;
+    Ok(StateSession {
+        name: name.to_string(),
+        owned: true,
+    })
+}
+
+/// Returns a [`StateSession`] handle for the already-active session with the specified
+/// `name`, such as a session begun by a different macro invocation.
+///
+/// Returns an [`Err`] of kind [`ErrorKind::NotFound`] if no such session is active.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_state_session_begin("shared session").unwrap().detach();
+/// let session = proc_state_session("shared session").unwrap();
+/// session.write("key", "value").unwrap();
+/// proc_state_session_end("shared session").unwrap();
+/// assert!(proc_state_session("shared session").is_err());
+/// ```
+pub fn proc_state_session(name: &str) -> StateResult<StateSession> {
+    if !file_exists(&session_dir(name)) {
+        return Err(session_not_active(name).into());
+    }
+    Ok(StateSession {
+        name: name.to_string(),
+        owned: false,
+    })
+}
+
+/// An analogue for [`state_session_end!`](crate::state_session_end) that should only be used
+/// within proc macros.
+///
+/// Ends the active session with the specified `name`, removing everything that was written to
+/// it.
+///
+/// Returns an [`Err`] of kind [`ErrorKind::NotFound`] if no such session is active.
+pub fn proc_state_session_end(name: &str) -> StateResult<()> {
+    let dir = session_dir(name);
+    if !file_exists(&dir) {
+        return Err(session_not_active(name).into());
+    }
+    check_file_write(&dir)?;
+    Ok(remove_dir_all(&dir)?)
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_session_state() {
+        state_session_begin!("macro session");
+        write_session_state!("macro session", "key", "scratch");
+        append_session_state!("macro session", "list", "a");
+        append_session_state!("macro session", "list", "b");
+        assert_eq!(read_session_state!("macro session", "key"), "scratch");
+        assert_eq!(read_session_state!("macro session", "list"), "a\nb\n");
+        assert_eq!(has_state!("key"), false);
+        state_session_end!("macro session");
+    }
+
+    #[test]
+    fn test_proc_state_session() {
+        assert!(proc_state_session("proc session").is_err());
+        assert!(proc_state_session_end("proc session").is_err());
+        let session = proc_state_session_begin("proc session").unwrap();
+        assert!(proc_state_session_begin("proc session").is_err());
+        session.write("proc session key", "value").unwrap();
+        session.append("proc session list", "one").unwrap();
+        session.append("proc session list", "two").unwrap();
+        assert!(session.has("proc session key"));
+        assert!(!proc_has_state("proc session key"));
+        assert_eq!(session.read("proc session key").unwrap(), "value");
+        assert_eq!(session.read_vec("proc session list"), vec!["one", "two"]);
+        let reopened = proc_state_session("proc session").unwrap();
+        assert_eq!(reopened.name(), "proc session");
+        assert_eq!(reopened.read("proc session key").unwrap(), "value");
+        session.end().unwrap();
+        assert!(proc_state_session("proc session").is_err());
+        let session = proc_state_session_begin("proc session").unwrap();
+        assert!(!session.has("proc session key"));
+        session.end().unwrap();
+    }
+
+    #[test]
+    fn test_state_session_drop() {
+        {
+            let session = proc_state_session_begin("dropped session").unwrap();
+            session.write("dropped key", "value").unwrap();
+            drop(proc_state_session("dropped session").unwrap());
+            assert!(session.has("dropped key"));
+        }
+        assert!(proc_state_session("dropped session").is_err());
+        proc_state_session_begin("detached session")
+            .unwrap()
+            .detach();
+        assert!(proc_state_session("detached session").is_ok());
+        proc_state_session_end("detached session").unwrap();
+    }
+
+    #[test]
+    fn test_target_kind() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            target_kind(args(&["--crate-type", "lib", "src/lib.rs"])),
+            "lib"
+        );
+        assert_eq!(
+            target_kind(args(&["--crate-type=proc-macro"])),
+            "proc-macro"
+        );
+        assert_eq!(
+            target_kind(args(&["--crate-type", "bin", "--test"])),
+            "test"
+        );
+        assert_eq!(target_kind(args(&["src/main.rs"])), "unknown");
+    }
+}
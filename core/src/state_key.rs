@@ -0,0 +1,173 @@
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{
+    cache_write, check_write, lock_state_dir, proc_read_state, proc_write_state, read_state_value,
+    record_key, report_missed_reads, state_file_path, write_state_value, MacroStateError,
+    StateResult,
+};
+
+/// A strongly typed handle to a state key whose value is a `T`, stored as JSON. Should only be
+/// used within proc macros.
+///
+/// Declaring each key once as a [`StateKey`] constant and sharing it between the macros that
+/// read and write it rules out misspelled keys and mismatched value formats, since both are
+/// checked by the compiler rather than discovered at expansion time.
+///
+/// Values are stored as regular state values, so they can also be read via
+/// [`proc_read_state`] (as JSON).
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// const ROUTES: StateKey<Vec<(String, u16)>> = StateKey::new("typed routes");
+///
+/// ROUTES.set(&vec![(String::from("/"), 200)]).unwrap();
+/// ROUTES
+///     .update(|routes| routes.push((String::from("/missing"), 404)))
+///     .unwrap();
+/// assert_eq!(ROUTES.get().unwrap()[1], (String::from("/missing"), 404));
+/// ```
+pub struct StateKey<T> {
+    key: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> StateKey<T> {
+    /// Creates a handle to the state key `key`, whose value is a `T`.
+    pub const fn new(key: &'static str) -> Self {
+        StateKey {
+            key,
+            _value: PhantomData,
+        }
+    }
+
+    /// Returns the underlying string key.
+    pub const fn key(&self) -> &'static str {
+        self.key
+    }
+}
+
+impl<T: DeserializeOwned> StateKey<T> {
+    /// Reads and deserializes the value of this key, analogous to
+    /// [`proc_read_state`](crate::proc_read_state).
+    ///
+    /// Returns a [`MacroStateError::Corrupted`] error if the stored value is not a valid `T`.
+    #[track_caller]
+    pub fn get(&self) -> StateResult<T> {
+        let value = proc_read_state(self.key)?;
+        self.decode(&value)
+    }
+
+    /// Updates the value of this key in place by applying `f` to it, starting from
+    /// [`T::default()`](Default::default) if the key has no value yet. The read and the write
+    /// happen under a single exclusive lock, so concurrent updates are never lost.
+    pub fn update<F>(&self, f: F) -> StateResult<()>
+    where
+        T: Serialize + Default,
+        F: FnOnce(&mut T),
+    {
+        let _lock = lock_state_dir()?;
+        let mut value = match read_state_value(self.key) {
+            Ok(value) => self.decode(&value)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => T::default(),
+            Err(e) => return Err(MacroStateError::for_key(self.key, e)),
+        };
+        f(&mut value);
+        let value = self.encode(&value)?;
+        check_write(self.key, &value, false)?;
+        let state_file = state_file_path(self.key);
+        record_key(&state_file, self.key)?;
+        write_state_value(&state_file, &value, None)?;
+        cache_write(&state_file, &value);
+        report_missed_reads(self.key);
+        Ok(())
+    }
+
+    fn decode(&self, value: &str) -> StateResult<T> {
+        serde_json::from_str(value).map_err(|e| MacroStateError::Corrupted {
+            key: self.key.to_string(),
+            reason: format!("not a valid {}: {}", std::any::type_name::<T>(), e),
+        })
+    }
+}
+
+impl<T: Serialize> StateKey<T> {
+    /// Serializes `value` and writes it to this key, analogous to
+    /// [`proc_write_state`](crate::proc_write_state).
+    #[track_caller]
+    pub fn set(&self, value: &T) -> StateResult<()> {
+        proc_write_state(self.key, &self.encode(value)?)
+    }
+
+    fn encode(&self, value: &T) -> StateResult<String> {
+        serde_json::to_string(value).map_err(|e| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the value for key \"{}\" could not be serialized: {}",
+                    self.key, e
+                ),
+            )
+            .into()
+        })
+    }
+}
+
+impl<T> Clone for StateKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for StateKey<T> {}
+
+impl<T> std::fmt::Debug for StateKey<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("StateKey").field(&self.key).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::collections::BTreeMap;
+
+    const MODELS: StateKey<BTreeMap<String, Vec<String>>> = StateKey::new("typed models");
+
+    #[test]
+    fn test_state_key() {
+        assert_eq!(MODELS.key(), "typed models");
+        assert!(matches!(
+            MODELS.get(),
+            Err(MacroStateError::KeyNotFound { .. })
+        ));
+        MODELS
+            .update(|models| {
+                models.insert(String::from("User"), vec![String::from("id")]);
+            })
+            .unwrap();
+        MODELS
+            .update(|models| models.get_mut("User").unwrap().push(String::from("email")))
+            .unwrap();
+        assert_eq!(MODELS.get().unwrap()["User"], vec!["id", "email"]);
+        assert_eq!(
+            proc_read_state("typed models").unwrap(),
+            r#"{"User":["id","email"]}"#
+        );
+
+        let count: StateKey<u32> = StateKey::new("typed count");
+        count.set(&7).unwrap();
+        assert_eq!(count.get().unwrap(), 7);
+        proc_write_state("typed count", "seven").unwrap();
+        assert!(matches!(
+            count.get(),
+            Err(MacroStateError::Corrupted { .. })
+        ));
+        assert!(count.update(|count| *count += 1).is_err());
+    }
+}
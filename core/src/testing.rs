@@ -0,0 +1,440 @@
+//! Utilities for testing proc macro logic that reads and writes state.
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::SETTING_OVERRIDES;
+use crate::{
+    now_nanos, proc_append_state, proc_has_state, proc_read_state, proc_read_state_vec,
+    proc_write_state, remove_dir_all,
+};
+
+/// A state directory (and optionally a generation) the current thread uses in place of the
+/// regular ones.
+#[derive(Clone)]
+struct StateOverride {
+    dir: PathBuf,
+    generation: Option<u128>,
+}
+
+thread_local! {
+    static STATE_OVERRIDE: RefCell<Option<StateOverride>> = const { RefCell::new(None) };
+}
+
+/// Distinguishes the temporary state directories created by a single process.
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a fresh path within the system temporary directory named after `prefix`.
+fn temp_state_dir(prefix: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "{}_{}_{}",
+        prefix,
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Returns the state directory the current thread uses in place of the regular one, if any.
+/// Threads that are already exiting use the regular one.
+pub(crate) fn overridden_state_dir() -> Option<PathBuf> {
+    let dir = STATE_OVERRIDE.try_with(|state| state.borrow().as_ref().map(|s| s.dir.clone()));
+    dir.ok().flatten()
+}
+
+/// Returns the generation the current thread uses in place of the regular one, if any.
+/// Threads that are already exiting use the regular one.
+pub(crate) fn overridden_generation() -> Option<u128> {
+    let generation =
+        STATE_OVERRIDE.try_with(|state| state.borrow().as_ref().and_then(|s| s.generation));
+    generation.ok().flatten()
+}
+
+/// Restores the previous [`StateOverride`] of the current thread when dropped, even if the
+/// closure the override was installed for panics, removing the overriding directory if
+/// `remove` is `true`.
+struct OverrideGuard {
+    previous: Option<StateOverride>,
+    remove: Option<PathBuf>,
+}
+
+impl Drop for OverrideGuard {
+    fn drop(&mut self) {
+        STATE_OVERRIDE.with(|state| *state.borrow_mut() = self.previous.take());
+        if let Some(dir) = &self.remove {
+            let _ = remove_dir_all(dir);
+        }
+    }
+}
+
+/// Runs `f` with `state` installed as the [`StateOverride`] of the current thread.
+fn with_override<T, F: FnOnce() -> T>(state: StateOverride, remove: bool, f: F) -> T {
+    let remove = remove.then(|| state.dir.clone());
+    let previous = STATE_OVERRIDE.with(|current| current.replace(Some(state)));
+    let _guard = OverrideGuard { previous, remove };
+    f()
+}
+
+/// Runs `f` with every state operation made by the current thread redirected to a fresh,
+/// empty state directory, which is removed once `f` returns (or panics). Tests exercising the
+/// `proc_*` functions can wrap themselves in this (or use the
+/// [`#[isolated_state]`](macro@isolated_state) attribute) so that they can't interfere with
+/// each other, even when run in parallel.
+///
+/// Only the current thread is affected, so threads spawned by `f` keep using the regular state
+/// directory. Calls can be nested, in which case the inner call gets its own directory. Note
+/// that macros such as [`write_state!`](crate::write_state) run at compile time, and are
+/// therefore never isolated.
+///
+/// # Example
+/// ```
+/// use macro_state::testing::with_isolated_state;
+/// use macro_state::*;
+///
+/// proc_write_state("isolated key", "outside").unwrap();
+/// with_isolated_state(|| {
+///     assert!(!proc_has_state("isolated key"));
+///     proc_write_state("isolated key", "inside").unwrap();
+///     assert_eq!(proc_read_state("isolated key").unwrap(), "inside");
+/// });
+/// assert_eq!(proc_read_state("isolated key").unwrap(), "outside");
+/// ```
+pub fn with_isolated_state<T, F: FnOnce() -> T>(f: F) -> T {
+    let state = StateOverride {
+        dir: temp_state_dir("macro_state_isolated"),
+        generation: None,
+    };
+    with_override(state, true, f)
+}
+
+/// Returns `true` if the current thread is running within [`with_isolated_state`].
+///
+/// # Example
+/// ```
+/// use macro_state::testing::*;
+///
+/// assert!(!is_state_isolated());
+/// with_isolated_state(|| assert!(is_state_isolated()));
+/// ```
+pub fn is_state_isolated() -> bool {
+    overridden_state_dir().is_some()
+}
+
+/// Removes the settings pushed by [`with_settings`] when dropped, even if the closure they were
+/// pushed for panics.
+struct SettingsGuard {
+    len: usize,
+}
+
+impl Drop for SettingsGuard {
+    fn drop(&mut self) {
+        SETTING_OVERRIDES.with(|overrides| overrides.borrow_mut().truncate(self.len));
+    }
+}
+
+/// Runs `f` with each of the specified settings (as `(name, value)` pairs, where `name` is the
+/// name used in `macro_state.toml`) overridden for the current thread, taking precedence over
+/// both `macro_state.toml` and the `MACRO_STATE_<NAME>` environment variables. This is the way
+/// for tests to exercise behavior gated by a setting, since modifying the environment of a
+/// multithreaded test binary is unsound and would affect every other test as well.
+///
+/// Settings that are only read once per process, such as `mode` and `state_dir`, can't be
+/// overridden this way. Calls can be nested, with inner overrides taking precedence.
+///
+/// # Example
+/// ```
+/// use macro_state::testing::*;
+/// use macro_state::*;
+///
+/// with_isolated_state(|| {
+///     with_settings(&[("read_only", "*")], || {
+///         assert!(proc_write_state("settings key", "denied").is_err());
+///     });
+///     proc_write_state("settings key", "allowed").unwrap();
+/// });
+/// ```
+pub fn with_settings<T, F: FnOnce() -> T>(settings: &[(&str, &str)], f: F) -> T {
+    let len = SETTING_OVERRIDES.with(|overrides| {
+        let mut overrides = overrides.borrow_mut();
+        let len = overrides.len();
+        let settings = settings
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        overrides.extend(settings);
+        len
+    });
+    let _guard = SettingsGuard { len };
+    f()
+}
+
+/// A state directory for end-to-end tests of producer/consumer macro pipelines, such as
+/// [trybuild](https://docs.rs/trybuild) pass and fail cases. Seed the state the fixture crates
+/// expect, pass [`StateFixture::env`] to the builds spawned by the test (or call
+/// [`StateFixture::apply_env`] for tools such as trybuild that spawn builds themselves), run the
+/// builds, and then assert on the state their macros left behind.
+///
+/// Every build sharing the fixture reads and writes a single generation of state, regardless
+/// of its workspace, and the directory is removed once the fixture is dropped. Fixtures store
+/// state on disk so that it can be shared with the builds, so they can't be used in memory
+/// mode.
+///
+/// The seeding and assertion methods panic on failure, since they are meant for tests.
+///
+/// # Example
+/// ```
+/// use macro_state::testing::StateFixture;
+///
+/// let mut fixture = StateFixture::new();
+/// fixture
+///     .seed("fixture models", "User")
+///     .seed_items("fixture routes", &["/", "/users"]);
+/// // SAFETY: this is the only thread of the doctest
+/// let env = unsafe { fixture.apply_env() };
+/// // run `trybuild::TestCases` here; this stands in for a fixture crate's macro
+/// fixture.with(|| macro_state::proc_append_state("fixture routes", "/posts").unwrap());
+/// fixture.assert_state("fixture models", "User");
+/// fixture.assert_state_items("fixture routes", &["/", "/users", "/posts"]);
+/// fixture.assert_no_state("fixture posts");
+/// drop(env);
+/// ```
+pub struct StateFixture {
+    state: StateOverride,
+}
+
+impl StateFixture {
+    /// Creates a fixture backed by a fresh, empty directory within the system temporary
+    /// directory.
+    pub fn new() -> Self {
+        StateFixture {
+            state: StateOverride {
+                dir: temp_state_dir("macro_state_fixture"),
+                generation: Some(now_nanos()),
+            },
+        }
+    }
+
+    /// Returns the directory holding the state of this fixture.
+    pub fn dir(&self) -> &Path {
+        &self.state.dir
+    }
+
+    /// Returns the environment variables that make a build use this fixture.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        vec![
+            (
+                "MACRO_STATE_STATE_DIR",
+                self.state.dir.to_string_lossy().to_string(),
+            ),
+            ("MACRO_STATE_SHARED", String::from("1")),
+            (
+                "MACRO_STATE_GENERATION",
+                self.state.generation.unwrap_or_default().to_string(),
+            ),
+        ]
+    }
+
+    /// Sets the [environment variables](StateFixture::env) of this fixture for the current
+    /// process until the returned guard is dropped, so that every build it spawns in the
+    /// meantime (such as the ones run by trybuild) uses the fixture. The state of the current
+    /// process itself is unaffected. Builds spawned via [`std::process::Command`] should be
+    /// passed [`StateFixture::env`] instead, which doesn't touch the environment of the process.
+    ///
+    /// # Safety
+    ///
+    /// Modifying the environment of a process races with every other thread reading or writing
+    /// it, including through C libraries, so no other thread may run until the guard has been
+    /// dropped. Test harnesses run tests on several threads at once, so tests calling this
+    /// should be run with `--test-threads=1` or live in a test binary of their own.
+    pub unsafe fn apply_env(&self) -> FixtureEnv {
+        lazy_static::initialize(&crate::STATE_ROOT);
+        lazy_static::initialize(&crate::GENERATION);
+        let mut previous = Vec::new();
+        for (name, value) in self.env() {
+            previous.push((name, std::env::var_os(name)));
+            std::env::set_var(name, value);
+        }
+        FixtureEnv { previous }
+    }
+
+    /// Runs `f` with every state operation made by the current thread redirected to this
+    /// fixture.
+    pub fn with<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        with_override(self.state.clone(), false, f)
+    }
+
+    /// Writes `value` to `key`.
+    #[track_caller]
+    pub fn seed(&mut self, key: &str, value: &str) -> &mut Self {
+        if let Err(e) = self.with(|| proc_write_state(key, value)) {
+            panic!("failed to seed key \"{}\": {}", key, e);
+        }
+        self
+    }
+
+    /// Appends each of the `items` to the list stored at `key`.
+    #[track_caller]
+    pub fn seed_items(&mut self, key: &str, items: &[&str]) -> &mut Self {
+        for item in items {
+            if let Err(e) = self.with(|| proc_append_state(key, item)) {
+                panic!("failed to seed key \"{}\": {}", key, e);
+            }
+        }
+        self
+    }
+
+    /// Writes the contents of the file at `path` to `key`.
+    #[track_caller]
+    pub fn seed_file(&mut self, key: &str, path: impl AsRef<Path>) -> &mut Self {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(value) => self.seed(key, &value),
+            Err(e) => panic!("failed to read seed file {}: {}", path.display(), e),
+        }
+    }
+
+    /// Asserts that the value of `key` is `expected`.
+    #[track_caller]
+    pub fn assert_state(&self, key: &str, expected: &str) {
+        match self.with(|| proc_read_state(key)) {
+            Ok(value) => assert_eq!(value, expected, "unexpected value for key \"{}\"", key),
+            Err(e) => panic!("expected key \"{}\" to have a value: {}", key, e),
+        }
+    }
+
+    /// Asserts that the list stored at `key` holds exactly the `expected` items.
+    #[track_caller]
+    pub fn assert_state_items(&self, key: &str, expected: &[&str]) {
+        let items = self.with(|| proc_read_state_vec(key));
+        assert_eq!(items, expected, "unexpected items for key \"{}\"", key);
+    }
+
+    /// Asserts that `key` has no value.
+    #[track_caller]
+    pub fn assert_no_state(&self, key: &str) {
+        if self.with(|| proc_has_state(key)) {
+            panic!("expected key \"{}\" to have no value", key);
+        }
+    }
+}
+
+/// Restores the environment variables set by [`StateFixture::apply_env`] to their previous
+/// values when dropped.
+#[must_use = "the environment is restored as soon as the guard is dropped"]
+pub struct FixtureEnv {
+    previous: Vec<(&'static str, Option<OsString>)>,
+}
+
+impl Drop for FixtureEnv {
+    fn drop(&mut self) {
+        for (name, value) in self.previous.drain(..).rev() {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+}
+
+impl Default for StateFixture {
+    fn default() -> Self {
+        StateFixture::new()
+    }
+}
+
+impl Drop for StateFixture {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.state.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_with_isolated_state() {
+        proc_write_state("isolation probe", "shared").unwrap();
+        let dir = with_isolated_state(|| {
+            assert!(!proc_has_state("isolation probe"));
+            proc_append_state("isolation probe", "a").unwrap();
+            let inner = with_isolated_state(|| {
+                assert!(!proc_has_state("isolation probe"));
+                proc_state_dir()
+            });
+            assert!(!inner.exists());
+            assert_eq!(proc_read_state_vec("isolation probe"), vec!["a"]);
+            proc_state_dir()
+        });
+        assert!(!dir.exists());
+        assert_eq!(proc_read_state("isolation probe").unwrap(), "shared");
+
+        let result = std::panic::catch_unwind(|| {
+            with_isolated_state(|| {
+                proc_write_state("isolation probe", "panicked").unwrap();
+                panic!("test failure");
+            })
+        });
+        assert!(result.is_err());
+        assert!(!is_state_isolated());
+        assert_eq!(proc_read_state("isolation probe").unwrap(), "shared");
+    }
+
+    #[test]
+    fn test_with_settings() {
+        assert_eq!(setting("max_value_len"), None);
+        with_settings(&[("max_value_len", "4"), ("log", "1")], || {
+            with_settings(&[("max_value_len", "8")], || {
+                assert_eq!(setting("max_value_len").as_deref(), Some("8"));
+                assert!(setting_enabled("log"));
+            });
+            assert_eq!(setting("max_value_len").as_deref(), Some("4"));
+            let result = std::panic::catch_unwind(|| {
+                with_settings(&[("max_value_len", "16")], || panic!("test failure"))
+            });
+            assert!(result.is_err());
+            assert_eq!(setting("max_value_len").as_deref(), Some("4"));
+        });
+        assert_eq!(setting("max_value_len"), None);
+    }
+
+    #[test]
+    fn test_with_memory_mode() {
+        if memory_mode() {
+            return;
+        }
+        with_settings(&[("mode", "memory")], || {
+            proc_write_state("memory mode key", "in memory").unwrap();
+            assert_eq!(proc_read_state("memory mode key").unwrap(), "in memory");
+            assert!(!state_file_path("memory mode key").exists());
+        });
+        assert!(!proc_has_state("memory mode key"));
+    }
+
+    #[test]
+    fn test_state_fixture() {
+        let dir;
+        {
+            let mut fixture = StateFixture::new();
+            dir = fixture.dir().to_path_buf();
+            fixture
+                .seed("fixture input", "seeded")
+                .seed_items("fixture list", &["a", "b"]);
+            assert!(!proc_has_state("fixture input"));
+            let generation = fixture.with(proc_state_generation);
+            assert_ne!(generation, proc_state_generation());
+            assert!(fixture
+                .env()
+                .contains(&("MACRO_STATE_GENERATION", generation.to_string())));
+            fixture.with(|| proc_write_state("fixture output", "done").unwrap());
+            fixture.assert_state("fixture input", "seeded");
+            fixture.assert_state("fixture output", "done");
+            fixture.assert_state_items("fixture list", &["a", "b"]);
+            fixture.assert_no_state("fixture missing");
+            let result = std::panic::catch_unwind(|| fixture.assert_state("fixture input", "x"));
+            assert!(result.is_err());
+        }
+        assert!(!dir.exists());
+    }
+}
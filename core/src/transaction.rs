@@ -0,0 +1,312 @@
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use crate::batch::{check_ops, coalesce, BatchOp};
+use crate::{
+    cache_invalidate, cache_write, cached_read, check_file_write, check_key, claim_state_file,
+    create_dir_all, decode_list, file_exists, lock_state_dir, metadata_file_path, note_change,
+    read_file, record_key, record_write, remove_file, remove_state_file, render_records,
+    reserve_sequences, state_file_path, write_file, MacroStateError, StateChangeOp, StateResult,
+};
+
+/// A set of staged state changes that are applied atomically once the closure passed to
+/// [`proc_state_transaction`] returns successfully. Should only be used within proc macros.
+///
+/// Reads performed through a transaction observe the changes staged so far, while the rest of
+/// the store continues to observe the state as it was before the transaction began.
+pub struct StateTransaction {
+    ops: Vec<BatchOp>,
+}
+
+impl StateTransaction {
+    /// Stages a write of `value` to `key`, analogous to
+    /// [`proc_write_state`](crate::proc_write_state).
+    pub fn write(&mut self, key: &str, value: &str) -> &mut Self {
+        self.ops
+            .push(BatchOp::Write(key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Stages an append of `value` to `key`, analogous to
+    /// [`proc_append_state`](crate::proc_append_state).
+    pub fn append(&mut self, key: &str, value: &str) -> &mut Self {
+        self.ops
+            .push(BatchOp::Append(key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Stages a clear of `key`, analogous to [`proc_clear_state`](crate::proc_clear_state).
+    pub fn clear(&mut self, key: &str) -> &mut Self {
+        self.ops.push(BatchOp::Clear(key.to_string()));
+        self
+    }
+
+    /// Reads the value of `key` as it would be if the transaction were committed right now,
+    /// analogous to [`proc_read_state`](crate::proc_read_state).
+    pub fn read(&self, key: &str) -> StateResult<String> {
+        self.read_contents(key).map(render_records)
+    }
+
+    /// Reads the raw contents `key` would have if the transaction were committed right now.
+    fn read_contents(&self, key: &str) -> StateResult<String> {
+        let ops = self
+            .ops
+            .iter()
+            .filter(|op| match op {
+                BatchOp::Write(k, _) | BatchOp::Append(k, _) | BatchOp::Clear(k) => k == key,
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let existing = read_existing(key);
+        match coalesce(ops).pop() {
+            Some((_, pending)) => pending
+                .resolve(existing.as_ref().ok().map(|value| value.as_str()))
+                .ok_or_else(|| {
+                    existing
+                        .err()
+                        .unwrap_or_else(|| MacroStateError::KeyNotFound {
+                            key: key.to_string(),
+                        })
+                }),
+            None => existing,
+        }
+    }
+
+    /// Reads the list stored at `key` as it would be if the transaction were committed right
+    /// now, analogous to [`proc_read_state_vec`](crate::proc_read_state_vec).
+    pub fn read_vec(&self, key: &str) -> Vec<String> {
+        match self.read_contents(key) {
+            Ok(value) => decode_list(value),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// Reads the raw contents of the state file for `key` as they are on disk. Unlike reads made
+/// outside of a transaction, neither the remote backend nor the missing-key handler is
+/// consulted, since whatever they supply would otherwise be committed as the stored value.
+fn read_existing(key: &str) -> StateResult<String> {
+    check_key(key, false)?;
+    cached_read(&state_file_path(key)).map_err(|e| MacroStateError::for_key(key, e))
+}
+
+/// Runs the specified closure against a new [`StateTransaction`], committing all of the
+/// changes it staged under the state directory lock if (and only if) the closure returns
+/// [`Ok`]. Should only be used within proc macros.
+///
+/// If the closure returns an [`Err`] or panics, none of the staged changes are applied, so the
+/// store is never left in a partially-updated state. Likewise, if an IO error occurs while
+/// committing, any changes that were already made (including their metadata and write
+/// sequence numbers) are rolled back before the error is returned.
+///
+/// Commits are all-or-nothing with respect to other transactions and batches (see
+/// [`StateBatch`](crate::StateBatch)), which take the same lock. Plain writes such as
+/// [`proc_write_state`](crate::proc_write_state) don't, so they may observe (or interleave
+/// with) a commit in progress.
+///
+/// # Example
+/// ```
+/// use macro_state::*;
+///
+/// proc_state_transaction(|tx| {
+///     tx.write("tx table", "users");
+///     tx.append("tx columns", "id");
+///     tx.append("tx columns", "email");
+///     Ok(())
+/// })
+/// .unwrap();
+/// assert_eq!(proc_read_state("tx table").unwrap(), "users");
+///
+/// let result: StateResult<()> = proc_state_transaction(|tx| {
+///     tx.write("tx table", "posts");
+///     Err(std::io::Error::other("something went wrong").into())
+/// });
+/// assert!(result.is_err());
+/// assert_eq!(proc_read_state("tx table").unwrap(), "users");
+/// ```
+pub fn proc_state_transaction<T, F>(f: F) -> StateResult<T>
+where
+    F: FnOnce(&mut StateTransaction) -> StateResult<T>,
+{
+    let mut tx = StateTransaction { ops: Vec::new() };
+    let result = f(&mut tx)?;
+    check_ops(&tx.ops)?;
+    let _lock = lock_state_dir()?;
+    // resolve final values and stage them next to their destinations
+    let mut staged: Vec<(String, PathBuf, Option<String>)> = Vec::new();
+    for (key, pending) in coalesce(tx.ops) {
+        let existing = read_existing(key.as_str()).ok();
+        let path = state_file_path(key.as_str());
+        let value = pending.resolve(existing.as_deref());
+        staged.push((key, path, value));
+    }
+    for (_, path, _) in &staged {
+        check_file_write(path)?;
+    }
+    let sequences = reserve_sequences(staged.len())?;
+    let mut backups: Vec<Backup> = Vec::new();
+    for (key, path, value) in &staged {
+        let path = match value {
+            Some(_) => claim_state_file(path),
+            None => Ok(path.clone()),
+        };
+        let applied = path.and_then(|path| {
+            backups.push(Backup::of(&path));
+            if value.is_some() {
+                record_key(&path, key)?;
+            }
+            apply(&path, value.as_deref())
+        });
+        if let Err(e) = applied {
+            rollback(backups);
+            let _ = sequences.rewind();
+            return Err(e.into());
+        }
+    }
+    Ok(result)
+}
+
+/// The contents of a state file and of its metadata file before a transaction touched them,
+/// [`None`] standing for a file that didn't exist.
+struct Backup {
+    path: PathBuf,
+    contents: Option<String>,
+    metadata: Option<String>,
+}
+
+impl Backup {
+    fn of(path: &Path) -> Backup {
+        Backup {
+            path: path.to_path_buf(),
+            contents: read_file(path).ok(),
+            metadata: read_file(&metadata_file_path(path)).ok(),
+        }
+    }
+}
+
+fn apply(path: &Path, value: Option<&str>) -> Result<()> {
+    match value {
+        Some(value) => {
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            let existed = file_exists(path);
+            write_file(path, value)?;
+            record_write(path, existed)?;
+            note_change(path, StateChangeOp::Write, value, None);
+            cache_write(path, value);
+        }
+        None => {
+            cache_invalidate(path);
+            if file_exists(path) {
+                remove_state_file(path, None)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn rollback(backups: Vec<Backup>) {
+    for backup in backups.into_iter().rev() {
+        let path = backup.path.as_path();
+        cache_invalidate(path);
+        let (result, op) = match &backup.contents {
+            Some(contents) => (write_file(path, contents), StateChangeOp::Write),
+            None => (restore_missing(path), StateChangeOp::Remove),
+        };
+        let metadata = metadata_file_path(path);
+        let _ = match &backup.metadata {
+            Some(contents) => write_file(&metadata, contents),
+            None => restore_missing(&metadata),
+        };
+        if result.is_ok() {
+            note_change(
+                path,
+                op,
+                backup.contents.as_deref().unwrap_or_default(),
+                None,
+            );
+        }
+    }
+}
+
+/// Removes the file at `path`, which didn't exist before the transaction, if it exists now.
+fn restore_missing(path: &Path) -> Result<()> {
+    match remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::io::Error;
+
+    #[test]
+    fn test_proc_state_transaction() {
+        proc_write_state("tx existing", "old").unwrap();
+        let value = proc_state_transaction(|tx| {
+            tx.write("tx existing", "new").append("tx list", "a");
+            assert_eq!(tx.read("tx existing").unwrap(), "new");
+            assert_eq!(proc_read_state("tx existing").unwrap(), "old");
+            tx.append("tx list", "b").clear("tx missing");
+            assert_eq!(tx.read_vec("tx list"), vec!["a", "b"]);
+            assert!(tx.read("tx missing").is_err());
+            Ok(17)
+        })
+        .unwrap();
+        assert_eq!(value, 17);
+        assert_eq!(proc_read_state("tx existing").unwrap(), "new");
+        assert_eq!(proc_read_state_vec("tx list"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_proc_state_transaction_rollback() {
+        proc_write_state("tx rollback", "before").unwrap();
+        let result: StateResult<()> = proc_state_transaction(|tx| {
+            tx.write("tx rollback", "after");
+            tx.write("tx rollback new", "value");
+            Err(Error::other("abort").into())
+        });
+        assert!(result.is_err());
+        assert_eq!(proc_read_state("tx rollback").unwrap(), "before");
+        assert!(!proc_has_state("tx rollback new"));
+        let panicked = std::panic::catch_unwind(|| {
+            proc_state_transaction::<(), _>(|tx| {
+                tx.write("tx rollback", "panicked");
+                panic!("macro panicked");
+            })
+        });
+        assert!(panicked.is_err());
+        assert_eq!(proc_read_state("tx rollback").unwrap(), "before");
+    }
+
+    #[test]
+    fn test_proc_state_transaction_failed_commit() {
+        testing::with_isolated_state(|| {
+            testing::with_settings(&[("sequence", "1")], || {
+                proc_write_state("tx failed", "before").unwrap();
+                let metadata_path = metadata_file_path(&state_file_path("tx failed"));
+                let metadata = read_file(&metadata_path).unwrap();
+                let sequence = proc_state_sequence("tx failed").unwrap();
+                // a file in place of the directory of the second key fails the commit midway
+                let blocker = state_file_path("tx failed/child");
+                let blocker = blocker.parent().unwrap();
+                std::fs::create_dir_all(blocker.parent().unwrap()).unwrap();
+                std::fs::write(blocker, "").unwrap();
+                let result = proc_state_transaction(|tx| {
+                    tx.write("tx failed", "after")
+                        .write("tx failed/child", "value");
+                    Ok(())
+                });
+                assert!(result.is_err());
+                assert_eq!(proc_read_state("tx failed").unwrap(), "before");
+                assert_eq!(read_file(&metadata_path).unwrap(), metadata);
+                assert_eq!(proc_state_sequence("tx failed").unwrap(), sequence);
+                proc_write_state("tx failed next", "value").unwrap();
+                assert_eq!(proc_state_sequence("tx failed next").unwrap(), sequence + 1);
+            });
+        });
+    }
+}
@@ -0,0 +1,9 @@
+-- the initial users table
+CREATE TABLE IF NOT EXISTS "users" (
+    id INTEGER PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    legacy_flag BOOLEAN,
+    CONSTRAINT users_name_unique UNIQUE (name)
+);
+
+CREATE TABLE sessions (id INTEGER, token TEXT);
@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN email TEXT;
+ALTER TABLE users DROP COLUMN legacy_flag;
+ALTER TABLE users RENAME COLUMN name TO display_name;
+DROP TABLE sessions;
@@ -0,0 +1,53 @@
+use macro_state::*;
+
+// memory mode is resolved once per process, so this binary holds a single test that enables it
+// before touching any state
+#[test]
+fn test_memory_mode() {
+    std::env::set_var("MACRO_STATE_MODE", "memory");
+    std::env::set_var("MACRO_STATE_SEQUENCE", "1");
+
+    proc_write_state("memory models", "User").unwrap();
+    proc_append_state("memory routes", "/").unwrap();
+    proc_append_state("memory routes", "/users").unwrap();
+    assert_eq!(proc_read_state("memory models").unwrap(), "User");
+    assert_eq!(proc_read_state_vec("memory routes"), vec!["/", "/users"]);
+    assert!(!state_file_path("memory models").exists());
+    assert!(!state_file_path("memory routes").exists());
+
+    let metadata = proc_state_metadata("memory models").unwrap();
+    assert_eq!(metadata.size, 4);
+    assert!(
+        proc_state_sequence("memory models").unwrap()
+            < proc_state_sequence("memory routes").unwrap()
+    );
+    match proc_read_state("memory model") {
+        Err(e @ MacroStateError::KeyNotFound { .. }) => {
+            assert_eq!(e.similar_keys()[0], "memory models")
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    proc_state_transaction(|tx| {
+        tx.write("memory models", "Post").clear("memory routes");
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(proc_read_state("memory models").unwrap(), "Post");
+    assert!(!proc_has_state("memory routes"));
+
+    let session = proc_state_session_begin("memory session").unwrap();
+    session.write("draft", "value").unwrap();
+    assert!(session.has("draft"));
+    session.end().unwrap();
+    assert!(proc_state_session("memory session").is_err());
+
+    let large = "x".repeat(1 << 16);
+    proc_write_state("memory large", &large).unwrap();
+    assert_eq!(proc_read_state("memory large").unwrap(), large);
+
+    proc_clear_state("memory models").unwrap();
+    assert!(!proc_has_state("memory models"));
+    proc_clear_crate_state("macro_state_core").unwrap();
+    assert!(!proc_has_state("memory large"));
+}
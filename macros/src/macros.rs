@@ -1,7 +1,12 @@
-extern crate proc_macro;
+//! The macros of `macro_state`, which should be used via that crate rather than directly.
+//!
+//! # Expansion order
+//!
+//! State is only visible to macros expanded after it was written, so macros that read
+//! everything written to a key (such as the `emit_*` macros) should be invoked after every
+//! macro contributing to it, typically at the very bottom of the crate.
 
-#[macro_use]
-extern crate lazy_static;
+extern crate proc_macro;
 
 extern crate quote;
 extern crate syn;
@@ -9,36 +14,48 @@ extern crate syn;
 extern crate derive_syn_parse;
 use derive_syn_parse::Parse;
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{Error, Write};
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use macro_state_core::__private;
+use macro_state_core::*;
 use proc_macro::TokenStream;
-use quote::quote;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::token::Comma;
-use syn::{parse_macro_input, LitStr};
+use syn::{parse_macro_input, Ident, Item, LitInt, LitStr, Token};
 
-lazy_static! {
-    static ref COMPILE_TIME: u128 = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos();
+/// Wraps `expr`, an expression that allocates, in a block bringing the `alloc` crate into scope
+/// as `__alloc`. Every crate can name `alloc` this way, so the expansion works in `std` and
+/// `#![no_std]` crates alike, whatever features are enabled.
+fn with_alloc(expr: impl quote::ToTokens) -> impl quote::ToTokens {
+    quote!({
+        extern crate alloc as __alloc;
+        #expr
+    })
 }
 
-fn state_file_path(key: &str) -> PathBuf {
-    let ctime = COMPILE_TIME.clone();
-    let filename = format!("macro_state_{}_{}", key, ctime);
-    let mut buf = PathBuf::new();
-    buf.push(env!("MACRO_STATE_DIR"));
-    buf.push(filename.as_str());
-    buf
+/// Converts an error returned by `macro_state_core` into a compile error at the call site.
+fn quote_io_error(e: impl std::fmt::Display) -> TokenStream {
+    let msg = e.to_string();
+    quote!(::core::compile_error!(#msg)).into()
 }
 
-fn quote_io_error(e: Error) -> TokenStream {
-    let msg = e.to_string();
-    quote!(compile_error!(#msg)).into()
+/// Converts an error returned by `macro_state_core` for `key` (and `value`, if any) into a
+/// compile error, spanned at the key or the value if that is what was rejected.
+fn state_error(e: MacroStateError, key: &LitStr, value: Option<&LitStr>) -> TokenStream {
+    let span = match (&e, value) {
+        (MacroStateError::InvalidKey { .. } | MacroStateError::WriteDenied { .. }, _) => key.span(),
+        (MacroStateError::InvalidValue { .. }, Some(value)) => value.span(),
+        _ => return quote_io_error(e),
+    };
+    syn::Error::new(span, e.to_string())
+        .to_compile_error()
+        .into()
 }
 
 #[derive(Parse)]
@@ -66,19 +83,16 @@ struct WriteStateInput {
 #[proc_macro]
 pub fn write_state(items: TokenStream) -> TokenStream {
     let args = parse_macro_input!(items as WriteStateInput);
-    let state_file = state_file_path(args.key.value().as_str());
-    match File::create(state_file) {
-        Ok(mut file) => match file.write_all(args.value.value().as_bytes()) {
-            Ok(_) => quote!().into(),
-            Err(e) => quote_io_error(e),
-        },
-        Err(e) => quote_io_error(e),
+    match proc_write_state(&args.key.value(), &args.value.value()) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
     }
 }
 
-/// Like [`write_state!`], but instead appends the specified `value` (newline-delimited) to the
-/// state file. Newlines contained in the `value` are automatically escaped so you can think of
-/// this as appending to a [`Vec<String>`] for all intents and purposes. Calling [`append_state!`]
+/// Like [`write_state!`], but instead appends the specified `value` to the state file as a
+/// length-prefixed record. The `value` is stored verbatim and may contain anything, including
+/// newlines, so you can think of this as appending to a [`Vec<String>`] for all intents and
+/// purposes. Calling [`append_state!`]
 /// is also more efficient than re-writing an entire state file via [`write_state!`] since the
 /// low level append IO option is not used by [`write_state!`].
 ///
@@ -99,19 +113,37 @@ pub fn write_state(items: TokenStream) -> TokenStream {
 #[proc_macro]
 pub fn append_state(items: TokenStream) -> TokenStream {
     let args = parse_macro_input!(items as WriteStateInput);
-    let state_file = state_file_path(args.key.value().as_str());
-    let value = args.value.value().replace("\n", "\\n");
-    let value = format!("{}\n", value);
-    match OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(state_file)
-    {
-        Ok(mut file) => match file.write_all(value.as_bytes()) {
-            Ok(_) => quote!().into(),
-            Err(e) => quote_io_error(e),
-        },
-        Err(e) => quote_io_error(e),
+    match proc_append_state(&args.key.value(), &args.value.value()) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
+    }
+}
+
+fn read_state_value(key: &LitStr) -> Result<String, TokenStream> {
+    proc_read_state(&key.value()).map_err(|e| state_error(e, key, None))
+}
+
+struct ReadStateInput {
+    key: LitStr,
+    owned: bool,
+}
+
+impl Parse for ReadStateInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        if input.is_empty() {
+            return Ok(ReadStateInput { key, owned: false });
+        }
+        input.parse::<Comma>()?;
+        let ty = input.parse::<Ident>()?;
+        if ty != "String" {
+            return Err(syn::Error::new(
+                ty.span(),
+                "expected `String`, the only type `read_state!` can expand to other than a \
+                string literal",
+            ));
+        }
+        Ok(ReadStateInput { key, owned: true })
     }
 }
 
@@ -120,7 +152,9 @@ pub fn append_state(items: TokenStream) -> TokenStream {
 /// with the specified key.
 ///
 /// The macro will expand into a string literal representing the state value in the event that
-/// a value exists for the provided key.
+/// a value exists for the provided key. Passing `String` as a second argument makes it expand
+/// into an owned `String` holding the value instead, so that it can be handed directly to APIs
+/// expecting owned values.
 ///
 /// If no value can be found for the provided key (or in the event of any sort of IO error),
 /// the macro will raise a compile-time IO error.
@@ -128,14 +162,544 @@ pub fn append_state(items: TokenStream) -> TokenStream {
 /// # Example
 /// ```rust
 /// read_state!("my key"); // => "something"
+/// read_state!("my key", String); // => String::from("something")
 /// ```
 #[proc_macro]
 pub fn read_state(items: TokenStream) -> TokenStream {
-    let key = parse_macro_input!(items as LitStr).value();
-    let state_file = state_file_path(key.as_str());
-    match fs::read_to_string(state_file) {
+    let args = parse_macro_input!(items as ReadStateInput);
+    let value = match read_state_value(&args.key) {
+        Ok(value) => value,
+        Err(error) => return error,
+    };
+    match args.owned {
+        true => {
+            let value = with_alloc(quote!(
+                <__alloc::string::String as ::core::convert::From<&::core::primitive::str>>::from(
+                    #value
+                )
+            ));
+            quote!(#value).into()
+        }
+        false => quote!(#value).into(),
+    }
+}
+
+/// Uploads the values written by macros while the `MACRO_STATE_REMOTE_WRITE` environment
+/// variable is set to `back`, which are kept in a local cache rather than uploaded one by one.
+/// Does nothing for other write policies, or if no remote location is configured via the
+/// `MACRO_STATE_REMOTE` environment variable.
+///
+/// Place the call so that it expands after every macro writing state, for example at the end
+/// of the last crate of the build.
+///
+/// # Example
+/// ```ignore
+/// flush_remote_state!();
+/// ```
+#[proc_macro]
+pub fn flush_remote_state(items: TokenStream) -> TokenStream {
+    if !items.is_empty() {
+        return quote!(::core::compile_error!(
+            "flush_remote_state! does not take any arguments"
+        ))
+        .into();
+    }
+    match proc_flush_state_backend() {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Like [`read_state!`], but guaranteed to expand to an expression that can initialize a
+/// `const` or `static`: the value is bound to a `&'static str` constant within the expansion,
+/// so using the macro anywhere a constant expression is expected always compiles, and any
+/// future change that would break that is caught here rather than in downstream tables.
+///
+/// The other read macros that expand to constant expressions, and can therefore be used to
+/// build `const` tables from collected state, are [`read_state_result!`],
+/// [`read_state_slice!`], [`read_state_array!`], [`read_state_usize!`], [`read_state_i64!`],
+/// [`read_state_rows!`] (given a column count), [`read_state_records!`] (for structs whose
+/// fields can all be initialized from string literals), [`read_keys_matching!`],
+/// [`has_state!`], and [`state_flag!`]. [`read_state_vec!`] and `read_state!("key", String)`
+/// allocate, so they can't.
+///
+/// If no value can be found for the provided key (or in the event of any sort of IO error),
+/// the macro will raise a compile-time IO error.
+///
+/// # Example
+/// ```
+/// write_state!("my greeting", "hello");
+/// const GREETING: &str = read_state_const!("my greeting");
+/// static GREETINGS: [&str; 2] = [read_state_const!("my greeting"), "hi"];
+/// assert_eq!(GREETING, "hello");
+/// assert_eq!(GREETINGS, ["hello", "hi"]);
+/// ```
+#[proc_macro]
+pub fn read_state_const(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    let value = match read_state_value(&key) {
+        Ok(value) => value,
+        Err(error) => return error,
+    };
+    quote! {
+        {
+            const VALUE: &'static ::core::primitive::str = #value;
+            VALUE
+        }
+    }
+    .into()
+}
+
+/// Reads the state value for the specified `key` and expands to it as a bare string literal,
+/// so that it can be interpolated into documentation via `#[doc = state_doc!("key")]`.
+/// Key-value attributes such as `doc` accept macro invocations only as long as they expand to
+/// a literal, which is why the expansion never contains anything else.
+///
+/// If the key holds a list (see [`append_state!`]), its items are rendered as a Markdown
+/// bullet list, one item per line, so that the notes collected by several macros show up as
+/// separate bullets. Any other value is interpolated as-is.
+///
+/// If no value can be found for the provided key (or in the event of any sort of IO error),
+/// the macro will raise a compile-time IO error.
+///
+/// # Example
+/// ```
+/// write_state!("version notes", "Generated by the `routes` macro.");
+/// append_state!("supported formats", "json");
+/// append_state!("supported formats", "toml");
+///
+/// /// The application configuration.
+/// ///
+/// #[doc = state_doc!("version notes")]
+/// ///
+/// /// Supported formats:
+/// ///
+/// #[doc = state_doc!("supported formats")]
+/// pub struct Config;
+///
+/// assert_eq!(state_doc!("supported formats"), "- json\n- toml\n");
+/// ```
+#[proc_macro]
+pub fn state_doc(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    let value = match read_state_value(&key) {
+        Ok(value) => value,
+        Err(error) => return error,
+    };
+    let doc = match __private::holds_list(&key.value()) {
+        true => __private::read_items(&key.value())
+            .iter()
+            .map(|item| format!("- {}\n", item.replace('\n', "\n  ")))
+            .collect(),
+        false => value,
+    };
+    quote!(#doc).into()
+}
+
+/// Reads the state value for the specified `key` and expands to it as a bare string literal,
+/// for use as the value of an attribute argument, such as
+/// `#[route(path = state_attr!("base_path"))]`.
+///
+/// The compiler only expands macros within the values of built-in key-value attributes such as
+/// `#[doc = ...]`, and passes the arguments of every other attribute through as raw tokens, so
+/// an attribute like `#[route(...)]` would otherwise receive the `state_attr!(...)` invocation
+/// itself. To have those invocations replaced with their literal values before any other
+/// attribute sees them, annotate the item with [`#[state_attrs]`](macro@state_attrs), placed
+/// above every attribute using [`state_attr!`].
+///
+/// If no value can be found for the provided key (or in the event of any sort of IO error),
+/// the macro will raise a compile-time IO error.
+///
+/// # Example
+/// ```
+/// write_state!("base path", "/api");
+/// assert_eq!(state_attr!("base path"), "/api");
+/// ```
+#[proc_macro]
+pub fn state_attr(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    match read_state_value(&key) {
         Ok(value) => quote!(#value).into(),
-        Err(err) => quote_io_error(err),
+        Err(error) => error,
+    }
+}
+
+/// Returns `true` if `token` is the punctuation character `c`.
+fn is_punct(token: Option<&proc_macro::TokenTree>, c: char) -> bool {
+    matches!(token, Some(proc_macro::TokenTree::Punct(punct)) if punct.as_char() == c)
+}
+
+/// Replaces every `state_attr!("key")` invocation within `tokens` (at any depth) with the
+/// string literal holding the value of `key`.
+fn expand_state_attrs(tokens: TokenStream) -> Result<TokenStream, TokenStream> {
+    let mut expanded = TokenStream::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let token = match token {
+            proc_macro::TokenTree::Ident(ident)
+                if ident.to_string() == "state_attr" && is_punct(tokens.peek(), '!') =>
+            {
+                tokens.next();
+                let Some(proc_macro::TokenTree::Group(args)) = tokens.next() else {
+                    let msg = "expected `state_attr!(\"key\")`";
+                    return Err(syn::Error::new(ident.span().into(), msg)
+                        .to_compile_error()
+                        .into());
+                };
+                let key = syn::parse::<LitStr>(args.stream())
+                    .map_err(|e| TokenStream::from(e.to_compile_error()))?;
+                let mut literal = proc_macro::Literal::string(&read_state_value(&key)?);
+                literal.set_span(args.span());
+                proc_macro::TokenTree::Literal(literal)
+            }
+            proc_macro::TokenTree::Group(group) => {
+                let stream = expand_state_attrs(group.stream())?;
+                let mut expanded_group = proc_macro::Group::new(group.delimiter(), stream);
+                expanded_group.set_span(group.span());
+                proc_macro::TokenTree::Group(expanded_group)
+            }
+            token => token,
+        };
+        expanded.extend([token]);
+    }
+    Ok(expanded)
+}
+
+/// Replaces every [`state_attr!`] invocation within the annotated item (including within the
+/// attributes of its fields, variants, and nested items) with the string literal it expands
+/// to, so that state can be interpolated into the arguments of other attributes, which the
+/// compiler never expands macros in. Since attributes are expanded from the top down, this
+/// attribute must be placed above every attribute using [`state_attr!`].
+///
+/// Only unqualified `state_attr!(...)` invocations are replaced. If any key can't be read, the
+/// macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// write_state!("base path", "/api");
+///
+/// #[state_attrs]
+/// #[route(path = state_attr!("base path"))]
+/// fn index() {}
+/// ```
+#[proc_macro_attribute]
+pub fn state_attrs(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return quote!(::core::compile_error!(
+            "#[state_attrs] does not take any arguments"
+        );)
+        .into();
+    }
+    match expand_state_attrs(tokens) {
+        Ok(tokens) => tokens,
+        Err(error) => error,
+    }
+}
+
+/// A single arm of [`match_state!`], holding the values it matches (or [`None`] for `_`) and
+/// its tokens.
+struct MatchStateArm {
+    values: Vec<Option<String>>,
+    tokens: TokenStream,
+}
+
+/// Parses the input of [`match_state!`] into the key and its arms.
+fn parse_match_state(items: TokenStream) -> syn::Result<(LitStr, Vec<MatchStateArm>)> {
+    let error = |span: proc_macro::Span, msg: &str| syn::Error::new(span.into(), msg);
+    let mut items = items.into_iter();
+    let key: TokenStream = items.next().into_iter().collect();
+    let key = syn::parse::<LitStr>(key)?;
+    let arms = match (items.next(), items.next()) {
+        (Some(proc_macro::TokenTree::Group(arms)), None)
+            if arms.delimiter() == proc_macro::Delimiter::Brace =>
+        {
+            arms
+        }
+        _ => {
+            let msg = "expected `match_state!(\"key\" { \"value\" => { ... }, _ => { ... } })`";
+            return Err(error(key.span().unwrap(), msg));
+        }
+    };
+    let mut parsed = Vec::new();
+    let mut tokens = arms.stream().into_iter().peekable();
+    while tokens.peek().is_some() {
+        let mut values = Vec::new();
+        loop {
+            match tokens.next() {
+                Some(proc_macro::TokenTree::Ident(ident)) if ident.to_string() == "_" => {
+                    values.push(None)
+                }
+                Some(proc_macro::TokenTree::Literal(literal)) => {
+                    let literal = TokenStream::from(proc_macro::TokenTree::Literal(literal));
+                    values.push(Some(syn::parse::<LitStr>(literal)?.value()));
+                }
+                token => {
+                    let span = token.map_or(arms.span_close(), |token| token.span());
+                    return Err(error(span, "expected a string literal or `_`"));
+                }
+            }
+            match tokens.next() {
+                Some(proc_macro::TokenTree::Punct(punct)) if punct.as_char() == '|' => continue,
+                Some(proc_macro::TokenTree::Punct(punct)) if punct.as_char() == '=' => match tokens
+                    .next()
+                {
+                    Some(proc_macro::TokenTree::Punct(punct)) if punct.as_char() == '>' => break,
+                    _ => return Err(error(punct.span(), "expected `=>`")),
+                },
+                token => {
+                    let span = token.map_or(arms.span_close(), |token| token.span());
+                    return Err(error(span, "expected `|` or `=>`"));
+                }
+            }
+        }
+        let tokens_group = match tokens.next() {
+            Some(proc_macro::TokenTree::Group(group))
+                if group.delimiter() == proc_macro::Delimiter::Brace =>
+            {
+                group
+            }
+            token => {
+                let span = token.map_or(arms.span_close(), |token| token.span());
+                return Err(error(span, "expected the tokens of the arm within braces"));
+            }
+        };
+        if let Some(proc_macro::TokenTree::Punct(punct)) = tokens.peek() {
+            if punct.as_char() == ',' {
+                tokens.next();
+            }
+        }
+        parsed.push(MatchStateArm {
+            values,
+            tokens: tokens_group.stream(),
+        });
+    }
+    Ok((key, parsed))
+}
+
+/// Reads the state value for the specified `key` and expands to the tokens of the first arm
+/// matching it, dropping every other arm entirely, so that whole code paths (items, impls, or
+/// expressions) can be selected by state without resorting to `cfg` attributes.
+///
+/// Each arm matches one or more string literals separated by `|`, or `_` to match any value,
+/// and its tokens must be enclosed in braces, which are removed. If the key has no value, only
+/// a `_` arm can match, while any other error reading it is raised even if there is a `_` arm.
+/// Since the braces are removed, an arm expanding to several statements in expression position
+/// must be enclosed in a second pair of braces.
+///
+/// If no arm matches, the macro will raise a compile-time error, which in the case of a
+/// missing key (or any other IO error) is the error that [`read_state!`] would raise.
+///
+/// # Example
+/// ```
+/// write_state!("backend", "sqlite");
+///
+/// match_state!("backend" {
+///     "postgres" | "mysql" => { const DEFAULT_PORT: u16 = 5432; },
+///     "sqlite" => { const DEFAULT_PORT: u16 = 0; },
+///     _ => { compile_error!("unsupported backend"); },
+/// });
+///
+/// assert_eq!(DEFAULT_PORT, 0);
+/// assert_eq!(match_state!("backend" { "sqlite" => { "file" }, _ => { "network" } }), "file");
+/// ```
+#[proc_macro]
+pub fn match_state(items: TokenStream) -> TokenStream {
+    let (key, arms) = match parse_match_state(items) {
+        Ok(parsed) => parsed,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    // only a missing key may fall through to a `_` arm, any other error is raised as is
+    let value = match proc_read_state(&key.value()) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return state_error(e, &key, None),
+        value => value,
+    };
+    let matched = arms.into_iter().find(|arm| {
+        arm.values.iter().any(|pattern| match (pattern, &value) {
+            (None, _) => true,
+            (Some(pattern), Ok(value)) => pattern == value,
+            (Some(_), Err(_)) => false,
+        })
+    });
+    match (matched, value) {
+        (Some(arm), _) => arm.tokens,
+        (None, Err(e)) => quote_io_error(e),
+        (None, Ok(value)) => {
+            let msg = format!(
+                "no arm matches the value \"{}\" of key \"{}\"",
+                value,
+                key.value()
+            );
+            syn::Error::new(key.span(), msg).to_compile_error().into()
+        }
+    }
+}
+
+/// Concatenates the segments of a `[< ... >]` group of [`state_paste!`] into a single
+/// identifier spanning the group.
+fn paste_ident(
+    segments: &[proc_macro::TokenTree],
+    span: proc_macro::Span,
+) -> Result<TokenStream, TokenStream> {
+    let error = |span: proc_macro::Span, msg: String| -> TokenStream {
+        syn::Error::new(span.into(), msg).to_compile_error().into()
+    };
+    let mut name = String::new();
+    let segments = flatten_invisible_groups(segments);
+    let mut segments = segments.iter().peekable();
+    while let Some(segment) = segments.next() {
+        match segment {
+            proc_macro::TokenTree::Ident(ident)
+                if ident.to_string() == "read_state" && is_punct(segments.peek().copied(), '!') =>
+            {
+                segments.next();
+                let Some(proc_macro::TokenTree::Group(args)) = segments.next() else {
+                    return Err(error(
+                        ident.span(),
+                        "expected `read_state!(\"key\")`".into(),
+                    ));
+                };
+                let key = syn::parse::<LitStr>(args.stream())
+                    .map_err(|e| TokenStream::from(e.to_compile_error()))?;
+                name.push_str(&read_state_value(&key)?);
+            }
+            proc_macro::TokenTree::Ident(ident) => {
+                let ident = ident.to_string();
+                name.push_str(ident.strip_prefix("r#").unwrap_or(&ident));
+            }
+            proc_macro::TokenTree::Literal(literal) => {
+                match syn::parse::<syn::Lit>(proc_macro::TokenTree::Literal(literal.clone()).into())
+                {
+                    Ok(syn::Lit::Str(lit)) => name.push_str(&lit.value()),
+                    Ok(syn::Lit::Int(lit)) => name.push_str(lit.base10_digits()),
+                    _ => {
+                        let msg = "expected a string or integer literal".into();
+                        return Err(error(literal.span(), msg));
+                    }
+                }
+            }
+            segment => {
+                let msg = format!("unexpected `{}` within `[< ... >]`", segment);
+                return Err(error(segment.span(), msg));
+            }
+        }
+    }
+    match syn::parse_str::<Ident>(&name) {
+        Ok(_) => Ok(TokenStream::from(proc_macro::TokenTree::Ident(
+            proc_macro::Ident::new(&name, span),
+        ))),
+        Err(_) => Err(error(span, format!("`{}` is not a valid identifier", name))),
+    }
+}
+
+/// Replaces every group without delimiters within `segments` (such as those wrapping the
+/// fragments substituted by `macro_rules!`) with its contents, at any depth.
+fn flatten_invisible_groups(segments: &[proc_macro::TokenTree]) -> Vec<proc_macro::TokenTree> {
+    let mut flattened = Vec::new();
+    for segment in segments {
+        match segment {
+            proc_macro::TokenTree::Group(group)
+                if group.delimiter() == proc_macro::Delimiter::None =>
+            {
+                let inner: Vec<proc_macro::TokenTree> = group.stream().into_iter().collect();
+                flattened.extend(flatten_invisible_groups(&inner));
+            }
+            segment => flattened.push(segment.clone()),
+        }
+    }
+    flattened
+}
+
+/// Replaces every `[< ... >]` group within `tokens` (at any depth) with the identifier it
+/// describes, as documented on [`state_paste!`].
+fn paste_tokens(tokens: TokenStream) -> Result<TokenStream, TokenStream> {
+    let mut pasted = TokenStream::new();
+    for token in tokens {
+        let token = match token {
+            proc_macro::TokenTree::Group(group) => {
+                let inner: Vec<proc_macro::TokenTree> = group.stream().into_iter().collect();
+                match (group.delimiter(), inner.as_slice()) {
+                    (proc_macro::Delimiter::Bracket, [open, segments @ .., close])
+                        if is_punct(Some(open), '<') && is_punct(Some(close), '>') =>
+                    {
+                        pasted.extend(paste_ident(segments, group.span())?);
+                        continue;
+                    }
+                    _ => {
+                        let stream = paste_tokens(group.stream())?;
+                        let mut pasted_group = proc_macro::Group::new(group.delimiter(), stream);
+                        pasted_group.set_span(group.span());
+                        proc_macro::TokenTree::Group(pasted_group)
+                    }
+                }
+            }
+            token => token,
+        };
+        pasted.extend([token]);
+    }
+    Ok(pasted)
+}
+
+/// Expands to the specified tokens, replacing every `[< ... >]` group with a single identifier
+/// made by concatenating its segments, so that names collected in state can be turned directly
+/// into item names without writing a dedicated proc macro.
+///
+/// Each segment may be an identifier, a string or integer literal, or a
+/// `read_state!("key")` invocation, which is replaced by the value of `key`. Segments may also
+/// come from `macro_rules!` fragments such as `$name:expr`. If the resulting name isn't a
+/// valid identifier (or is a keyword), or if any key can't be read, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// write_state!("handler suffix", "login");
+///
+/// state_paste! {
+///     fn [<handle_ read_state!("handler suffix")>]() -> &'static str {
+///         "logged in"
+///     }
+///     const [<LOGIN_ "ATTEMPTS" _ 3>]: usize = 3;
+/// }
+///
+/// assert_eq!(handle_login(), "logged in");
+/// assert_eq!(LOGIN_ATTEMPTS_3, 3);
+/// ```
+#[proc_macro]
+pub fn state_paste(items: TokenStream) -> TokenStream {
+    match paste_tokens(items) {
+        Ok(tokens) => tokens,
+        Err(error) => error,
+    }
+}
+
+/// Like [`read_state!`], but never raises a compile-time error. Instead, the macro expands to a
+/// `Result<&'static str, &'static str>` expression: `Ok("value")` if a value exists for the
+/// specified `key`, or `Err("reason")` describing why it could not be read.
+///
+/// This lets generated code handle missing state at runtime, which is useful when the presence
+/// of a key legitimately varies between builds (for example because it is only written when a
+/// certain feature is enabled). Since a missing key is expected here, it is never reported by
+/// read-before-write diagnostics.
+///
+/// # Example
+/// ```
+/// write_state!("my key", "something");
+/// assert_eq!(read_state_result!("my key"), Ok("something"));
+/// assert!(read_state_result!("unknown key").is_err());
+/// ```
+#[proc_macro]
+pub fn read_state_result(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    match __private::read_state_value(&key) {
+        Ok(value) => {
+            quote!(::core::result::Result::<&'static str, &'static str>::Ok(#value)).into()
+        }
+        Err(err) => {
+            let reason = match err.kind() {
+                ErrorKind::NotFound => format!("no state value exists for key \"{}\"", key),
+                _ => err.to_string(),
+            };
+            quote!(::core::result::Result::<&'static str, &'static str>::Err(#reason)).into()
+        }
     }
 }
 
@@ -157,6 +721,10 @@ pub fn read_state(items: TokenStream) -> TokenStream {
 /// Note: This macro is infallible -- if any issue occurs trying to read the specified key, it
 /// is assumed that we should return an empty [`Vec`].
 ///
+/// Note: the expansion refers to `::std::vec!` by its fully qualified path. When the `alloc`
+/// feature is enabled, `::alloc` is used instead, in which case the calling crate must declare
+/// `extern crate alloc;`.
+///
 /// # Example
 /// ```
 /// append_state!("my_key", "first item");
@@ -166,24 +734,163 @@ pub fn read_state(items: TokenStream) -> TokenStream {
 #[proc_macro]
 pub fn read_state_vec(items: TokenStream) -> TokenStream {
     let key = parse_macro_input!(items as LitStr).value();
-    let state_file = state_file_path(key.as_str());
-    match fs::read_to_string(state_file) {
-        Ok(mut value) => {
-            if let Some(last) = value.as_str().chars().last() {
-                if last == '\n' {
-                    value = value[0..(value.len() - 1)].to_string();
-                }
+    match __private::read_state_list(key.as_str()) {
+        Ok(items) => {
+            let items = with_alloc(quote!(__alloc::vec![#(#items), *]));
+            quote!(#items).into()
+        }
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                __private::note_missed_read(&key);
             }
-            let items: Vec<String> = value
-                .split("\n")
-                .map(|item| item.replace("\\n", "\n"))
-                .collect();
-            quote!(vec![#(#items), *]).into()
+            let items = with_alloc(quote!(__alloc::vec::Vec::<__alloc::string::String>::new()));
+            quote!(#items).into()
+        }
+    }
+}
+
+/// Reads the items of the list stored for `key` for a reduction, treating a missing key as an
+/// empty list and raising a compile-time error for any other IO error.
+fn read_reduced_items(key: &str) -> Result<Vec<String>, TokenStream> {
+    match __private::read_state_list(key) {
+        Ok(items) => Ok(items),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            __private::note_missed_read(key);
+            Ok(Vec::new())
+        }
+        Err(e) => Err(quote_io_error(e)),
+    }
+}
+
+/// Parses every item of the list stored for `key` as an [`i64`], raising a compile-time error
+/// naming the first item that isn't one.
+fn read_reduced_integers(key: &str) -> Result<Vec<i64>, TokenStream> {
+    read_reduced_items(key)?
+        .iter()
+        .map(|item| {
+            item.trim().parse::<i64>().map_err(|e| {
+                let msg = format!(
+                    "the list for key \"{}\" contains an item that is not a valid i64: \"{}\" ({})",
+                    key, item, e
+                );
+                TokenStream::from(quote!(::core::compile_error!(#msg)))
+            })
+        })
+        .collect()
+}
+
+/// Expands to the number of items in the list stored for `key` as a [`usize`] literal, or `0`
+/// if the key has no value, without materializing the list in generated code.
+///
+/// # Example
+/// ```
+/// append_state!("my routes", "/");
+/// append_state!("my routes", "/about");
+/// const ROUTE_COUNT: usize = state_vec_len!("my routes");
+/// assert_eq!(ROUTE_COUNT, 2);
+/// ```
+#[proc_macro]
+pub fn state_vec_len(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    let len = match read_reduced_items(key.as_str()) {
+        Ok(items) => items.len(),
+        Err(error) => return error,
+    };
+    let literal = LitInt::new(
+        &format!("{}usize", len),
+        proc_macro::Span::call_site().into(),
+    );
+    quote!(#literal).into()
+}
+
+/// Expands to the sum of the items in the list stored for `key` as an [`i64`] literal, or `0`
+/// if the key has no value. The sum is computed at expansion time, so the list never needs to
+/// be materialized and folded at runtime.
+///
+/// If any item is not a valid [`i64`], or if the sum overflows, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// append_state!("my table sizes", "128");
+/// append_state!("my table sizes", "64");
+/// const TOTAL: i64 = state_vec_sum!("my table sizes");
+/// assert_eq!(TOTAL, 192);
+/// ```
+#[proc_macro]
+pub fn state_vec_sum(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    let values = match read_reduced_integers(key.as_str()) {
+        Ok(values) => values,
+        Err(error) => return error,
+    };
+    match values.into_iter().try_fold(0i64, i64::checked_add) {
+        Some(sum) => {
+            let literal = LitInt::new(&format!("{}i64", sum), proc_macro::Span::call_site().into());
+            quote!(#literal).into()
+        }
+        None => {
+            let msg = format!("the sum of the list for key \"{}\" overflowed", key);
+            quote!(::core::compile_error!(#msg)).into()
+        }
+    }
+}
+
+/// Reduces the integers of the list stored for the key in `items` via `reduce`, expanding to
+/// `Some(value)`, or to `None` if the list is empty.
+fn reduce_state_integers(items: TokenStream, reduce: fn(i64, i64) -> i64) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    let values = match read_reduced_integers(key.as_str()) {
+        Ok(values) => values,
+        Err(error) => return error,
+    };
+    match values.into_iter().reduce(reduce) {
+        Some(value) => {
+            let literal = LitInt::new(
+                &format!("{}i64", value),
+                proc_macro::Span::call_site().into(),
+            );
+            quote!(::core::option::Option::Some(#literal)).into()
         }
-        Err(_) => quote!(Vec::<String>::new()).into(),
+        None => quote!(::core::option::Option::None::<::core::primitive::i64>).into(),
     }
 }
 
+/// Expands to the largest item in the list stored for `key` as `Some(n)` (where `n` is an
+/// [`i64`] literal), or to `None` if the list is empty or the key has no value.
+///
+/// If any item is not a valid [`i64`], the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// append_state!("my arities", "2");
+/// append_state!("my arities", "5");
+/// const MAX_ARITY: Option<i64> = state_vec_max!("my arities");
+/// assert_eq!(MAX_ARITY, Some(5));
+/// assert_eq!(state_vec_max!("unknown arities"), None);
+/// ```
+#[proc_macro]
+pub fn state_vec_max(items: TokenStream) -> TokenStream {
+    reduce_state_integers(items, i64::max)
+}
+
+/// Expands to the smallest item in the list stored for `key` as `Some(n)` (where `n` is an
+/// [`i64`] literal), or to `None` if the list is empty or the key has no value.
+///
+/// If any item is not a valid [`i64`], the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// append_state!("my versions", "3");
+/// append_state!("my versions", "1");
+/// const MIN_VERSION: Option<i64> = state_vec_min!("my versions");
+/// assert_eq!(MIN_VERSION, Some(1));
+/// ```
+#[proc_macro]
+pub fn state_vec_min(items: TokenStream) -> TokenStream {
+    reduce_state_integers(items, i64::min)
+}
+
 /// Checks if an existing state value can be found for the specified `key`.
 ///
 /// Note that this function is infallible -- it should never panic and will always return
@@ -198,10 +905,9 @@ pub fn read_state_vec(items: TokenStream) -> TokenStream {
 #[proc_macro]
 pub fn has_state(items: TokenStream) -> TokenStream {
     let key = parse_macro_input!(items as LitStr).value();
-    let state_file = state_file_path(key.as_str());
-    match fs::read_to_string(state_file) {
-        Ok(_) => quote!(true).into(),
-        Err(_) => quote!(false).into(),
+    match proc_has_state(&key) {
+        true => quote!(true).into(),
+        false => quote!(false).into(),
     }
 }
 
@@ -219,11 +925,10 @@ pub fn has_state(items: TokenStream) -> TokenStream {
 /// ```
 #[proc_macro]
 pub fn clear_state(items: TokenStream) -> TokenStream {
-    let key = parse_macro_input!(items as LitStr).value();
-    let state_file = state_file_path(key.as_str());
-    match fs::remove_file(state_file) {
-        Ok(_) => quote!().into(),
-        Err(e) => quote_io_error(e),
+    let key = parse_macro_input!(items as LitStr);
+    match proc_clear_state(&key.value()) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &key, None),
     }
 }
 
@@ -239,17 +944,3796 @@ pub fn clear_state(items: TokenStream) -> TokenStream {
 #[proc_macro]
 pub fn init_state(items: TokenStream) -> TokenStream {
     let args = parse_macro_input!(items as WriteStateInput);
-    let key = args.key.value().to_string();
-    let value = args.value.value().to_string();
-    let state_file = state_file_path(key.as_str());
-    match fs::read_to_string(state_file) {
-        Ok(string) => quote!(#string).into(),
-        Err(_) => match File::create(state_file_path(key.as_str())) {
-            Ok(mut file) => match file.write_all(value.as_bytes()) {
-                Ok(_) => quote!(#value).into(),
-                Err(err) => quote_io_error(err),
-            },
-            Err(err) => quote_io_error(err),
-        },
+    match proc_init_state(&args.key.value(), &args.value.value()) {
+        Ok(value) => track_write_policy(quote!(#value), true),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
+    }
+}
+
+/// Resolves `relative` against the manifest directory of the crate being compiled.
+fn manifest_relative_path(relative: &str) -> PathBuf {
+    let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    path.push(relative);
+    path
+}
+
+/// Publishes the specified `value` on the specified `channel`. Channels live in a shared area
+/// of the state directory rather than in the per-compilation key-value store, making them the
+/// sanctioned way for the macros of an upstream crate to hand data to the macros of a
+/// downstream crate. The name of the publishing crate is recorded alongside the value.
+///
+/// Publishing to a channel that already has a value replaces that value.
+///
+/// If any IO error occurs while trying to write to the channel file, the IO error will surface
+/// as a compile-time error on the macro call.
+///
+/// # Example
+/// ```
+/// // in an upstream crate
+/// publish_state!("routes", "/users,/posts");
+///
+/// // in a downstream crate
+/// assert_eq!(subscribe_state!("routes"), "/users,/posts");
+/// ```
+#[proc_macro]
+pub fn publish_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as WriteStateInput);
+    match proc_publish_state(&args.key.value(), &args.value.value()) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Reads the value most recently published on the specified `channel` via [`publish_state!`],
+/// regardless of which crate published it, expanding to a string literal.
+///
+/// If nothing has been published on the channel (or in the event of any sort of IO error),
+/// the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// publish_state!("my channel", "hello");
+/// assert_eq!(subscribe_state!("my channel"), "hello");
+/// ```
+#[proc_macro]
+pub fn subscribe_state(items: TokenStream) -> TokenStream {
+    let channel = parse_macro_input!(items as LitStr).value();
+    match proc_subscribe_state(&channel) {
+        Ok(value) => quote!(#value).into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+#[derive(Parse)]
+struct ImportStateInput {
+    crate_name: LitStr,
+    _comma: Comma,
+    key: LitStr,
+}
+
+/// Copies the current value of the specified state `key` to a stable location associated with
+/// the crate currently being compiled, so that the macros of dependent crates can later read
+/// it via [`import_dependency_state!`]. This allows registry data collected in a library crate
+/// to be consumed by the macros of an application crate.
+///
+/// If no value exists for `key` (or in the event of any sort of IO error), the macro will
+/// raise a compile-time error.
+///
+/// # Example
+/// ```
+/// // at the bottom of an upstream library crate
+/// append_state!("models", "User");
+/// append_state!("models", "Post");
+/// export_state_for_dependents!("models");
+///
+/// // in a downstream application crate
+/// import_dependency_state!("my_library", "models"); // => "User\nPost\n"
+/// ```
+#[proc_macro]
+pub fn export_state_for_dependents(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    match proc_export_state_for_dependents(&key) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Reads the value of `key` as exported by the crate `crate_name` via
+/// [`export_state_for_dependents!`], expanding to a string literal. Crate names may be
+/// specified with either dashes or underscores.
+///
+/// If the specified crate has not exported `key` (or in the event of any sort of IO error),
+/// the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// import_dependency_state!("my_library", "models"); // => "User\nPost\n"
+/// ```
+#[proc_macro]
+pub fn import_dependency_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as ImportStateInput);
+    match proc_import_dependency_state(&args.crate_name.value(), &args.key.value()) {
+        Ok(value) => quote!(#value).into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+fn item_ident(item: &Item) -> Option<&Ident> {
+    match item {
+        Item::Const(item) => Some(&item.ident),
+        Item::Enum(item) => Some(&item.ident),
+        Item::ExternCrate(item) => Some(&item.ident),
+        Item::Fn(item) => Some(&item.sig.ident),
+        Item::Macro(item) => item.ident.as_ref(),
+        Item::Macro2(item) => Some(&item.ident),
+        Item::Mod(item) => Some(&item.ident),
+        Item::Static(item) => Some(&item.ident),
+        Item::Struct(item) => Some(&item.ident),
+        Item::Trait(item) => Some(&item.ident),
+        Item::TraitAlias(item) => Some(&item.ident),
+        Item::Type(item) => Some(&item.ident),
+        Item::Union(item) => Some(&item.ident),
+        _ => None,
+    }
+}
+
+/// Attribute macro that mirrors the tokens of the item it is attached to into state, in the
+/// spirit of the `#[export_tokens]` attribute from `macro_magic`. The item itself is emitted
+/// unchanged.
+///
+/// The tokens are stored under the key passed to the attribute, or under the name of the item
+/// if no key is specified, and can later be expanded via [`import_state_tokens!`] or read
+/// as a string via [`read_state!`].
+///
+/// # Example
+/// ```
+/// #[export_state_tokens]
+/// struct Point {
+///     x: usize,
+///     y: usize,
+/// }
+///
+/// #[export_state_tokens("my tokens")]
+/// fn hello() {}
+///
+/// read_state!("Point"); // => "struct Point { x : usize, y : usize }"
+/// ```
+#[proc_macro_attribute]
+pub fn export_state_tokens(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let item_tokens = tokens.clone();
+    let item = parse_macro_input!(item_tokens as Item);
+    let key = if attr.is_empty() {
+        match item_ident(&item) {
+            Some(ident) => ident.to_string(),
+            None => {
+                return quote!(::core::compile_error!(
+                    "a key must be specified when exporting the tokens of an unnamed item"
+                ))
+                .into()
+            }
+        }
+    } else {
+        parse_macro_input!(attr as LitStr).value()
+    };
+    match proc_write_state_tokens(&key, &tokens) {
+        Ok(_) => tokens,
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Expands to the tokens stored in the state value for the specified `key`, such as tokens
+/// that were stored via [`export_state_tokens`]. This is the `macro_state` equivalent of the
+/// `import_tokens!` macro from `macro_magic`, and can also be used to turn any state value
+/// that contains valid Rust syntax into code.
+///
+/// If no value can be found for the provided key, or if the value cannot be parsed as a token
+/// stream, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// #[export_state_tokens]
+/// pub fn answer() -> usize {
+///     42
+/// }
+///
+/// mod copy {
+///     import_state_tokens!("answer");
+/// }
+///
+/// assert_eq!(copy::answer(), 42);
+/// ```
+#[proc_macro]
+pub fn import_state_tokens(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    match proc_read_state_tokens::<TokenStream>(&key) {
+        Ok(tokens) => tokens,
+        Err(e) => quote_io_error(e),
+    }
+}
+
+#[derive(Parse)]
+struct RegistryBridgeInput {
+    key: LitStr,
+    _comma: Comma,
+    target: syn::Path,
+}
+
+/// Registers every item of the state list for the specified `key` (see [`append_state!`] and
+/// [`read_state_vec!`]) with a [`linkme`](https://docs.rs/linkme) distributed slice, so that
+/// data collected at compile-time can also be discovered at runtime through the `linkme`
+/// ecosystem without any duplicate bookkeeping.
+///
+/// The second argument is the path to a `#[distributed_slice]` of type `[&'static str]`. The
+/// crate invoking this macro must depend on `linkme` directly.
+///
+/// If the specified key does not exist, no items are registered.
+///
+/// # Example
+/// ```
+/// use linkme::distributed_slice;
+///
+/// #[distributed_slice]
+/// pub static ROUTES: [&'static str] = [..];
+///
+/// append_state!("routes", "/users");
+/// append_state!("routes", "/posts");
+/// register_state_linkme!("routes", ROUTES);
+///
+/// assert_eq!(ROUTES.len(), 2);
+/// ```
+#[proc_macro]
+pub fn register_state_linkme(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as RegistryBridgeInput);
+    let slice = args.target;
+    let items = __private::read_state_list(args.key.value().as_str()).unwrap_or_default();
+    quote! {
+        #(
+            const _: () = {
+                #[::linkme::distributed_slice(#slice)]
+                static ENTRY: &'static ::core::primitive::str = #items;
+            };
+        )*
+    }
+    .into()
+}
+
+/// Submits every item of the state list for the specified `key` (see [`append_state!`] and
+/// [`read_state_vec!`]) to an [`inventory`](https://docs.rs/inventory) collection, so that
+/// data collected at compile-time can also be discovered at runtime through the `inventory`
+/// ecosystem without any duplicate bookkeeping.
+///
+/// The second argument is the path to a `const fn` that takes a `&'static str` and returns a
+/// value of the collected type. The crate invoking this macro must depend on `inventory`
+/// directly.
+///
+/// If the specified key does not exist, nothing is submitted.
+///
+/// # Example
+/// ```
+/// pub struct Route(&'static str);
+///
+/// impl Route {
+///     pub const fn new(path: &'static str) -> Self {
+///         Route(path)
+///     }
+/// }
+///
+/// inventory::collect!(Route);
+///
+/// append_state!("routes", "/users");
+/// append_state!("routes", "/posts");
+/// submit_state_inventory!("routes", Route::new);
+///
+/// assert_eq!(inventory::iter::<Route>.into_iter().count(), 2);
+/// ```
+#[proc_macro]
+pub fn submit_state_inventory(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as RegistryBridgeInput);
+    let constructor = args.target;
+    let items = __private::read_state_list(args.key.value().as_str()).unwrap_or_default();
+    quote! {
+        #(
+            ::inventory::submit! {
+                #constructor(#items)
+            }
+        )*
+    }
+    .into()
+}
+
+/// Expands to a [`u128`] literal containing the time at which the value for the specified
+/// `key` was last written to (or appended to), in nanoseconds since the UNIX epoch. This can be
+/// used to implement cache-invalidation logic on top of state.
+///
+/// If no value can be found for the provided key (or in the event of any sort of IO error),
+/// the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// write_state!("my key", "value");
+/// state_mtime!("my key"); // => 1666300000000000000u128
+/// ```
+#[proc_macro]
+pub fn state_mtime(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    match proc_state_mtime(&key) {
+        Ok(modified) => {
+            let nanos = modified
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos())
+                .unwrap_or(0);
+            quote!(#nanos).into()
+        }
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Expands to a `macro_state::StateMetadata` describing the value stored for the specified
+/// `key` (its size in bytes, creation and modification times, and the name of the crate that
+/// last wrote to it) without embedding the value itself.
+///
+/// If no value can be found for the provided key (or in the event of any sort of IO error),
+/// the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// write_state!("my key", "12345");
+/// let metadata = state_metadata!("my key");
+/// assert_eq!(metadata.size, 5);
+/// assert_eq!(metadata.writer_crate.unwrap(), "my_crate");
+/// ```
+#[proc_macro]
+pub fn state_metadata(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    let metadata = match proc_state_metadata(&key) {
+        Ok(metadata) => metadata,
+        Err(e) => return quote_io_error(e),
+    };
+    let size = metadata.size;
+    let nanos = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0)
+    };
+    let created = nanos(metadata.created);
+    let modified = nanos(metadata.modified);
+    let writer_crate = match metadata.writer_crate {
+        Some(writer) => {
+            quote!(::core::option::Option::Some(::std::string::ToString::to_string(#writer)))
+        }
+        None => quote!(::core::option::Option::None),
+    };
+    quote! {
+        ::macro_state::StateMetadata {
+            size: #size,
+            created: ::std::time::UNIX_EPOCH + ::std::time::Duration::from_nanos(#created),
+            modified: ::std::time::UNIX_EPOCH + ::std::time::Duration::from_nanos(#modified),
+            writer_crate: #writer_crate,
+        }
+    }
+    .into()
+}
+
+#[derive(Parse)]
+struct SessionKeyInput {
+    session: LitStr,
+    _comma: Comma,
+    key: LitStr,
+}
+
+#[derive(Parse)]
+struct SessionWriteInput {
+    session: LitStr,
+    _comma1: Comma,
+    key: LitStr,
+    _comma2: Comma,
+    value: LitStr,
+}
+
+/// Begins a new state session with the specified `name`. A session is an isolated sub-store
+/// of state that exists for a bounded phase of macro expansion: keys written to a session via
+/// [`write_session_state!`] and [`append_session_state!`] never collide with keys in the main
+/// store, and everything written to the session is removed by [`state_session_end!`].
+/// Sessions are scoped to the target being compiled, so the library and the tests of a crate
+/// may each have a session of the same name.
+///
+/// If a session with the same name is already active (usually indicating a missing call to
+/// [`state_session_end!`]), the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// state_session_begin!("codegen");
+/// write_session_state!("codegen", "scratch", "temporary");
+/// assert_eq!(read_session_state!("codegen", "scratch"), "temporary");
+/// assert_eq!(has_state!("scratch"), false);
+/// state_session_end!("codegen");
+/// ```
+#[proc_macro]
+pub fn state_session_begin(items: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(items as LitStr).value();
+    match proc_state_session_begin(&name) {
+        Ok(session) => {
+            // the session spans several macro invocations, until `state_session_end!`
+            session.detach();
+            quote!().into()
+        }
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Ends the active state session with the specified `name` (see [`state_session_begin!`]),
+/// removing everything that was written to it.
+///
+/// If no such session is active, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// state_session_begin!("codegen");
+/// state_session_end!("codegen");
+/// ```
+#[proc_macro]
+pub fn state_session_end(items: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(items as LitStr).value();
+    match proc_state_session_end(&name) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Like [`write_state!`], but writes to the specified key within the active state session
+/// `session` (see [`state_session_begin!`]) rather than to the main store.
+///
+/// If the session is not active (or in the event of any sort of IO error), the macro will
+/// raise a compile-time error.
+///
+/// # Example
+/// ```
+/// state_session_begin!("codegen");
+/// write_session_state!("codegen", "my key", "my value");
+/// ```
+#[proc_macro]
+pub fn write_session_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as SessionWriteInput);
+    let session = proc_state_session(&args.session.value());
+    match session.and_then(|session| session.write(&args.key.value(), &args.value.value())) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Like [`append_state!`], but appends to the specified key within the active state session
+/// `session` (see [`state_session_begin!`]) rather than to the main store.
+///
+/// If the session is not active (or in the event of any sort of IO error), the macro will
+/// raise a compile-time error.
+///
+/// # Example
+/// ```
+/// state_session_begin!("codegen");
+/// append_session_state!("codegen", "my list", "first");
+/// append_session_state!("codegen", "my list", "second");
+/// ```
+#[proc_macro]
+pub fn append_session_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as SessionWriteInput);
+    let session = proc_state_session(&args.session.value());
+    match session.and_then(|session| session.append(&args.key.value(), &args.value.value())) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Like [`read_state!`], but reads the specified key from the active state session `session`
+/// (see [`state_session_begin!`]) rather than from the main store.
+///
+/// If the session is not active, no value exists for the key, or in the event of any sort of
+/// IO error, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// state_session_begin!("codegen");
+/// write_session_state!("codegen", "my key", "my value");
+/// read_session_state!("codegen", "my key"); // => "my value"
+/// ```
+#[proc_macro]
+pub fn read_session_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as SessionKeyInput);
+    let session = proc_state_session(&args.session.value());
+    match session.and_then(|session| session.read(&args.key.value())) {
+        Ok(value) => quote!(#value).into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Reads the state value for the specified key as a list, in the same way as
+/// [`read_state_vec!`], but expands to a `&'static [&'static str]` slice literal rather than a
+/// `vec![...]`. Because no allocation or library paths are involved, this macro can be used
+/// in `#![no_std]` crates and in `const` contexts.
+///
+/// Note: This macro is infallible -- if any issue occurs trying to read the specified key, it
+/// is assumed that we should return an empty slice.
+///
+/// # Example
+/// ```
+/// append_state!("my_key", "first item");
+/// append_state!("my_key", "2nd item");
+/// const ITEMS: &[&str] = read_state_slice!("my_key");
+/// assert_eq!(ITEMS, &["first item", "2nd item"]);
+/// ```
+#[proc_macro]
+pub fn read_state_slice(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    let items = __private::read_state_list(key.as_str()).unwrap_or_default();
+    quote!((&[#(#items), *] as &[&::core::primitive::str])).into()
+}
+
+/// Pushes `value` onto the back of the FIFO queue stored for `key`, in the same format used by
+/// [`append_state!`]. Unlike [`append_state!`], the push happens while holding an exclusive
+/// lock over the state directory, so it can safely be combined with [`dequeue_state!`] and
+/// [`drain_state!`].
+///
+/// A typical use is to have many producer macros push work items, and a single consumer macro
+/// at the bottom of the crate drain them to generate code.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// push_state!("my queue", "first");
+/// push_state!("my queue", "second");
+/// assert_eq!(read_state_vec!("my queue"), vec!["first", "second"]);
+/// ```
+#[proc_macro]
+pub fn push_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as WriteStateInput);
+    match proc_push_state(&args.key.value(), &args.value.value()) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
+    }
+}
+
+/// Removes the item at the front of the FIFO queue stored for `key` (see [`push_state!`]),
+/// expanding to `Some("item")`, or to `None` if the queue is empty.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// push_state!("my jobs", "a");
+/// push_state!("my jobs", "b");
+/// assert_eq!(dequeue_state!("my jobs"), Some("a"));
+/// assert_eq!(dequeue_state!("my jobs"), Some("b"));
+/// assert_eq!(dequeue_state!("my jobs"), None);
+/// ```
+#[proc_macro]
+pub fn dequeue_state(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    match proc_dequeue_state(&key.value()) {
+        Ok(Some(item)) => track_write_policy(quote!(::core::option::Option::Some(#item)), true),
+        Ok(None) => track_write_policy(
+            quote!(::core::option::Option::None::<&::core::primitive::str>),
+            true,
+        ),
+        Err(e) => state_error(e, &key, None),
+    }
+}
+
+/// Removes every item of the FIFO queue stored for `key` (see [`push_state!`]) under a single
+/// exclusive lock, expanding to a [`Vec`] literal of the items in the order they were pushed,
+/// in the same form as [`read_state_vec!`].
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// push_state!("my work", "a");
+/// push_state!("my work", "b");
+/// assert_eq!(drain_state!("my work"), vec!["a", "b"]);
+/// assert_eq!(drain_state!("my work"), Vec::<String>::new());
+/// ```
+#[proc_macro]
+pub fn drain_state(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    match proc_drain_state(&key.value()) {
+        Ok(items) if items.is_empty() => track_write_policy(
+            with_alloc(quote!(__alloc::vec::Vec::<__alloc::string::String>::new())),
+            true,
+        ),
+        Ok(items) => track_write_policy(with_alloc(quote!(__alloc::vec![#(#items), *])), true),
+        Err(e) => state_error(e, &key, None),
+    }
+}
+
+fn parse_signed_int(input: ParseStream) -> syn::Result<i64> {
+    let negative = input.parse::<Option<Token![-]>>()?.is_some();
+    let literal = input.parse::<LitInt>()?;
+    let magnitude = literal.base10_parse::<i128>()?;
+    i64::try_from(if negative { -magnitude } else { magnitude })
+        .map_err(|_| syn::Error::new(literal.span(), "number too large to fit in target type"))
+}
+
+struct SortedAppendInput {
+    key: LitStr,
+    value: LitStr,
+    priority: i64,
+}
+
+impl Parse for SortedAppendInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<Comma>()?;
+        let value = input.parse()?;
+        input.parse::<Comma>()?;
+        let priority = parse_signed_int(input)?;
+        Ok(SortedAppendInput {
+            key,
+            value,
+            priority,
+        })
+    }
+}
+
+/// Like [`append_state!`], but stores a sort `priority` (an integer literal) along with the
+/// `value`. Whenever the list is read via [`read_state_vec!`], its items are returned in
+/// ascending priority order (items appended without a priority count as priority `0`), with
+/// ties kept in the order they were appended.
+///
+/// This gives generated registries a deterministic, user-controlled order that does not depend
+/// on the order in which macros happen to be expanded.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// append_state_sorted!("my handlers", "fallback", 100);
+/// append_state_sorted!("my handlers", "auth", -10);
+/// append_state!("my handlers", "logging");
+/// assert_eq!(read_state_vec!("my handlers"), vec!["auth", "logging", "fallback"]);
+/// ```
+#[proc_macro]
+pub fn append_state_sorted(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as SortedAppendInput);
+    match proc_append_state_sorted(&args.key.value(), &args.value.value(), args.priority) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
+    }
+}
+
+#[derive(Parse)]
+struct AppendStateRowInput {
+    key: LitStr,
+    _comma: Comma,
+    #[call(Punctuated::parse_separated_nonempty)]
+    columns: Punctuated<LitStr, Comma>,
+}
+
+/// Like [`append_state!`], but appends a row made up of several columns (string literals),
+/// which is read back as a whole via [`read_state_rows!`]. Each column is stored verbatim, so
+/// common "register (name, path, priority)" use cases don't need to join columns with (and
+/// escape) an ad-hoc separator.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// append_state_row!("my routes", "users", "/users", "10");
+/// append_state_row!("my routes", "a|b", "/a,b", "20");
+/// assert_eq!(
+///     read_state_rows!("my routes"),
+///     &[("users", "/users", "10"), ("a|b", "/a,b", "20")]
+/// );
+/// ```
+#[proc_macro]
+pub fn append_state_row(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as AppendStateRowInput);
+    let columns: Vec<String> = args.columns.iter().map(LitStr::value).collect();
+    let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+    match proc_append_state_row(&args.key.value(), &columns) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.columns[0])),
+    }
+}
+
+struct ReadStateRowsInput {
+    key: LitStr,
+    columns: Option<usize>,
+}
+
+impl Parse for ReadStateRowsInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        if input.is_empty() {
+            return Ok(ReadStateRowsInput { key, columns: None });
+        }
+        input.parse::<Comma>()?;
+        let columns = input.parse::<LitInt>()?.base10_parse::<usize>()?;
+        Ok(ReadStateRowsInput {
+            key,
+            columns: Some(columns),
+        })
+    }
+}
+
+/// Reads the rows appended to the list stored for `key` via [`append_state_row!`], expanding
+/// to a slice of tuples of string literals, in the same order [`read_state_vec!`] returns
+/// items. Items appended via [`append_state!`] count as rows with a single column.
+///
+/// Every row must have the same number of columns, otherwise a compile-time error is raised.
+/// The number of columns may also be passed as a second argument, which checks it and gives
+/// the slice a concrete type even when the list is empty.
+///
+/// Note: This macro is infallible -- if any issue occurs trying to read the specified key, it
+/// is assumed that we should return an empty slice.
+///
+/// # Example
+/// ```
+/// append_state_row!("my columns", "id", "u64");
+/// append_state_row!("my columns", "name", "String");
+/// for (name, ty) in read_state_rows!("my columns", 2) {
+///     println!("{}: {}", name, ty);
+/// }
+/// ```
+#[proc_macro]
+pub fn read_state_rows(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as ReadStateRowsInput);
+    let key = args.key.value();
+    let items = match __private::read_state_list(key.as_str()) {
+        Ok(items) => items,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                __private::note_missed_read(&key);
+            }
+            Vec::new()
+        }
+    };
+    let rows: Vec<Vec<String>> = items
+        .iter()
+        .map(|item| __private::decode_state_row(item))
+        .collect();
+    let expected = args.columns.or_else(|| rows.first().map(Vec::len));
+    if let Some(row) = rows.iter().find(|row| Some(row.len()) != expected) {
+        let msg = format!(
+            "the rows of key \"{}\" must all have {} columns, but one has {}",
+            key,
+            expected.unwrap_or_default(),
+            row.len()
+        );
+        return syn::Error::new(args.key.span(), msg)
+            .to_compile_error()
+            .into();
+    }
+    let rows = rows.iter().map(|row| quote!((#(#row,)*)));
+    match args.columns {
+        Some(columns) => {
+            let types = (0..columns).map(|_| quote!(&'static str));
+            quote!((&[#(#rows),*] as &'static [(#(#types,)*)])).into()
+        }
+        None => quote!(&[#(#rows),*]).into(),
+    }
+}
+
+struct RecordField {
+    name: Ident,
+    value: syn::Expr,
+}
+
+impl Parse for RecordField {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let value = input.parse()?;
+        Ok(RecordField { name, value })
+    }
+}
+
+struct AppendStateRecordInput {
+    key: LitStr,
+    fields: Punctuated<RecordField, Comma>,
+}
+
+impl Parse for AppendStateRecordInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<Comma>()?;
+        let content;
+        syn::braced!(content in input);
+        let fields = content.parse_terminated(RecordField::parse)?;
+        if fields.is_empty() {
+            return Err(content.error("expected at least one field"));
+        }
+        Ok(AppendStateRecordInput { key, fields })
+    }
+}
+
+/// Like [`append_state_row!`], but appends a record made up of named fields, written like the
+/// body of a struct literal, which [`read_state_records!`] expands back into struct literals
+/// of a caller-specified type. This covers the vast majority of registry payloads.
+///
+/// The value of each field may be any expression, and is stored as Rust source that is only
+/// resolved where the records are read, so paths (such as handler functions) must be
+/// nameable from there.
+///
+/// If a field is specified more than once, or if an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// struct Route {
+///     method: &'static str,
+///     path: &'static str,
+///     weight: i32,
+/// }
+///
+/// append_state_record!("my routes", { method: "GET", path: "/x", weight: -1 });
+/// append_state_record!("my routes", { path: "/y", method: "POST", weight: 2 });
+/// let routes = read_state_records!("my routes", Route);
+/// assert_eq!(routes[1].method, "POST");
+/// ```
+#[proc_macro]
+pub fn append_state_record(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as AppendStateRecordInput);
+    let mut names = HashSet::new();
+    let mut fields = Vec::new();
+    for field in &args.fields {
+        if !names.insert(field.name.to_string()) {
+            let msg = format!("field `{}` specified more than once", field.name);
+            return syn::Error::new(field.name.span(), msg)
+                .to_compile_error()
+                .into();
+        }
+        let value = &field.value;
+        fields.push((field.name.to_string(), quote!(#value).to_string()));
+    }
+    let fields: Vec<(&str, &str)> = fields
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+    match proc_append_state_record(&args.key.value(), &fields) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.key)),
+    }
+}
+
+#[derive(Parse)]
+struct ReadStateRecordsInput {
+    key: LitStr,
+    _comma: Comma,
+    ty: syn::Path,
+}
+
+/// Reads the records appended to the list stored for `key` via [`append_state_record!`],
+/// expanding to a slice of struct literals of the type `ty`, in the same order
+/// [`read_state_vec!`] returns items.
+///
+/// Items of the list that aren't records are skipped. Since the slice has an explicit element
+/// type, the expansion is well-typed even when the list is empty.
+///
+/// # Example
+/// ```
+/// struct Model {
+///     name: &'static str,
+///     table: &'static str,
+/// }
+///
+/// append_state_record!("my models", { name: "User", table: "users" });
+/// for model in read_state_records!("my models", Model) {
+///     println!("{} => {}", model.name, model.table);
+/// }
+/// ```
+#[proc_macro]
+pub fn read_state_records(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as ReadStateRecordsInput);
+    let key = args.key.value();
+    let items = match __private::read_state_list(key.as_str()) {
+        Ok(items) => items,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                __private::note_missed_read(&key);
+            }
+            Vec::new()
+        }
+    };
+    let mut records = Vec::new();
+    for item in items {
+        let row = __private::decode_state_row(&item);
+        if row.is_empty()
+            || !row.len().is_multiple_of(2)
+            || row.chunks(2).any(|field| field[0].is_empty())
+        {
+            continue;
+        }
+        let mut fields = Vec::new();
+        for field in row.chunks(2) {
+            let source = format!("{}: {}", field[0], field[1]);
+            match syn::parse_str::<syn::FieldValue>(&source) {
+                Ok(field) => fields.push(field),
+                Err(e) => {
+                    let msg = format!("invalid field in a record of key \"{}\": {}", key, e);
+                    return syn::Error::new(args.key.span(), msg)
+                        .to_compile_error()
+                        .into();
+                }
+            }
+        }
+        records.push(fields);
+    }
+    let ty = &args.ty;
+    let records = records.iter().map(|fields| quote!(#ty { #(#fields),* }));
+    quote!((&[#(#records),*] as &[#ty])).into()
+}
+
+struct DedupInput {
+    key: LitStr,
+    sort: bool,
+}
+
+impl Parse for DedupInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        if input.is_empty() {
+            return Ok(DedupInput { key, sort: false });
+        }
+        input.parse::<Comma>()?;
+        let mode = input.parse::<Ident>()?;
+        if mode != "sort" {
+            return Err(syn::Error::new(mode.span(), "expected `sort`"));
+        }
+        Ok(DedupInput { key, sort: true })
+    }
+}
+
+/// Removes duplicate items from the list stored for `key` (see [`append_state!`]) in place,
+/// keeping the first occurrence of each item, while holding an exclusive lock over the state
+/// directory. Passing `sort` as a second argument also sorts the remaining items
+/// lexicographically.
+///
+/// This is useful for normalizing registries built up by many independent [`append_state!`]
+/// calls before the consuming macro reads them.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// append_state!("my types", "u8");
+/// append_state!("my types", "bool");
+/// append_state!("my types", "u8");
+/// dedup_state!("my types");
+/// assert_eq!(read_state_vec!("my types"), vec!["u8", "bool"]);
+/// dedup_state!("my types", sort);
+/// assert_eq!(read_state_vec!("my types"), vec!["bool", "u8"]);
+/// ```
+#[proc_macro]
+pub fn dedup_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as DedupInput);
+    match proc_dedup_state(&args.key.value(), args.sort) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, None),
+    }
+}
+
+#[derive(Parse)]
+struct ExtendStateInput {
+    key: LitStr,
+    _comma: Comma,
+    #[call(Punctuated::parse_terminated)]
+    values: Punctuated<LitStr, Comma>,
+}
+
+/// Appends each of the specified values to the list stored for `key`, exactly as if
+/// [`append_state!`] had been called once per value, but opening the state file only once.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// extend_state!("my_list", "apples", "pears");
+/// extend_state!("my_list", "oh my!");
+/// assert_eq!(read_state_vec!("my_list"), vec!["apples", "pears", "oh my!"]);
+/// ```
+#[proc_macro]
+pub fn extend_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as ExtendStateInput);
+    let values: Vec<String> = args.values.iter().map(LitStr::value).collect();
+    let values: Vec<&str> = values.iter().map(String::as_str).collect();
+    match proc_extend_state(&args.key.value(), &values) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, args.values.first()),
+    }
+}
+
+/// Reads the state value for the specified key as a list, in the same way as
+/// [`read_state_vec!`], but expands to a fixed-size `[&'static str; N]` array literal, with
+/// `N` determined at expansion time. This avoids heap allocation entirely and can be used in
+/// `const` contexts.
+///
+/// Note: This macro is infallible -- if any issue occurs trying to read the specified key, it
+/// is assumed that we should return an empty array.
+///
+/// # Example
+/// ```
+/// append_state!("my_key", "first item");
+/// append_state!("my_key", "2nd item");
+/// const ITEMS: [&str; 2] = read_state_array!("my_key");
+/// assert_eq!(ITEMS, ["first item", "2nd item"]);
+/// ```
+#[proc_macro]
+pub fn read_state_array(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    let items = __private::read_state_list(key.as_str()).unwrap_or_default();
+    let len = items.len();
+    quote! {
+        {
+            const ITEMS: [&::core::primitive::str; #len] = [#(#items), *];
+            ITEMS
+        }
+    }
+    .into()
+}
+
+fn read_state_integer<T>(key: &str, suffix: &str) -> TokenStream
+where
+    T: std::str::FromStr + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    let value = match __private::read_state_value(key) {
+        Ok(value) => value,
+        Err(e) => return quote_io_error(e),
+    };
+    match value.trim().parse::<T>() {
+        Ok(number) => {
+            let literal = LitInt::new(
+                &format!("{}{}", number, suffix),
+                proc_macro::Span::call_site().into(),
+            );
+            quote!(#literal).into()
+        }
+        Err(e) => {
+            let msg = format!(
+                "the state value for key \"{}\" is not a valid {}: \"{}\" ({})",
+                key, suffix, value, e
+            );
+            quote!(::core::compile_error!(#msg)).into()
+        }
+    }
+}
+
+/// Reads the state value for the specified `key` and expands to it as a [`usize`] literal.
+/// Unlike parsing the result of [`read_state!`] at runtime, the value is validated at compile
+/// time.
+///
+/// If no value can be found for the provided key, or if the value is not a valid [`usize`],
+/// the macro will raise a compile-time error naming the key and the offending value.
+///
+/// # Example
+/// ```
+/// write_state!("my count", "42");
+/// const COUNT: usize = read_state_usize!("my count");
+/// assert_eq!(COUNT, 42);
+/// ```
+#[proc_macro]
+pub fn read_state_usize(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    read_state_integer::<usize>(key.as_str(), "usize")
+}
+
+/// Reads the state value for the specified `key` and expands to it as an [`i64`] literal.
+/// Unlike parsing the result of [`read_state!`] at runtime, the value is validated at compile
+/// time.
+///
+/// If no value can be found for the provided key, or if the value is not a valid [`i64`], the
+/// macro will raise a compile-time error naming the key and the offending value.
+///
+/// # Example
+/// ```
+/// write_state!("my offset", "-7");
+/// const OFFSET: i64 = read_state_i64!("my offset");
+/// assert_eq!(OFFSET, -7);
+/// ```
+#[proc_macro]
+pub fn read_state_i64(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    read_state_integer::<i64>(key.as_str(), "i64")
+}
+
+/// Marks the flag with the specified `name` as set, so that subsequent [`state_flag!`] calls
+/// for it expand to `true`. Flags are kept apart from regular state keys, and setting a flag
+/// that is already set has no effect.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// set_state_flag!("has_migrations");
+/// assert_eq!(state_flag!("has_migrations"), true);
+/// ```
+#[proc_macro]
+pub fn set_state_flag(items: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(items as LitStr).value();
+    match proc_set_state_flag(&name) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Expands to `true` if the flag with the specified `name` has been set via
+/// [`set_state_flag!`], otherwise `false`.
+///
+/// Note that this macro is infallible -- it will always expand to `true` or `false`.
+///
+/// # Example
+/// ```
+/// assert_eq!(state_flag!("has_migrations"), false);
+/// set_state_flag!("has_migrations");
+/// assert_eq!(state_flag!("has_migrations"), true);
+/// ```
+#[proc_macro]
+pub fn state_flag(items: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(items as LitStr).value();
+    let set = proc_state_flag(&name);
+    quote!(#set).into()
+}
+
+struct CounterInput {
+    key: LitStr,
+    amount: i64,
+}
+
+impl Parse for CounterInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<Comma>()?;
+        let amount = parse_signed_int(input)?;
+        Ok(CounterInput { key, amount })
+    }
+}
+
+/// Adds `n` (an integer literal, which may be negative) to the counter stored for `key`.
+/// Counters that have never been written to start out at `0`. The read and the write happen
+/// under a single exclusive lock, so concurrent additions are never lost.
+///
+/// This allows macros to accumulate statistics, such as the number of generated endpoints,
+/// which can then be embedded via [`read_counter!`].
+///
+/// If the stored value is not a valid counter or an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// add_to_counter!("my endpoints", 2);
+/// add_to_counter!("my endpoints", 3);
+/// assert_eq!(read_counter!("my endpoints"), 5);
+/// ```
+#[proc_macro]
+pub fn add_to_counter(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as CounterInput);
+    fold_counter(&args, proc_add_state)
+}
+
+/// Folds the value of `args` into the counter stored for its key via `fold` under a single
+/// exclusive lock, storing the value itself if the counter has never been written to. `fold`
+/// returns [`None`] on overflow.
+fn fold_counter(args: &CounterInput, fold: fn(&str, i64) -> StateResult<i64>) -> TokenStream {
+    match fold(&args.key.value(), args.amount) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, None),
+    }
+}
+
+/// Adds `n` (an integer literal, which may be negative) to the integer aggregate stored for
+/// `key`, exactly like [`add_to_counter!`]. Together with [`max_state!`] and [`min_state!`],
+/// this lets macros accumulate aggregates (such as the total size of generated tables) that a
+/// final consumer embeds as constants via [`read_counter!`] or [`read_state_i64!`].
+///
+/// If the stored value is not a valid integer, the sum overflows, or an IO error occurs, the
+/// macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// add_state!("total size", 128);
+/// add_state!("total size", 64);
+/// const TOTAL_SIZE: i64 = read_counter!("total size");
+/// assert_eq!(TOTAL_SIZE, 192);
+/// ```
+#[proc_macro]
+pub fn add_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as CounterInput);
+    fold_counter(&args, proc_add_state)
+}
+
+/// Replaces the integer aggregate stored for `key` with `n` (an integer literal, which may be
+/// negative) if it is greater, or if the key has never been written to. The read and the
+/// write happen under a single exclusive lock, so concurrent updates are never lost. The
+/// maximum can then be embedded via [`read_counter!`] or [`read_state_i64!`].
+///
+/// If the stored value is not a valid integer or an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// max_state!("max arity", 2);
+/// max_state!("max arity", 5);
+/// max_state!("max arity", 3);
+/// const MAX_ARITY: i64 = read_counter!("max arity");
+/// assert_eq!(MAX_ARITY, 5);
+/// ```
+#[proc_macro]
+pub fn max_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as CounterInput);
+    fold_counter(&args, proc_max_state)
+}
+
+/// Replaces the integer aggregate stored for `key` with `n` (an integer literal, which may be
+/// negative) if it is smaller, or if the key has never been written to. The read and the
+/// write happen under a single exclusive lock, so concurrent updates are never lost. The
+/// minimum can then be embedded via [`read_counter!`] or [`read_state_i64!`].
+///
+/// If the stored value is not a valid integer or an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// min_state!("min version", 3);
+/// min_state!("min version", 1);
+/// assert_eq!(read_state_i64!("min version"), 1);
+/// ```
+#[proc_macro]
+pub fn min_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as CounterInput);
+    fold_counter(&args, proc_min_state)
+}
+
+/// Expands to the current value of the counter stored for `key` as an [`i64`] literal, or `0`
+/// if the counter has never been written to (see [`add_to_counter!`]).
+///
+/// If the stored value is not a valid counter, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// assert_eq!(read_counter!("my widgets"), 0);
+/// add_to_counter!("my widgets", 7);
+/// const WIDGETS: i64 = read_counter!("my widgets");
+/// assert_eq!(WIDGETS, 7);
+/// ```
+#[proc_macro]
+pub fn read_counter(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr).value();
+    match proc_read_counter(&key) {
+        Ok(value) => {
+            let literal = LitInt::new(
+                &format!("{}i64", value),
+                proc_macro::Span::call_site().into(),
+            );
+            quote!(#literal).into()
+        }
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Resets the counter stored for `key` back to `0` (see [`add_to_counter!`]).
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// add_to_counter!("my errors", 3);
+/// reset_counter!("my errors");
+/// assert_eq!(read_counter!("my errors"), 0);
+/// ```
+#[proc_macro]
+pub fn reset_counter(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    match proc_reset_counter(&key.value()) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &key, None),
+    }
+}
+
+fn translation_keys() -> String {
+    format!(
+        "__macro_state/translations/{}",
+        __private::current_crate_name()
+    )
+}
+
+/// Flattens the JSON `value` of a locale file into the dotted keys it provides, appending them
+/// to `keys`, with `path` being the dotted key of `value` itself.
+fn json_keys(value: &serde_json::Value, path: &str, keys: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(entries) => {
+            for (key, value) in entries {
+                match path {
+                    "" => json_keys(value, key, keys),
+                    _ => json_keys(value, &format!("{}.{}", path, key), keys),
+                }
+            }
+        }
+        _ => keys.push(path.to_string()),
+    }
+}
+
+/// Records `key` as a translation key used by the crate being compiled and expands to it as a
+/// string literal, so that it can be passed on to whatever runtime localization library is in
+/// use.
+///
+/// Used in conjunction with [`validate_translations!`], which checks every recorded key
+/// against a locale file at compile time.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// let key = t!("greeting.hello");
+/// assert_eq!(key, "greeting.hello");
+/// ```
+#[proc_macro]
+pub fn t(items: TokenStream) -> TokenStream {
+    let key = parse_macro_input!(items as LitStr);
+    match __private::append_item(&translation_keys(), &key.value()) {
+        Ok(_) => quote!(#key).into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Checks every translation key recorded so far via [`t!`] against the specified JSON locale
+/// file, whose path is relative to the root of the crate being compiled. Nested objects in the
+/// locale file are flattened into dotted keys, so `{"greeting": {"hello": "Hi"}}` provides the
+/// key `greeting.hello`.
+///
+/// This macro should be invoked at the very bottom of the crate (see
+/// [expansion order](crate#expansion-order)).
+///
+/// If the locale file cannot be read or parsed, or if any recorded key is missing from it, the
+/// macro will raise a compile-time error listing the missing keys.
+///
+/// # Example
+/// ```ignore
+/// fn greet() -> &'static str {
+///     t!("greeting.hello")
+/// }
+///
+/// validate_translations!("locales/en.json");
+/// ```
+#[proc_macro]
+pub fn validate_translations(items: TokenStream) -> TokenStream {
+    let locale = parse_macro_input!(items as LitStr).value();
+    let path = manifest_relative_path(&locale);
+    let source = match __private::retry_io(|| fs::read_to_string(&path)) {
+        Ok(source) => source,
+        Err(e) => {
+            let msg = format!("failed to read locale file \"{}\": {}", locale, e);
+            return quote!(::core::compile_error!(#msg);).into();
+        }
+    };
+    let provided: HashSet<String> = match serde_json::from_str(&source) {
+        Ok(value) => {
+            let mut keys = Vec::new();
+            json_keys(&value, "", &mut keys);
+            keys.into_iter().collect()
+        }
+        Err(e) => {
+            let msg = format!("failed to parse locale file \"{}\": {}", locale, e);
+            return quote!(::core::compile_error!(#msg);).into();
+        }
+    };
+    let mut missing = __private::read_items(&translation_keys());
+    missing.retain(|key| !provided.contains(key));
+    let mut seen = HashSet::new();
+    missing.retain(|key| seen.insert(key.clone()));
+    if missing.is_empty() {
+        return quote!().into();
+    }
+    let msg = format!(
+        "the following translation keys are missing from \"{}\": {}",
+        locale,
+        missing.join(", ")
+    );
+    quote!(::core::compile_error!(#msg);).into()
+}
+
+const FEATURE_FLAGS: &str = "__macro_state/feature_flags";
+
+/// Declares a runtime feature flag with the specified `name` in a registry shared by every
+/// crate in the build, so that the full set of flags can later be retrieved via
+/// [`all_flags!`].
+///
+/// Each flag may only be declared once. If the same flag is declared at two different
+/// locations, anywhere in the workspace, or if an IO error occurs, the macro will raise a
+/// compile-time error pointing at the earlier declaration. Flag names may not contain `=`.
+///
+/// # Example
+/// ```
+/// declare_flag!("new_checkout");
+/// declare_flag!("dark_mode");
+///
+/// assert_eq!(all_flags!(), &["new_checkout", "dark_mode"]);
+/// ```
+#[proc_macro]
+pub fn declare_flag(items: TokenStream) -> TokenStream {
+    let name = parse_macro_input!(items as LitStr);
+    // the registry stores each flag as `name=location`
+    if name.value().contains('=') {
+        let msg = "feature flag names may not contain `=`";
+        return syn::Error::new(name.span(), msg).to_compile_error().into();
+    }
+    let name = name.value();
+    let span = proc_macro::Span::call_site();
+    let location = format!(
+        "{} ({}:{}:{})",
+        __private::current_crate_name(),
+        span.file(),
+        span.line(),
+        span.column()
+    );
+    let entry = format!("{}={}", name, location);
+    let result = __private::with_state_dir_lock(|| {
+        let existing = __private::read_items(FEATURE_FLAGS);
+        for declared in existing.iter() {
+            match declared.split_once('=') {
+                Some((declared, _)) if declared != name => {}
+                Some((_, earlier)) if earlier == location => return Ok(()),
+                Some((_, earlier)) => {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!(
+                            "feature flag \"{}\" was already declared in {}",
+                            name, earlier
+                        ),
+                    ))
+                }
+                None => {}
+            }
+        }
+        __private::append_item(FEATURE_FLAGS, &entry)
+    });
+    match result {
+        Ok(_) => quote!().into(),
+        Err(e) => {
+            let msg = e.to_string();
+            quote!(::core::compile_error!(#msg);).into()
+        }
+    }
+}
+
+/// Expands to a `&'static [&'static str]` slice containing the name of every feature flag
+/// declared so far via [`declare_flag!`], in declaration order.
+///
+/// Note: This macro is infallible -- if no flags have been declared, it expands to an empty
+/// slice.
+///
+/// # Example
+/// ```
+/// declare_flag!("new_checkout");
+///
+/// for flag in all_flags!() {
+///     println!("{}", flag);
+/// }
+/// ```
+#[proc_macro]
+pub fn all_flags(items: TokenStream) -> TokenStream {
+    if !items.is_empty() {
+        return quote!(::core::compile_error!(
+            "all_flags! does not take any arguments"
+        ))
+        .into();
+    }
+    let flags: Vec<String> = __private::read_items(FEATURE_FLAGS)
+        .into_iter()
+        .filter_map(|entry| entry.split_once('=').map(|(name, _)| name.to_string()))
+        .collect();
+    quote!((&[#(#flags), *] as &[&::core::primitive::str])).into()
+}
+
+fn schema_key() -> String {
+    format!("__macro_state/schema/{}", __private::current_crate_name())
+}
+
+fn unquote_identifier(identifier: &str) -> String {
+    identifier
+        .trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'))
+        .to_lowercase()
+}
+
+fn apply_migration(sql: &str, tables: &mut HashMap<String, Vec<String>>) {
+    let sql: String = sql
+        .lines()
+        .map(|line| line.split("--").next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+    for statement in sql.split(';') {
+        let lowered = statement.to_lowercase();
+        let words: Vec<&str> = lowered.split_whitespace().collect();
+        match words.as_slice() {
+            ["create", "table", "if", "not", "exists", name, ..]
+            | ["create", "table", name, ..] => {
+                let name = unquote_identifier(name.split('(').next().unwrap_or_default());
+                let Some(open) = statement.find('(') else {
+                    continue;
+                };
+                let Some(close) = statement.rfind(')') else {
+                    continue;
+                };
+                let mut columns = Vec::new();
+                let mut depth = 0;
+                let mut definition = String::new();
+                for c in statement[(open + 1)..close].chars().chain([',']) {
+                    match c {
+                        '(' => depth += 1,
+                        ')' => depth -= 1,
+                        ',' if depth == 0 => {
+                            let column = unquote_identifier(
+                                definition.split_whitespace().next().unwrap_or_default(),
+                            );
+                            let constraint = matches!(
+                                column.as_str(),
+                                "primary" | "foreign" | "unique" | "constraint" | "check" | "key"
+                            );
+                            if !column.is_empty() && !constraint {
+                                columns.push(column);
+                            }
+                            definition.clear();
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    definition.push(c);
+                }
+                tables.insert(name, columns);
+            }
+            ["drop", "table", "if", "exists", name, ..] | ["drop", "table", name, ..] => {
+                tables.remove(&unquote_identifier(name));
+            }
+            ["alter", "table", table, action @ ..] => {
+                let table = unquote_identifier(table);
+                if let ["rename", "to", to, ..] = action {
+                    if let Some(columns) = tables.remove(&table) {
+                        tables.insert(unquote_identifier(to), columns);
+                    }
+                    continue;
+                }
+                let Some(columns) = tables.get_mut(&table) else {
+                    continue;
+                };
+                match action {
+                    ["add", "constraint" | "primary" | "foreign" | "unique" | "check", ..] => {}
+                    ["add", "column", column, ..] | ["add", column, ..] => {
+                        columns.push(unquote_identifier(column))
+                    }
+                    ["drop", "constraint", ..] => {}
+                    ["drop", "column", column, ..] | ["drop", column, ..] => {
+                        let column = unquote_identifier(column);
+                        columns.retain(|existing| *existing != column);
+                    }
+                    ["rename", "column", from, "to", to, ..] => {
+                        let (from, to) = (unquote_identifier(from), unquote_identifier(to));
+                        for column in columns.iter_mut().filter(|column| **column == from) {
+                            *column = to.clone();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn to_snake_case(ident: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in ident.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// Records the table name and columns of the annotated struct into state, so that they can be
+/// checked against the crate's SQL migrations by [`check_schema!`].
+///
+/// The table name defaults to the name of the struct in `snake_case`, and can be overridden
+/// via a `#[table_name = "..."]` attribute. Each named field of the struct is recorded as a
+/// column. This derive does not generate any code.
+///
+/// If the annotated item is not a struct with named fields, or if an IO error occurs, the
+/// macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// #[derive(RecordSchema)]
+/// #[table_name = "users"]
+/// struct User {
+///     id: i64,
+///     email: String,
+/// }
+/// ```
+#[proc_macro_derive(RecordSchema, attributes(table_name))]
+pub fn record_schema(tokens: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(tokens as syn::DeriveInput);
+    let fields = match &input.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => fields,
+        _ => {
+            return quote!(::core::compile_error!(
+                "RecordSchema can only be derived for structs with named fields"
+            );)
+            .into()
+        }
+    };
+    let mut table = to_snake_case(&input.ident.to_string());
+    for attr in input.attrs.iter() {
+        if !attr.path.is_ident("table_name") {
+            continue;
+        }
+        match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(name),
+                ..
+            })) => table = name.value(),
+            _ => {
+                return quote!(::core::compile_error!(
+                    "expected an attribute of the form `#[table_name = \"...\"]`"
+                );)
+                .into()
+            }
+        }
+    }
+    let columns: Vec<String> = fields
+        .named
+        .iter()
+        .filter_map(|field| field.ident.as_ref())
+        .map(|ident| ident.to_string().trim_start_matches("r#").to_string())
+        .collect();
+    let entry = format!("{}:{}", table, columns.join(","));
+    match __private::append_item(&schema_key(), &entry) {
+        Ok(_) => quote!().into(),
+        Err(e) => {
+            let msg = e.to_string();
+            quote!(::core::compile_error!(#msg);).into()
+        }
+    }
+}
+
+/// Cross-references the schema recorded so far via [`RecordSchema`](derive@RecordSchema)
+/// against the SQL migration files (`*.sql`) in the specified directory, whose path is
+/// relative to the root of the crate being compiled. Migrations are applied in file name
+/// order, and `CREATE TABLE`, `ALTER TABLE`, and `DROP TABLE` statements are understood.
+///
+/// This macro should be invoked after every model has been declared, typically at the very
+/// bottom of the crate (see [expansion order](crate#expansion-order)).
+///
+/// If the migrations cannot be read, or if any recorded model refers to a table that does not
+/// exist or whose columns differ from those produced by the migrations, the macro will raise
+/// a compile-time error describing the drift.
+///
+/// # Example
+/// ```ignore
+/// #[derive(RecordSchema)]
+/// struct User {
+///     id: i64,
+///     email: String,
+/// }
+///
+/// check_schema!("migrations/");
+/// ```
+#[proc_macro]
+pub fn check_schema(items: TokenStream) -> TokenStream {
+    let dir = parse_macro_input!(items as LitStr).value();
+    let path = manifest_relative_path(&dir);
+    let mut migrations: Vec<PathBuf> = match fs::read_dir(&path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+            .collect(),
+        Err(e) => {
+            let msg = format!("failed to read migrations in \"{}\": {}", dir, e);
+            return quote!(::core::compile_error!(#msg);).into();
+        }
+    };
+    migrations.sort();
+    let mut tables = HashMap::new();
+    for migration in migrations {
+        match __private::retry_io(|| fs::read_to_string(&migration)) {
+            Ok(sql) => apply_migration(&sql, &mut tables),
+            Err(e) => {
+                let msg = format!("failed to read migration {}: {}", migration.display(), e);
+                return quote!(::core::compile_error!(#msg);).into();
+            }
+        }
+    }
+    let mut drift = Vec::new();
+    for entry in __private::read_items(&schema_key()) {
+        let Some((table, columns)) = entry.split_once(':') else {
+            continue;
+        };
+        let Some(migrated) = tables.get(&table.to_lowercase()) else {
+            drift.push(format!("table \"{}\" does not exist", table));
+            continue;
+        };
+        let columns: Vec<String> = columns
+            .split(',')
+            .filter(|column| !column.is_empty())
+            .map(|column| column.to_lowercase())
+            .collect();
+        for column in columns.iter().filter(|column| !migrated.contains(column)) {
+            drift.push(format!("column \"{}.{}\" does not exist", table, column));
+        }
+        for column in migrated.iter().filter(|column| !columns.contains(column)) {
+            drift.push(format!(
+                "column \"{}.{}\" is not part of the model",
+                table, column
+            ));
+        }
+    }
+    if drift.is_empty() {
+        return quote!().into();
+    }
+    let msg = format!(
+        "the recorded schema has drifted from the migrations in \"{}\": {}",
+        dir,
+        drift.join("; ")
+    );
+    quote!(::core::compile_error!(#msg);).into()
+}
+
+fn messages_key() -> String {
+    format!("__macro_state/messages/{}", __private::current_crate_name())
+}
+
+fn read_messages() -> Result<Vec<(String, u64)>, Error> {
+    Ok(__private::read_items(&messages_key())
+        .into_iter()
+        .filter_map(|entry| {
+            let (ty, id) = entry.rsplit_once('=')?;
+            Some((ty.to_string(), id.parse().ok()?))
+        })
+        .collect())
+}
+
+#[derive(Parse)]
+struct RegisterMessageInput {
+    ty: syn::Path,
+    _eq: Token![=],
+    id: LitInt,
+}
+
+/// Registers the message type `Type` under the numeric ID `id` in a registry scoped to the
+/// crate being compiled, for use by protocol and codec crates. The registered types can then
+/// be dispatched on by ID via [`dispatch_message!`].
+///
+/// IDs must be unique, and each type may only be registered once. If either rule is broken,
+/// or if an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// struct Ping;
+/// struct Pong;
+///
+/// register_message!(Ping = 1);
+/// register_message!(Pong = 2);
+/// ```
+#[proc_macro]
+pub fn register_message(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as RegisterMessageInput);
+    let ty = args.ty;
+    let ty = quote!(#ty).to_string();
+    let id = match args.id.base10_parse::<u64>() {
+        Ok(id) => id,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let result = __private::with_state_dir_lock(|| {
+        for (existing_ty, existing_id) in read_messages()? {
+            if existing_ty == ty && existing_id == id {
+                return Ok(());
+            }
+            let msg = if existing_ty == ty {
+                format!(
+                    "message type {} is already registered with ID {}",
+                    ty, existing_id
+                )
+            } else if existing_id == id {
+                format!("message ID {} is already taken by {}", id, existing_ty)
+            } else {
+                continue;
+            };
+            return Err(Error::new(ErrorKind::AlreadyExists, msg));
+        }
+        let entry = format!("{}={}", ty, id);
+        __private::append_item(&messages_key(), &entry)
+    });
+    match result {
+        Ok(_) => quote!().into(),
+        Err(e) => {
+            let msg = e.to_string();
+            quote!(::core::compile_error!(#msg);).into()
+        }
+    }
+}
+
+#[derive(Parse)]
+struct DispatchMessageInput {
+    id: syn::Expr,
+    _comma: Comma,
+    alias: Ident,
+    _arrow: Token![=>],
+    body: syn::Expr,
+}
+
+/// Expands to a `match` over every message type registered so far via [`register_message!`],
+/// acting as an `ID → type` dispatch table.
+///
+/// The first argument is the message ID to dispatch on. It is followed by `T => expr`, where
+/// `expr` is evaluated with `T` aliased to the message type registered under that ID. The
+/// macro evaluates to `Some(expr)` for a registered ID and to `None` for an unknown one.
+///
+/// This macro should be invoked after every message type has been registered (see
+/// [expansion order](crate#expansion-order)).
+///
+/// # Example
+/// ```
+/// trait Message {
+///     const NAME: &'static str;
+/// }
+///
+/// struct Ping;
+/// impl Message for Ping {
+///     const NAME: &'static str = "ping";
+/// }
+///
+/// register_message!(Ping = 1);
+///
+/// fn message_name(id: u16) -> Option<&'static str> {
+///     dispatch_message!(id, T => <T as Message>::NAME)
+/// }
+///
+/// assert_eq!(message_name(1), Some("ping"));
+/// assert_eq!(message_name(2), None);
+/// ```
+#[proc_macro]
+pub fn dispatch_message(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as DispatchMessageInput);
+    let messages = match read_messages() {
+        Ok(messages) => messages,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => return quote_io_error(e),
+    };
+    let (alias, body) = (&args.alias, &args.body);
+    let mut arms = Vec::new();
+    for (ty, id) in messages {
+        let ty = match syn::parse_str::<syn::Path>(&ty) {
+            Ok(ty) => ty,
+            Err(e) => return e.to_compile_error().into(),
+        };
+        let id = LitInt::new(&id.to_string(), proc_macro::Span::call_site().into());
+        arms.push(quote!(#id => ::core::option::Option::Some({ type #alias = #ty; #body })));
+    }
+    let id = args.id;
+    quote! {
+        match #id {
+            #(#arms,)*
+            _ => ::core::option::Option::None,
+        }
+    }
+    .into()
+}
+
+const FIELD_SEPARATOR: char = '\u{1f}';
+
+fn collected_tests_key() -> String {
+    format!("__macro_state/tests/{}", __private::current_crate_name())
+}
+
+fn call_site_location() -> (String, usize) {
+    let span = proc_macro::Span::call_site();
+    (span.file(), span.line())
+}
+
+#[derive(Default)]
+struct CollectTestArgs {
+    meta: String,
+    tags: Vec<String>,
+}
+
+impl Parse for CollectTestArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = CollectTestArgs::default();
+        while !input.is_empty() {
+            let name = input.parse::<Ident>()?;
+            input.parse::<Token![=]>()?;
+            let value = input.parse::<LitStr>()?;
+            if name == "meta" {
+                args.meta = value.value();
+            } else if name == "tags" {
+                args.tags = value
+                    .value()
+                    .split(',')
+                    .map(|tag| tag.trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect();
+            } else {
+                return Err(syn::Error::new(name.span(), "expected `meta` or `tags`"));
+            }
+            if !input.is_empty() {
+                input.parse::<Comma>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Records the annotated test function, along with its location and any attached metadata,
+/// in a registry scoped to the crate being compiled. The recorded tests can then be emitted as
+/// a static table via [`emit_test_manifest!`], for use by custom test harnesses.
+///
+/// Free-form metadata can be attached via `meta = "..."`, and a comma-separated list of tags
+/// via `tags = "..."`. The annotated function itself is left untouched.
+///
+/// If the annotated item is not a function, or if an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```ignore
+/// #[collect_test(meta = "talks to the database", tags = "slow, db")]
+/// #[test]
+/// fn test_query() {}
+/// ```
+#[proc_macro_attribute]
+pub fn collect_test(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as CollectTestArgs);
+    let item_tokens = tokens.clone();
+    let item = parse_macro_input!(item_tokens as syn::ItemFn);
+    let (file, line) = call_site_location();
+    let entry = [
+        item.sig.ident.to_string(),
+        args.meta,
+        args.tags.join(","),
+        file,
+        line.to_string(),
+    ]
+    .join(&FIELD_SEPARATOR.to_string());
+    match __private::record_unique_entry(&collected_tests_key(), &entry) {
+        Ok(_) => tokens,
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Expands to a `&'static [macro_state::CollectedTest]` table describing every test function
+/// recorded so far via [`#[collect_test]`](macro@collect_test), including its name, metadata,
+/// tags, and location.
+///
+/// This macro should be invoked after every collected test has been declared (see
+/// [expansion order](crate#expansion-order)).
+///
+/// Note: This macro is infallible -- if no tests have been collected, it expands to an empty
+/// table.
+///
+/// # Example
+/// ```ignore
+/// #[collect_test(tags = "fast")]
+/// #[test]
+/// fn test_addition() {}
+///
+/// for test in emit_test_manifest!() {
+///     println!("{} ({}:{}) {:?}", test.name, test.file, test.line, test.tags);
+/// }
+/// ```
+#[proc_macro]
+pub fn emit_test_manifest(items: TokenStream) -> TokenStream {
+    if !items.is_empty() {
+        return quote!(::core::compile_error!(
+            "emit_test_manifest! does not take any arguments"
+        ))
+        .into();
+    }
+    let tests = __private::read_items(&collected_tests_key())
+        .into_iter()
+        .filter_map(|entry| {
+            let fields: Vec<&str> = entry.split(FIELD_SEPARATOR).collect();
+            let [name, meta, tags, file, line] = fields.as_slice() else {
+                return None;
+            };
+            let tags = tags.split(',').filter(|tag| !tag.is_empty());
+            let line = line.parse::<u32>().ok()?;
+            Some(quote! {
+                ::macro_state::CollectedTest {
+                    name: #name,
+                    meta: #meta,
+                    tags: &[#(#tags),*],
+                    file: #file,
+                    line: #line,
+                }
+            })
+        });
+    quote!((&[#(#tests),*] as &[::macro_state::CollectedTest])).into()
+}
+
+fn collected_benches_key() -> String {
+    format!("__macro_state/benches/{}", __private::current_crate_name())
+}
+
+/// Runs the annotated function (typically a `#[test]`) within
+/// `macro_state::testing::with_isolated_state`, so that every state operation it makes via the
+/// `proc_*` functions is redirected to a fresh, empty state directory that is removed once the
+/// function returns. The return value of the function is passed through unchanged.
+///
+/// If the annotated item is not a function, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// #[isolated_state]
+/// #[test]
+/// fn test_registry() {
+///     proc_write_state("models", "User").unwrap();
+///     assert_eq!(proc_read_state("models").unwrap(), "User");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn isolated_state(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return quote!(::core::compile_error!(
+            "#[isolated_state] does not take any arguments"
+        );)
+        .into();
+    }
+    let mut item = parse_macro_input!(tokens as syn::ItemFn);
+    let block = &item.block;
+    item.block = syn::parse_quote!({
+        ::macro_state::testing::with_isolated_state(move || #block)
+    });
+    quote!(#item).into()
+}
+
+/// Records the annotated benchmark function in a registry scoped to the crate being compiled,
+/// so that [`emit_bench_main!`] can generate a `main` function running every collected
+/// benchmark. Benchmark functions must take no arguments, and must be in scope wherever
+/// [`emit_bench_main!`] is invoked. The annotated function itself is left untouched.
+///
+/// If the annotated item is not a function, or if an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```ignore
+/// #[collect_bench]
+/// fn bench_sum() -> u64 {
+///     (0..1000).sum()
+/// }
+///
+/// emit_bench_main!();
+/// ```
+#[proc_macro_attribute]
+pub fn collect_bench(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return quote!(::core::compile_error!(
+            "#[collect_bench] does not take any arguments"
+        );)
+        .into();
+    }
+    let item_tokens = tokens.clone();
+    let item = parse_macro_input!(item_tokens as syn::ItemFn);
+    let (file, line) = call_site_location();
+    let entry =
+        [item.sig.ident.to_string(), file, line.to_string()].join(&FIELD_SEPARATOR.to_string());
+    match __private::record_unique_entry(&collected_benches_key(), &entry) {
+        Ok(_) => tokens,
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Expands to a `fn main()` that runs every benchmark recorded so far via
+/// [`#[collect_bench]`](macro@collect_bench), printing the average time taken per iteration.
+/// The number of iterations defaults to `100`, and can be specified as a positive integer
+/// literal, e.g. `emit_bench_main!(1000)`.
+///
+/// Any command-line arguments not starting with `-` are treated as filters, so that only
+/// benchmarks whose names contain one of them are run, mirroring `cargo bench -- <filter>`.
+/// This macro should be invoked at the very bottom of the bench target (see
+/// [expansion order](crate#expansion-order)), with `harness = false` set for it in
+/// `Cargo.toml`.
+///
+/// # Example
+/// ```ignore
+/// #[collect_bench]
+/// fn bench_sum() -> u64 {
+///     (0..1000).sum()
+/// }
+///
+/// emit_bench_main!(1000);
+/// ```
+#[proc_macro]
+pub fn emit_bench_main(items: TokenStream) -> TokenStream {
+    let iterations = if items.is_empty() {
+        100
+    } else {
+        let literal = parse_macro_input!(items as LitInt);
+        match literal.base10_parse::<u32>() {
+            Ok(0) => {
+                let msg = "the number of iterations must be at least 1";
+                return syn::Error::new(literal.span(), msg)
+                    .to_compile_error()
+                    .into();
+            }
+            Ok(iterations) => iterations,
+            Err(e) => return e.to_compile_error().into(),
+        }
+    };
+    let benches: Vec<Ident> = __private::read_items(&collected_benches_key())
+        .iter()
+        .filter_map(|entry| entry.split(FIELD_SEPARATOR).next())
+        .map(|name| format_ident!("{}", name))
+        .collect();
+    let names = benches.iter().map(|bench| bench.to_string());
+    quote! {
+        fn main() {
+            let filters: ::std::vec::Vec<::std::string::String> = ::std::env::args()
+                .skip(1)
+                .filter(|arg| !arg.starts_with('-'))
+                .collect();
+            let iterations: u32 = #iterations;
+            #(
+                let selected = filters.iter().any(|filter| #names.contains(filter.as_str()));
+                if filters.is_empty() || selected {
+                    let start = ::std::time::Instant::now();
+                    for _ in 0..iterations {
+                        ::core::hint::black_box(#benches());
+                    }
+                    let elapsed = start.elapsed();
+                    ::std::println!("{:<40} {:>12?}/iter", #names, elapsed / iterations);
+                }
+            )*
+        }
+    }
+    .into()
+}
+
+fn plugins_key(list: &str) -> String {
+    format!(
+        "__macro_state/plugins/{}/{}",
+        __private::current_crate_name(),
+        list
+    )
+}
+
+/// Registers the annotated type in the plugin list with the specified name, scoped to the
+/// crate being compiled. Every type registered in a list can then be instantiated via
+/// [`emit_plugin_dispatcher!`]. Registered types must implement [`Default`], and must be in
+/// scope wherever [`emit_plugin_dispatcher!`] is invoked. The annotated item itself is left
+/// untouched.
+///
+/// If the annotated item is not a struct, enum, or union, or if an IO error occurs, the macro
+/// will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// #[state_plugin("audio")]
+/// #[derive(Default)]
+/// struct Reverb;
+/// ```
+#[proc_macro_attribute]
+pub fn state_plugin(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let list = parse_macro_input!(attr as LitStr).value();
+    let item_tokens = tokens.clone();
+    let item = parse_macro_input!(item_tokens as Item);
+    let ident = match &item {
+        Item::Struct(item) => &item.ident,
+        Item::Enum(item) => &item.ident,
+        Item::Union(item) => &item.ident,
+        _ => {
+            return quote!(::core::compile_error!(
+                "#[state_plugin] can only be applied to structs, enums, and unions"
+            );)
+            .into()
+        }
+    };
+    let (file, line) = call_site_location();
+    let entry = [ident.to_string(), file, line.to_string()].join(&FIELD_SEPARATOR.to_string());
+    match __private::record_unique_entry(&plugins_key(list.as_str()), &entry) {
+        Ok(_) => tokens,
+        Err(e) => quote_io_error(e),
+    }
+}
+
+#[derive(Parse)]
+struct PluginDispatcherInput {
+    list: LitStr,
+    _comma: Comma,
+    _trait: Token![trait],
+    trait_path: syn::Path,
+}
+
+/// Expands to an expression constructing a `Vec<Box<dyn Trait>>` containing a default instance
+/// of every type registered so far in the specified plugin list via
+/// [`#[state_plugin]`](macro@state_plugin), in registration order.
+///
+/// This macro should be invoked after every plugin has been declared (see
+/// [expansion order](crate#expansion-order)).
+///
+/// Note: This macro is infallible -- if no plugins have been registered, it expands to an
+/// empty [`Vec`].
+///
+/// # Example
+/// ```ignore
+/// trait AudioPlugin {
+///     fn name(&self) -> &'static str;
+/// }
+///
+/// #[state_plugin("audio")]
+/// #[derive(Default)]
+/// struct Reverb;
+///
+/// impl AudioPlugin for Reverb {
+///     fn name(&self) -> &'static str {
+///         "reverb"
+///     }
+/// }
+///
+/// fn audio_plugins() -> Vec<Box<dyn AudioPlugin>> {
+///     emit_plugin_dispatcher!("audio", trait AudioPlugin)
+/// }
+/// ```
+#[proc_macro]
+pub fn emit_plugin_dispatcher(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as PluginDispatcherInput);
+    let plugins: Vec<Ident> = __private::read_items(&plugins_key(args.list.value().as_str()))
+        .iter()
+        .filter_map(|entry| entry.split(FIELD_SEPARATOR).next())
+        .map(|name| format_ident!("{}", name))
+        .collect();
+    let trait_path = args.trait_path;
+    quote! {
+        {
+            let plugins: ::std::vec::Vec<::std::boxed::Box<dyn #trait_path>> = ::std::vec![
+                #(::std::boxed::Box::new(<#plugins as ::core::default::Default>::default())),*
+            ];
+            plugins
+        }
+    }
+    .into()
+}
+
+/// Like [`append_state!`], but raises a compile-time error if `value` is already present in
+/// the list stored for `key`, naming the crate and source location that appended it first.
+/// The check and the append happen under a single exclusive lock, so two macros can never
+/// both claim the same value. This is useful for assigning values that must never collide,
+/// such as wire-format discriminants.
+///
+/// If `value` violates any constraint declared for `key` (see [`declare_state_key!`]), or if
+/// an IO error occurs, the macro will also raise a compile-time error.
+///
+/// # Example
+/// ```
+/// append_state_unique_or_error!("discriminants", "0x1F");
+/// append_state_unique_or_error!("discriminants", "0x20");
+/// // append_state_unique_or_error!("discriminants", "0x1F"); // compile error
+/// assert_eq!(read_state_vec!("discriminants"), vec!["0x1F", "0x20"]);
+/// ```
+#[proc_macro]
+pub fn append_state_unique_or_error(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as WriteStateInput);
+    match proc_append_state_unique(&args.key.value(), &args.value.value()) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
+    }
+}
+
+fn variants_key(registry: &str) -> String {
+    format!(
+        "__macro_state/variants/{}/{}",
+        __private::current_crate_name(),
+        registry
+    )
+}
+
+#[derive(Parse)]
+struct RegisterVariantInput {
+    registry: LitStr,
+    _comma: Comma,
+    variant: Ident,
+    _arrow: Token![=>],
+    ty: syn::Path,
+}
+
+/// Registers a `(variant, type)` pair in the specified registry, scoped to the crate being
+/// compiled. The pairs of a registry can then be turned into an exhaustive `match` via
+/// [`registry_match!`].
+///
+/// Each variant may only be registered once per registry. If a variant is registered again
+/// with a different type, or if an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// register_variant!("shapes", Circle => shapes::Circle);
+/// register_variant!("shapes", Square => shapes::Square);
+/// ```
+#[proc_macro]
+pub fn register_variant(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as RegisterVariantInput);
+    let key = variants_key(args.registry.value().as_str());
+    let variant = args.variant.to_string();
+    let ty = args.ty;
+    let ty = quote!(#ty).to_string();
+    let result = __private::with_state_dir_lock(|| {
+        for entry in __private::read_items(&key) {
+            let Some((existing, existing_ty)) = entry.split_once(FIELD_SEPARATOR) else {
+                continue;
+            };
+            if existing != variant {
+                continue;
+            }
+            if existing_ty == ty {
+                return Ok(());
+            }
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!(
+                    "variant {} of registry \"{}\" is already registered for {}",
+                    variant,
+                    args.registry.value(),
+                    existing_ty
+                ),
+            ));
+        }
+        let entry = format!("{}{}{}", variant, FIELD_SEPARATOR, ty);
+        __private::append_item(&key, &entry)
+    });
+    match result {
+        Ok(_) => quote!().into(),
+        Err(e) => {
+            let msg = e.to_string();
+            quote!(::core::compile_error!(#msg);).into()
+        }
+    }
+}
+
+struct RegistryMatchInput {
+    registry: LitStr,
+    tag: syn::Expr,
+    tag_type: syn::Path,
+    alias: Ident,
+    body: syn::Expr,
+}
+
+impl Parse for RegistryMatchInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let registry = input.parse()?;
+        input.parse::<Comma>()?;
+        let tag = input.parse()?;
+        input.parse::<Comma>()?;
+        let tag_type = input.parse()?;
+        input.parse::<Comma>()?;
+        input.parse::<Token![|]>()?;
+        let alias = input.parse()?;
+        input.parse::<Token![|]>()?;
+        let body = input.parse()?;
+        Ok(RegistryMatchInput {
+            registry,
+            tag,
+            tag_type,
+            alias,
+            body,
+        })
+    }
+}
+
+/// Expands to an exhaustive `match` on the runtime `tag`, an enum of type `Tag` with one
+/// variant per `(variant, type)` pair registered via [`register_variant!`]. Each arm evaluates
+/// `expr` with `T` aliased to the type registered for that variant, which is typically used
+/// to call a constructor.
+///
+/// Since no wildcard arm is generated, the compiler reports any variant of `Tag` that was
+/// never registered, keeping the enum and the registry in sync.
+///
+/// If nothing has been registered in the registry, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// enum ShapeKind {
+///     Circle,
+///     Square,
+/// }
+///
+/// register_variant!("shapes", Circle => shapes::Circle);
+/// register_variant!("shapes", Square => shapes::Square);
+///
+/// fn build(kind: ShapeKind) -> Box<dyn Shape> {
+///     registry_match!("shapes", kind, ShapeKind, |T| Box::new(T::new()))
+/// }
+/// ```
+#[proc_macro]
+pub fn registry_match(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as RegistryMatchInput);
+    let registry = args.registry.value();
+    let entries = __private::read_items(&variants_key(registry.as_str()));
+    if entries.is_empty() {
+        let msg = format!("nothing has been registered in registry \"{}\"", registry);
+        return quote!(::core::compile_error!(#msg)).into();
+    }
+    let (tag_type, alias, body) = (&args.tag_type, &args.alias, &args.body);
+    let mut arms = Vec::new();
+    for entry in entries {
+        let Some((variant, ty)) = entry.split_once(FIELD_SEPARATOR) else {
+            continue;
+        };
+        let variant = format_ident!("{}", variant);
+        let ty = match syn::parse_str::<syn::Path>(ty) {
+            Ok(ty) => ty,
+            Err(e) => return e.to_compile_error().into(),
+        };
+        arms.push(quote!(#tag_type::#variant => { type #alias = #ty; #body }));
+    }
+    let tag = args.tag;
+    quote! {
+        match #tag {
+            #(#arms,)*
+        }
+    }
+    .into()
+}
+
+fn to_upper_camel_case(value: &str) -> String {
+    let mut camel = String::new();
+    for word in value.split(|c: char| !c.is_alphanumeric()) {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    if camel.is_empty() || camel.starts_with(|c: char| c.is_numeric()) {
+        camel.insert(0, '_');
+    }
+    camel
+}
+
+#[derive(Parse)]
+struct RegistryEnumInput {
+    key: LitStr,
+    _as: Token![as],
+    vis: syn::Visibility,
+    name: Ident,
+}
+
+/// Expands to an enum named `Name` with one variant per item of the list stored for `key`,
+/// keeping the enum automatically in sync with whatever macros registered items during the
+/// build. Variant names are derived from the items in `UpperCamelCase`, so an item such as
+/// `"text_input"` becomes `TextInput`. An item that doesn't map to a valid variant name (such
+/// as `"_"` or `"self"`), or that maps to the same name as another item, raises a compile-time
+/// error.
+///
+/// The generated enum derives `Copy`, `Clone`, `Debug`, `PartialEq`, `Eq`, and `Hash`, and
+/// comes with an `ALL` constant listing every variant, an `as_str()` method returning the
+/// original item, and a [`FromStr`](core::str::FromStr) impl performing the reverse mapping.
+/// A visibility can be given before the name, e.g. `"widgets" as pub WidgetKind`.
+///
+/// This macro should be invoked after every item has been registered (see
+/// [expansion order](crate#expansion-order)).
+///
+/// # Example
+/// ```
+/// append_state!("widgets", "button");
+/// append_state!("widgets", "text_input");
+///
+/// emit_registry_enum!("widgets" as pub WidgetKind);
+///
+/// assert_eq!(WidgetKind::TextInput.as_str(), "text_input");
+/// assert_eq!("button".parse(), Ok(WidgetKind::Button));
+/// assert_eq!(WidgetKind::ALL.len(), 2);
+/// ```
+#[proc_macro]
+pub fn emit_registry_enum(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as RegistryEnumInput);
+    let mut entries = __private::read_state_list(&args.key.value()).unwrap_or_default();
+    let mut seen = HashSet::new();
+    entries.retain(|entry| seen.insert(entry.clone()));
+    let mut variants: Vec<Ident> = Vec::new();
+    for entry in &entries {
+        let variant = to_upper_camel_case(entry);
+        match syn::parse_str::<Ident>(&variant) {
+            Ok(_) => variants.push(format_ident!("{}", variant)),
+            Err(_) => {
+                let msg = format!(
+                    "the item \"{}\" maps to `{}`, which is not a valid variant name",
+                    entry, variant
+                );
+                return syn::Error::new(args.key.span(), msg)
+                    .to_compile_error()
+                    .into();
+            }
+        }
+    }
+    let mut idents = HashSet::new();
+    for (entry, variant) in entries.iter().zip(variants.iter()) {
+        if !idents.insert(variant.to_string()) {
+            let msg = format!(
+                "the item \"{}\" maps to the variant {}, which is already taken by another item",
+                entry, variant
+            );
+            return syn::Error::new(args.key.span(), msg)
+                .to_compile_error()
+                .into();
+        }
+    }
+    let (vis, name) = (&args.vis, &args.name);
+    quote! {
+        #[derive(
+            ::core::marker::Copy,
+            ::core::clone::Clone,
+            ::core::fmt::Debug,
+            ::core::cmp::PartialEq,
+            ::core::cmp::Eq,
+            ::core::hash::Hash,
+        )]
+        #vis enum #name {
+            #(#variants,)*
+        }
+
+        impl #name {
+            /// Every variant of this enum, in registration order (or sorted, in deterministic
+            /// mode).
+            pub const ALL: &'static [#name] = &[#(#name::#variants),*];
+
+            /// Returns the registered item this variant was generated from.
+            pub fn as_str(&self) -> &'static ::core::primitive::str {
+                match *self {
+                    #(#name::#variants => #entries,)*
+                }
+            }
+        }
+
+        impl ::core::str::FromStr for #name {
+            type Err = ();
+
+            fn from_str(value: &::core::primitive::str) -> ::core::result::Result<Self, ()> {
+                match value {
+                    #(#entries => ::core::result::Result::Ok(#name::#variants),)*
+                    _ => ::core::result::Result::Err(()),
+                }
+            }
+        }
+    }
+    .into()
+}
+
+/// Returns `true` if `ty` is a shared reference to `str`, with the specified `lifetime` (or any
+/// lifetime if it is [`None`]).
+fn is_str_ref(ty: &syn::Type, lifetime: Option<&str>) -> bool {
+    let syn::Type::Reference(reference) = ty else {
+        return false;
+    };
+    let lifetime_matches = match lifetime {
+        Some(lifetime) => reference
+            .lifetime
+            .as_ref()
+            .is_some_and(|existing| existing.ident == lifetime),
+        None => true,
+    };
+    lifetime_matches
+        && reference.mutability.is_none()
+        && matches!(&*reference.elem, syn::Type::Path(path) if path.path.is_ident("str"))
+}
+
+/// Returns `true` if `output` is `Option<&'static str>`.
+fn returns_static_str_option(output: &syn::ReturnType) -> bool {
+    let syn::ReturnType::Type(_, ty) = output else {
+        return false;
+    };
+    let syn::Type::Path(path) = &**ty else {
+        return false;
+    };
+    let Some(segment) = path.path.segments.last() else {
+        return false;
+    };
+    let syn::PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return false;
+    };
+    segment.ident == "Option"
+        && matches!(
+            args.args.iter().collect::<Vec<_>>().as_slice(),
+            [syn::GenericArgument::Type(ty)] if is_str_ref(ty, Some("static"))
+        )
+}
+
+#[derive(Parse)]
+struct LookupFnInput {
+    key: LitStr,
+    _as: Token![as],
+    vis: syn::Visibility,
+    sig: syn::Signature,
+}
+
+/// Reads the map stored for `key`, made up of `(key, value)` rows appended via
+/// [`append_state_row!`], and expands to a function with the given signature that looks up a
+/// value by its key via a `match`, returning `Some("value")` or `None`. No map is constructed
+/// at runtime, and since the body only matches on the bytes of the key, the function can be
+/// declared `const fn` and called in `const` contexts. If a key appears more than once, its
+/// last value wins.
+///
+/// The signature must take a single `&str` argument and return an `Option<&'static str>`. A
+/// visibility can be given before it, e.g.
+/// `"config" as pub const fn config(key: &str) -> Option<&'static str>`. Like [`read_state!`],
+/// the macro raises a compile-time error if no map exists for `key` (or in the event of any
+/// other IO error), as it does for rows that don't have exactly two columns.
+///
+/// This macro should be invoked after every entry has been written (see
+/// [expansion order](crate#expansion-order)).
+///
+/// # Example
+/// ```
+/// append_state_row!("my config", "host", "localhost");
+/// append_state_row!("my config", "port", "8080");
+///
+/// emit_state_lookup_fn!("my config" as const fn config(key: &str) -> Option<&'static str>);
+///
+/// const PORT: Option<&str> = config("port");
+/// assert_eq!(PORT, Some("8080"));
+/// assert_eq!(config("user"), None);
+/// ```
+#[proc_macro]
+pub fn emit_state_lookup_fn(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as LookupFnInput);
+    let arg = match args.sig.inputs.iter().collect::<Vec<_>>().as_slice() {
+        [syn::FnArg::Typed(arg)] if is_str_ref(&arg.ty, None) => match &*arg.pat {
+            syn::Pat::Ident(pat) => pat.ident.clone(),
+            pat => {
+                return syn::Error::new_spanned(pat, "expected an argument name")
+                    .to_compile_error()
+                    .into()
+            }
+        },
+        _ => {
+            let msg = "the lookup function must take a single `&str` argument";
+            return syn::Error::new_spanned(&args.sig, msg)
+                .to_compile_error()
+                .into();
+        }
+    };
+    if !returns_static_str_option(&args.sig.output) {
+        let msg = "the lookup function must return an `Option<&'static str>`";
+        return syn::Error::new_spanned(&args.sig, msg)
+            .to_compile_error()
+            .into();
+    }
+    let key = args.key.value();
+    if let Err(e) = __private::check_key(&key, false) {
+        return state_error(e, &args.key, None);
+    }
+    let items = match __private::read_state_list(key.as_str()) {
+        Ok(items) => items,
+        Err(e) => {
+            if e.kind() == ErrorKind::NotFound {
+                __private::note_missed_read(&key);
+            }
+            return quote_io_error(e);
+        }
+    };
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for item in items {
+        match __private::decode_state_row(&item).as_slice() {
+            [name, value] => match entries.iter_mut().find(|(existing, _)| existing == name) {
+                Some(entry) => entry.1 = value.clone(),
+                None => entries.push((name.clone(), value.clone())),
+            },
+            row => {
+                let msg = format!(
+                    "the rows of key \"{}\" must all be (key, value) pairs, but one has {} columns",
+                    key,
+                    row.len()
+                );
+                return syn::Error::new(args.key.span(), msg)
+                    .to_compile_error()
+                    .into();
+            }
+        }
+    }
+    entries.sort();
+    let names = entries
+        .iter()
+        .map(|(name, _)| syn::LitByteStr::new(name.as_bytes(), args.key.span()));
+    let values = entries.iter().map(|(_, value)| value);
+    let (vis, sig) = (&args.vis, &args.sig);
+    quote! {
+        #vis #sig {
+            match #arg.as_bytes() {
+                #(#names => ::core::option::Option::Some(#values),)*
+                _ => ::core::option::Option::None,
+            }
+        }
+    }
+    .into()
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn doc_comments(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                lit: syn::Lit::Str(doc),
+                ..
+            })) => Some(doc.value()),
+            _ => None,
+        })
+        .map(|line| line.strip_prefix(' ').unwrap_or(&line).to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn item_attrs(item: &Item) -> &[syn::Attribute] {
+    match item {
+        Item::Const(item) => &item.attrs,
+        Item::Enum(item) => &item.attrs,
+        Item::ExternCrate(item) => &item.attrs,
+        Item::Fn(item) => &item.attrs,
+        Item::Impl(item) => &item.attrs,
+        Item::Macro(item) => &item.attrs,
+        Item::Macro2(item) => &item.attrs,
+        Item::Mod(item) => &item.attrs,
+        Item::Static(item) => &item.attrs,
+        Item::Struct(item) => &item.attrs,
+        Item::Trait(item) => &item.attrs,
+        Item::TraitAlias(item) => &item.attrs,
+        Item::Type(item) => &item.attrs,
+        Item::Union(item) => &item.attrs,
+        Item::Use(item) => &item.attrs,
+        _ => &[],
+    }
+}
+
+fn item_kind(item: &Item) -> &'static str {
+    match item {
+        Item::Const(_) => "const",
+        Item::Enum(_) => "enum",
+        Item::ExternCrate(_) => "extern crate",
+        Item::Fn(_) => "fn",
+        Item::Impl(_) => "impl",
+        Item::Macro(_) | Item::Macro2(_) => "macro",
+        Item::Mod(_) => "mod",
+        Item::Static(_) => "static",
+        Item::Struct(_) => "struct",
+        Item::Trait(_) | Item::TraitAlias(_) => "trait",
+        Item::Type(_) => "type",
+        Item::Union(_) => "union",
+        Item::Use(_) => "use",
+        _ => "item",
+    }
+}
+
+fn item_members(item: &Item) -> Vec<(String, &[syn::Attribute])> {
+    let fields = match item {
+        Item::Struct(item) => &item.fields,
+        Item::Enum(item) => {
+            return item
+                .variants
+                .iter()
+                .map(|variant| (variant.ident.to_string(), variant.attrs.as_slice()))
+                .collect()
+        }
+        Item::Union(item) => {
+            return item
+                .fields
+                .named
+                .iter()
+                .filter_map(|field| {
+                    Some((field.ident.as_ref()?.to_string(), field.attrs.as_slice()))
+                })
+                .collect()
+        }
+        _ => return Vec::new(),
+    };
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let name = match &field.ident {
+                Some(ident) => ident.to_string(),
+                None => i.to_string(),
+            };
+            (name, field.attrs.as_slice())
+        })
+        .collect()
+}
+
+fn attrs_json(attrs: &[syn::Attribute], selected: &[String]) -> String {
+    let entries: Vec<String> = attrs
+        .iter()
+        .filter_map(|attr| {
+            let path = attr.path.get_ident()?.to_string();
+            if !selected.contains(&path) {
+                return None;
+            }
+            let tokens = &attr.tokens;
+            Some(format!(
+                "{}:{}",
+                json_string(&path),
+                json_string(&quote!(#tokens).to_string())
+            ))
+        })
+        .collect();
+    format!("{{{}}}", entries.join(","))
+}
+
+struct HarvestDocsArgs {
+    key: LitStr,
+    attrs: Vec<String>,
+}
+
+impl Parse for HarvestDocsArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        let mut attrs = Vec::new();
+        if !input.is_empty() {
+            input.parse::<Comma>()?;
+            let name = input.parse::<Ident>()?;
+            if name != "attrs" {
+                return Err(syn::Error::new(name.span(), "expected `attrs(...)`"));
+            }
+            let content;
+            syn::parenthesized!(content in input);
+            attrs = Punctuated::<Ident, Comma>::parse_terminated(&content)?
+                .iter()
+                .map(|ident| ident.to_string())
+                .collect();
+        }
+        Ok(HarvestDocsArgs { key, attrs })
+    }
+}
+
+/// Harvests the doc comments of the annotated item, along with the doc comments of its fields
+/// or variants and any attributes selected via `attrs(...)`, and appends them to the list
+/// stored for `key` as a single JSON object. A later macro can then read the list (see
+/// [`read_state_vec!`]) to generate documentation tables, CLI help text, and the like. The
+/// annotated item itself is left untouched.
+///
+/// Each JSON object has the following shape:
+/// ```json
+/// {
+///   "name": "Deploy",
+///   "kind": "struct",
+///   "docs": "Deploys the current project.",
+///   "attributes": {"deprecated": "(note = \"use ship\")"},
+///   "members": [{"name": "force", "docs": "Skips confirmation.", "attributes": {}}]
+/// }
+/// ```
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// /// Deploys the current project.
+/// #[harvest_docs("commands", attrs(deprecated))]
+/// #[deprecated(note = "use ship")]
+/// struct Deploy {
+///     /// Skips confirmation.
+///     force: bool,
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn harvest_docs(attr: TokenStream, tokens: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as HarvestDocsArgs);
+    let item_tokens = tokens.clone();
+    let item = parse_macro_input!(item_tokens as Item);
+    let name = item_ident(&item)
+        .map(|ident| ident.to_string())
+        .unwrap_or_default();
+    let members: Vec<String> = item_members(&item)
+        .into_iter()
+        .map(|(name, attrs)| {
+            format!(
+                "{{\"name\":{},\"docs\":{},\"attributes\":{}}}",
+                json_string(&name),
+                json_string(&doc_comments(attrs)),
+                attrs_json(attrs, &args.attrs)
+            )
+        })
+        .collect();
+    let attrs = item_attrs(&item);
+    let json = format!(
+        "{{\"name\":{},\"kind\":{},\"docs\":{},\"attributes\":{},\"members\":[{}]}}",
+        json_string(&name),
+        json_string(item_kind(&item)),
+        json_string(&doc_comments(attrs)),
+        attrs_json(attrs, &args.attrs),
+        members.join(",")
+    );
+    match __private::record_unique_entry(args.key.value().as_str(), &json) {
+        Ok(_) => tokens,
+        Err(e) => quote_io_error(e),
+    }
+}
+
+fn recorded_modules_key() -> String {
+    format!("__macro_state/modules/{}", __private::current_crate_name())
+}
+
+/// Infers the module path of the source file at `file`, assuming the standard `src/` layout.
+/// The crate root is `lib.rs`, `main.rs`, or a file named after the crate, and anything under
+/// `src/bin/` is treated as the root of its own binary.
+fn module_path_from_file(file: &str) -> String {
+    let file = file.replace('\\', "/");
+    let relative = match file.rfind("src/") {
+        Some(pos) => &file[pos + 4..],
+        None => file.as_str(),
+    };
+    let relative = relative.strip_suffix(".rs").unwrap_or(relative);
+    if relative.starts_with("bin/") {
+        return String::from("crate");
+    }
+    let mut segments: Vec<&str> = relative.split('/').collect();
+    if segments.last() == Some(&"mod") {
+        segments.pop();
+    }
+    let crate_name = std::env::var("CARGO_CRATE_NAME").unwrap_or_default();
+    if let [root] = segments.as_slice() {
+        if *root == "lib" || *root == "main" || *root == crate_name {
+            segments.clear();
+        }
+    }
+    std::iter::once("crate")
+        .chain(segments)
+        .collect::<Vec<_>>()
+        .join("::")
+}
+
+/// Appends the path of the module in which it is expanded to a registry scoped to the crate
+/// being compiled, so that [`emit_module_tree!`] can later emit the crate's module tree.
+///
+/// By default the module path is inferred from the source file containing the invocation,
+/// following the standard `src/` layout (`src/lib.rs` is `crate`, `src/a/b.rs` and
+/// `src/a/b/mod.rs` are `crate::a::b`). Since inline modules cannot be distinguished from
+/// their parent file this way, the path can also be specified explicitly, as in
+/// `record_module!("crate::a::inline")`. Recording the same module more than once has no
+/// effect.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// // in src/shapes/circle.rs
+/// record_module!();
+///
+/// pub struct Circle;
+/// ```
+#[proc_macro]
+pub fn record_module(items: TokenStream) -> TokenStream {
+    let path = if items.is_empty() {
+        module_path_from_file(&call_site_location().0)
+    } else {
+        parse_macro_input!(items as LitStr).value()
+    };
+    match __private::record_unique_entry(&recorded_modules_key(), &path) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Emits the module tree recorded so far via [`record_module!`] for the crate being compiled.
+///
+/// When invoked without arguments, expands to a `&'static [&'static str]` containing every
+/// recorded module path, sorted so that each module directly precedes its submodules. When
+/// invoked as `emit_module_tree!(pub use)`, instead expands to a `pub use path::*;` item for
+/// every recorded module other than the crate root, which is a convenient way to generate a
+/// prelude that stays in sync with the structure of the crate.
+///
+/// This macro should be invoked after every module of interest has been recorded, e.g. at the
+/// bottom of `lib.rs` (see [expansion order](crate#expansion-order)).
+///
+/// # Example
+/// ```ignore
+/// mod shapes;
+///
+/// pub mod prelude {
+///     macro_state::emit_module_tree!(pub use);
+/// }
+///
+/// const MODULES: &[&str] = emit_module_tree!();
+/// ```
+#[proc_macro]
+pub fn emit_module_tree(items: TokenStream) -> TokenStream {
+    let reexport = match items.to_string().as_str() {
+        "" => false,
+        "pub use" => true,
+        _ => {
+            return quote!(::core::compile_error!(
+                "expected either no arguments or `pub use`"
+            ))
+            .into()
+        }
+    };
+    let mut modules = __private::read_items(&recorded_modules_key());
+    modules.sort_by(|a, b| a.split("::").cmp(b.split("::")));
+    modules.dedup();
+    if !reexport {
+        return quote!((&[#(#modules),*] as &[&::core::primitive::str])).into();
+    }
+    let reexports = modules
+        .iter()
+        .filter(|module| module.as_str() != "crate")
+        .map(|module| {
+            let path = syn::parse_str::<syn::Path>(module)
+                .map_err(|e| syn::Error::new(proc_macro::Span::call_site().into(), e))?;
+            Ok(quote!(pub use #path::*;))
+        })
+        .collect::<syn::Result<Vec<_>>>();
+    match reexports {
+        Ok(reexports) => quote!(#(#reexports)*).into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Reads the file at the specified `path`, relative to the `CARGO_MANIFEST_DIR` of the crate
+/// being compiled, and writes its contents as the state for the specified state `key`, as
+/// though they had been passed to [`write_state!`]. This allows file-based configuration to
+/// flow into the state store without having to be copied into the source.
+///
+/// The file is also registered with the compiler as a dependency of the invoking crate, so
+/// editing it triggers a recompilation that picks up the new contents. Because of this, the
+/// macro expands to an item and may only be invoked in item or statement position.
+///
+/// The contents are written exactly as [`write_state!`] would write them, so the key is checked
+/// against the write policy and any constraints declared for it, and the write takes part in
+/// read-before-write diagnostics and remote mirroring.
+///
+/// If the file cannot be read (or is not valid UTF-8), if the key or contents fail validation,
+/// or if an IO error occurs while writing the state file, the macro will raise a compile-time
+/// error.
+///
+/// # Example
+/// ```ignore
+/// write_state_from_file!("app config", "config/app.toml");
+///
+/// const CONFIG: &str = read_state!("app config");
+/// ```
+#[proc_macro]
+pub fn write_state_from_file(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as WriteStateInput);
+    let relative = args.value.value();
+    let path = manifest_relative_path(&relative);
+    let contents = match __private::retry_io(|| fs::read_to_string(&path)) {
+        Ok(contents) => contents,
+        Err(e) => {
+            let msg = format!("failed to read \"{}\": {}", relative, e);
+            return quote!(::core::compile_error!(#msg);).into();
+        }
+    };
+    if let Err(e) = proc_write_state(&args.key.value(), &contents) {
+        return state_error(e, &args.key, Some(&args.value));
+    }
+    let path = path.to_string_lossy();
+    track_write_policy(
+        quote!(
+            const _: &[::core::primitive::u8] = ::core::include_bytes!(#path);
+        ),
+        false,
+    )
+}
+
+fn glob_segment_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| glob_segment_matches(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && glob_segment_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_segment_matches(rest, &name[1..]),
+    }
+}
+
+fn glob_matches(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        Some((segment, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                let segment: Vec<char> = segment.chars().collect();
+                let name: Vec<char> = name.chars().collect();
+                glob_segment_matches(&segment, &name) && glob_matches(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn collect_files(dir: &Path, relative: &str, files: &mut Vec<String>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let path = match relative {
+            "" => name.clone(),
+            _ => format!("{}/{}", relative, name),
+        };
+        if entry.file_type()?.is_dir() {
+            // build output and VCS metadata are huge and never what a glob is after
+            if !name.starts_with('.') && name != "target" {
+                collect_files(&entry.path(), &path, files)?;
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Finds every file matching the specified glob `pattern`, relative to the
+/// `CARGO_MANIFEST_DIR` of the crate being compiled, and writes the matching paths (sorted,
+/// with `/` separators) as a list to the specified state `key`, replacing any existing value.
+/// Later macros can then read the list via [`read_state_vec!`] to generate embedding code or
+/// asset manifests for the collected files.
+///
+/// Within a path segment, `*` matches any sequence of characters and `?` matches any single
+/// character, while a `**` segment matches any number of directories. Hidden directories (such
+/// as `.git`) and directories named `target` are skipped unless they are spelled out in the
+/// leading segments of the pattern before any wildcard, e.g. `.github/workflows/*.yml`.
+///
+/// Note that the file set is only refreshed when the invoking crate is recompiled, so adding or
+/// removing matching files does not by itself trigger a rebuild.
+///
+/// The list is written exactly as [`write_state!`] would write it: the key and every matching
+/// path are checked against the write policy and any constraints declared for the key before
+/// anything is written, so a single rejected path rejects the whole glob, leaving the previous
+/// value in place.
+///
+/// If any of the paths fails validation, or if an IO error occurs, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```ignore
+/// write_state_glob!("assets", "assets/**/*.png");
+///
+/// for asset in read_state_vec!("assets") {
+///     println!("{}", asset);
+/// }
+/// ```
+#[proc_macro]
+pub fn write_state_glob(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as WriteStateInput);
+    let pattern = args.value.value();
+    let segments: Vec<&str> = pattern
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+    let literal = segments
+        .iter()
+        .take_while(|segment| !segment.contains(['*', '?']))
+        .count()
+        .min(segments.len().saturating_sub(1));
+    let base = segments[..literal].join("/");
+    let mut files = Vec::new();
+    let dir = manifest_relative_path(&base);
+    if dir.is_dir() {
+        if let Err(e) = collect_files(&dir, &base, &mut files) {
+            return quote_io_error(e);
+        }
+    }
+    files.retain(|file| glob_matches(&segments, &file.split('/').collect::<Vec<_>>()));
+    files.sort();
+    match __private::write_list(&args.key.value(), &files) {
+        Ok(_) => track_write_policy(quote!(), false),
+        Err(e) => state_error(e, &args.key, Some(&args.value)),
+    }
+}
+
+/// Snapshots the build environment into well-known state keys, so that any macro in the build
+/// can make environment-aware decisions without having to inspect the environment itself:
+/// * `"__macro_state/build_env/target"` holds the target triple being compiled for.
+/// * `"__macro_state/build_env/profile"` holds `"release"` if optimizations are enabled, and
+///   `"debug"` otherwise, mirroring the `PROFILE` variable cargo passes to build scripts.
+/// * `"__macro_state/build_env/rustc_version"` holds the version of the compiler, as reported
+///   by `rustc --version`.
+/// * `"__macro_state/build_env/features/<crate>"` holds the list of enabled cargo features of
+///   the invoking crate (with dashes in its name replaced by underscores), sorted, readable via
+///   [`read_state_vec!`].
+///
+/// Since the target, profile, and compiler are shared by the entire build, they are only
+/// captured by the first invocation in each build, while the features are recorded for every
+/// crate that invokes this macro.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```
+/// capture_build_env!();
+///
+/// assert!(read_state!("__macro_state/build_env/rustc_version").starts_with("rustc "));
+/// ```
+#[proc_macro]
+pub fn capture_build_env(items: TokenStream) -> TokenStream {
+    if !items.is_empty() {
+        return quote!(::core::compile_error!(
+            "capture_build_env! does not take any arguments"
+        ))
+        .into();
+    }
+    match proc_capture_build_env() {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Records metadata about the current build into well-known state keys, so that multiple
+/// macros can embed consistent build metadata without each of them invoking `git`. Only
+/// available with the `git` feature enabled.
+/// * `"__macro_state/build_meta/git_commit"` holds the hash of the `HEAD` commit, or an empty
+///   string if the invoking crate is not within a git repository (or git is not installed).
+/// * `"__macro_state/build_meta/git_dirty"` holds `"true"` if the working tree has
+///   uncommitted changes, and `"false"` otherwise.
+/// * `"__macro_state/build_meta/timestamp"` holds the time of the build in seconds since the
+///   UNIX epoch, taken from the `SOURCE_DATE_EPOCH` environment variable if it is set, for
+///   reproducible builds.
+///
+/// The metadata is only captured by the first invocation in each build (under an exclusive
+/// lock), so every macro sees the same values.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// capture_build_metadata!();
+///
+/// const COMMIT: &str = read_state!("__macro_state/build_meta/git_commit");
+/// ```
+#[cfg(feature = "git")]
+#[proc_macro]
+pub fn capture_build_metadata(items: TokenStream) -> TokenStream {
+    if !items.is_empty() {
+        return quote!(::core::compile_error!(
+            "capture_build_metadata! does not take any arguments"
+        ))
+        .into();
+    }
+    match proc_capture_build_metadata() {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+#[cfg(feature = "json_schema")]
+struct DeclareStateSchemaInput {
+    key: LitStr,
+    schema: LitStr,
+    file: bool,
+}
+
+#[cfg(feature = "json_schema")]
+impl Parse for DeclareStateSchemaInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        input.parse::<Comma>()?;
+        let file = input.peek(Ident);
+        if file {
+            let name: Ident = input.parse()?;
+            if name != "file" {
+                return Err(syn::Error::new(name.span(), "expected `file`"));
+            }
+            input.parse::<Token![=]>()?;
+        }
+        let schema = input.parse()?;
+        Ok(DeclareStateSchemaInput { key, schema, file })
+    }
+}
+
+/// Attaches a [JSON schema](https://json-schema.org) to the specified `key`. From then on,
+/// every value written to `key` (via [`write_state!`], [`init_state!`], their `proc_`
+/// analogues, and so on) is validated against the schema, and a value that does not conform
+/// fails the build with a compile error describing the exact violation, located at the
+/// offending write. Items appended to `key` (via [`append_state!`] and friends) are validated
+/// individually against the `items` keyword of the schema, since appending builds up a list.
+///
+/// The schema can be specified inline, as a string literal, or loaded from a seed file via
+/// `file = "path"`, resolved relative to the directory containing the invoking crate's
+/// `Cargo.toml` (the build is re-run whenever the file changes).
+///
+/// Values are interpreted as JSON, except that values which are not valid JSON are treated as
+/// JSON strings, so `info` and `"info"` are equivalent. The supported keywords are `type`,
+/// `enum`, `const`, `minLength`, `maxLength`, `minimum`, `maximum`, `exclusiveMinimum`,
+/// `exclusiveMaximum`, `minItems`, `maxItems`, `items`, `required`, `properties`,
+/// `additionalProperties`, `allOf`, `anyOf`, `oneOf`, and `not`. Other keywords are ignored.
+///
+/// Since only writes made after the schema is declared are validated, schemas should be
+/// declared before any macro writes to the key.
+///
+/// Requires the `json_schema` feature.
+///
+/// # Example
+/// ```ignore
+/// declare_state_schema!("models", r#"{
+///     "type": "array",
+///     "items": { "type": "object", "required": ["name"] }
+/// }"#);
+/// declare_state_schema!("routes", file = "schemas/routes.json");
+///
+/// write_state!("models", r#"[{ "name": "User" }]"#);
+/// write_state!("models", r#"[{ "title": "User" }]"#);
+/// // error: invalid value for key "models": at /0: missing required property "name"
+/// ```
+#[cfg(feature = "json_schema")]
+#[proc_macro]
+pub fn declare_state_schema(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as DeclareStateSchemaInput);
+    let key = args.key.value();
+    let (schema, tracked) = match args.file {
+        true => {
+            let relative = args.schema.value();
+            let path = manifest_relative_path(&relative);
+            match __private::retry_io(|| fs::read_to_string(&path)) {
+                Ok(schema) => {
+                    let path = path.to_string_lossy();
+                    let tracked = quote!(
+                        const _: &[::core::primitive::u8] = ::core::include_bytes!(#path);
+                    );
+                    (schema, tracked)
+                }
+                Err(e) => {
+                    let msg = format!("failed to read \"{}\": {}", relative, e);
+                    return syn::Error::new(args.schema.span(), msg)
+                        .to_compile_error()
+                        .into();
+                }
+            }
+        }
+        false => (args.schema.value(), quote!()),
+    };
+    match proc_declare_state_schema(&key, &schema) {
+        Ok(_) => tracked.into(),
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            syn::Error::new(args.schema.span(), e.to_string())
+                .to_compile_error()
+                .into()
+        }
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Returns the output of a macro that writes state, along with a dependency on the write policy
+/// file if there is one, so that editing the policy rebuilds (and therefore rechecks) every
+/// crate that writes state. If `expression` is `true`, the output is an expression and is
+/// wrapped in a block to leave room for the dependency.
+fn track_write_policy(output: impl quote::ToTokens, expression: bool) -> TokenStream {
+    let Some(path) = __private::write_policy_path() else {
+        return quote!(#output).into();
+    };
+    let path = match std::env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path,
+    };
+    let path = path.to_string_lossy();
+    let dependency = quote!(
+        const _: &[::core::primitive::u8] = ::core::include_bytes!(#path);
+    );
+    match expression {
+        true => quote!({ #dependency #output }).into(),
+        false => quote!(#dependency #output).into(),
+    }
+}
+
+struct DeclareStateKeyInput {
+    key: LitStr,
+    constraints: Vec<StateConstraint>,
+}
+
+impl Parse for DeclareStateKeyInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let key = input.parse()?;
+        let mut constraints = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Comma>()?;
+            if input.is_empty() {
+                break;
+            }
+            let name = input.parse::<Ident>()?;
+            input.parse::<Token![=]>()?;
+            let constraint = if name == "one_of" {
+                let content;
+                syn::bracketed!(content in input);
+                let options = Punctuated::<LitStr, Comma>::parse_terminated(&content)?;
+                StateConstraint::OneOf(options.iter().map(LitStr::value).collect())
+            } else if name == "pattern" {
+                StateConstraint::Pattern(input.parse::<LitStr>()?.value())
+            } else if name == "min_len" {
+                StateConstraint::MinLen(input.parse::<LitInt>()?.base10_parse::<usize>()?)
+            } else if name == "max_len" {
+                StateConstraint::MaxLen(input.parse::<LitInt>()?.base10_parse::<usize>()?)
+            } else {
+                return Err(syn::Error::new(
+                    name.span(),
+                    "expected `one_of`, `pattern`, `min_len`, or `max_len`",
+                ));
+            };
+            constraints.push(constraint);
+        }
+        Ok(DeclareStateKeyInput { key, constraints })
+    }
+}
+
+/// Declares lightweight constraints that every value written to the specified `key` must
+/// satisfy. From then on, writing a value that violates any of them (via [`write_state!`],
+/// [`init_state!`], their `proc_` analogues, and so on) fails the build with a compile error
+/// describing the violation, located at the offending write. Items appended to `key` (via
+/// [`append_state!`] and friends) are checked individually.
+///
+/// The following constraints are supported, separated by commas:
+/// * `one_of = ["a", "b", ...]` requires the value to be one of the listed values.
+/// * `pattern = "regex"` requires the entire value to match a simple regular expression
+///   supporting `.`, character classes such as `[a-z_]` and `[^0-9]`, `*`, `+`, `?`, and `\`
+///   escapes.
+/// * `min_len = N` and `max_len = N` bound the length of the value, in characters.
+///
+/// Declaring constraints for a key replaces any constraints previously declared for it. Since
+/// only writes made after the declaration are checked, constraints should be declared before
+/// any macro writes to the key.
+///
+/// # Example
+/// ```ignore
+/// declare_state_key!("log_level", one_of = ["debug", "info", "warn"]);
+/// declare_state_key!("service_name", pattern = "[a-z][a-z0-9_]*", max_len = 32);
+///
+/// write_state!("log_level", "info");
+/// write_state!("log_level", "verbose");
+/// // error: invalid value for key "log_level": expected one of "debug", "info", "warn", found
+/// // "verbose"
+/// ```
+#[proc_macro]
+pub fn declare_state_key(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as DeclareStateKeyInput);
+    match proc_declare_state_key(&args.key.value(), &args.constraints) {
+        Ok(_) => quote!().into(),
+        Err(e) => state_error(e, &args.key, None),
+    }
+}
+
+struct StateNodeInput {
+    name: LitStr,
+    relations: Vec<(String, String)>,
+}
+
+impl Parse for StateNodeInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse::<LitStr>()?;
+        let mut relations = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let relation = input.parse::<Ident>()?;
+            if relation != "produces" && relation != "consumes" {
+                return Err(syn::Error::new(
+                    relation.span(),
+                    "expected `produces(...)` or `consumes(...)`",
+                ));
+            }
+            let content;
+            syn::parenthesized!(content in input);
+            let keys = Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?;
+            relations.extend(keys.iter().map(|key| (relation.to_string(), key.value())));
+        }
+        Ok(StateNodeInput { name, relations })
+    }
+}
+
+/// Declares that the component (typically a macro) with the specified name writes the state
+/// keys listed in `produces(...)` and reads the state keys listed in `consumes(...)`. Either
+/// list may be omitted.
+///
+/// Declarations are shared by every crate in the build, and each one is checked against all
+/// of the declarations made before it. If a declaration closes a cycle (`A` consumes a key
+/// produced by `B`, while `B` consumes a key produced by `A`), no ordering of the two can ever
+/// work, so the macro raises a single compile-time error listing the entire cycle, rather than
+/// leaving both sides to fail with unrelated missing-key errors. Declaring the same
+/// relationships more than once has no effect.
+///
+/// If an IO error occurs, the macro will raise a compile-time error.
+///
+/// # Example
+/// ```ignore
+/// declare_state_node!("derive_model", produces("models"), consumes("routes"));
+///
+/// // error: state dependency cycle detected: router -> derive_model -> router (...)
+/// declare_state_node!("router", produces("routes"), consumes("models"));
+/// ```
+#[proc_macro]
+pub fn declare_state_node(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as StateNodeInput);
+    let keys = |relation: &str| -> Vec<&str> {
+        args.relations
+            .iter()
+            .filter(|(r, _)| r == relation)
+            .map(|(_, key)| key.as_str())
+            .collect()
+    };
+    match proc_declare_state_node(&args.name.value(), &keys("produces"), &keys("consumes")) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Returns the span of the first `$crate` within `tokens` (at any depth), which would no
+/// longer resolve once the tokens are stored as text and parsed again by [`flush_deferred!`].
+fn find_dollar_crate(tokens: TokenStream) -> Option<proc_macro::Span> {
+    tokens.into_iter().find_map(|token| match token {
+        proc_macro::TokenTree::Ident(ident) if ident.to_string() == "$crate" => Some(ident.span()),
+        proc_macro::TokenTree::Group(group) => find_dollar_crate(group.stream()),
+        _ => None,
+    })
+}
+
+/// Splits the input of [`defer_tokens!`] into the slot and the deferred tokens, unwrapping the
+/// tokens if they are enclosed in a single pair of braces.
+fn parse_deferred_tokens(items: TokenStream) -> syn::Result<(String, TokenStream)> {
+    let mut items = items.into_iter();
+    let slot: TokenStream = items.next().into_iter().collect();
+    let slot = syn::parse::<LitStr>(slot)?.value();
+    match items.next() {
+        Some(proc_macro::TokenTree::Punct(punct)) if punct.as_char() == ',' => {}
+        _ => {
+            return Err(syn::Error::new(
+                proc_macro::Span::call_site().into(),
+                "expected `defer_tokens!(\"slot\", { ... })`",
+            ))
+        }
+    }
+    let tokens: Vec<proc_macro::TokenTree> = items.collect();
+    match tokens.as_slice() {
+        [proc_macro::TokenTree::Group(group)]
+            if group.delimiter() == proc_macro::Delimiter::Brace =>
+        {
+            Ok((slot, group.stream()))
+        }
+        _ => Ok((slot, tokens.into_iter().collect())),
+    }
+}
+
+/// Defers the specified tokens to the specified slot, so that they are emitted by a later
+/// [`flush_deferred!`] call for the same slot rather than where this macro is invoked. The
+/// tokens may optionally be wrapped in braces, which are removed.
+///
+/// This is the standard workaround for ordering problems: any number of macros can contribute
+/// code to a slot as they expand, and a single [`flush_deferred!`] placed near the end of the
+/// crate expands everything contributed so far, in order. Slots are scoped to the crate being
+/// compiled, and contributing the exact same tokens to a slot more than once has no effect.
+///
+/// Deferred tokens are stored as text, since spans can't outlive the macro invocation they
+/// belong to, so once flushed they are spanned at the [`flush_deferred!`] invocation, and any
+/// error within them is reported there. To keep the errors that can be caught early pointing
+/// at the right place, the tokens are checked when deferred: tokens that would no longer
+/// resolve once flushed, such as `$crate`, raise a compile-time error spanned at them, as does
+/// any IO error.
+///
+/// # Example
+/// ```ignore
+/// defer_tokens!("routes", {
+///     pub fn home() -> &'static str { "/" }
+/// });
+///
+/// flush_deferred!("routes");
+/// ```
+#[proc_macro]
+pub fn defer_tokens(items: TokenStream) -> TokenStream {
+    let (slot, tokens) = match parse_deferred_tokens(items) {
+        Ok(parsed) => parsed,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    if let Some(span) = find_dollar_crate(tokens.clone()) {
+        let msg = "`$crate` cannot be deferred, since it only resolves within the expansion it \
+            came from; refer to the crate by name instead";
+        return syn::Error::new(span.into(), msg).to_compile_error().into();
+    }
+    match proc_defer_tokens(&slot, &tokens) {
+        Ok(_) => quote!().into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+/// Expands to every set of tokens deferred to the specified slot via [`defer_tokens!`] (or
+/// `proc_defer_tokens`) so far, in the order they were deferred. This macro should be placed
+/// after every contributing macro, typically at the end of the crate (see
+/// [expansion order](crate#expansion-order)), and each slot should only be flushed once.
+///
+/// Every flushed token is spanned at this invocation (see [`defer_tokens!`]). If any deferred
+/// tokens cannot be parsed, or if an IO error occurs, the macro will raise a compile-time
+/// error.
+///
+/// # Example
+/// ```ignore
+/// defer_tokens!("consts", { const A: usize = 1; });
+/// defer_tokens!("consts", { const B: usize = 2; });
+///
+/// flush_deferred!("consts");
+/// ```
+#[proc_macro]
+pub fn flush_deferred(items: TokenStream) -> TokenStream {
+    let slot = parse_macro_input!(items as LitStr);
+    let mut output = TokenStream::new();
+    for entry in __private::deferred_tokens(&slot.value()) {
+        match entry.parse::<TokenStream>() {
+            Ok(tokens) => output.extend(tokens),
+            Err(e) => {
+                let msg = format!(
+                    "failed to parse tokens deferred to \"{}\": {}",
+                    slot.value(),
+                    e
+                );
+                return syn::Error::new(slot.span(), msg).to_compile_error().into();
+            }
+        }
+    }
+    output
+}
+
+/// Expands to a `&'static [&'static str]` slice literal of every key that currently has a value
+/// and matches the glob `pattern`, sorted lexicographically, which is useful when consumers
+/// only know the shape of the keys generated by producer macros.
+///
+/// Keys are matched segment by segment, where segments are separated by `/`. Within a segment,
+/// `*` matches any sequence of characters and `?` matches any single character, while a `**`
+/// segment matches any number of segments. Keys reserved for internal use are only matched if
+/// `pattern` starts with `__macro_state/`.
+///
+/// If an IO error occurs while scanning the state directory, the macro will raise a
+/// compile-time error.
+///
+/// # Example
+/// ```
+/// write_state!("models/User/fields", "id,name");
+/// write_state!("models/Post/fields", "id,title");
+/// write_state!("models/Post/table", "posts");
+/// assert_eq!(
+///     read_keys_matching!("models/*/fields"),
+///     &["models/Post/fields", "models/User/fields"]
+/// );
+/// ```
+#[proc_macro]
+pub fn read_keys_matching(items: TokenStream) -> TokenStream {
+    let pattern = parse_macro_input!(items as LitStr).value();
+    match proc_read_keys_matching(&pattern) {
+        Ok(keys) => quote!((&[#(#keys), *] as &[&::core::primitive::str])).into(),
+        Err(e) => quote_io_error(e),
+    }
+}
+
+enum StateCheck {
+    Has(Ident, LitStr),
+    Unique(Ident, LitStr),
+    Eq(Ident, LitStr, LitStr),
+}
+
+struct FinalizeStateInput {
+    checks: Vec<StateCheck>,
+}
+
+impl Parse for FinalizeStateInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut checks = Vec::new();
+        while !input.is_empty() {
+            let name = input.parse::<Ident>()?;
+            let content;
+            syn::parenthesized!(content in input);
+            let check = if name == "assert_has" {
+                StateCheck::Has(name, content.parse()?)
+            } else if name == "assert_unique" {
+                StateCheck::Unique(name, content.parse()?)
+            } else if name == "assert_eq" {
+                let key = content.parse()?;
+                content.parse::<Token![,]>()?;
+                StateCheck::Eq(name, key, content.parse()?)
+            } else {
+                return Err(syn::Error::new(
+                    name.span(),
+                    "expected `assert_has`, `assert_unique`, or `assert_eq`",
+                ));
+            };
+            if !content.is_empty() {
+                return Err(content.error("unexpected tokens"));
+            }
+            checks.push(check);
+            input.parse::<Token![;]>()?;
+        }
+        Ok(FinalizeStateInput { checks })
+    }
+}
+
+/// Runs a battery of validations over the state collected so far, reporting every failed
+/// check at once, each pointing at the check that failed. Intended to be placed at the bottom
+/// of `lib.rs`, after every macro contributing state has expanded. The following checks are
+/// supported, each terminated by a `;`:
+/// * `assert_has("pattern")` requires at least one key to match `pattern`, a simple regular
+///   expression supporting `.`, character classes such as `[a-z]`, `*`, `+`, `?`, and `\`
+///   escapes that must match the entire key.
+/// * `assert_unique("key")` requires the list stored for `key` to contain no duplicates.
+/// * `assert_eq("key", "value")` requires the value of `key` to equal `value`.
+///
+/// # Example
+/// ```ignore
+/// finalize_state! {
+///     assert_has("models/..*");
+///     assert_unique("error_codes");
+///     assert_eq("schema version", "3");
+/// }
+/// ```
+#[proc_macro]
+pub fn finalize_state(items: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(items as FinalizeStateInput);
+    let keys = match __private::state_keys() {
+        Ok(keys) => keys,
+        Err(e) => return quote_io_error(e),
+    };
+    let mut errors: Option<syn::Error> = None;
+    for check in args.checks {
+        let failure = match check {
+            StateCheck::Has(name, pattern) => {
+                let pattern_value = pattern.value();
+                (!keys
+                    .iter()
+                    .any(|key| __private::pattern_matches(&pattern_value, key)))
+                .then(|| {
+                    let msg = format!("no state key matches \"{}\"", pattern_value);
+                    syn::Error::new(name.span(), msg)
+                })
+            }
+            StateCheck::Unique(name, key) => {
+                let mut seen = HashSet::new();
+                let mut duplicates: Vec<String> = __private::read_items(&key.value())
+                    .into_iter()
+                    .filter(|item| !seen.insert(item.clone()))
+                    .collect();
+                duplicates.dedup();
+                (!duplicates.is_empty()).then(|| {
+                    let msg = format!(
+                        "the list for key \"{}\" contains duplicates: {}",
+                        key.value(),
+                        duplicates.join(", ")
+                    );
+                    syn::Error::new(name.span(), msg)
+                })
+            }
+            StateCheck::Eq(name, key, expected) => {
+                let actual = __private::read_state_value(&key.value());
+                match actual {
+                    Ok(actual) if actual == expected.value() => None,
+                    Ok(actual) => Some(format!(
+                        "expected key \"{}\" to be \"{}\", but it is \"{}\"",
+                        key.value(),
+                        expected.value(),
+                        actual
+                    )),
+                    Err(e) => Some(format!(
+                        "expected key \"{}\" to be \"{}\", but it could not be read: {}",
+                        key.value(),
+                        expected.value(),
+                        e
+                    )),
+                }
+                .map(|msg| syn::Error::new(name.span(), msg))
+            }
+        };
+        if let Some(failure) = failure {
+            match &mut errors {
+                Some(errors) => errors.combine(failure),
+                None => errors = Some(failure),
+            }
+        }
+    }
+    match errors {
+        Some(errors) => errors.to_compile_error().into(),
+        None => quote!().into(),
     }
 }